@@ -0,0 +1,97 @@
+/// Carry-less product of `a` and `b`: the low and high 64 bits.
+fn clmul_wide(a: u64, b: u64) -> (u64, u64)
+{
+    let (mut low, mut high) = (0u64, 0u64);
+    for bit in 0..64
+    {
+        if b >> bit & 1 != 0
+        {
+            low ^= a << bit;
+            if bit > 0
+            {
+                high ^= a >> (64 - bit);
+            }
+        }
+    }
+    (low, high)
+}
+
+/// clmul: the low half of the carry-less product.
+pub fn clmul(a: u64, b: u64) -> u64
+{
+    clmul_wide(a, b).0
+}
+
+/// clmulh: the high half of the carry-less product.
+pub fn clmulh(a: u64, b: u64) -> u64
+{
+    clmul_wide(a, b).1
+}
+
+/// clmulr: bits 126:63 of the carry-less product.
+pub fn clmulr(a: u64, b: u64) -> u64
+{
+    let (low, high) = clmul_wide(a, b);
+    (high << 1) | (low >> 63)
+}
+
+/// orc.b: each byte becomes 0xff if any of its bits are set, else 0.
+pub fn orc_b(value: u64) -> u64
+{
+    (0..8)
+        .filter(|byte| value >> (8 * byte) & 0xff != 0)
+        .fold(0, |result, byte| result | 0xff << (8 * byte))
+}
+
+/// brev8: reverses the bits within each byte.
+pub fn brev8(value: u64) -> u64
+{
+    u64::from_le_bytes(value.to_le_bytes().map(u8::reverse_bits))
+}
+
+/// Replaces each `width`-bit element of `indices` with the element of
+/// `table` it selects, or 0 if the index is out of range.
+fn crossbar_permute(table: u64, indices: u64, width: u32) -> u64
+{
+    let mask = (1 << width) - 1;
+    (0..64).step_by(width as usize).fold(0, |result, position| {
+        let index = (indices >> position) & mask;
+        let element = if index < (64 / width) as u64 { (table >> (index * width as u64)) & mask } else { 0 };
+        result | element << position
+    })
+}
+
+/// xperm4: a nibble-wise lookup into `table`.
+pub fn xperm4(table: u64, indices: u64) -> u64
+{
+    crossbar_permute(table, indices, 4)
+}
+
+/// xperm8: a byte-wise lookup into `table`.
+pub fn xperm8(table: u64, indices: u64) -> u64
+{
+    crossbar_permute(table, indices, 8)
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_carry_less_multiply()
+    {
+        assert_eq!(clmul(0b101, 0b11), 0b1111);
+        assert_eq!(clmul(0b110, 0b110), 0b10100);
+        let a = 0x8000_0000_0000_0001;
+        assert_eq!(clmul(a, 0b11), 0x8000_0000_0000_0003);
+        assert_eq!(clmulh(a, 0b11), 1);
+        assert_eq!(clmulr(a, 0b11), 0x3);
+        assert_eq!(clmulr(u64::MAX, 1 << 63), u64::MAX);
+        assert_eq!(orc_b(0x0100_8000_0000_0001), 0xff00_ff00_0000_00ff);
+        assert_eq!(brev8(0x0180_0000_0000_0f01), 0x8001_0000_0000_f080);
+        assert_eq!(xperm8(0x8877_6655_4433_2211, 0x0008_0700_0102_0304), 0x1100_8811_2233_4455);
+        assert_eq!(xperm4(0xfedc_ba98_7654_3210, 0x0123_4567_89ab_cdef), 0x0123_4567_89ab_cdef);
+    }
+}
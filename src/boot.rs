@@ -0,0 +1,178 @@
+/// Size of the header at the start of a RISC-V Linux `Image`.
+pub const IMAGE_HEADER_SIZE: usize = 64;
+
+/// "RISCV\0\0\0", the magic that kernels before 5.x place at offset 48.
+const IMAGE_MAGIC: &[u8; 8] = b"RISCV\0\0\0";
+/// "RSC\x05", the current magic at offset 56.
+const IMAGE_MAGIC2: &[u8; 4] = b"RSC\x05";
+
+/// Bit 0 of the flags field is set for big-endian kernels.
+const IMAGE_FLAG_BE: u64 = 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BootError
+{
+    /// The kernel is shorter than the `Image` header.
+    ImageTooSmall,
+    /// Neither of the header magic values is present.
+    BadImageMagic,
+    /// The kernel was built big-endian.
+    BigEndianImage,
+    /// The kernel does not fit in RAM at its text offset.
+    KernelTooLarge,
+    /// The initrd does not fit in RAM after the kernel.
+    InitrdTooLarge,
+    /// No room is left at the top of RAM for the device tree.
+    DeviceTreeTooLarge,
+    /// The firmware does not fit in RAM or overlaps the next stage.
+    FirmwareTooLarge,
+}
+
+/// The fields of the RISC-V Linux `Image` header that a loader needs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageHeader
+{
+    /// Where the kernel expects to be placed, relative to the start of RAM.
+    pub text_offset: u64,
+    /// Memory the kernel occupies once running, including bss. Zero in
+    /// old kernels, where the file size has to do.
+    pub image_size: u64,
+    pub flags: u64,
+    /// Header version, major in the upper 16 bits.
+    pub version: u32,
+}
+
+impl ImageHeader
+{
+    pub fn parse(image: &[u8]) -> Result<Self, BootError>
+    {
+        if image.len() < IMAGE_HEADER_SIZE
+        {
+            return Err(BootError::ImageTooSmall);
+        }
+        let u64_at = |offset: usize| u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap());
+        if &image[48..56] != IMAGE_MAGIC && &image[56..60] != IMAGE_MAGIC2
+        {
+            return Err(BootError::BadImageMagic);
+        }
+        let header = ImageHeader
+        {
+            text_offset: u64_at(8),
+            image_size: u64_at(16),
+            flags: u64_at(24),
+            version: u32::from_le_bytes(image[32..36].try_into().unwrap()),
+        };
+        if header.flags & IMAGE_FLAG_BE != 0
+        {
+            return Err(BootError::BigEndianImage);
+        }
+        Ok(header)
+    }
+
+    /// Bytes of RAM to set aside for a kernel file of `file_size` bytes.
+    pub fn memory_size(&self, file_size: usize) -> u64
+    {
+        self.image_size.max(file_size as u64)
+    }
+}
+
+/// "OSBI", marking a valid fw_dynamic_info.
+const FW_DYNAMIC_INFO_MAGIC: u64 = 0x4942_534f;
+const FW_DYNAMIC_INFO_VERSION: u64 = 2;
+
+/// Privilege modes the firmware can enter the next stage in.
+pub const NEXT_MODE_U: u64 = 0;
+pub const NEXT_MODE_S: u64 = 1;
+pub const NEXT_MODE_M: u64 = 3;
+
+/// OpenSBI's `struct fw_dynamic_info`, passed in a2 to describe the stage
+/// after the firmware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FwDynamicInfo
+{
+    pub next_addr: u64,
+    pub next_mode: u64,
+    pub options: u64,
+    /// The hart that boots the system; the others wait to be started.
+    pub boot_hart: u64,
+}
+
+impl FwDynamicInfo
+{
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        [FW_DYNAMIC_INFO_MAGIC, FW_DYNAMIC_INFO_VERSION, self.next_addr, self.next_mode, self.options, self.boot_hart]
+            .iter()
+            .flat_map(|field| field.to_le_bytes())
+            .collect()
+    }
+}
+
+/// Code every hart runs from reset: a0 = mhartid, a1 = DTB and
+/// a2 = the fw_dynamic_info that follows the code, then a jump to the
+/// firmware. The addresses are stored after the code, so it runs from
+/// wherever the ROM is mapped.
+pub fn reset_vector(firmware: u64, fdt: u64, info: &FwDynamicInfo) -> Vec<u8>
+{
+    let code: [u32; 6] = [
+        0x0000_0297, // auipc t0, 0
+        0x0282_8613, // addi a2, t0, 40
+        0xf140_2573, // csrr a0, mhartid
+        0x0202_b583, // ld a1, 32(t0)
+        0x0182_b283, // ld t0, 24(t0)
+        0x0002_8067, // jr t0
+    ];
+    let mut rom: Vec<u8> = code.iter().flat_map(|word| word.to_le_bytes()).collect();
+    rom.extend_from_slice(&firmware.to_le_bytes());
+    rom.extend_from_slice(&fdt.to_le_bytes());
+    rom.extend_from_slice(&info.to_bytes());
+    rom
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests
+{
+    use super::*;
+
+    /// A kernel whose header is followed by `code`, with the header's
+    /// first instruction jumping over the rest of the header to it.
+    pub(crate) fn fake_image(code: &[u32], image_size: u64) -> Vec<u8>
+    {
+        let mut image = vec![0u8; IMAGE_HEADER_SIZE];
+        image[0..4].copy_from_slice(&0x0400_006fu32.to_le_bytes()); // j 64
+        image[4..8].copy_from_slice(&0x0000_0013u32.to_le_bytes()); // nop
+        image[8..16].copy_from_slice(&0x20_0000u64.to_le_bytes());
+        image[16..24].copy_from_slice(&image_size.to_le_bytes());
+        image[32..36].copy_from_slice(&0x2u32.to_le_bytes());
+        image[48..56].copy_from_slice(IMAGE_MAGIC);
+        image[56..60].copy_from_slice(IMAGE_MAGIC2);
+        for word in code
+        {
+            image.extend_from_slice(&word.to_le_bytes());
+        }
+        image
+    }
+
+    #[test]
+    fn test_parse_image_header()
+    {
+        let mut image = fake_image(&[], 0x8000);
+        let header = ImageHeader::parse(&image).unwrap();
+        assert_eq!(header.text_offset, 0x20_0000);
+        assert_eq!(header.image_size, 0x8000);
+        assert_eq!(header.version, 2);
+        assert_eq!(header.memory_size(image.len()), 0x8000);
+
+        // Either magic is enough.
+        image[48..56].fill(0);
+        assert!(ImageHeader::parse(&image).is_ok());
+        image[56..60].fill(0);
+        assert_eq!(ImageHeader::parse(&image), Err(BootError::BadImageMagic));
+
+        let mut big_endian = fake_image(&[], 0);
+        big_endian[24] = 1;
+        assert_eq!(ImageHeader::parse(&big_endian), Err(BootError::BigEndianImage));
+        assert_eq!(ImageHeader::parse(&big_endian[..32]), Err(BootError::ImageTooSmall));
+    }
+}
@@ -0,0 +1,321 @@
+//! Scalar cryptography primitives (Zkn, Zks): the round functions behind the
+//! AES, SHA-2, SM3 and SM4 instructions.
+
+const AES_SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+const AES_INV_SBOX: [u8; 256] = [
+    0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,
+    0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,
+    0x54, 0x7b, 0x94, 0x32, 0xa6, 0xc2, 0x23, 0x3d, 0xee, 0x4c, 0x95, 0x0b, 0x42, 0xfa, 0xc3, 0x4e,
+    0x08, 0x2e, 0xa1, 0x66, 0x28, 0xd9, 0x24, 0xb2, 0x76, 0x5b, 0xa2, 0x49, 0x6d, 0x8b, 0xd1, 0x25,
+    0x72, 0xf8, 0xf6, 0x64, 0x86, 0x68, 0x98, 0x16, 0xd4, 0xa4, 0x5c, 0xcc, 0x5d, 0x65, 0xb6, 0x92,
+    0x6c, 0x70, 0x48, 0x50, 0xfd, 0xed, 0xb9, 0xda, 0x5e, 0x15, 0x46, 0x57, 0xa7, 0x8d, 0x9d, 0x84,
+    0x90, 0xd8, 0xab, 0x00, 0x8c, 0xbc, 0xd3, 0x0a, 0xf7, 0xe4, 0x58, 0x05, 0xb8, 0xb3, 0x45, 0x06,
+    0xd0, 0x2c, 0x1e, 0x8f, 0xca, 0x3f, 0x0f, 0x02, 0xc1, 0xaf, 0xbd, 0x03, 0x01, 0x13, 0x8a, 0x6b,
+    0x3a, 0x91, 0x11, 0x41, 0x4f, 0x67, 0xdc, 0xea, 0x97, 0xf2, 0xcf, 0xce, 0xf0, 0xb4, 0xe6, 0x73,
+    0x96, 0xac, 0x74, 0x22, 0xe7, 0xad, 0x35, 0x85, 0xe2, 0xf9, 0x37, 0xe8, 0x1c, 0x75, 0xdf, 0x6e,
+    0x47, 0xf1, 0x1a, 0x71, 0x1d, 0x29, 0xc5, 0x89, 0x6f, 0xb7, 0x62, 0x0e, 0xaa, 0x18, 0xbe, 0x1b,
+    0xfc, 0x56, 0x3e, 0x4b, 0xc6, 0xd2, 0x79, 0x20, 0x9a, 0xdb, 0xc0, 0xfe, 0x78, 0xcd, 0x5a, 0xf4,
+    0x1f, 0xdd, 0xa8, 0x33, 0x88, 0x07, 0xc7, 0x31, 0xb1, 0x12, 0x10, 0x59, 0x27, 0x80, 0xec, 0x5f,
+    0x60, 0x51, 0x7f, 0xa9, 0x19, 0xb5, 0x4a, 0x0d, 0x2d, 0xe5, 0x7a, 0x9f, 0x93, 0xc9, 0x9c, 0xef,
+    0xa0, 0xe0, 0x3b, 0x4d, 0xae, 0x2a, 0xf5, 0xb0, 0xc8, 0xeb, 0xbb, 0x3c, 0x83, 0x53, 0x99, 0x61,
+    0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d,
+];
+
+const SM4_SBOX: [u8; 256] = [
+    0xd6, 0x90, 0xe9, 0xfe, 0xcc, 0xe1, 0x3d, 0xb7, 0x16, 0xb6, 0x14, 0xc2, 0x28, 0xfb, 0x2c, 0x05,
+    0x2b, 0x67, 0x9a, 0x76, 0x2a, 0xbe, 0x04, 0xc3, 0xaa, 0x44, 0x13, 0x26, 0x49, 0x86, 0x06, 0x99,
+    0x9c, 0x42, 0x50, 0xf4, 0x91, 0xef, 0x98, 0x7a, 0x33, 0x54, 0x0b, 0x43, 0xed, 0xcf, 0xac, 0x62,
+    0xe4, 0xb3, 0x1c, 0xa9, 0xc9, 0x08, 0xe8, 0x95, 0x80, 0xdf, 0x94, 0xfa, 0x75, 0x8f, 0x3f, 0xa6,
+    0x47, 0x07, 0xa7, 0xfc, 0xf3, 0x73, 0x17, 0xba, 0x83, 0x59, 0x3c, 0x19, 0xe6, 0x85, 0x4f, 0xa8,
+    0x68, 0x6b, 0x81, 0xb2, 0x71, 0x64, 0xda, 0x8b, 0xf8, 0xeb, 0x0f, 0x4b, 0x70, 0x56, 0x9d, 0x35,
+    0x1e, 0x24, 0x0e, 0x5e, 0x63, 0x58, 0xd1, 0xa2, 0x25, 0x22, 0x7c, 0x3b, 0x01, 0x21, 0x78, 0x87,
+    0xd4, 0x00, 0x46, 0x57, 0x9f, 0xd3, 0x27, 0x52, 0x4c, 0x36, 0x02, 0xe7, 0xa0, 0xc4, 0xc8, 0x9e,
+    0xea, 0xbf, 0x8a, 0xd2, 0x40, 0xc7, 0x38, 0xb5, 0xa3, 0xf7, 0xf2, 0xce, 0xf9, 0x61, 0x15, 0xa1,
+    0xe0, 0xae, 0x5d, 0xa4, 0x9b, 0x34, 0x1a, 0x55, 0xad, 0x93, 0x32, 0x30, 0xf5, 0x8c, 0xb1, 0xe3,
+    0x1d, 0xf6, 0xe2, 0x2e, 0x82, 0x66, 0xca, 0x60, 0xc0, 0x29, 0x23, 0xab, 0x0d, 0x53, 0x4e, 0x6f,
+    0xd5, 0xdb, 0x37, 0x45, 0xde, 0xfd, 0x8e, 0x2f, 0x03, 0xff, 0x6a, 0x72, 0x6d, 0x6c, 0x5b, 0x51,
+    0x8d, 0x1b, 0xaf, 0x92, 0xbb, 0xdd, 0xbc, 0x7f, 0x11, 0xd9, 0x5c, 0x41, 0x1f, 0x10, 0x5a, 0xd8,
+    0x0a, 0xc1, 0x31, 0x88, 0xa5, 0xcd, 0x7b, 0xbd, 0x2d, 0x74, 0xd0, 0x12, 0xb8, 0xe5, 0xb4, 0xb0,
+    0x89, 0x69, 0x97, 0x4a, 0x0c, 0x96, 0x77, 0x7e, 0x65, 0xb9, 0xf1, 0x09, 0xc5, 0x6e, 0xc6, 0x84,
+    0x18, 0xf0, 0x7d, 0xec, 0x3a, 0xdc, 0x4d, 0x20, 0x79, 0xee, 0x5f, 0x3e, 0xd7, 0xcb, 0x39, 0x48,
+];
+
+/// Round constants for aes64ks1i; round number 0xa selects none.
+const AES_RCON: [u8; 11] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36, 0x00];
+
+/// Multiplies by x in GF(2^8) modulo the AES polynomial.
+fn xtime(value: u8) -> u8
+{
+    (value << 1) ^ if value & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+fn gf_mul(mut a: u8, mut b: u8) -> u8
+{
+    let mut product = 0;
+    while b != 0
+    {
+        if b & 1 != 0
+        {
+            product ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    product
+}
+
+fn sub_bytes(value: u64, sbox: &[u8; 256]) -> u64
+{
+    u64::from_le_bytes(value.to_le_bytes().map(|byte| sbox[byte as usize]))
+}
+
+/// Mixes one column, held little-endian with row 0 in the low byte, by the
+/// circulant matrix whose first row is `coefficients`.
+fn mix_column(column: u32, coefficients: [u8; 4]) -> u32
+{
+    let bytes = column.to_le_bytes();
+    let mixed: [u8; 4] = std::array::from_fn(|row| {
+        (0..4).fold(0, |sum, i| sum ^ gf_mul(bytes[(row + i) % 4], coefficients[i]))
+    });
+    u32::from_le_bytes(mixed)
+}
+
+fn mix_columns(value: u64, coefficients: [u8; 4]) -> u64
+{
+    let low = mix_column(value as u32, coefficients) as u64;
+    let high = mix_column((value >> 32) as u32, coefficients) as u64;
+    (high << 32) | low
+}
+
+const MIX_FORWARD: [u8; 4] = [2, 3, 1, 1];
+const MIX_INVERSE: [u8; 4] = [0x0e, 0x0b, 0x0d, 0x09];
+
+/// Columns 0 and 1 of the state `high:low` after ShiftRows, or InvShiftRows
+/// when `inverse` is set.
+fn shift_rows(low: u64, high: u64, inverse: bool) -> u64
+{
+    let mut state = [0u8; 16];
+    state[..8].copy_from_slice(&low.to_le_bytes());
+    state[8..].copy_from_slice(&high.to_le_bytes());
+    let shifted: [u8; 8] = std::array::from_fn(|i| {
+        let (column, row) = (i / 4, i % 4);
+        let source = if inverse { (column + 4 - row) % 4 } else { (column + row) % 4 };
+        state[4 * source + row]
+    });
+    u64::from_le_bytes(shifted)
+}
+
+/// aes64es: ShiftRows and SubBytes, giving the low half of the next state.
+pub fn aes64es(rs1: u64, rs2: u64) -> u64
+{
+    sub_bytes(shift_rows(rs1, rs2, false), &AES_SBOX)
+}
+
+/// aes64esm: a middle encryption round, aes64es followed by MixColumns.
+pub fn aes64esm(rs1: u64, rs2: u64) -> u64
+{
+    mix_columns(aes64es(rs1, rs2), MIX_FORWARD)
+}
+
+/// aes64ds: InvShiftRows and InvSubBytes.
+pub fn aes64ds(rs1: u64, rs2: u64) -> u64
+{
+    sub_bytes(shift_rows(rs1, rs2, true), &AES_INV_SBOX)
+}
+
+/// aes64dsm: a middle decryption round, aes64ds followed by InvMixColumns.
+pub fn aes64dsm(rs1: u64, rs2: u64) -> u64
+{
+    mix_columns(aes64ds(rs1, rs2), MIX_INVERSE)
+}
+
+/// aes64im: InvMixColumns, turning an encryption round key into one for the
+/// equivalent inverse cipher.
+pub fn aes64im(rs1: u64) -> u64
+{
+    mix_columns(rs1, MIX_INVERSE)
+}
+
+/// aes64ks1i: SubWord (and RotWord, except for round 0xa) of the high word
+/// plus the round constant, in both halves. None for a reserved round number.
+pub fn aes64ks1i(rs1: u64, rnum: u32) -> Option<u64>
+{
+    let rcon = *AES_RCON.get(rnum as usize)? as u64;
+    let word = (rs1 >> 32) as u32;
+    let word = if rnum == 0xa { word } else { word.rotate_right(8) };
+    let word = (sub_bytes(word as u64, &AES_SBOX) & 0xffff_ffff) ^ rcon;
+    Some((word << 32) | word)
+}
+
+/// aes64ks2: the running XOR that finishes a key schedule step.
+pub fn aes64ks2(rs1: u64, rs2: u64) -> u64
+{
+    let low = (rs1 >> 32) ^ (rs2 & 0xffff_ffff);
+    let high = low ^ (rs2 >> 32);
+    (high << 32) | low
+}
+
+fn sign_extend_word(value: u32) -> u64
+{
+    value as i32 as i64 as u64
+}
+
+pub fn sha256sig0(rs1: u64) -> u64
+{
+    let x = rs1 as u32;
+    sign_extend_word(x.rotate_right(7) ^ x.rotate_right(18) ^ (x >> 3))
+}
+
+pub fn sha256sig1(rs1: u64) -> u64
+{
+    let x = rs1 as u32;
+    sign_extend_word(x.rotate_right(17) ^ x.rotate_right(19) ^ (x >> 10))
+}
+
+pub fn sha256sum0(rs1: u64) -> u64
+{
+    let x = rs1 as u32;
+    sign_extend_word(x.rotate_right(2) ^ x.rotate_right(13) ^ x.rotate_right(22))
+}
+
+pub fn sha256sum1(rs1: u64) -> u64
+{
+    let x = rs1 as u32;
+    sign_extend_word(x.rotate_right(6) ^ x.rotate_right(11) ^ x.rotate_right(25))
+}
+
+pub fn sha512sig0(x: u64) -> u64
+{
+    x.rotate_right(1) ^ x.rotate_right(8) ^ (x >> 7)
+}
+
+pub fn sha512sig1(x: u64) -> u64
+{
+    x.rotate_right(19) ^ x.rotate_right(61) ^ (x >> 6)
+}
+
+pub fn sha512sum0(x: u64) -> u64
+{
+    x.rotate_right(28) ^ x.rotate_right(34) ^ x.rotate_right(39)
+}
+
+pub fn sha512sum1(x: u64) -> u64
+{
+    x.rotate_right(14) ^ x.rotate_right(18) ^ x.rotate_right(41)
+}
+
+/// sm3p0: the SM3 permutation used in compression.
+pub fn sm3p0(rs1: u64) -> u64
+{
+    let x = rs1 as u32;
+    sign_extend_word(x ^ x.rotate_left(9) ^ x.rotate_left(17))
+}
+
+/// sm3p1: the SM3 permutation used in message expansion.
+pub fn sm3p1(rs1: u64) -> u64
+{
+    let x = rs1 as u32;
+    sign_extend_word(x ^ x.rotate_left(15) ^ x.rotate_left(23))
+}
+
+/// Passes byte `bs` of rs2 through the SM4 S-box and the linear transform
+/// `linear`, then XORs it into rs1 at the byte's position.
+fn sm4_round(rs1: u64, rs2: u64, bs: u32, linear: fn(u32) -> u32) -> u64
+{
+    let shift = 8 * bs;
+    let byte = SM4_SBOX[(rs2 >> shift) as usize & 0xff] as u32;
+    sign_extend_word(rs1 as u32 ^ linear(byte).rotate_left(shift))
+}
+
+/// sm4ed: one byte of an SM4 encryption or decryption round.
+pub fn sm4ed(rs1: u64, rs2: u64, bs: u32) -> u64
+{
+    sm4_round(rs1, rs2, bs, |x| x ^ x.rotate_left(2) ^ x.rotate_left(10) ^ x.rotate_left(18) ^ x.rotate_left(24))
+}
+
+/// sm4ks: one byte of an SM4 key schedule round.
+pub fn sm4ks(rs1: u64, rs2: u64, bs: u32) -> u64
+{
+    sm4_round(rs1, rs2, bs, |x| x ^ x.rotate_left(13) ^ x.rotate_left(23))
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_block_ciphers()
+    {
+        // FIPS-197 appendix C.1, with the key schedule and rounds built from
+        // the instructions the way a guest would.
+        let mut key = [0x0706_0504_0302_0100u64, 0x0f0e_0d0c_0b0a_0908];
+        let mut round_keys = vec![key];
+        for rnum in 0..10
+        {
+            let step = aes64ks1i(key[1], rnum).unwrap();
+            key[0] = aes64ks2(step, key[0]);
+            key[1] = aes64ks2(key[0], key[1]);
+            round_keys.push(key);
+        }
+        let plaintext = [0x7766_5544_3322_1100u64, 0xffee_ddcc_bbaa_9988];
+        let mut state = [plaintext[0] ^ round_keys[0][0], plaintext[1] ^ round_keys[0][1]];
+        for (round, round_key) in round_keys.iter().enumerate().skip(1)
+        {
+            let (low, high) = if round == 10
+            {
+                (aes64es(state[0], state[1]), aes64es(state[1], state[0]))
+            }
+            else
+            {
+                (aes64esm(state[0], state[1]), aes64esm(state[1], state[0]))
+            };
+            state = [low ^ round_key[0], high ^ round_key[1]];
+        }
+        assert_eq!(state, [0x3004_7b6a_d8e0_c469, 0x5ac5_b470_80b7_cdd8]);
+        state = [state[0] ^ round_keys[10][0], state[1] ^ round_keys[10][1]];
+        for round in (0..10).rev()
+        {
+            let (low, high) = if round == 0
+            {
+                (aes64ds(state[0], state[1]), aes64ds(state[1], state[0]))
+            }
+            else
+            {
+                (aes64dsm(state[0], state[1]), aes64dsm(state[1], state[0]))
+            };
+            let round_key = round_keys[round];
+            let round_key = if round == 0 { round_key } else { round_key.map(aes64im) };
+            state = [low ^ round_key[0], high ^ round_key[1]];
+        }
+        assert_eq!(state, plaintext);
+        assert_eq!(aes64ks1i(0, 0xb), None);
+
+        // One SM4 round computes X4 = X0 ^ T(X1 ^ X2 ^ X3 ^ rk) a byte at a time.
+        let mixed = 0x0123_4567u64;
+        let x0 = 0x89ab_cdefu64;
+        let round = (0..4).fold(x0, |acc, bs| sm4ed(acc, mixed, bs));
+        assert_eq!(round, 0xffff_ffff_e75a_4d26);
+        assert_eq!(sha256sum0(0x6a09_e667), 0xffff_ffff_ce20_b47e);
+        assert_eq!(sm3p0(1), 0x0002_0201);
+    }
+}
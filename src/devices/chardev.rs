@@ -0,0 +1,203 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::raw::{c_char, c_int};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const O_NONBLOCK: c_int = 0o4000;
+const O_NOCTTY: c_int = 0o400;
+
+extern "C"
+{
+    fn grantpt(fd: c_int) -> c_int;
+    fn unlockpt(fd: c_int) -> c_int;
+    fn ptsname_r(fd: c_int, buf: *mut c_char, buflen: usize) -> c_int;
+}
+
+/// Host end of a guest character device such as a serial port.
+///
+/// Output is written synchronously. Input is read by a background thread
+/// and handed over through a channel, so the device side never blocks.
+pub struct CharBackend
+{
+    output: Box<dyn Write + Send>,
+    input: Option<Receiver<u8>>,
+}
+
+impl CharBackend
+{
+    pub fn new(output: Box<dyn Write + Send>, input: Option<Receiver<u8>>) -> Self
+    {
+        CharBackend { output, input }
+    }
+
+    /// Writes to the VMM's stdout and reads from its stdin.
+    pub fn stdio() -> Self
+    {
+        let (sender, receiver) = mpsc::channel();
+        spawn_reader(io::stdin(), sender);
+        Self::new(Box::new(io::stdout()), Some(receiver))
+    }
+
+    /// Appends output to a file. There is no input.
+    pub fn file(path: &Path) -> io::Result<Self>
+    {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(Box::new(file), None))
+    }
+
+    /// Allocates a pseudo-terminal and returns the backend with the path of
+    /// the slave device that a terminal program can attach to.
+    pub fn pty() -> io::Result<(Self, PathBuf)>
+    {
+        // Non-blocking so guest output is dropped rather than stalling the
+        // vCPU while nobody has the slave open.
+        let master = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(O_NOCTTY | O_NONBLOCK)
+            .open("/dev/ptmx")?;
+        let fd = master.as_raw_fd();
+        let mut name = [0 as c_char; 128];
+        // SAFETY: `fd` is an open pty master and `name` outlives the call.
+        let result = unsafe
+        {
+            if grantpt(fd) != 0 || unlockpt(fd) != 0
+            {
+                -1
+            }
+            else
+            {
+                ptsname_r(fd, name.as_mut_ptr(), name.len())
+            }
+        };
+        if result != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        let name: Vec<u8> = name.iter().take_while(|&&c| c != 0).map(|&c| c as u8).collect();
+        let path = PathBuf::from(String::from_utf8_lossy(&name).into_owned());
+
+        let (sender, receiver) = mpsc::channel();
+        spawn_polling_reader(master.try_clone()?, sender);
+        Ok((Self::new(Box::new(master), Some(receiver)), path))
+    }
+
+    /// Listens on a Unix stream socket and talks to whichever client
+    /// connected last. Output is discarded while no client is connected.
+    pub fn unix_listen(path: &Path) -> io::Result<Self>
+    {
+        let listener = UnixListener::bind(path)?;
+        let client: Arc<Mutex<Option<UnixStream>>> = Arc::new(Mutex::new(None));
+        let (sender, receiver) = mpsc::channel();
+
+        let accepted = client.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten()
+            {
+                if let Ok(reader) = stream.try_clone()
+                {
+                    *accepted.lock().unwrap() = Some(stream);
+                    spawn_reader(reader, sender.clone());
+                }
+            }
+        });
+        Ok(Self::new(Box::new(SharedStream(client)), Some(receiver)))
+    }
+
+    /// Connects to an existing Unix stream socket.
+    pub fn unix_connect(path: &Path) -> io::Result<Self>
+    {
+        let stream = UnixStream::connect(path)?;
+        let (sender, receiver) = mpsc::channel();
+        spawn_reader(stream.try_clone()?, sender);
+        Ok(Self::new(Box::new(stream), Some(receiver)))
+    }
+
+    /// Sends guest output to the host. Errors mean the host side went away
+    /// and are ignored, just like a serial line with nothing attached.
+    pub fn write(&mut self, data: &[u8])
+    {
+        let _ = self.output.write_all(data);
+        let _ = self.output.flush();
+    }
+
+    /// Takes the next byte of host input, if one has arrived.
+    pub fn read_byte(&mut self) -> Option<u8>
+    {
+        self.input.as_ref()?.try_recv().ok()
+    }
+}
+
+/// Writer for a socket whose peer may come and go.
+struct SharedStream(Arc<Mutex<Option<UnixStream>>>);
+
+impl Write for SharedStream
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        let mut client = self.0.lock().unwrap();
+        match client.as_mut()
+        {
+            Some(stream) =>
+            {
+                let result = stream.write(buf);
+                if result.is_err()
+                {
+                    *client = None;
+                }
+                result
+            }
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        Ok(())
+    }
+}
+
+fn spawn_reader<R: Read + Send + 'static>(mut reader: R, sender: Sender<u8>)
+{
+    thread::spawn(move || {
+        let mut buf = [0u8; 256];
+        while let Ok(count) = reader.read(&mut buf)
+        {
+            if count == 0 || buf[..count].iter().any(|&byte| sender.send(byte).is_err())
+            {
+                break;
+            }
+        }
+    });
+}
+
+/// Reader for a non-blocking pty master, which also reports errors while
+/// no slave is open; both cases just mean "try again later".
+fn spawn_polling_reader(mut reader: File, sender: Sender<u8>)
+{
+    thread::spawn(move || {
+        let mut buf = [0u8; 256];
+        loop
+        {
+            match reader.read(&mut buf)
+            {
+                Ok(count) if count > 0 =>
+                {
+                    if buf[..count].iter().any(|&byte| sender.send(byte).is_err())
+                    {
+                        break;
+                    }
+                }
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                _ => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    });
+}
@@ -0,0 +1,174 @@
+use std::time::{Duration, Instant};
+
+use crate::devices::{IrqLine, MmioDevice};
+use crate::ram::Ram;
+
+pub const CLINT_MMIO_SIZE: u64 = 0x1_0000;
+
+// Register offsets
+const MSIP_BASE: u64 = 0x0000;
+const MTIMECMP_BASE: u64 = 0x4000;
+const MTIME: u64 = 0xbff8;
+
+/// The machine timer: a counter running at the timebase frequency from
+/// when the clock was created. Clones share the same time.
+#[derive(Clone, Copy)]
+pub struct Clock
+{
+    start: Instant,
+    frequency: u64,
+}
+
+impl Clock
+{
+    pub fn new(frequency: u64) -> Self
+    {
+        Clock { start: Instant::now(), frequency }
+    }
+
+    pub fn now(&self) -> u64
+    {
+        (self.start.elapsed().as_nanos() * self.frequency as u128 / 1_000_000_000) as u64
+    }
+
+    /// Host time left until the counter reaches `deadline`.
+    pub fn until(&self, deadline: u64) -> Duration
+    {
+        let ticks = deadline.saturating_sub(self.now()) as u128;
+        Duration::from_nanos((ticks * 1_000_000_000 / self.frequency as u128).min(u64::MAX as u128) as u64)
+    }
+}
+
+/// A SiFive-compatible CLINT: per-hart software interrupt bits and timer
+/// compare registers, plus the shared mtime counter.
+///
+/// Each hart has a software and a timer line that the machine copies into
+/// its mip.MSIP and mip.MTIP. mtime follows the Clock and ignores writes.
+pub struct Clint
+{
+    clock: Clock,
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
+    software_irqs: Vec<IrqLine>,
+    timer_irqs: Vec<IrqLine>,
+}
+
+impl Clint
+{
+    /// One hart per pair of lines; `software_irqs` and `timer_irqs` are
+    /// indexed by hart ID.
+    pub fn new(clock: Clock, software_irqs: Vec<IrqLine>, timer_irqs: Vec<IrqLine>) -> Self
+    {
+        let harts = software_irqs.len();
+        Clint
+        {
+            clock,
+            msip: vec![false; harts],
+            mtimecmp: vec![u64::MAX; harts],
+            software_irqs,
+            timer_irqs,
+        }
+    }
+
+    fn update_timers(&self)
+    {
+        let now = self.clock.now();
+        for (compare, irq) in self.mtimecmp.iter().zip(&self.timer_irqs)
+        {
+            irq.set_level(now >= *compare);
+        }
+    }
+}
+
+/// Reads `size` bytes at byte `offset` within a 64-bit register.
+fn read_part(register: u64, offset: u64, size: usize) -> u64
+{
+    let value = register >> (8 * offset);
+    if size >= 8 { value } else { value & ((1 << (8 * size)) - 1) }
+}
+
+/// Replaces `size` bytes at byte `offset` within a 64-bit register, so
+/// 32-bit halves can be written separately.
+fn write_part(register: u64, offset: u64, size: usize, value: u64) -> u64
+{
+    let mask = if size >= 8 { u64::MAX } else { ((1 << (8 * size)) - 1) << (8 * offset) };
+    (register & !mask) | ((value << (8 * offset)) & mask)
+}
+
+impl MmioDevice for Clint
+{
+    fn read(&mut self, offset: u64, size: usize) -> u64
+    {
+        let harts = self.msip.len() as u64;
+        if offset < MSIP_BASE + 4 * harts
+        {
+            return self.msip[(offset / 4) as usize] as u64;
+        }
+        if (MTIMECMP_BASE..MTIMECMP_BASE + 8 * harts).contains(&offset)
+        {
+            let hart = ((offset - MTIMECMP_BASE) / 8) as usize;
+            return read_part(self.mtimecmp[hart], offset % 8, size);
+        }
+        if (MTIME..MTIME + 8).contains(&offset)
+        {
+            return read_part(self.clock.now(), offset - MTIME, size);
+        }
+        0
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64)
+    {
+        let harts = self.msip.len() as u64;
+        if offset < MSIP_BASE + 4 * harts
+        {
+            let hart = (offset / 4) as usize;
+            self.msip[hart] = value & 1 != 0;
+            self.software_irqs[hart].set_level(self.msip[hart]);
+        }
+        else if (MTIMECMP_BASE..MTIMECMP_BASE + 8 * harts).contains(&offset)
+        {
+            let hart = ((offset - MTIMECMP_BASE) / 8) as usize;
+            self.mtimecmp[hart] = write_part(self.mtimecmp[hart], offset % 8, size, value);
+            self.update_timers();
+        }
+    }
+
+    fn tick(&mut self, _ram: &mut Ram)
+    {
+        self.update_timers();
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_software_and_timer_interrupts()
+    {
+        let software = vec![IrqLine::new(), IrqLine::new()];
+        let timer = vec![IrqLine::new(), IrqLine::new()];
+        let mut clint = Clint::new(Clock::new(10_000_000), software.clone(), timer.clone());
+
+        clint.write(4, 4, 1);
+        assert!(software[1].is_raised() && !software[0].is_raised());
+        assert_eq!(clint.read(4, 4), 1);
+        clint.write(4, 4, 0);
+        assert!(!software[1].is_raised());
+
+        // A compare value in the past fires at once; the upper half can be
+        // written on its own.
+        clint.write(MTIMECMP_BASE, 4, 0);
+        clint.write(MTIMECMP_BASE + 4, 4, 0);
+        assert!(timer[0].is_raised() && !timer[1].is_raised());
+        clint.write(MTIMECMP_BASE + 4, 4, 0xffff_ffff);
+        assert_eq!(clint.read(MTIMECMP_BASE, 8), 0xffff_ffff_0000_0000);
+        assert!(!timer[0].is_raised());
+
+        let before = clint.read(MTIME, 8);
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(clint.read(MTIME, 8) > before);
+    }
+}
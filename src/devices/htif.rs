@@ -0,0 +1,160 @@
+use crate::devices::chardev::CharBackend;
+use crate::devices::{IrqLine, MmioDevice};
+use crate::ram::Ram;
+
+pub const HTIF_MMIO_SIZE: u64 = 0x1000;
+
+// Register offsets
+const TOHOST: u64 = 0x0;
+const FROMHOST: u64 = 0x8;
+
+// Devices and commands, in bits 63:56 and 55:48 of tohost
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+const CONSOLE_GETCHAR: u64 = 0;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+const PAYLOAD_MASK: u64 = (1 << 48) - 1;
+
+/// The Berkeley host-target interface used by Spike: the guest writes
+/// requests to `tohost` and reads replies from `fromhost`.
+///
+/// Only the console and the exit request are served. An exit raises
+/// `poweroff`; the exit code itself is dropped. Other syscall requests,
+/// which point into guest memory, are ignored.
+pub struct Htif
+{
+    backend: CharBackend,
+    poweroff: IrqLine,
+    tohost: u64,
+    fromhost: u64,
+    read_pending: bool,
+}
+
+impl Htif
+{
+    pub fn new(backend: CharBackend, poweroff: IrqLine) -> Self
+    {
+        Htif
+        {
+            backend,
+            poweroff,
+            tohost: 0,
+            fromhost: 0,
+            read_pending: false,
+        }
+    }
+
+    fn handle_request(&mut self)
+    {
+        let device = self.tohost >> 56;
+        let command = (self.tohost >> 48) & 0xff;
+        let payload = self.tohost & PAYLOAD_MASK;
+        match (device, command)
+        {
+            (DEVICE_SYSCALL, 0) if payload & 1 != 0 => self.poweroff.set_level(true),
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) =>
+            {
+                self.backend.write(&[payload as u8]);
+                self.fromhost = (DEVICE_CONSOLE << 56) | (CONSOLE_PUTCHAR << 48);
+            }
+            // The reply comes once a byte arrives and fromhost is free.
+            (DEVICE_CONSOLE, CONSOLE_GETCHAR) => self.read_pending = true,
+            _ => {}
+        }
+        self.tohost = 0;
+    }
+
+    fn poll_input(&mut self)
+    {
+        if self.read_pending && self.fromhost == 0
+        {
+            if let Some(byte) = self.backend.read_byte()
+            {
+                self.fromhost = (DEVICE_CONSOLE << 56) | (CONSOLE_GETCHAR << 48) | byte as u64;
+                self.read_pending = false;
+            }
+        }
+    }
+}
+
+/// Replaces `size` bytes at byte `offset` within a 64-bit register.
+fn write_part(register: u64, offset: u64, size: usize, value: u64) -> u64
+{
+    let mask = if size >= 8 { u64::MAX } else { ((1 << (8 * size)) - 1) << (8 * offset) };
+    (register & !mask) | ((value << (8 * offset)) & mask)
+}
+
+impl MmioDevice for Htif
+{
+    fn read(&mut self, offset: u64, size: usize) -> u64
+    {
+        let register = match offset & !0x7
+        {
+            TOHOST => self.tohost,
+            FROMHOST => self.fromhost,
+            _ => 0,
+        };
+        let value = register >> (8 * (offset & 0x7));
+        if size >= 8 { value } else { value & ((1 << (8 * size)) - 1) }
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64)
+    {
+        let part = offset & 0x7;
+        match offset & !0x7
+        {
+            TOHOST =>
+            {
+                self.tohost = write_part(self.tohost, part, size, value);
+                // 32-bit guests write the low half first; the request is
+                // complete once the upper half lands.
+                if part + size as u64 == 8
+                {
+                    self.handle_request();
+                }
+            }
+            FROMHOST => self.fromhost = write_part(self.fromhost, part, size, value),
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, _ram: &mut Ram)
+    {
+        self.poll_input();
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_console_and_exit()
+    {
+        let (sender, receiver) = mpsc::channel();
+        let poweroff = IrqLine::new();
+        let mut htif = Htif::new(CharBackend::new(Box::new(std::io::sink()), Some(receiver)), poweroff.clone());
+        let mut ram = Ram::new(0, 0);
+
+        htif.write(TOHOST, 8, (DEVICE_CONSOLE << 56) | (CONSOLE_PUTCHAR << 48) | b'x' as u64);
+        assert_eq!(htif.read(TOHOST, 8), 0);
+        assert_eq!(htif.read(FROMHOST, 8), (DEVICE_CONSOLE << 56) | (CONSOLE_PUTCHAR << 48));
+        htif.write(FROMHOST, 8, 0);
+
+        htif.write(TOHOST, 8, DEVICE_CONSOLE << 56);
+        htif.tick(&mut ram);
+        assert_eq!(htif.read(FROMHOST, 8), 0);
+        sender.send(b'q').unwrap();
+        htif.tick(&mut ram);
+        assert_eq!(htif.read(FROMHOST, 8), (DEVICE_CONSOLE << 56) | b'q' as u64);
+
+        htif.write(TOHOST, 4, 1);
+        assert!(!poweroff.is_raised());
+        htif.write(TOHOST + 4, 4, 0);
+        assert!(poweroff.is_raised());
+    }
+}
@@ -0,0 +1,123 @@
+pub mod chardev;
+pub mod clint;
+pub mod htif;
+pub mod netdev;
+pub mod plic;
+pub mod rom;
+pub mod sifive_uart;
+pub mod uart;
+pub mod usernet;
+pub mod virtio;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::ram::Ram;
+
+/// A device whose registers are mapped into the guest physical address space.
+///
+/// Offsets are relative to the start of the device's region and `size` is
+/// the access width in bytes (1, 2, 4 or 8).
+pub trait MmioDevice
+{
+    fn read(&mut self, offset: u64, size: usize) -> u64;
+
+    fn write(&mut self, offset: u64, size: usize, value: u64);
+
+    /// Lets the device make progress outside of register accesses: drain
+    /// host input, service guest requests in RAM and update its interrupt
+    /// line. Called after every MMIO write and periodically by the run loop.
+    fn tick(&mut self, _ram: &mut Ram) {}
+}
+
+/// A level-triggered interrupt line from a device to the interrupt controller.
+///
+/// Clones share the same line, so the device keeps one end and the
+/// interrupt controller samples the other.
+#[derive(Clone, Default)]
+pub struct IrqLine(Arc<AtomicBool>);
+
+impl IrqLine
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    pub fn set_level(&self, raised: bool)
+    {
+        self.0.store(raised, Ordering::Release);
+    }
+
+    pub fn is_raised(&self) -> bool
+    {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+struct MmioRegion
+{
+    base: u64,
+    size: u64,
+    device: Box<dyn MmioDevice>,
+}
+
+/// The set of MMIO regions that the slow memory path dispatches to.
+#[derive(Default)]
+pub struct MmioBus
+{
+    regions: Vec<MmioRegion>,
+}
+
+impl MmioBus
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    pub fn attach(&mut self, base: u64, size: u64, device: Box<dyn MmioDevice>)
+    {
+        self.regions.push(MmioRegion { base, size, device });
+    }
+
+    fn find(&mut self, addr: u64, size: usize) -> Option<(&mut MmioRegion, u64)>
+    {
+        // Offsets avoid overflow for accesses near the top of the address space.
+        self.regions.iter_mut()
+            .find_map(|region| {
+                let offset = addr.checked_sub(region.base)?;
+                (offset.checked_add(size as u64)? <= region.size).then_some((region, offset))
+            })
+    }
+
+    /// Reads from the device mapped at `addr`, if any.
+    pub fn read(&mut self, addr: u64, size: usize) -> Option<u64>
+    {
+        let (region, offset) = self.find(addr, size)?;
+        Some(region.device.read(offset, size))
+    }
+
+    /// Writes to the device mapped at `addr`, returning false if there is none.
+    pub fn write(&mut self, addr: u64, size: usize, value: u64, ram: &mut Ram) -> bool
+    {
+        match self.find(addr, size)
+        {
+            Some((region, offset)) =>
+            {
+                region.device.write(offset, size, value);
+                region.device.tick(ram);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn tick(&mut self, ram: &mut Ram)
+    {
+        for region in &mut self.regions
+        {
+            region.device.tick(ram);
+        }
+    }
+}
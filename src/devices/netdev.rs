@@ -0,0 +1,59 @@
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+/// Largest Ethernet frame exchanged with a backend (no jumbo frames).
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+/// Host end of a guest network interface, exchanging whole Ethernet frames.
+pub trait NetBackend
+{
+    /// Sends a frame from the guest. Frames that cannot be delivered are
+    /// dropped, as they would be on a real wire.
+    fn send(&mut self, frame: &[u8]);
+
+    /// Takes the next frame for the guest, if one is waiting.
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+/// Exchanges frames with a peer over Unix datagram sockets, one frame per
+/// datagram. Two VMs are cross-connected by giving each the other's path.
+pub struct UnixDatagramBackend
+{
+    socket: UnixDatagram,
+    peer: PathBuf,
+}
+
+impl UnixDatagramBackend
+{
+    /// Binds `local` and sends to `peer`. The peer does not have to exist
+    /// yet; frames sent before it binds are lost.
+    pub fn new(local: &Path, peer: &Path) -> io::Result<Self>
+    {
+        let socket = UnixDatagram::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(UnixDatagramBackend { socket, peer: peer.to_path_buf() })
+    }
+}
+
+impl NetBackend for UnixDatagramBackend
+{
+    fn send(&mut self, frame: &[u8])
+    {
+        let _ = self.socket.send_to(frame, &self.peer);
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>>
+    {
+        let mut buf = vec![0; MAX_FRAME_SIZE];
+        match self.socket.recv(&mut buf)
+        {
+            Ok(len) =>
+            {
+                buf.truncate(len);
+                Some(buf)
+            }
+            Err(_) => None,
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use crate::devices::{IrqLine, MmioDevice};
+use crate::ram::Ram;
+
+// Register offsets
+const PRIORITY_BASE: u64 = 0x0;
+const PENDING_BASE: u64 = 0x1000;
+const ENABLE_BASE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT_BASE: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+const CONTEXT_THRESHOLD: u64 = 0x0;
+const CONTEXT_CLAIM: u64 = 0x4;
+
+/// Highest priority a source can be given; writes are truncated to it.
+const MAX_PRIORITY: u32 = 7;
+
+/// A SiFive-compatible PLIC routing level-triggered device interrupts to
+/// hart contexts.
+///
+/// Source N is driven by `sources[N]` (source 0 does not exist). Each
+/// context has an output line that the machine copies into a hart's
+/// mip.MEIP or mip.SEIP; the virt layout gives hart H contexts 2H (M-mode)
+/// and 2H + 1 (S-mode).
+pub struct Plic
+{
+    sources: Vec<IrqLine>,
+    contexts: Vec<IrqLine>,
+    priority: Vec<u32>,
+    pending: Vec<bool>,
+    /// Claimed and not yet completed, so not pending again until completed.
+    in_service: Vec<bool>,
+    enable: Vec<Vec<bool>>,
+    threshold: Vec<u32>,
+}
+
+impl Plic
+{
+    pub fn new(sources: Vec<IrqLine>, contexts: Vec<IrqLine>) -> Self
+    {
+        let count = sources.len();
+        Plic
+        {
+            priority: vec![0; count],
+            pending: vec![false; count],
+            in_service: vec![false; count],
+            enable: vec![vec![false; count]; contexts.len()],
+            threshold: vec![0; contexts.len()],
+            sources,
+            contexts,
+        }
+    }
+
+    /// The pending, enabled source with the highest priority above the
+    /// context's threshold; the lowest ID wins a tie.
+    fn best_source(&self, context: usize) -> Option<usize>
+    {
+        (1..self.sources.len())
+            .filter(|&source| self.pending[source] && self.enable[context][source])
+            .filter(|&source| self.priority[source] > self.threshold[context])
+            .fold(None, |best: Option<usize>, source| match best
+            {
+                Some(best) if self.priority[best] >= self.priority[source] => Some(best),
+                _ => Some(source),
+            })
+    }
+
+    fn update(&mut self)
+    {
+        for source in 1..self.sources.len()
+        {
+            if self.sources[source].is_raised() && !self.in_service[source]
+            {
+                self.pending[source] = true;
+            }
+        }
+        for context in 0..self.contexts.len()
+        {
+            self.contexts[context].set_level(self.best_source(context).is_some());
+        }
+    }
+
+    /// The context and register offset within it, for context registers.
+    fn context_register(&self, offset: u64) -> Option<(usize, u64)>
+    {
+        let context = offset.checked_sub(CONTEXT_BASE)? / CONTEXT_STRIDE;
+        if context < self.contexts.len() as u64
+        {
+            Some((context as usize, offset % CONTEXT_STRIDE))
+        }
+        else
+        {
+            None
+        }
+    }
+
+    /// The context and first source of a 32-bit enable word.
+    fn enable_word(&self, offset: u64) -> Option<(usize, usize)>
+    {
+        if offset >= CONTEXT_BASE
+        {
+            return None;
+        }
+        let context = offset.checked_sub(ENABLE_BASE)? / ENABLE_STRIDE;
+        let first = (offset % ENABLE_STRIDE) as usize / 4 * 32;
+        if context < self.contexts.len() as u64 && first < self.sources.len()
+        {
+            Some((context as usize, first))
+        }
+        else
+        {
+            None
+        }
+    }
+
+    fn bits(&self, flags: &[bool], first: usize) -> u64
+    {
+        (0..32)
+            .filter(|bit| flags.get(first + bit).copied().unwrap_or(false))
+            .fold(0, |word, bit| word | (1 << bit))
+    }
+}
+
+impl MmioDevice for Plic
+{
+    fn read(&mut self, offset: u64, _size: usize) -> u64
+    {
+        self.update();
+        let count = self.sources.len() as u64;
+        if (PRIORITY_BASE..PRIORITY_BASE + 4 * count).contains(&offset)
+        {
+            return self.priority[(offset / 4) as usize] as u64;
+        }
+        if (PENDING_BASE..PENDING_BASE + count.div_ceil(32) * 4).contains(&offset)
+        {
+            return self.bits(&self.pending, (offset - PENDING_BASE) as usize / 4 * 32);
+        }
+        if let Some((context, first)) = self.enable_word(offset)
+        {
+            return self.bits(&self.enable[context], first);
+        }
+        match self.context_register(offset)
+        {
+            Some((context, CONTEXT_THRESHOLD)) => self.threshold[context] as u64,
+            Some((context, CONTEXT_CLAIM)) =>
+            {
+                let Some(source) = self.best_source(context) else { return 0 };
+                self.pending[source] = false;
+                self.in_service[source] = true;
+                self.update();
+                source as u64
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u64, _size: usize, value: u64)
+    {
+        let count = self.sources.len() as u64;
+        if (PRIORITY_BASE + 4..PRIORITY_BASE + 4 * count).contains(&offset)
+        {
+            self.priority[(offset / 4) as usize] = (value as u32).min(MAX_PRIORITY);
+        }
+        else if let Some((context, first)) = self.enable_word(offset)
+        {
+            for bit in 0..32
+            {
+                // Source 0 does not exist and cannot be enabled.
+                if first + bit != 0 && first + bit < self.sources.len()
+                {
+                    self.enable[context][first + bit] = value & (1 << bit) != 0;
+                }
+            }
+        }
+        else
+        {
+            match self.context_register(offset)
+            {
+                Some((context, CONTEXT_THRESHOLD)) => self.threshold[context] = (value as u32).min(MAX_PRIORITY),
+                Some((_, CONTEXT_CLAIM)) =>
+                {
+                    // Completion lets a still-raised line become pending again.
+                    if let Some(in_service) = self.in_service.get_mut(value as usize)
+                    {
+                        *in_service = false;
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.update();
+    }
+
+    fn tick(&mut self, _ram: &mut Ram)
+    {
+        self.update();
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_claim_and_complete()
+    {
+        let sources: Vec<IrqLine> = (0..8).map(|_| IrqLine::new()).collect();
+        let contexts = vec![IrqLine::new(), IrqLine::new()];
+        let mut plic = Plic::new(sources.clone(), contexts.clone());
+        plic.write(4 * 3, 4, 1);
+        plic.write(4 * 5, 4, 2);
+        plic.write(ENABLE_BASE + ENABLE_STRIDE, 4, (1 << 3) | (1 << 5));
+
+        sources[3].set_level(true);
+        sources[5].set_level(true);
+        plic.tick(&mut Ram::new(0, 0));
+        assert!(!contexts[0].is_raised());
+        assert!(contexts[1].is_raised());
+        assert_eq!(plic.read(PENDING_BASE, 4), (1 << 3) | (1 << 5));
+
+        // Higher priority first; a claimed source stays quiet until completed.
+        let claim = CONTEXT_BASE + CONTEXT_STRIDE + CONTEXT_CLAIM;
+        assert_eq!(plic.read(claim, 4), 5);
+        assert_eq!(plic.read(claim, 4), 3);
+        assert_eq!(plic.read(claim, 4), 0);
+        assert!(!contexts[1].is_raised());
+        sources[3].set_level(false);
+        plic.write(claim, 4, 3);
+        plic.write(claim, 4, 5);
+        assert_eq!(plic.read(claim, 4), 5);
+
+        // The threshold masks priorities at or below it.
+        plic.write(claim, 4, 5);
+        plic.write(CONTEXT_BASE + CONTEXT_STRIDE, 4, 2);
+        assert!(!contexts[1].is_raised());
+    }
+}
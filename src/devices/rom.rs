@@ -0,0 +1,49 @@
+use crate::devices::MmioDevice;
+
+/// Read-only memory mapped as a device, such as the reset vector ROM.
+/// Writes are ignored and reads past the contents return zero.
+pub struct Rom
+{
+    data: Vec<u8>,
+}
+
+impl Rom
+{
+    pub fn new(data: Vec<u8>) -> Self
+    {
+        Rom { data }
+    }
+}
+
+impl MmioDevice for Rom
+{
+    fn read(&mut self, offset: u64, size: usize) -> u64
+    {
+        let mut value = 0;
+        for i in 0..size
+        {
+            let byte = self.data.get(offset as usize + i).copied().unwrap_or(0);
+            value |= (byte as u64) << (8 * i);
+        }
+        value
+    }
+
+    fn write(&mut self, _offset: u64, _size: usize, _value: u64) {}
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_reads_contents_and_ignores_writes()
+    {
+        let mut rom = Rom::new(vec![0x11, 0x22, 0x33, 0x44, 0x55]);
+        assert_eq!(rom.read(0, 4), 0x4433_2211);
+        rom.write(0, 4, 0);
+        assert_eq!(rom.read(3, 4), 0x5544);
+        assert_eq!(rom.read(0x100, 8), 0);
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+
+use crate::devices::chardev::CharBackend;
+use crate::devices::{IrqLine, MmioDevice};
+use crate::ram::Ram;
+
+pub const SIFIVE_UART_MMIO_SIZE: u64 = 0x1000;
+
+// Register offsets
+const TXDATA: u64 = 0x00;
+const RXDATA: u64 = 0x04;
+const TXCTRL: u64 = 0x08;
+const RXCTRL: u64 = 0x0c;
+const IE: u64 = 0x10;
+const IP: u64 = 0x14;
+const DIV: u64 = 0x18;
+
+/// Set in rxdata when the receive FIFO is empty.
+const RXDATA_EMPTY: u64 = 1 << 31;
+
+// ie/ip bits
+const IP_TXWM: u32 = 1 << 0;
+const IP_RXWM: u32 = 1 << 1;
+
+const FIFO_SIZE: usize = 8;
+
+/// The watermark count in bits 18:16 of txctrl and rxctrl.
+fn watermark(ctrl: u32) -> usize
+{
+    ((ctrl >> 16) & 0x7) as usize
+}
+
+/// The UART of SiFive's FU540 and FE310, as found on the HiFive boards.
+///
+/// Transmitted bytes go straight to the host backend, so the transmit
+/// FIFO is always empty. Received bytes are pulled from the backend while
+/// the receive FIFO has room.
+pub struct SifiveUart
+{
+    backend: CharBackend,
+    irq: IrqLine,
+    rx_fifo: VecDeque<u8>,
+    txctrl: u32,
+    rxctrl: u32,
+    ie: u32,
+    div: u32,
+}
+
+impl SifiveUart
+{
+    pub fn new(backend: CharBackend, irq: IrqLine) -> Self
+    {
+        SifiveUart
+        {
+            backend,
+            irq,
+            rx_fifo: VecDeque::with_capacity(FIFO_SIZE),
+            txctrl: 0,
+            rxctrl: 0,
+            ie: 0,
+            div: 0,
+        }
+    }
+
+    fn pending(&self) -> u32
+    {
+        let mut ip = 0;
+        // The empty transmit FIFO is below any non-zero watermark.
+        if watermark(self.txctrl) > 0
+        {
+            ip |= IP_TXWM;
+        }
+        if self.rx_fifo.len() > watermark(self.rxctrl)
+        {
+            ip |= IP_RXWM;
+        }
+        ip
+    }
+
+    fn update_irq(&self)
+    {
+        self.irq.set_level(self.pending() & self.ie != 0);
+    }
+}
+
+impl MmioDevice for SifiveUart
+{
+    fn read(&mut self, offset: u64, _size: usize) -> u64
+    {
+        let value = match offset
+        {
+            TXDATA => 0,
+            RXDATA => match self.rx_fifo.pop_front()
+            {
+                Some(byte) => byte as u64,
+                None => RXDATA_EMPTY,
+            },
+            TXCTRL => self.txctrl as u64,
+            RXCTRL => self.rxctrl as u64,
+            IE => self.ie as u64,
+            IP => self.pending() as u64,
+            DIV => self.div as u64,
+            _ => 0,
+        };
+        self.update_irq();
+        value
+    }
+
+    fn write(&mut self, offset: u64, _size: usize, value: u64)
+    {
+        match offset
+        {
+            TXDATA => self.backend.write(&[value as u8]),
+            TXCTRL => self.txctrl = value as u32,
+            RXCTRL => self.rxctrl = value as u32,
+            IE => self.ie = value as u32 & (IP_TXWM | IP_RXWM),
+            DIV => self.div = value as u32,
+            _ => {}
+        }
+        self.update_irq();
+    }
+
+    fn tick(&mut self, _ram: &mut Ram)
+    {
+        while self.rx_fifo.len() < FIFO_SIZE
+        {
+            match self.backend.read_byte()
+            {
+                Some(byte) => self.rx_fifo.push_back(byte),
+                None => break,
+            }
+        }
+        self.update_irq();
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::Write;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer
+    {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize>
+        {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()>
+        {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_transmit_receive_and_watermarks()
+    {
+        let output = SharedBuffer::default();
+        let (sender, receiver) = mpsc::channel();
+        let irq = IrqLine::new();
+        let mut uart = SifiveUart::new(CharBackend::new(Box::new(output.clone()), Some(receiver)), irq.clone());
+        let mut ram = Ram::new(0, 0);
+
+        uart.write(TXDATA, 4, b'h' as u64);
+        assert_eq!(*output.0.lock().unwrap(), b"h");
+        assert_eq!(uart.read(RXDATA, 4), RXDATA_EMPTY);
+
+        // rxwm fires once the FIFO holds more than the watermark.
+        uart.write(RXCTRL, 4, 1 | (1 << 16));
+        uart.write(IE, 4, IP_RXWM as u64);
+        sender.send(b'a').unwrap();
+        uart.tick(&mut ram);
+        assert!(!irq.is_raised());
+        sender.send(b'b').unwrap();
+        uart.tick(&mut ram);
+        assert!(irq.is_raised());
+        assert_eq!(uart.read(RXDATA, 4), b'a' as u64);
+        assert!(!irq.is_raised());
+
+        uart.write(TXCTRL, 4, 1 | (1 << 16));
+        uart.write(IE, 4, IP_TXWM as u64);
+        assert!(irq.is_raised());
+    }
+}
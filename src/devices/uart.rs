@@ -0,0 +1,479 @@
+use std::collections::VecDeque;
+
+use crate::devices::chardev::CharBackend;
+use crate::devices::{IrqLine, MmioDevice};
+use crate::ram::Ram;
+
+pub const UART_MMIO_SIZE: u64 = 0x100;
+
+const FIFO_SIZE: usize = 16;
+
+// Register offsets
+const RBR_THR_DLL: u64 = 0;
+const IER_DLM: u64 = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+// IER bits
+const IER_RDI: u8 = 1 << 0;
+const IER_THRI: u8 = 1 << 1;
+const IER_RLSI: u8 = 1 << 2;
+const IER_MSI: u8 = 1 << 3;
+
+// IIR values
+const IIR_NO_INT: u8 = 0x01;
+const IIR_MSI: u8 = 0x00;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_RLSI: u8 = 0x06;
+const IIR_CTI: u8 = 0x0c;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+// FCR bits
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+// MCR bits
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOP: u8 = 1 << 4;
+
+// LSR bits
+const LSR_DR: u8 = 1 << 0;
+const LSR_OE: u8 = 1 << 1;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+// MSR bits
+const MSR_DELTA_MASK: u8 = 0x0f;
+const MSR_CTS: u8 = 1 << 4;
+const MSR_DSR: u8 = 1 << 5;
+const MSR_RI: u8 = 1 << 6;
+const MSR_DCD: u8 = 1 << 7;
+
+/// An NS16550A-compatible UART with 16-byte FIFOs.
+///
+/// Transmitted bytes go straight to the host backend, so the transmitter
+/// is always empty by the time the guest looks at LSR. Received bytes are
+/// pulled from the backend only while the RX FIFO has room.
+pub struct Uart16550
+{
+    backend: CharBackend,
+    irq: IrqLine,
+    rx_fifo: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr: u8,
+    msr: u8,
+    scr: u8,
+    divisor: u16,
+    thr_interrupt: bool,
+    rx_timeout: bool,
+    rx_idle: bool,
+}
+
+impl Uart16550
+{
+    pub fn new(backend: CharBackend, irq: IrqLine) -> Self
+    {
+        Uart16550
+        {
+            backend,
+            irq,
+            rx_fifo: VecDeque::with_capacity(FIFO_SIZE),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            lsr: LSR_THRE | LSR_TEMT,
+            msr: MSR_CTS | MSR_DSR | MSR_DCD,
+            scr: 0,
+            divisor: 12,
+            thr_interrupt: false,
+            rx_timeout: false,
+            rx_idle: false,
+        }
+    }
+
+    fn fifo_enabled(&self) -> bool
+    {
+        self.fcr & FCR_ENABLE != 0
+    }
+
+    fn rx_capacity(&self) -> usize
+    {
+        if self.fifo_enabled() { FIFO_SIZE } else { 1 }
+    }
+
+    fn rx_trigger_level(&self) -> usize
+    {
+        if !self.fifo_enabled()
+        {
+            return 1;
+        }
+        match self.fcr >> 6
+        {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    fn receive(&mut self, byte: u8)
+    {
+        if self.rx_fifo.len() >= self.rx_capacity()
+        {
+            self.lsr |= LSR_OE;
+            return;
+        }
+        self.rx_fifo.push_back(byte);
+        self.lsr |= LSR_DR;
+        self.rx_idle = false;
+        self.rx_timeout = false;
+    }
+
+    fn fill_rx_from_backend(&mut self)
+    {
+        if self.mcr & MCR_LOOP != 0
+        {
+            return;
+        }
+        while self.rx_fifo.len() < self.rx_capacity()
+        {
+            match self.backend.read_byte()
+            {
+                Some(byte) => self.receive(byte),
+                None => break,
+            }
+        }
+    }
+
+    fn transmit(&mut self, byte: u8)
+    {
+        if self.mcr & MCR_LOOP != 0
+        {
+            self.receive(byte);
+        }
+        else
+        {
+            self.backend.write(&[byte]);
+        }
+        // The byte leaves immediately, so THR is empty again.
+        self.lsr |= LSR_THRE | LSR_TEMT;
+        self.thr_interrupt = true;
+    }
+
+    /// Modem status inputs, which loopback mode wires to the MCR outputs.
+    fn modem_inputs(&self) -> u8
+    {
+        if self.mcr & MCR_LOOP != 0
+        {
+            let mut inputs = 0;
+            if self.mcr & MCR_RTS != 0 { inputs |= MSR_CTS; }
+            if self.mcr & MCR_DTR != 0 { inputs |= MSR_DSR; }
+            if self.mcr & MCR_OUT1 != 0 { inputs |= MSR_RI; }
+            if self.mcr & MCR_OUT2 != 0 { inputs |= MSR_DCD; }
+            inputs
+        }
+        else
+        {
+            MSR_CTS | MSR_DSR | MSR_DCD
+        }
+    }
+
+    fn update_modem_status(&mut self)
+    {
+        let inputs = self.modem_inputs();
+        let changed = (self.msr ^ inputs) & !MSR_DELTA_MASK;
+        let mut delta = (changed >> 4) & MSR_DELTA_MASK;
+        // TERI only reports the trailing edge of RI.
+        if inputs & MSR_RI != 0
+        {
+            delta &= !(MSR_RI >> 4);
+        }
+        self.msr = inputs | (self.msr & MSR_DELTA_MASK) | delta;
+    }
+
+    /// The highest priority pending interrupt, as reported in IIR.
+    fn pending_interrupt(&self) -> u8
+    {
+        if self.ier & IER_RLSI != 0 && self.lsr & LSR_OE != 0
+        {
+            IIR_RLSI
+        }
+        else if self.ier & IER_RDI != 0 && self.rx_fifo.len() >= self.rx_trigger_level()
+        {
+            IIR_RDI
+        }
+        else if self.ier & IER_RDI != 0 && self.rx_timeout && !self.rx_fifo.is_empty()
+        {
+            IIR_CTI
+        }
+        else if self.ier & IER_THRI != 0 && self.thr_interrupt
+        {
+            IIR_THRI
+        }
+        else if self.ier & IER_MSI != 0 && self.msr & MSR_DELTA_MASK != 0
+        {
+            IIR_MSI
+        }
+        else
+        {
+            IIR_NO_INT
+        }
+    }
+
+    fn update_irq(&self)
+    {
+        self.irq.set_level(self.pending_interrupt() != IIR_NO_INT);
+    }
+
+    fn read_register(&mut self, offset: u64) -> u8
+    {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset
+        {
+            RBR_THR_DLL if dlab => self.divisor as u8,
+            RBR_THR_DLL =>
+            {
+                let byte = self.rx_fifo.pop_front().unwrap_or(0);
+                self.fill_rx_from_backend();
+                if self.rx_fifo.is_empty()
+                {
+                    self.lsr &= !LSR_DR;
+                }
+                self.rx_timeout = false;
+                byte
+            }
+            IER_DLM if dlab => (self.divisor >> 8) as u8,
+            IER_DLM => self.ier,
+            IIR_FCR =>
+            {
+                let interrupt = self.pending_interrupt();
+                if interrupt == IIR_THRI
+                {
+                    self.thr_interrupt = false;
+                }
+                let fifo_bits = if self.fifo_enabled() { IIR_FIFO_ENABLED } else { 0 };
+                interrupt | fifo_bits
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR =>
+            {
+                self.fill_rx_from_backend();
+                let lsr = self.lsr;
+                self.lsr &= !LSR_OE;
+                lsr
+            }
+            MSR =>
+            {
+                let msr = self.msr;
+                self.msr &= !MSR_DELTA_MASK;
+                msr
+            }
+            SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u8)
+    {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset
+        {
+            RBR_THR_DLL if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            RBR_THR_DLL =>
+            {
+                self.thr_interrupt = false;
+                self.transmit(value);
+            }
+            IER_DLM if dlab => self.divisor = (self.divisor & 0x00ff) | ((value as u16) << 8),
+            IER_DLM =>
+            {
+                let enabled_thri = value & IER_THRI != 0 && self.ier & IER_THRI == 0;
+                self.ier = value & 0x0f;
+                // Enabling ETBEI with an empty THR raises the interrupt at once.
+                if enabled_thri && self.lsr & LSR_THRE != 0
+                {
+                    self.thr_interrupt = true;
+                }
+            }
+            IIR_FCR =>
+            {
+                if (value ^ self.fcr) & FCR_ENABLE != 0 || value & FCR_CLEAR_RX != 0
+                {
+                    self.rx_fifo.clear();
+                    self.lsr &= !LSR_DR;
+                    self.rx_timeout = false;
+                }
+                if value & FCR_CLEAR_TX != 0
+                {
+                    self.lsr |= LSR_THRE | LSR_TEMT;
+                }
+                self.fcr = value & !(FCR_CLEAR_RX | FCR_CLEAR_TX);
+            }
+            LCR => self.lcr = value,
+            MCR =>
+            {
+                self.mcr = value & 0x1f;
+                self.update_modem_status();
+            }
+            SCR => self.scr = value,
+            _ => {},
+        }
+    }
+}
+
+impl MmioDevice for Uart16550
+{
+    fn read(&mut self, offset: u64, _size: usize) -> u64
+    {
+        let value = self.read_register(offset) as u64;
+        self.update_irq();
+        value
+    }
+
+    fn write(&mut self, offset: u64, _size: usize, value: u64)
+    {
+        self.write_register(offset, value as u8);
+        self.update_irq();
+    }
+
+    fn tick(&mut self, _ram: &mut Ram)
+    {
+        // Bytes that sat below the trigger level for a whole tick raise the
+        // character timeout interrupt, standing in for the 4-character timer.
+        self.fill_rx_from_backend();
+        if self.rx_idle && !self.rx_fifo.is_empty()
+        {
+            self.rx_timeout = true;
+        }
+        self.rx_idle = true;
+        self.update_irq();
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::{self, Write};
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer
+    {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+        {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()>
+        {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_transmit_and_thr_interrupt()
+    {
+        let output = SharedBuffer::default();
+        let irq = IrqLine::new();
+        let mut uart = Uart16550::new(CharBackend::new(Box::new(output.clone()), None), irq.clone());
+
+        uart.write(IER_DLM, 1, IER_THRI as u64);
+        assert!(irq.is_raised());
+        assert_eq!(uart.read(IIR_FCR, 1) as u8, IIR_THRI);
+        assert!(!irq.is_raised());
+
+        for &byte in b"hi\n"
+        {
+            uart.write(RBR_THR_DLL, 1, byte as u64);
+        }
+        assert_eq!(&*output.0.lock().unwrap(), b"hi\n");
+        assert_eq!(uart.read(LSR, 1) as u8 & (LSR_THRE | LSR_TEMT), LSR_THRE | LSR_TEMT);
+        assert!(irq.is_raised());
+    }
+
+    #[test]
+    fn test_receive_fifo_trigger_and_timeout()
+    {
+        let (sender, receiver) = mpsc::channel();
+        let irq = IrqLine::new();
+        let mut uart = Uart16550::new(CharBackend::new(Box::new(io::sink()), Some(receiver)), irq.clone());
+        let mut ram = Ram::new(0, 0);
+
+        // FIFO on, trigger level 4, receive interrupts enabled.
+        uart.write(IIR_FCR, 1, (FCR_ENABLE | (1 << 6)) as u64);
+        uart.write(IER_DLM, 1, IER_RDI as u64);
+        for &byte in b"ab"
+        {
+            sender.send(byte).unwrap();
+        }
+        uart.tick(&mut ram);
+        assert_eq!(uart.read(LSR, 1) as u8 & LSR_DR, LSR_DR);
+        assert!(!irq.is_raised());
+
+        // Below the trigger level, a quiet tick raises the timeout interrupt.
+        uart.tick(&mut ram);
+        assert!(irq.is_raised());
+        assert_eq!(uart.read(IIR_FCR, 1) as u8, IIR_CTI | IIR_FIFO_ENABLED);
+
+        for &byte in b"cdef"
+        {
+            sender.send(byte).unwrap();
+        }
+        uart.tick(&mut ram);
+        assert_eq!(uart.read(IIR_FCR, 1) as u8, IIR_RDI | IIR_FIFO_ENABLED);
+
+        let received: Vec<u8> = (0..6).map(|_| uart.read(RBR_THR_DLL, 1) as u8).collect();
+        assert_eq!(received, b"abcdef");
+        assert_eq!(uart.read(LSR, 1) as u8 & LSR_DR, 0);
+        assert!(!irq.is_raised());
+    }
+
+    #[test]
+    fn test_loopback_and_divisor_latch()
+    {
+        let output = SharedBuffer::default();
+        let mut uart = Uart16550::new(CharBackend::new(Box::new(output.clone()), None), IrqLine::new());
+
+        uart.write(LCR, 1, LCR_DLAB as u64);
+        uart.write(RBR_THR_DLL, 1, 0x01);
+        uart.write(IER_DLM, 1, 0x02);
+        assert_eq!(uart.read(RBR_THR_DLL, 1), 0x01);
+        assert_eq!(uart.read(IER_DLM, 1), 0x02);
+        uart.write(LCR, 1, 0x03);
+        assert_eq!(uart.divisor, 0x0201);
+
+        uart.write(MCR, 1, (MCR_LOOP | MCR_RTS) as u64);
+        assert_eq!(uart.read(MSR, 1) as u8 & (MSR_CTS | MSR_DCD), MSR_CTS);
+        uart.write(RBR_THR_DLL, 1, b'x' as u64);
+        assert_eq!(uart.read(RBR_THR_DLL, 1), b'x' as u64);
+        assert!(output.0.lock().unwrap().is_empty());
+
+        // Without FIFOs a second byte overruns the holding register.
+        uart.write(RBR_THR_DLL, 1, b'y' as u64);
+        uart.write(RBR_THR_DLL, 1, b'z' as u64);
+        assert_eq!(uart.read(LSR, 1) as u8 & (LSR_DR | LSR_OE), LSR_DR | LSR_OE);
+        assert_eq!(uart.read(LSR, 1) as u8 & LSR_OE, 0);
+    }
+}
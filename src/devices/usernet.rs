@@ -0,0 +1,365 @@
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+
+use crate::devices::netdev::NetBackend;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERNET_HEADER_SIZE: usize = 14;
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const ARP_PACKET_SIZE: usize = 28;
+
+const IP_PROTOCOL_ICMP: u8 = 1;
+const IP_PROTOCOL_UDP: u8 = 17;
+const IPV4_HEADER_SIZE: usize = 20;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+const UDP_HEADER_SIZE: usize = 8;
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+
+const DHCP_OP_REPLY: u8 = 2;
+const DHCP_MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const DHCP_OPTIONS_OFFSET: usize = 240;
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_OPTION_SUBNET_MASK: u8 = 1;
+const DHCP_OPTION_ROUTER: u8 = 3;
+const DHCP_OPTION_LEASE_TIME: u8 = 51;
+const DHCP_OPTION_MESSAGE_TYPE: u8 = 53;
+const DHCP_OPTION_SERVER_ID: u8 = 54;
+const DHCP_OPTION_END: u8 = 255;
+const DHCP_LEASE_SECONDS: u32 = 86400;
+
+/// Queued replies are capped so a chatty guest cannot grow memory forever.
+const MAX_QUEUED_FRAMES: usize = 256;
+
+/// A minimal built-in network with a gateway that answers ARP, hands out a
+/// single DHCP lease and replies to pings. Nothing is forwarded to the host
+/// network, so it needs no privileges or host configuration.
+///
+/// The defaults mirror QEMU's user networking: gateway 10.0.2.2, guest
+/// 10.0.2.15, netmask 255.255.255.0.
+pub struct UserNet
+{
+    gateway_mac: [u8; 6],
+    gateway_ip: Ipv4Addr,
+    guest_ip: Ipv4Addr,
+    netmask: Ipv4Addr,
+    to_guest: VecDeque<Vec<u8>>,
+}
+
+impl Default for UserNet
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl UserNet
+{
+    pub fn new() -> Self
+    {
+        UserNet
+        {
+            gateway_mac: [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02],
+            gateway_ip: Ipv4Addr::new(10, 0, 2, 2),
+            guest_ip: Ipv4Addr::new(10, 0, 2, 15),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            to_guest: VecDeque::new(),
+        }
+    }
+
+    fn queue(&mut self, frame: Vec<u8>)
+    {
+        if self.to_guest.len() < MAX_QUEUED_FRAMES
+        {
+            self.to_guest.push_back(frame);
+        }
+    }
+
+    fn ethernet_frame(&self, dst: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8>
+    {
+        let mut frame = Vec::with_capacity(ETHERNET_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&self.gateway_mac);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn handle_arp(&mut self, packet: &[u8])
+    {
+        if packet.len() < ARP_PACKET_SIZE || be16(packet, 6) != ARP_REQUEST || packet[24..28] != self.gateway_ip.octets()
+        {
+            return;
+        }
+        let sender_mac: [u8; 6] = packet[8..14].try_into().unwrap();
+        let mut reply = packet[..ARP_PACKET_SIZE].to_vec();
+        reply[6..8].copy_from_slice(&ARP_REPLY.to_be_bytes());
+        reply[8..14].copy_from_slice(&self.gateway_mac);
+        reply[14..18].copy_from_slice(&self.gateway_ip.octets());
+        reply[18..28].copy_from_slice(&packet[8..18]);
+        let frame = self.ethernet_frame(sender_mac, ETHERTYPE_ARP, &reply);
+        self.queue(frame);
+    }
+
+    fn handle_ipv4(&mut self, source_mac: [u8; 6], packet: &[u8])
+    {
+        if packet.len() < IPV4_HEADER_SIZE || packet[0] >> 4 != 4
+        {
+            return;
+        }
+        let header_len = (packet[0] & 0x0f) as usize * 4;
+        let total_len = be16(packet, 2) as usize;
+        if header_len < IPV4_HEADER_SIZE || total_len < header_len || total_len > packet.len()
+        {
+            return;
+        }
+        let source = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+        let destination = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+        let payload = &packet[header_len..total_len];
+        match packet[9]
+        {
+            IP_PROTOCOL_ICMP if destination == self.gateway_ip && payload.len() >= 8 && payload[0] == ICMP_ECHO_REQUEST =>
+            {
+                let mut reply = payload.to_vec();
+                reply[0] = ICMP_ECHO_REPLY;
+                reply[2..4].fill(0);
+                let checksum = internet_checksum(&reply);
+                reply[2..4].copy_from_slice(&checksum.to_be_bytes());
+                let ip = self.ipv4_packet(self.gateway_ip, source, IP_PROTOCOL_ICMP, &reply);
+                let frame = self.ethernet_frame(source_mac, ETHERTYPE_IPV4, &ip);
+                self.queue(frame);
+            }
+            IP_PROTOCOL_UDP if payload.len() >= UDP_HEADER_SIZE && be16(payload, 2) == DHCP_SERVER_PORT =>
+            {
+                self.handle_dhcp(&payload[UDP_HEADER_SIZE..]);
+            }
+            _ => {},
+        }
+    }
+
+    fn handle_dhcp(&mut self, message: &[u8])
+    {
+        if message.len() < DHCP_OPTIONS_OFFSET || message[236..240] != DHCP_MAGIC_COOKIE
+        {
+            return;
+        }
+        let reply_type = match dhcp_message_type(&message[DHCP_OPTIONS_OFFSET..])
+        {
+            Some(DHCP_DISCOVER) => DHCP_OFFER,
+            Some(DHCP_REQUEST) => DHCP_ACK,
+            _ => return,
+        };
+
+        let mut reply = vec![0u8; DHCP_OPTIONS_OFFSET];
+        reply[0] = DHCP_OP_REPLY;
+        reply[1..3].copy_from_slice(&message[1..3]); // htype, hlen
+        reply[4..8].copy_from_slice(&message[4..8]); // xid
+        reply[10..12].copy_from_slice(&message[10..12]); // flags
+        reply[16..20].copy_from_slice(&self.guest_ip.octets()); // yiaddr
+        reply[20..24].copy_from_slice(&self.gateway_ip.octets()); // siaddr
+        reply[28..44].copy_from_slice(&message[28..44]); // chaddr
+        reply[236..240].copy_from_slice(&DHCP_MAGIC_COOKIE);
+        reply.extend_from_slice(&[DHCP_OPTION_MESSAGE_TYPE, 1, reply_type]);
+        reply.extend_from_slice(&[DHCP_OPTION_SERVER_ID, 4]);
+        reply.extend_from_slice(&self.gateway_ip.octets());
+        reply.extend_from_slice(&[DHCP_OPTION_LEASE_TIME, 4]);
+        reply.extend_from_slice(&DHCP_LEASE_SECONDS.to_be_bytes());
+        reply.extend_from_slice(&[DHCP_OPTION_SUBNET_MASK, 4]);
+        reply.extend_from_slice(&self.netmask.octets());
+        reply.extend_from_slice(&[DHCP_OPTION_ROUTER, 4]);
+        reply.extend_from_slice(&self.gateway_ip.octets());
+        reply.push(DHCP_OPTION_END);
+
+        // The client has no address yet, so the reply is broadcast.
+        let mut udp = Vec::with_capacity(UDP_HEADER_SIZE + reply.len());
+        udp.extend_from_slice(&DHCP_SERVER_PORT.to_be_bytes());
+        udp.extend_from_slice(&DHCP_CLIENT_PORT.to_be_bytes());
+        udp.extend_from_slice(&((UDP_HEADER_SIZE + reply.len()) as u16).to_be_bytes());
+        udp.extend_from_slice(&[0, 0]); // checksum is optional over IPv4
+        udp.extend_from_slice(&reply);
+        let ip = self.ipv4_packet(self.gateway_ip, Ipv4Addr::BROADCAST, IP_PROTOCOL_UDP, &udp);
+        let frame = self.ethernet_frame(BROADCAST_MAC, ETHERTYPE_IPV4, &ip);
+        self.queue(frame);
+    }
+
+    fn ipv4_packet(&self, source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8>
+    {
+        let mut packet = vec![0u8; IPV4_HEADER_SIZE];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&((IPV4_HEADER_SIZE + payload.len()) as u16).to_be_bytes());
+        packet[8] = 64; // TTL
+        packet[9] = protocol;
+        packet[12..16].copy_from_slice(&source.octets());
+        packet[16..20].copy_from_slice(&destination.octets());
+        let checksum = internet_checksum(&packet);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+}
+
+impl NetBackend for UserNet
+{
+    fn send(&mut self, frame: &[u8])
+    {
+        if frame.len() < ETHERNET_HEADER_SIZE
+        {
+            return;
+        }
+        let source_mac: [u8; 6] = frame[6..12].try_into().unwrap();
+        let payload = &frame[ETHERNET_HEADER_SIZE..];
+        match be16(frame, 12)
+        {
+            ETHERTYPE_ARP => self.handle_arp(payload),
+            ETHERTYPE_IPV4 => self.handle_ipv4(source_mac, payload),
+            _ => {},
+        }
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>>
+    {
+        self.to_guest.pop_front()
+    }
+}
+
+fn be16(data: &[u8], offset: usize) -> u16
+{
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+/// The one's complement checksum used by IPv4 and ICMP.
+fn internet_checksum(data: &[u8]) -> u16
+{
+    let mut sum: u32 = data.chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum >> 16 != 0
+    {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn dhcp_message_type(mut options: &[u8]) -> Option<u8>
+{
+    while let Some((&code, rest)) = options.split_first()
+    {
+        match code
+        {
+            0 => options = rest,
+            DHCP_OPTION_END => return None,
+            _ =>
+            {
+                let (&len, rest) = rest.split_first()?;
+                let value = rest.get(..len as usize)?;
+                if code == DHCP_OPTION_MESSAGE_TYPE && len == 1
+                {
+                    return Some(value[0]);
+                }
+                options = &rest[len as usize..];
+            }
+        }
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    fn guest_frame(dst: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8>
+    {
+        let mut frame = dst.to_vec();
+        frame.extend_from_slice(&GUEST_MAC);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn test_arp_for_gateway()
+    {
+        let mut net = UserNet::new();
+        let mut request = vec![0, 1, 8, 0, 6, 4, 0, 1];
+        request.extend_from_slice(&GUEST_MAC);
+        request.extend_from_slice(&[10, 0, 2, 15]);
+        request.extend_from_slice(&[0; 6]);
+        request.extend_from_slice(&[10, 0, 2, 2]);
+        net.send(&guest_frame(BROADCAST_MAC, ETHERTYPE_ARP, &request));
+
+        let reply = net.recv().unwrap();
+        assert_eq!(&reply[0..6], &GUEST_MAC);
+        assert_eq!(be16(&reply, 12), ETHERTYPE_ARP);
+        let arp = &reply[ETHERNET_HEADER_SIZE..];
+        assert_eq!(be16(arp, 6), ARP_REPLY);
+        assert_eq!(&arp[8..14], &net.gateway_mac);
+        assert_eq!(&arp[14..18], &[10, 0, 2, 2]);
+        assert_eq!(&arp[24..28], &[10, 0, 2, 15]);
+        assert!(net.recv().is_none());
+    }
+
+    #[test]
+    fn test_dhcp_discover_gets_offer()
+    {
+        let mut net = UserNet::new();
+        let mut dhcp = vec![0u8; DHCP_OPTIONS_OFFSET];
+        dhcp[0] = 1;
+        dhcp[1] = 1;
+        dhcp[2] = 6;
+        dhcp[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        dhcp[28..34].copy_from_slice(&GUEST_MAC);
+        dhcp[236..240].copy_from_slice(&DHCP_MAGIC_COOKIE);
+        dhcp.extend_from_slice(&[DHCP_OPTION_MESSAGE_TYPE, 1, DHCP_DISCOVER, DHCP_OPTION_END]);
+        let mut udp = DHCP_CLIENT_PORT.to_be_bytes().to_vec();
+        udp.extend_from_slice(&DHCP_SERVER_PORT.to_be_bytes());
+        udp.extend_from_slice(&((8 + dhcp.len()) as u16).to_be_bytes());
+        udp.extend_from_slice(&[0, 0]);
+        udp.extend_from_slice(&dhcp);
+        let ip = net.ipv4_packet(Ipv4Addr::UNSPECIFIED, Ipv4Addr::BROADCAST, IP_PROTOCOL_UDP, &udp);
+        net.send(&guest_frame(BROADCAST_MAC, ETHERTYPE_IPV4, &ip));
+
+        let reply = net.recv().unwrap();
+        let ip = &reply[ETHERNET_HEADER_SIZE..];
+        assert_eq!(internet_checksum(&ip[..IPV4_HEADER_SIZE]), 0);
+        let offer = &ip[IPV4_HEADER_SIZE + UDP_HEADER_SIZE..];
+        assert_eq!(offer[0], DHCP_OP_REPLY);
+        assert_eq!(&offer[4..8], &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(&offer[16..20], &[10, 0, 2, 15]);
+        assert_eq!(&offer[28..34], &GUEST_MAC);
+        assert_eq!(dhcp_message_type(&offer[DHCP_OPTIONS_OFFSET..]), Some(DHCP_OFFER));
+    }
+
+    #[test]
+    fn test_ping_gateway()
+    {
+        let mut net = UserNet::new();
+        let mut icmp = vec![ICMP_ECHO_REQUEST, 0, 0, 0, 0x12, 0x34, 0, 1, b'p', b'i', b'n', b'g'];
+        let checksum = internet_checksum(&icmp);
+        icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+        let ip = net.ipv4_packet(Ipv4Addr::new(10, 0, 2, 15), Ipv4Addr::new(10, 0, 2, 2), IP_PROTOCOL_ICMP, &icmp);
+        net.send(&guest_frame(net.gateway_mac, ETHERTYPE_IPV4, &ip));
+
+        let reply = net.recv().unwrap();
+        let ip = &reply[ETHERNET_HEADER_SIZE..];
+        assert_eq!(&ip[16..20], &[10, 0, 2, 15]);
+        let icmp_reply = &ip[IPV4_HEADER_SIZE..];
+        assert_eq!(icmp_reply[0], ICMP_ECHO_REPLY);
+        assert_eq!(&icmp_reply[4..], &icmp[4..]);
+        assert_eq!(internet_checksum(icmp_reply), 0);
+    }
+}
@@ -0,0 +1,426 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::devices::virtio::queue::{DescriptorChain, Queue, QueueError};
+use crate::devices::virtio::{read_config_bytes, VirtioDevice, VIRTIO_ID_BLOCK};
+use crate::ram::Ram;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+const QUEUE_SIZE: u16 = 128;
+
+// Feature bits
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+// Request status values
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const REQUEST_HEADER_SIZE: usize = 16;
+const ID_BYTES: usize = 20;
+/// The most data a request stages in host memory at once.
+const TRANSFER_CHUNK: usize = 64 * 1024;
+
+/// Storage behind a virtio-blk device, addressed in bytes.
+pub trait DiskImage
+{
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+impl DiskImage for File
+{
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>
+    {
+        self.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>
+    {
+        self.write_all_at(data, offset)
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        self.sync_data()
+    }
+}
+
+/// An image held in host memory, such as an ISO already read by `main`.
+impl DiskImage for Vec<u8>
+{
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>
+    {
+        let start = offset as usize;
+        let source = self.get(start..start + buf.len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "read past end of image"))?;
+        buf.copy_from_slice(source);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>
+    {
+        let start = offset as usize;
+        let target = self.get_mut(start..start + data.len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "write past end of image"))?;
+        target.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        Ok(())
+    }
+}
+
+/// A virtio-blk device serving a raw disk or ISO image.
+pub struct VirtioBlock
+{
+    image: Box<dyn DiskImage>,
+    capacity: u64,
+    read_only: bool,
+    block_size: u32,
+    id: [u8; ID_BYTES],
+}
+
+impl VirtioBlock
+{
+    /// `size` is the image size in bytes; a trailing partial sector is not
+    /// visible to the guest.
+    pub fn new(image: Box<dyn DiskImage>, size: u64, read_only: bool) -> Self
+    {
+        VirtioBlock
+        {
+            image,
+            capacity: size / SECTOR_SIZE,
+            read_only,
+            block_size: SECTOR_SIZE as u32,
+            id: [0; ID_BYTES],
+        }
+    }
+
+    /// Opens a raw image file, read-write unless `read_only` is set.
+    pub fn open(path: &Path, read_only: bool) -> io::Result<Self>
+    {
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        let size = file.metadata()?.len();
+        let mut block = Self::new(Box::new(file), size, read_only);
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        block.set_id(&name);
+        Ok(block)
+    }
+
+    /// Opens ISO 9660 media: read-only, with 2048-byte logical blocks.
+    pub fn open_iso(path: &Path) -> io::Result<Self>
+    {
+        let mut block = Self::open(path, true)?;
+        block.block_size = 2048;
+        Ok(block)
+    }
+
+    /// Sets the serial string returned by GET_ID, truncated to 20 bytes.
+    pub fn set_id(&mut self, id: &str)
+    {
+        self.id = [0; ID_BYTES];
+        let len = id.len().min(ID_BYTES);
+        self.id[..len].copy_from_slice(&id.as_bytes()[..len]);
+    }
+
+    /// Capacity in 512-byte sectors.
+    pub fn capacity(&self) -> u64
+    {
+        self.capacity
+    }
+
+    /// Byte offset of a transfer, if it lies within the disk.
+    fn transfer_offset(&self, sector: u64, len: usize) -> Option<u64>
+    {
+        let offset = sector.checked_mul(SECTOR_SIZE)?;
+        let end = offset.checked_add(len as u64)?;
+        if end > self.capacity * SECTOR_SIZE
+        {
+            return None;
+        }
+        Some(offset)
+    }
+
+    /// Copies `len` bytes of the image at `offset` into the chain's
+    /// writable buffers a chunk at a time. Returns the bytes copied and the
+    /// request status.
+    fn read_to_chain(&mut self, chain: &DescriptorChain, ram: &mut Ram, offset: u64, len: usize) -> Result<(usize, u8), QueueError>
+    {
+        let mut chunk = vec![0; len.min(TRANSFER_CHUNK)];
+        let mut done = 0;
+        while done < len
+        {
+            let count = (len - done).min(chunk.len());
+            if self.image.read_at(offset + done as u64, &mut chunk[..count]).is_err()
+            {
+                return Ok((done, VIRTIO_BLK_S_IOERR));
+            }
+            done += chain.write_at(ram, done, &chunk[..count])?;
+        }
+        Ok((done, VIRTIO_BLK_S_OK))
+    }
+
+    /// Copies `len` bytes after the request header to the image at
+    /// `offset`, a chunk at a time.
+    fn write_from_chain(&mut self, chain: &DescriptorChain, ram: &Ram, offset: u64, len: usize) -> Result<u8, QueueError>
+    {
+        let mut chunk = vec![0; len.min(TRANSFER_CHUNK)];
+        let mut done = 0;
+        while done < len
+        {
+            let count = (len - done).min(chunk.len());
+            chain.read_at(ram, REQUEST_HEADER_SIZE + done, &mut chunk[..count])?;
+            if self.image.write_at(offset + done as u64, &chunk[..count]).is_err()
+            {
+                return Ok(VIRTIO_BLK_S_IOERR);
+            }
+            done += count;
+        }
+        Ok(VIRTIO_BLK_S_OK)
+    }
+
+    /// Serves one request and returns the number of bytes written to the guest.
+    fn handle_request(&mut self, chain: &DescriptorChain, ram: &mut Ram) -> Result<u32, QueueError>
+    {
+        let writable_len = chain.writable_len();
+        let mut header = [0u8; REQUEST_HEADER_SIZE];
+        if chain.read_at(ram, 0, &mut header)? < REQUEST_HEADER_SIZE || writable_len == 0
+        {
+            // Nowhere to put a status byte; return the buffer untouched.
+            return Ok(0);
+        }
+        let request_type = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let data_len = writable_len - 1;
+
+        let mut written = 0;
+        let status = match request_type
+        {
+            // Transfers are checked against the disk and guest RAM before
+            // anything is staged, since the lengths come from the driver.
+            VIRTIO_BLK_T_IN => match self.transfer_offset(sector, data_len)
+            {
+                Some(offset) if data_len as u64 <= ram.size() =>
+                {
+                    let (copied, status) = self.read_to_chain(chain, ram, offset, data_len)?;
+                    written = copied;
+                    status
+                }
+                _ => VIRTIO_BLK_S_IOERR,
+            },
+            VIRTIO_BLK_T_OUT =>
+            {
+                let len = chain.readable_len() - REQUEST_HEADER_SIZE;
+                match self.transfer_offset(sector, len)
+                {
+                    Some(offset) if !self.read_only && len as u64 <= ram.size() => self.write_from_chain(chain, ram, offset, len)?,
+                    _ => VIRTIO_BLK_S_IOERR,
+                }
+            }
+            VIRTIO_BLK_T_FLUSH =>
+            {
+                if self.image.flush().is_ok() { VIRTIO_BLK_S_OK } else { VIRTIO_BLK_S_IOERR }
+            }
+            VIRTIO_BLK_T_GET_ID =>
+            {
+                let len = data_len.min(ID_BYTES);
+                written = chain.write_at(ram, 0, &self.id[..len])?;
+                VIRTIO_BLK_S_OK
+            }
+            _ => VIRTIO_BLK_S_UNSUPP,
+        };
+
+        // The status byte is the last device-writable byte of the chain.
+        chain.write_at(ram, data_len, &[status])?;
+        Ok((written + 1) as u32)
+    }
+}
+
+impl VirtioDevice for VirtioBlock
+{
+    fn device_type(&self) -> u32
+    {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn features(&self) -> u64
+    {
+        let mut features = VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH;
+        if self.read_only
+        {
+            features |= VIRTIO_BLK_F_RO;
+        }
+        features
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16>
+    {
+        vec![QUEUE_SIZE]
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8])
+    {
+        // struct virtio_blk_config up to blk_size
+        let mut config = [0u8; 24];
+        config[0..8].copy_from_slice(&self.capacity.to_le_bytes());
+        config[12..16].copy_from_slice(&(QUEUE_SIZE as u32 - 2).to_le_bytes());
+        config[20..24].copy_from_slice(&self.block_size.to_le_bytes());
+        read_config_bytes(&config, offset, data);
+    }
+
+    fn process_queue(&mut self, index: usize, queues: &mut [Queue], ram: &mut Ram) -> Result<(), QueueError>
+    {
+        while let Some(chain) = queues[index].pop(ram)?
+        {
+            let written = self.handle_request(&chain, ram)?;
+            queues[index].add_used(ram, chain.head, written)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::devices::virtio::queue::tests::{make_available, ready_queue, write_descriptor, DESC_TABLE, USED_RING};
+    use crate::devices::virtio::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const HEADER: u64 = 0x4000;
+    const DATA: u64 = 0x5000;
+    const STATUS: u64 = 0x6000;
+
+    /// Queues a header/data/status request and runs it, returning the status.
+    fn submit(block: &mut VirtioBlock, queues: &mut [Queue], ram: &mut Ram, request_type: u32, sector: u64, data_len: u32, write: bool) -> u8
+    {
+        let mut header = [0u8; 16];
+        header[0..4].copy_from_slice(&request_type.to_le_bytes());
+        header[8..16].copy_from_slice(&sector.to_le_bytes());
+        ram.write(HEADER, &header).unwrap();
+        ram.write(STATUS, &[0xff]).unwrap();
+        let data_flags = if write { VIRTQ_DESC_F_NEXT } else { VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE };
+        write_descriptor(ram, DESC_TABLE, 0, HEADER, 16, VIRTQ_DESC_F_NEXT, 1);
+        write_descriptor(ram, DESC_TABLE, 1, DATA, data_len, data_flags, 2);
+        write_descriptor(ram, DESC_TABLE, 2, STATUS, 1, VIRTQ_DESC_F_WRITE, 0);
+        make_available(ram, 8, 0);
+        block.process_queue(0, queues, ram).unwrap();
+        let mut status = [0u8; 1];
+        ram.read(STATUS, &mut status).unwrap();
+        status[0]
+    }
+
+    fn disk() -> Vec<u8>
+    {
+        (0..4 * SECTOR_SIZE).map(|i| (i / SECTOR_SIZE) as u8).collect()
+    }
+
+    #[test]
+    fn test_read_write_flush_and_id()
+    {
+        let mut ram = Ram::new(0, 0x10000);
+        let mut queues = vec![ready_queue(8)];
+        let mut block = VirtioBlock::new(Box::new(disk()), 4 * SECTOR_SIZE + 100, false);
+        block.set_id("rootfs");
+        assert_eq!(block.capacity(), 4);
+
+        assert_eq!(submit(&mut block, &mut queues, &mut ram, VIRTIO_BLK_T_IN, 2, 512, false), VIRTIO_BLK_S_OK);
+        assert_eq!(ram.read_u64(DATA), Some(0x0202_0202_0202_0202));
+        assert_eq!(ram.read_u32(USED_RING + 8), Some(513));
+
+        ram.write(DATA, &[0xaa; 512]).unwrap();
+        assert_eq!(submit(&mut block, &mut queues, &mut ram, VIRTIO_BLK_T_OUT, 1, 512, true), VIRTIO_BLK_S_OK);
+        assert_eq!(ram.read_u32(USED_RING + 16), Some(1));
+        ram.write(DATA, &[0; 512]).unwrap();
+        assert_eq!(submit(&mut block, &mut queues, &mut ram, VIRTIO_BLK_T_IN, 1, 512, false), VIRTIO_BLK_S_OK);
+        assert_eq!(ram.read_u64(DATA + 504), Some(0xaaaa_aaaa_aaaa_aaaa));
+
+        assert_eq!(submit(&mut block, &mut queues, &mut ram, VIRTIO_BLK_T_FLUSH, 0, 0, true), VIRTIO_BLK_S_OK);
+        assert_eq!(submit(&mut block, &mut queues, &mut ram, VIRTIO_BLK_T_GET_ID, 0, 20, false), VIRTIO_BLK_S_OK);
+        let mut id = [0u8; 20];
+        ram.read(DATA, &mut id).unwrap();
+        assert_eq!(&id[..7], b"rootfs\0");
+    }
+
+    #[test]
+    fn test_errors_and_read_only_media()
+    {
+        let mut ram = Ram::new(0, 0x10000);
+        let mut queues = vec![ready_queue(8)];
+        let mut block = VirtioBlock::new(Box::new(disk()), 4 * SECTOR_SIZE, true);
+        assert_ne!(block.features() & VIRTIO_BLK_F_RO, 0);
+
+        assert_eq!(submit(&mut block, &mut queues, &mut ram, VIRTIO_BLK_T_OUT, 0, 512, true), VIRTIO_BLK_S_IOERR);
+        assert_eq!(submit(&mut block, &mut queues, &mut ram, VIRTIO_BLK_T_IN, 3, 1024, false), VIRTIO_BLK_S_IOERR);
+        assert_eq!(submit(&mut block, &mut queues, &mut ram, 11, 0, 512, false), VIRTIO_BLK_S_UNSUPP);
+
+        // A data buffer far larger than the disk or RAM is refused up front.
+        assert_eq!(submit(&mut block, &mut queues, &mut ram, VIRTIO_BLK_T_IN, 0, u32::MAX, false), VIRTIO_BLK_S_IOERR);
+        assert_eq!(ram.read_u32(USED_RING + 4 + 8 * 3 + 4), Some(1));
+
+        let mut capacity = [0u8; 8];
+        block.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), 4);
+    }
+
+    #[test]
+    fn test_transfers_span_chunks_and_descriptors()
+    {
+        // The data is split over two descriptors, the second larger than a
+        // transfer chunk, so copies cross both kinds of boundary.
+        const SECOND: u64 = 0x10000;
+        let first_len = 3 * SECTOR_SIZE as usize;
+        let len = first_len + TRANSFER_CHUNK;
+        let mut ram = Ram::new(0, SECOND as usize + TRANSFER_CHUNK);
+        let mut queues = vec![ready_queue(8)];
+        let mut block = VirtioBlock::new(Box::new(vec![0u8; 2 * len]), 2 * len as u64, false);
+        let mut run = |ram: &mut Ram, request_type: u32, data_flags: u16|
+        {
+            let mut header = [0u8; 16];
+            header[0..4].copy_from_slice(&request_type.to_le_bytes());
+            header[8..16].copy_from_slice(&1u64.to_le_bytes());
+            ram.write(HEADER, &header).unwrap();
+            write_descriptor(ram, DESC_TABLE, 0, HEADER, 16, VIRTQ_DESC_F_NEXT, 1);
+            write_descriptor(ram, DESC_TABLE, 1, DATA, first_len as u32, data_flags, 2);
+            write_descriptor(ram, DESC_TABLE, 2, SECOND, TRANSFER_CHUNK as u32, data_flags, 3);
+            write_descriptor(ram, DESC_TABLE, 3, STATUS, 1, VIRTQ_DESC_F_WRITE, 0);
+            make_available(ram, 8, 0);
+            block.process_queue(0, &mut queues, ram).unwrap();
+            let mut status = [0u8; 1];
+            ram.read(STATUS, &mut status).unwrap();
+            status[0]
+        };
+
+        let pattern: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        ram.write(DATA, &pattern[..first_len]).unwrap();
+        ram.write(SECOND, &pattern[first_len..]).unwrap();
+        assert_eq!(run(&mut ram, VIRTIO_BLK_T_OUT, VIRTQ_DESC_F_NEXT), VIRTIO_BLK_S_OK);
+        ram.write(DATA, &vec![0; first_len]).unwrap();
+        ram.write(SECOND, &vec![0; TRANSFER_CHUNK]).unwrap();
+        assert_eq!(run(&mut ram, VIRTIO_BLK_T_IN, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE), VIRTIO_BLK_S_OK);
+
+        let mut read = vec![0; len];
+        ram.read(DATA, &mut read[..first_len]).unwrap();
+        ram.read(SECOND, &mut read[first_len..]).unwrap();
+        assert_eq!(read, pattern);
+        assert_eq!(ram.read_u32(USED_RING + 16), Some(len as u32 + 1));
+    }
+}
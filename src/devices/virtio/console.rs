@@ -0,0 +1,459 @@
+use std::collections::VecDeque;
+
+use crate::devices::chardev::CharBackend;
+use crate::devices::virtio::queue::{DescriptorChain, Queue, QueueError};
+use crate::devices::virtio::{read_config_bytes, VirtioDevice, VIRTIO_ID_CONSOLE};
+use crate::ram::Ram;
+
+const QUEUE_SIZE: u16 = 64;
+
+// Feature bits
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+// Control queue indices when multiport is negotiated
+const CONTROL_RX_QUEUE: usize = 2;
+const CONTROL_TX_QUEUE: usize = 3;
+
+// Control message events
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+const CONTROL_MESSAGE_SIZE: usize = 8;
+
+/// Most host input buffered per port while the guest has no RX buffers.
+const INPUT_CHUNK: usize = 4096;
+
+/// Most guest output copied at once on its way to a port's backend.
+const OUTPUT_CHUNK: usize = 4096;
+
+struct ConsolePort
+{
+    backend: CharBackend,
+    name: Option<String>,
+    is_console: bool,
+    guest_open: bool,
+    pending_input: Vec<u8>,
+}
+
+/// A virtio-console device. Port 0 is the console; further ports are
+/// named serial ports that need the multiport feature.
+pub struct VirtioConsole
+{
+    ports: Vec<ConsolePort>,
+    multiport: bool,
+    control_pending: VecDeque<Vec<u8>>,
+}
+
+fn rx_queue(port: usize) -> usize
+{
+    if port == 0 { 0 } else { 2 * port + 2 }
+}
+
+/// Port number served by a data queue, if `index` is one.
+fn port_of_queue(index: usize) -> Option<usize>
+{
+    match index
+    {
+        0 | 1 => Some(0),
+        CONTROL_RX_QUEUE | CONTROL_TX_QUEUE => None,
+        _ => Some((index - 2) / 2),
+    }
+}
+
+fn control_message(id: u32, event: u16, value: u16) -> Vec<u8>
+{
+    let mut message = Vec::with_capacity(CONTROL_MESSAGE_SIZE);
+    message.extend_from_slice(&id.to_le_bytes());
+    message.extend_from_slice(&event.to_le_bytes());
+    message.extend_from_slice(&value.to_le_bytes());
+    message
+}
+
+impl VirtioConsole
+{
+    pub fn new(console: CharBackend) -> Self
+    {
+        VirtioConsole
+        {
+            ports: vec![ConsolePort
+            {
+                backend: console,
+                name: None,
+                is_console: true,
+                guest_open: false,
+                pending_input: Vec::new(),
+            }],
+            multiport: false,
+            control_pending: VecDeque::new(),
+        }
+    }
+
+    /// Adds a named port, which guests see as /dev/vport*p* and
+    /// /dev/virtio-ports/`name`. Must be called before the guest boots.
+    pub fn add_port(&mut self, name: &str, backend: CharBackend)
+    {
+        self.ports.push(ConsolePort
+        {
+            backend,
+            name: Some(name.to_string()),
+            is_console: false,
+            guest_open: false,
+            pending_input: Vec::new(),
+        });
+    }
+
+    fn offers_multiport(&self) -> bool
+    {
+        self.ports.len() > 1
+    }
+
+    /// Passes a chain's output to the port's backend a chunk at a time.
+    fn write_output(port: &mut ConsolePort, chain: &DescriptorChain, ram: &Ram) -> Result<(), QueueError>
+    {
+        let mut chunk = [0u8; OUTPUT_CHUNK];
+        let len = chain.readable_len();
+        let mut done = 0;
+        while done < len
+        {
+            let count = chain.read_at(ram, done, &mut chunk)?;
+            port.backend.write(&chunk[..count]);
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn handle_control(&mut self, message: &[u8])
+    {
+        if message.len() < CONTROL_MESSAGE_SIZE
+        {
+            return;
+        }
+        let id = u32::from_le_bytes(message[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes([message[4], message[5]]);
+        let value = u16::from_le_bytes([message[6], message[7]]);
+        match event
+        {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 =>
+            {
+                for port in 0..self.ports.len()
+                {
+                    self.control_pending.push_back(control_message(port as u32, VIRTIO_CONSOLE_DEVICE_ADD, 0));
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if value == 1 =>
+            {
+                let Some(port) = self.ports.get(id as usize) else { return };
+                if port.is_console
+                {
+                    self.control_pending.push_back(control_message(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1));
+                }
+                if let Some(name) = &port.name
+                {
+                    let mut message = control_message(id, VIRTIO_CONSOLE_PORT_NAME, 1);
+                    message.extend_from_slice(name.as_bytes());
+                    self.control_pending.push_back(message);
+                }
+                // The host end is always connected.
+                self.control_pending.push_back(control_message(id, VIRTIO_CONSOLE_PORT_OPEN, 1));
+            }
+            VIRTIO_CONSOLE_PORT_OPEN =>
+            {
+                if let Some(port) = self.ports.get_mut(id as usize)
+                {
+                    port.guest_open = value != 0;
+                }
+            }
+            _ => {},
+        }
+    }
+
+    fn deliver_control(&mut self, queue: &mut Queue, ram: &mut Ram) -> Result<(), QueueError>
+    {
+        while !self.control_pending.is_empty()
+        {
+            let Some(chain) = queue.pop(ram)? else { break };
+            let message = self.control_pending.pop_front().unwrap();
+            let written = chain.write_at(ram, 0, &message)?;
+            queue.add_used(ram, chain.head, written as u32)?;
+        }
+        Ok(())
+    }
+
+    fn deliver_input(port: &mut ConsolePort, queue: &mut Queue, ram: &mut Ram) -> Result<(), QueueError>
+    {
+        loop
+        {
+            while port.pending_input.len() < INPUT_CHUNK
+            {
+                match port.backend.read_byte()
+                {
+                    Some(byte) => port.pending_input.push(byte),
+                    None => break,
+                }
+            }
+            if port.pending_input.is_empty()
+            {
+                return Ok(());
+            }
+            let Some(chain) = queue.pop(ram)? else { return Ok(()) };
+            let written = chain.write_at(ram, 0, &port.pending_input)?;
+            port.pending_input.drain(..written);
+            queue.add_used(ram, chain.head, written as u32)?;
+        }
+    }
+}
+
+impl VirtioDevice for VirtioConsole
+{
+    fn device_type(&self) -> u32
+    {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64
+    {
+        let mut features = VIRTIO_CONSOLE_F_EMERG_WRITE;
+        if self.offers_multiport()
+        {
+            features |= VIRTIO_CONSOLE_F_MULTIPORT;
+        }
+        features
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16>
+    {
+        let queues = if self.offers_multiport() { 2 * self.ports.len() + 2 } else { 2 };
+        vec![QUEUE_SIZE; queues]
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8])
+    {
+        // cols, rows, max_nr_ports, emerg_wr
+        let mut config = [0u8; 12];
+        config[4..8].copy_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        read_config_bytes(&config, offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8])
+    {
+        // emerg_wr sends one character to the console port.
+        if offset == 8
+        {
+            self.ports[0].backend.write(&data[..1]);
+        }
+    }
+
+    fn activate(&mut self, features: u64)
+    {
+        self.multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    }
+
+    fn reset(&mut self)
+    {
+        self.multiport = false;
+        self.control_pending.clear();
+        for port in &mut self.ports
+        {
+            port.guest_open = false;
+        }
+    }
+
+    fn process_queue(&mut self, index: usize, queues: &mut [Queue], ram: &mut Ram) -> Result<(), QueueError>
+    {
+        if self.multiport && index == CONTROL_TX_QUEUE
+        {
+            while let Some(chain) = queues[index].pop(ram)?
+            {
+                // Control messages have a fixed size, so a longer chain is
+                // a driver error rather than something to buffer.
+                if chain.readable_len() > CONTROL_MESSAGE_SIZE
+                {
+                    return Err(QueueError::BufferTooLong);
+                }
+                let mut message = [0u8; CONTROL_MESSAGE_SIZE];
+                let len = chain.read_at(ram, 0, &mut message)?;
+                self.handle_control(&message[..len]);
+                queues[index].add_used(ram, chain.head, 0)?;
+            }
+        }
+        // Other odd queues carry guest output; even ones are refilled in poll().
+        else if index % 2 == 1
+        {
+            if let Some(port) = port_of_queue(index).and_then(|port| self.ports.get_mut(port))
+            {
+                while let Some(chain) = queues[index].pop(ram)?
+                {
+                    Self::write_output(port, &chain, ram)?;
+                    queues[index].add_used(ram, chain.head, 0)?;
+                }
+            }
+        }
+        self.poll(queues, ram)
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], ram: &mut Ram) -> Result<(), QueueError>
+    {
+        let active_ports = if self.multiport { self.ports.len() } else { 1 };
+        if self.multiport
+        {
+            self.deliver_control(&mut queues[CONTROL_RX_QUEUE], ram)?;
+        }
+        for (number, port) in self.ports.iter_mut().enumerate().take(active_ports)
+        {
+            if port.is_console || port.guest_open
+            {
+                Self::deliver_input(port, &mut queues[rx_queue(number)], ram)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::devices::virtio::queue::tests::write_descriptor;
+    use crate::devices::virtio::queue::VIRTQ_DESC_F_WRITE;
+    use std::io::{self, Write};
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer
+    {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+        {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()>
+        {
+            Ok(())
+        }
+    }
+
+    /// Sets up queue `index` with its rings at a private spot in RAM.
+    fn queue_at(index: usize) -> Queue
+    {
+        let base = 0x1000 * (4 * index as u64 + 1);
+        let mut queue = Queue::new(QUEUE_SIZE);
+        queue.size = 4;
+        queue.desc_table = base;
+        queue.avail_ring = base + 0x1000;
+        queue.used_ring = base + 0x2000;
+        queue.ready = true;
+        queue
+    }
+
+    fn post_buffer(ram: &mut Ram, queue: &Queue, slot: u16, addr: u64, len: u32, flags: u16)
+    {
+        write_descriptor(ram, queue.desc_table, slot, addr, len, flags, 0);
+        let idx = ram.read_u16(queue.avail_ring + 2).unwrap();
+        ram.write_u16(queue.avail_ring + 4 + 2 * (idx % queue.size) as u64, slot).unwrap();
+        ram.write_u16(queue.avail_ring + 2, idx.wrapping_add(1)).unwrap();
+    }
+
+    fn used_len(ram: &Ram, queue: &Queue, n: u64) -> u32
+    {
+        ram.read_u32(queue.used_ring + 8 + 8 * n).unwrap()
+    }
+
+    #[test]
+    fn test_console_port_input_and_output()
+    {
+        let mut ram = Ram::new(0, 0x40000);
+        let output = SharedBuffer::default();
+        let (sender, receiver) = mpsc::channel();
+        let mut console = VirtioConsole::new(CharBackend::new(Box::new(output.clone()), Some(receiver)));
+        assert_eq!(console.queue_max_sizes().len(), 2);
+        let mut queues = vec![queue_at(0), queue_at(1)];
+        console.activate(0);
+
+        ram.write(0x30000, b"login: ").unwrap();
+        post_buffer(&mut ram, &queues[1], 0, 0x30000, 7, 0);
+        console.process_queue(1, &mut queues, &mut ram).unwrap();
+        assert_eq!(&*output.0.lock().unwrap(), b"login: ");
+
+        for &byte in b"root\n"
+        {
+            sender.send(byte).unwrap();
+        }
+        post_buffer(&mut ram, &queues[0], 0, 0x31000, 3, VIRTQ_DESC_F_WRITE);
+        post_buffer(&mut ram, &queues[0], 1, 0x32000, 16, VIRTQ_DESC_F_WRITE);
+        console.poll(&mut queues, &mut ram).unwrap();
+        assert_eq!(used_len(&ram, &queues[0], 0), 3);
+        assert_eq!(used_len(&ram, &queues[0], 1), 2);
+        let mut input = [0u8; 5];
+        ram.read(0x31000, &mut input[..3]).unwrap();
+        ram.read(0x32000, &mut input[3..]).unwrap();
+        assert_eq!(&input, b"root\n");
+
+        // Long output reaches the backend whole, across several chunks.
+        let long: Vec<u8> = (0..3 * OUTPUT_CHUNK + 5).map(|i| b'a' + (i % 26) as u8).collect();
+        ram.write(0x20000, &long).unwrap();
+        post_buffer(&mut ram, &queues[1], 1, 0x20000, long.len() as u32, 0);
+        console.process_queue(1, &mut queues, &mut ram).unwrap();
+        assert_eq!(&output.0.lock().unwrap()[7..], &long[..]);
+
+        console.write_config(8, b"!");
+        assert_eq!(output.0.lock().unwrap().last(), Some(&b'!'));
+    }
+
+    #[test]
+    fn test_multiport_control_handshake()
+    {
+        let mut ram = Ram::new(0, 0x40000);
+        let mut console = VirtioConsole::new(CharBackend::new(Box::new(io::sink()), None));
+        let (sender, receiver) = mpsc::channel();
+        console.add_port("org.test.agent", CharBackend::new(Box::new(io::sink()), Some(receiver)));
+        assert_ne!(console.features() & VIRTIO_CONSOLE_F_MULTIPORT, 0);
+        let mut queues: Vec<Queue> = (0..6).map(queue_at).collect();
+        console.activate(VIRTIO_CONSOLE_F_MULTIPORT);
+
+        let send_control = |console: &mut VirtioConsole, queues: &mut Vec<Queue>, ram: &mut Ram, slot: u16, message: Vec<u8>| {
+            let addr = 0x38000 + 0x100 * slot as u64;
+            ram.write(addr, &message).unwrap();
+            post_buffer(ram, &queues[CONTROL_TX_QUEUE], slot, addr, message.len() as u32, 0);
+            console.process_queue(CONTROL_TX_QUEUE, queues, ram).unwrap();
+        };
+        for slot in 0..4
+        {
+            post_buffer(&mut ram, &queues[CONTROL_RX_QUEUE], slot, 0x3c000 + 0x100 * slot as u64, 64, VIRTQ_DESC_F_WRITE);
+        }
+
+        send_control(&mut console, &mut queues, &mut ram, 0, control_message(0, VIRTIO_CONSOLE_DEVICE_READY, 1));
+        let mut message = [0u8; 8];
+        ram.read(0x3c100, &mut message).unwrap();
+        assert_eq!(message.to_vec(), control_message(1, VIRTIO_CONSOLE_DEVICE_ADD, 0));
+
+        send_control(&mut console, &mut queues, &mut ram, 1, control_message(1, VIRTIO_CONSOLE_PORT_READY, 1));
+        let mut name = [0u8; 22];
+        ram.read(0x3c200, &mut name).unwrap();
+        assert_eq!(&name[..8], &control_message(1, VIRTIO_CONSOLE_PORT_NAME, 1)[..]);
+        assert_eq!(&name[8..], b"org.test.agent");
+        assert_eq!(used_len(&ram, &queues[CONTROL_RX_QUEUE], 2), 22);
+
+        // Port 1 input waits until the guest opens the port.
+        sender.send(b'x').unwrap();
+        post_buffer(&mut ram, &queues[rx_queue(1)], 0, 0x3e000, 8, VIRTQ_DESC_F_WRITE);
+        console.poll(&mut queues, &mut ram).unwrap();
+        assert_eq!(queues[rx_queue(1)].used_count(), 0);
+        send_control(&mut console, &mut queues, &mut ram, 2, control_message(1, VIRTIO_CONSOLE_PORT_OPEN, 1));
+        assert_eq!(queues[rx_queue(1)].used_count(), 1);
+        assert_eq!(ram.read_u16(0x3e000).map(|value| value as u8), Some(b'x'));
+
+        // A control chain longer than a message is refused.
+        post_buffer(&mut ram, &queues[CONTROL_TX_QUEUE], 3, 0x38300, u32::MAX, 0);
+        assert_eq!(console.process_queue(CONTROL_TX_QUEUE, &mut queues, &mut ram), Err(QueueError::BufferTooLong));
+    }
+}
@@ -0,0 +1,453 @@
+use crate::devices::virtio::queue::Queue;
+use crate::devices::virtio::{VirtioDevice, VIRTIO_F_VERSION_1, VIRTIO_RING_F_INDIRECT_DESC};
+use crate::devices::{IrqLine, MmioDevice};
+use crate::ram::Ram;
+
+pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+
+const MAGIC_VALUE: u32 = 0x7472_6976; // "virt"
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0x4d4d_5652; // "RVMM"
+
+// Register offsets
+const REG_MAGIC_VALUE: u64 = 0x000;
+const REG_VERSION: u64 = 0x004;
+const REG_DEVICE_ID: u64 = 0x008;
+const REG_VENDOR_ID: u64 = 0x00c;
+const REG_DEVICE_FEATURES: u64 = 0x010;
+const REG_DEVICE_FEATURES_SEL: u64 = 0x014;
+const REG_DRIVER_FEATURES: u64 = 0x020;
+const REG_DRIVER_FEATURES_SEL: u64 = 0x024;
+const REG_QUEUE_SEL: u64 = 0x030;
+const REG_QUEUE_NUM_MAX: u64 = 0x034;
+const REG_QUEUE_NUM: u64 = 0x038;
+const REG_QUEUE_READY: u64 = 0x044;
+const REG_QUEUE_NOTIFY: u64 = 0x050;
+const REG_INTERRUPT_STATUS: u64 = 0x060;
+const REG_INTERRUPT_ACK: u64 = 0x064;
+const REG_STATUS: u64 = 0x070;
+const REG_QUEUE_DESC_LOW: u64 = 0x080;
+const REG_QUEUE_DESC_HIGH: u64 = 0x084;
+const REG_QUEUE_DRIVER_LOW: u64 = 0x090;
+const REG_QUEUE_DRIVER_HIGH: u64 = 0x094;
+const REG_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const REG_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const REG_CONFIG_GENERATION: u64 = 0x0fc;
+const REG_CONFIG: u64 = 0x100;
+
+// Device status bits
+pub const STATUS_ACKNOWLEDGE: u32 = 1;
+pub const STATUS_DRIVER: u32 = 2;
+pub const STATUS_DRIVER_OK: u32 = 4;
+pub const STATUS_FEATURES_OK: u32 = 8;
+pub const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+pub const STATUS_FAILED: u32 = 128;
+
+// Interrupt status bits
+pub const INTERRUPT_USED_BUFFER: u32 = 1;
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+const TRANSPORT_FEATURES: u64 = VIRTIO_F_VERSION_1 | VIRTIO_RING_F_INDIRECT_DESC;
+
+/// The virtio-mmio (version 2) transport around a virtio device model.
+pub struct VirtioMmio
+{
+    device: Box<dyn VirtioDevice>,
+    irq: IrqLine,
+    queues: Vec<Queue>,
+    notified: Vec<bool>,
+    queue_sel: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    status: u32,
+    interrupt_status: u32,
+    config_generation: u32,
+}
+
+impl VirtioMmio
+{
+    pub fn new(device: Box<dyn VirtioDevice>, irq: IrqLine) -> Self
+    {
+        let queues: Vec<Queue> = device.queue_max_sizes().into_iter().map(Queue::new).collect();
+        let notified = vec![false; queues.len()];
+        VirtioMmio
+        {
+            device,
+            irq,
+            queues,
+            notified,
+            queue_sel: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            status: 0,
+            interrupt_status: 0,
+            config_generation: 0,
+        }
+    }
+
+    fn device_features(&self) -> u64
+    {
+        self.device.features() | TRANSPORT_FEATURES
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Queue>
+    {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn reset(&mut self)
+    {
+        self.device.reset();
+        for queue in &mut self.queues
+        {
+            queue.reset();
+        }
+        self.notified.iter_mut().for_each(|pending| *pending = false);
+        self.queue_sel = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.interrupt_status = 0;
+        self.update_irq();
+    }
+
+    fn write_status(&mut self, value: u32)
+    {
+        if value == 0
+        {
+            self.reset();
+            return;
+        }
+        // Drivers only ever add bits; anything else is a driver bug.
+        if value & self.status != self.status
+        {
+            return;
+        }
+        let added = value & !self.status;
+        let mut status = value;
+        if added & STATUS_FEATURES_OK != 0
+        {
+            let acceptable = self.driver_features & !self.device_features() == 0
+                && self.driver_features & VIRTIO_F_VERSION_1 != 0;
+            if !acceptable
+            {
+                // Leaving FEATURES_OK clear tells the driver we refused.
+                status &= !STATUS_FEATURES_OK;
+            }
+        }
+        if added & STATUS_DRIVER_OK != 0 && status & STATUS_FEATURES_OK != 0
+        {
+            self.device.activate(self.driver_features);
+        }
+        self.status = status;
+    }
+
+    fn update_irq(&self)
+    {
+        self.irq.set_level(self.interrupt_status != 0);
+    }
+
+    fn read_register(&mut self, offset: u64) -> u32
+    {
+        match offset
+        {
+            REG_MAGIC_VALUE => MAGIC_VALUE,
+            REG_VERSION => VERSION,
+            REG_DEVICE_ID => self.device.device_type(),
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => match self.device_features_sel
+            {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            REG_QUEUE_NUM_MAX => self.selected_queue().map_or(0, |queue| queue.max_size as u32),
+            REG_QUEUE_READY => self.selected_queue().map_or(0, |queue| queue.ready as u32),
+            REG_INTERRUPT_STATUS => self.interrupt_status,
+            REG_STATUS => self.status,
+            REG_QUEUE_DESC_LOW => self.selected_queue().map_or(0, |queue| queue.desc_table as u32),
+            REG_QUEUE_DESC_HIGH => self.selected_queue().map_or(0, |queue| (queue.desc_table >> 32) as u32),
+            REG_QUEUE_DRIVER_LOW => self.selected_queue().map_or(0, |queue| queue.avail_ring as u32),
+            REG_QUEUE_DRIVER_HIGH => self.selected_queue().map_or(0, |queue| (queue.avail_ring >> 32) as u32),
+            REG_QUEUE_DEVICE_LOW => self.selected_queue().map_or(0, |queue| queue.used_ring as u32),
+            REG_QUEUE_DEVICE_HIGH => self.selected_queue().map_or(0, |queue| (queue.used_ring >> 32) as u32),
+            REG_CONFIG_GENERATION => self.config_generation,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32)
+    {
+        // Feature and queue setup is only allowed before the driver is up.
+        let features_open = self.status & STATUS_DRIVER != 0 && self.status & STATUS_FEATURES_OK == 0;
+        let setup_open = self.status & STATUS_DRIVER_OK == 0;
+        match offset
+        {
+            REG_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            REG_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            REG_DRIVER_FEATURES if features_open => match self.driver_features_sel
+            {
+                0 => self.driver_features = (self.driver_features & !0xffff_ffff) | value as u64,
+                1 => self.driver_features = (self.driver_features & 0xffff_ffff) | ((value as u64) << 32),
+                _ => {},
+            },
+            REG_QUEUE_SEL => self.queue_sel = value,
+            REG_QUEUE_NUM if setup_open =>
+            {
+                if let Some(queue) = self.selected_queue()
+                {
+                    // Split rings must be a power of two in size.
+                    if value <= queue.max_size as u32 && value.is_power_of_two()
+                    {
+                        queue.size = value as u16;
+                    }
+                }
+            }
+            REG_QUEUE_READY if setup_open =>
+            {
+                if let Some(queue) = self.selected_queue()
+                {
+                    queue.ready = value & 1 != 0;
+                }
+            }
+            REG_QUEUE_DESC_LOW | REG_QUEUE_DESC_HIGH | REG_QUEUE_DRIVER_LOW | REG_QUEUE_DRIVER_HIGH
+            | REG_QUEUE_DEVICE_LOW | REG_QUEUE_DEVICE_HIGH if setup_open =>
+            {
+                if let Some(queue) = self.selected_queue()
+                {
+                    let field = match offset & !0x7
+                    {
+                        REG_QUEUE_DESC_LOW => &mut queue.desc_table,
+                        REG_QUEUE_DRIVER_LOW => &mut queue.avail_ring,
+                        _ => &mut queue.used_ring,
+                    };
+                    if offset & 0x4 == 0
+                    {
+                        *field = (*field & !0xffff_ffff) | value as u64;
+                    }
+                    else
+                    {
+                        *field = (*field & 0xffff_ffff) | ((value as u64) << 32);
+                    }
+                }
+            }
+            REG_QUEUE_NOTIFY =>
+            {
+                if let Some(pending) = self.notified.get_mut(value as usize & 0xffff)
+                {
+                    *pending = true;
+                }
+            }
+            REG_INTERRUPT_ACK =>
+            {
+                self.interrupt_status &= !value;
+                self.update_irq();
+            }
+            REG_STATUS => self.write_status(value),
+            _ => {},
+        }
+    }
+}
+
+impl MmioDevice for VirtioMmio
+{
+    fn read(&mut self, offset: u64, size: usize) -> u64
+    {
+        if offset >= REG_CONFIG
+        {
+            let mut data = [0u8; 8];
+            self.device.read_config(offset - REG_CONFIG, &mut data[..size]);
+            return u64::from_le_bytes(data);
+        }
+        if size != 4
+        {
+            return 0;
+        }
+        self.read_register(offset) as u64
+    }
+
+    fn write(&mut self, offset: u64, size: usize, value: u64)
+    {
+        if offset >= REG_CONFIG
+        {
+            self.device.write_config(offset - REG_CONFIG, &value.to_le_bytes()[..size]);
+            return;
+        }
+        if size == 4
+        {
+            self.write_register(offset, value as u32);
+        }
+    }
+
+    fn tick(&mut self, ram: &mut Ram)
+    {
+        if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_DEVICE_NEEDS_RESET != 0
+        {
+            return;
+        }
+        let used_before: Vec<u16> = self.queues.iter().map(Queue::used_count).collect();
+
+        let mut result = Ok(());
+        for index in 0..self.queues.len()
+        {
+            if std::mem::take(&mut self.notified[index])
+            {
+                result = result.and(self.device.process_queue(index, &mut self.queues, ram));
+            }
+        }
+        result = result.and(self.device.poll(&mut self.queues, ram));
+
+        if result.is_err()
+        {
+            self.status |= STATUS_DEVICE_NEEDS_RESET;
+            self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+        }
+        let used_buffers = self.queues.iter().zip(&used_before)
+            .any(|(queue, &before)| queue.used_count() != before && !queue.interrupt_suppressed(ram));
+        if used_buffers
+        {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+        self.update_irq();
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::devices::virtio::queue::tests::{make_available, write_descriptor, AVAIL_RING, DESC_TABLE, USED_RING};
+    use crate::devices::virtio::queue::{QueueError, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use crate::devices::virtio::read_config_bytes;
+
+    /// Reverses the bytes of each request into its writable buffer.
+    struct Reverser
+    {
+        activated_with: Option<u64>,
+    }
+
+    impl VirtioDevice for Reverser
+    {
+        fn device_type(&self) -> u32
+        {
+            42
+        }
+
+        fn features(&self) -> u64
+        {
+            1 << 3
+        }
+
+        fn queue_max_sizes(&self) -> Vec<u16>
+        {
+            vec![16]
+        }
+
+        fn read_config(&self, offset: u64, data: &mut [u8])
+        {
+            read_config_bytes(&0x1234_5678u32.to_le_bytes(), offset, data);
+        }
+
+        fn activate(&mut self, features: u64)
+        {
+            self.activated_with = Some(features);
+        }
+
+        fn process_queue(&mut self, index: usize, queues: &mut [Queue], ram: &mut Ram) -> Result<(), QueueError>
+        {
+            while let Some(chain) = queues[index].pop(ram)?
+            {
+                let mut data = chain.read_all(ram, 0x1000)?;
+                data.reverse();
+                let written = chain.write_at(ram, 0, &data)?;
+                queues[index].add_used(ram, chain.head, written as u32)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn bring_up(transport: &mut VirtioMmio, driver_features: u64) -> u32
+    {
+        transport.write(REG_STATUS, 4, STATUS_ACKNOWLEDGE as u64);
+        transport.write(REG_STATUS, 4, (STATUS_ACKNOWLEDGE | STATUS_DRIVER) as u64);
+        transport.write(REG_DRIVER_FEATURES_SEL, 4, 0);
+        transport.write(REG_DRIVER_FEATURES, 4, driver_features & 0xffff_ffff);
+        transport.write(REG_DRIVER_FEATURES_SEL, 4, 1);
+        transport.write(REG_DRIVER_FEATURES, 4, driver_features >> 32);
+        transport.write(REG_STATUS, 4, (STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK) as u64);
+        transport.read(REG_STATUS, 4) as u32
+    }
+
+    #[test]
+    fn test_identification_and_feature_negotiation()
+    {
+        let mut transport = VirtioMmio::new(Box::new(Reverser { activated_with: None }), IrqLine::new());
+        assert_eq!(transport.read(REG_MAGIC_VALUE, 4), 0x7472_6976);
+        assert_eq!(transport.read(REG_VERSION, 4), 2);
+        assert_eq!(transport.read(REG_DEVICE_ID, 4), 42);
+        transport.write(REG_DEVICE_FEATURES_SEL, 4, 1);
+        assert_eq!(transport.read(REG_DEVICE_FEATURES, 4), 1);
+        assert_eq!(transport.read(REG_CONFIG + 1, 2), 0x3456);
+
+        // Features the device does not offer are refused.
+        assert_eq!(bring_up(&mut transport, VIRTIO_F_VERSION_1 | (1 << 5)) & STATUS_FEATURES_OK, 0);
+        transport.write(REG_STATUS, 4, 0);
+        assert_eq!(transport.read(REG_STATUS, 4), 0);
+
+        let features = VIRTIO_F_VERSION_1 | (1 << 3);
+        assert_ne!(bring_up(&mut transport, features) & STATUS_FEATURES_OK, 0);
+        transport.write(REG_STATUS, 4, (STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK) as u64);
+        assert_eq!(transport.read(REG_STATUS, 4) as u32 & STATUS_DRIVER_OK, STATUS_DRIVER_OK);
+    }
+
+    #[test]
+    fn test_notify_processes_queue_and_interrupts()
+    {
+        let mut ram = Ram::new(0, 0x10000);
+        let irq = IrqLine::new();
+        let mut transport = VirtioMmio::new(Box::new(Reverser { activated_with: None }), irq.clone());
+        bring_up(&mut transport, VIRTIO_F_VERSION_1);
+
+        transport.write(REG_QUEUE_SEL, 4, 0);
+        assert_eq!(transport.read(REG_QUEUE_NUM_MAX, 4), 16);
+        transport.write(REG_QUEUE_NUM, 4, 8);
+        // Sizes that are not a power of two, or above the maximum, are ignored.
+        transport.write(REG_QUEUE_NUM, 4, 6);
+        transport.write(REG_QUEUE_NUM, 4, 32);
+        assert_eq!(transport.queues[0].size, 8);
+        transport.write(REG_QUEUE_DESC_LOW, 4, DESC_TABLE);
+        transport.write(REG_QUEUE_DRIVER_LOW, 4, AVAIL_RING);
+        transport.write(REG_QUEUE_DEVICE_LOW, 4, USED_RING);
+        transport.write(REG_QUEUE_READY, 4, 1);
+        transport.write(REG_STATUS, 4, (STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK) as u64);
+
+        ram.write(0x4000, b"virtio").unwrap();
+        write_descriptor(&mut ram, DESC_TABLE, 0, 0x4000, 6, VIRTQ_DESC_F_NEXT, 1);
+        write_descriptor(&mut ram, DESC_TABLE, 1, 0x5000, 6, VIRTQ_DESC_F_WRITE, 0);
+        make_available(&mut ram, 8, 0);
+
+        transport.write(REG_QUEUE_NOTIFY, 4, 0);
+        transport.tick(&mut ram);
+        let mut reversed = [0u8; 6];
+        ram.read(0x5000, &mut reversed).unwrap();
+        assert_eq!(&reversed, b"oitriv");
+        assert_eq!(ram.read_u16(USED_RING + 2), Some(1));
+        assert!(irq.is_raised());
+        assert_eq!(transport.read(REG_INTERRUPT_STATUS, 4) as u32, INTERRUPT_USED_BUFFER);
+
+        transport.write(REG_INTERRUPT_ACK, 4, INTERRUPT_USED_BUFFER as u64);
+        assert!(!irq.is_raised());
+
+        // A malformed chain flags the device as needing a reset.
+        write_descriptor(&mut ram, DESC_TABLE, 2, 0x4000, 1, VIRTQ_DESC_F_NEXT, 12);
+        make_available(&mut ram, 8, 2);
+        transport.write(REG_QUEUE_NOTIFY, 4, 0);
+        transport.tick(&mut ram);
+        assert_ne!(transport.read(REG_STATUS, 4) as u32 & STATUS_DEVICE_NEEDS_RESET, 0);
+        assert_eq!(transport.read(REG_INTERRUPT_STATUS, 4) as u32, INTERRUPT_CONFIG_CHANGE);
+    }
+}
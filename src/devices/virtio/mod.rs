@@ -0,0 +1,64 @@
+pub mod blk;
+pub mod console;
+pub mod mmio;
+pub mod net;
+pub mod queue;
+pub mod rng;
+
+use crate::ram::Ram;
+use queue::{Queue, QueueError};
+
+// Device IDs
+pub const VIRTIO_ID_NET: u32 = 1;
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+pub const VIRTIO_ID_CONSOLE: u32 = 3;
+pub const VIRTIO_ID_RNG: u32 = 4;
+
+// Feature bits common to all device types
+pub const VIRTIO_RING_F_INDIRECT_DESC: u64 = 1 << 28;
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// A virtio device model, independent of the transport it is exposed on.
+///
+/// The transport owns the virtqueues and hands them to the device when
+/// the driver notifies a queue or when the device is polled for host-side
+/// events such as received packets.
+pub trait VirtioDevice
+{
+    fn device_type(&self) -> u32;
+
+    /// Device-specific feature bits. The transport adds the ones it implements.
+    fn features(&self) -> u64;
+
+    /// Maximum size of each virtqueue; the length is the number of queues.
+    fn queue_max_sizes(&self) -> Vec<u16>;
+
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// Called when the driver sets DRIVER_OK, with the negotiated features.
+    fn activate(&mut self, _features: u64) {}
+
+    /// Called when the driver resets the device by writing 0 to the status.
+    fn reset(&mut self) {}
+
+    /// Handles buffers the driver made available on queue `index`.
+    fn process_queue(&mut self, index: usize, queues: &mut [Queue], ram: &mut Ram) -> Result<(), QueueError>;
+
+    /// Handles host-side events, such as input arriving for the guest.
+    fn poll(&mut self, _queues: &mut [Queue], _ram: &mut Ram) -> Result<(), QueueError>
+    {
+        Ok(())
+    }
+}
+
+/// Copies part of a little-endian config structure, as seen at `offset`.
+/// Bytes past the end of the structure read as zero.
+pub fn read_config_bytes(config: &[u8], offset: u64, data: &mut [u8])
+{
+    for (i, byte) in data.iter_mut().enumerate()
+    {
+        *byte = config.get(offset as usize + i).copied().unwrap_or(0);
+    }
+}
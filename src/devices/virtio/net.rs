@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+
+use crate::devices::netdev::NetBackend;
+use crate::devices::virtio::queue::{Queue, QueueError};
+use crate::devices::virtio::{read_config_bytes, VirtioDevice, VIRTIO_ID_NET};
+use crate::ram::Ram;
+
+const QUEUE_SIZE: u16 = 256;
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+// Feature bits
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// struct virtio_net_hdr, which always includes num_buffers with VERSION_1.
+const NET_HEADER_SIZE: usize = 12;
+
+/// The largest frame without offloads: a 1500-byte payload behind an
+/// Ethernet header and a VLAN tag.
+const MAX_FRAME_SIZE: usize = 1518;
+
+/// Frames held while the guest has no RX buffers; later ones are dropped.
+const MAX_PENDING_FRAMES: usize = 256;
+
+/// A virtio-net device that passes Ethernet frames to a NetBackend.
+///
+/// No offloads are offered, so every header the guest sends is ignored
+/// and every header it receives is zero apart from num_buffers.
+pub struct VirtioNet
+{
+    backend: Box<dyn NetBackend>,
+    mac: [u8; 6],
+    pending_rx: VecDeque<Vec<u8>>,
+}
+
+impl VirtioNet
+{
+    pub fn new(backend: Box<dyn NetBackend>, mac: [u8; 6]) -> Self
+    {
+        VirtioNet
+        {
+            backend,
+            mac,
+            pending_rx: VecDeque::new(),
+        }
+    }
+
+    fn transmit(&mut self, queue: &mut Queue, ram: &mut Ram) -> Result<(), QueueError>
+    {
+        while let Some(chain) = queue.pop(ram)?
+        {
+            // Chains too long to hold a frame are dropped before anything
+            // is copied, since their lengths come from the guest.
+            let len = chain.readable_len();
+            if len > NET_HEADER_SIZE && len <= NET_HEADER_SIZE + MAX_FRAME_SIZE
+            {
+                let mut frame = vec![0; len - NET_HEADER_SIZE];
+                chain.read_at(ram, NET_HEADER_SIZE, &mut frame)?;
+                self.backend.send(&frame);
+            }
+            queue.add_used(ram, chain.head, 0)?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, queue: &mut Queue, ram: &mut Ram) -> Result<(), QueueError>
+    {
+        while let Some(frame) = self.backend.recv()
+        {
+            if self.pending_rx.len() < MAX_PENDING_FRAMES
+            {
+                self.pending_rx.push_back(frame);
+            }
+        }
+        while !self.pending_rx.is_empty()
+        {
+            let Some(chain) = queue.pop(ram)? else { break };
+            let frame = self.pending_rx.pop_front().unwrap();
+            let mut header = [0u8; NET_HEADER_SIZE];
+            header[10..12].copy_from_slice(&1u16.to_le_bytes()); // num_buffers
+            let mut written = chain.write_at(ram, 0, &header)?;
+            // Frames that do not fit in the buffer are truncated.
+            written += chain.write_at(ram, NET_HEADER_SIZE, &frame)?;
+            queue.add_used(ram, chain.head, written as u32)?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioNet
+{
+    fn device_type(&self) -> u32
+    {
+        VIRTIO_ID_NET
+    }
+
+    fn features(&self) -> u64
+    {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16>
+    {
+        vec![QUEUE_SIZE, QUEUE_SIZE]
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8])
+    {
+        // mac, status
+        let mut config = [0u8; 8];
+        config[0..6].copy_from_slice(&self.mac);
+        config[6..8].copy_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        read_config_bytes(&config, offset, data);
+    }
+
+    fn reset(&mut self)
+    {
+        self.pending_rx.clear();
+    }
+
+    fn process_queue(&mut self, index: usize, queues: &mut [Queue], ram: &mut Ram) -> Result<(), QueueError>
+    {
+        if index == TX_QUEUE
+        {
+            self.transmit(&mut queues[TX_QUEUE], ram)?;
+        }
+        // Transmits may produce immediate replies, and new RX buffers may
+        // let pending frames through.
+        self.receive(&mut queues[RX_QUEUE], ram)
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], ram: &mut Ram) -> Result<(), QueueError>
+    {
+        self.receive(&mut queues[RX_QUEUE], ram)
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::devices::virtio::queue::tests::write_descriptor;
+    use crate::devices::virtio::queue::VIRTQ_DESC_F_WRITE;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct MirrorState
+    {
+        sent: Vec<Vec<u8>>,
+        echoes: VecDeque<Vec<u8>>,
+    }
+
+    /// Records sent frames and echoes each one back reversed.
+    #[derive(Clone, Default)]
+    struct Mirror(Arc<Mutex<MirrorState>>);
+
+    impl NetBackend for Mirror
+    {
+        fn send(&mut self, frame: &[u8])
+        {
+            let mut state = self.0.lock().unwrap();
+            state.sent.push(frame.to_vec());
+            state.echoes.push_back(frame.iter().rev().copied().collect());
+        }
+
+        fn recv(&mut self) -> Option<Vec<u8>>
+        {
+            self.0.lock().unwrap().echoes.pop_front()
+        }
+    }
+
+    fn queue_at(base: u64) -> Queue
+    {
+        let mut queue = Queue::new(QUEUE_SIZE);
+        queue.size = 4;
+        queue.desc_table = base;
+        queue.avail_ring = base + 0x1000;
+        queue.used_ring = base + 0x2000;
+        queue.ready = true;
+        queue
+    }
+
+    fn post_buffer(ram: &mut Ram, queue: &Queue, addr: u64, len: u32, flags: u16)
+    {
+        let idx = ram.read_u16(queue.avail_ring + 2).unwrap();
+        let slot = idx % queue.size;
+        write_descriptor(ram, queue.desc_table, slot, addr, len, flags, 0);
+        ram.write_u16(queue.avail_ring + 4 + 2 * slot as u64, slot).unwrap();
+        ram.write_u16(queue.avail_ring + 2, idx.wrapping_add(1)).unwrap();
+    }
+
+    #[test]
+    fn test_transmit_and_receive_frames()
+    {
+        let mut ram = Ram::new(0, 0x20000);
+        let backend = Mirror::default();
+        let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let mut net = VirtioNet::new(Box::new(backend.clone()), mac);
+        let mut queues = vec![queue_at(0x1000), queue_at(0x5000)];
+
+        let mut mac_config = [0u8; 6];
+        net.read_config(0, &mut mac_config);
+        assert_eq!(mac_config, mac);
+
+        let mut packet = vec![0u8; NET_HEADER_SIZE];
+        packet.extend_from_slice(b"frame");
+        ram.write(0x10000, &packet).unwrap();
+        post_buffer(&mut ram, &queues[TX_QUEUE], 0x10000, packet.len() as u32, 0);
+        net.process_queue(TX_QUEUE, &mut queues, &mut ram).unwrap();
+        assert_eq!(backend.0.lock().unwrap().sent, vec![b"frame".to_vec()]);
+
+        // An oversized chain is used without being sent.
+        post_buffer(&mut ram, &queues[TX_QUEUE], 0x10000, u32::MAX, 0);
+        net.process_queue(TX_QUEUE, &mut queues, &mut ram).unwrap();
+        assert_eq!(queues[TX_QUEUE].used_count(), 2);
+        assert_eq!(backend.0.lock().unwrap().sent.len(), 1);
+
+        // The echo waits for an RX buffer, then arrives behind a header.
+        assert_eq!(queues[RX_QUEUE].used_count(), 0);
+        post_buffer(&mut ram, &queues[RX_QUEUE], 0x11000, 1526, VIRTQ_DESC_F_WRITE);
+        net.poll(&mut queues, &mut ram).unwrap();
+        assert_eq!(queues[RX_QUEUE].used_count(), 1);
+        assert_eq!(ram.read_u32(queues[RX_QUEUE].used_ring + 8), Some(17));
+        assert_eq!(ram.read_u16(0x11000 + 10), Some(1));
+        let mut received = [0u8; 5];
+        ram.read(0x11000 + NET_HEADER_SIZE as u64, &mut received).unwrap();
+        assert_eq!(&received, b"emarf");
+    }
+}
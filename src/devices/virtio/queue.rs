@@ -0,0 +1,412 @@
+use crate::ram::Ram;
+
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+pub const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const DESCRIPTOR_SIZE: u64 = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum QueueError
+{
+    /// A ring or descriptor table lies outside guest RAM.
+    RingOutOfRange,
+    /// A descriptor index is not below the table size.
+    DescriptorOutOfRange(u16),
+    /// A chain is longer than its table, so it must contain a loop.
+    ChainTooLong,
+    /// An indirect descriptor inside an indirect table, or a bad table size.
+    InvalidIndirectTable,
+    /// A buffer described by a descriptor lies outside guest RAM.
+    BufferOutOfRange,
+    /// A chain carries more data than the device accepts in one buffer.
+    BufferTooLong,
+}
+
+/// A guest address `offset` bytes into a ring or table. The driver picks
+/// the base, so the sum may not fit.
+fn ring_address(base: u64, offset: u64) -> Result<u64, QueueError>
+{
+    base.checked_add(offset).ok_or(QueueError::RingOutOfRange)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Descriptor
+{
+    pub addr: u64,
+    pub len: u32,
+    pub writable: bool,
+}
+
+/// A buffer made available by the driver, with any indirect table already
+/// flattened into the descriptor list.
+#[derive(Debug)]
+pub struct DescriptorChain
+{
+    pub head: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain
+{
+    pub fn readable(&self) -> impl Iterator<Item = &Descriptor>
+    {
+        self.descriptors.iter().filter(|descriptor| !descriptor.writable)
+    }
+
+    pub fn writable(&self) -> impl Iterator<Item = &Descriptor>
+    {
+        self.descriptors.iter().filter(|descriptor| descriptor.writable)
+    }
+
+    pub fn readable_len(&self) -> usize
+    {
+        self.readable().map(|descriptor| descriptor.len as usize).sum()
+    }
+
+    pub fn writable_len(&self) -> usize
+    {
+        self.writable().map(|descriptor| descriptor.len as usize).sum()
+    }
+
+    /// Copies device-readable bytes starting `offset` bytes into the chain.
+    /// Returns how many bytes were copied, which is short at the chain's end.
+    pub fn read_at(&self, ram: &Ram, offset: usize, buf: &mut [u8]) -> Result<usize, QueueError>
+    {
+        let mut skip = offset;
+        let mut copied = 0;
+        for descriptor in self.readable()
+        {
+            let len = descriptor.len as usize;
+            if skip >= len
+            {
+                skip -= len;
+                continue;
+            }
+            let count = (len - skip).min(buf.len() - copied);
+            let addr = descriptor.addr.checked_add(skip as u64).ok_or(QueueError::BufferOutOfRange)?;
+            ram.read(addr, &mut buf[copied..copied + count])
+                .ok_or(QueueError::BufferOutOfRange)?;
+            copied += count;
+            skip = 0;
+            if copied == buf.len()
+            {
+                break;
+            }
+        }
+        Ok(copied)
+    }
+
+    /// Copies `data` into the device-writable part of the chain, starting
+    /// `offset` bytes in. Returns how many bytes fit.
+    pub fn write_at(&self, ram: &mut Ram, offset: usize, data: &[u8]) -> Result<usize, QueueError>
+    {
+        let mut skip = offset;
+        let mut copied = 0;
+        for descriptor in self.writable()
+        {
+            let len = descriptor.len as usize;
+            if skip >= len
+            {
+                skip -= len;
+                continue;
+            }
+            let count = (len - skip).min(data.len() - copied);
+            let addr = descriptor.addr.checked_add(skip as u64).ok_or(QueueError::BufferOutOfRange)?;
+            ram.write(addr, &data[copied..copied + count])
+                .ok_or(QueueError::BufferOutOfRange)?;
+            copied += count;
+            skip = 0;
+            if copied == data.len()
+            {
+                break;
+            }
+        }
+        Ok(copied)
+    }
+
+    /// Reads all device-readable bytes, failing if there are more than
+    /// `max`. The lengths come from the driver, so callers bound them.
+    pub fn read_all(&self, ram: &Ram, max: usize) -> Result<Vec<u8>, QueueError>
+    {
+        let len = self.readable_len();
+        if len > max
+        {
+            return Err(QueueError::BufferTooLong);
+        }
+        let mut data = vec![0; len];
+        self.read_at(ram, 0, &mut data)?;
+        Ok(data)
+    }
+}
+
+/// The device side of a split virtqueue.
+pub struct Queue
+{
+    pub max_size: u16,
+    pub size: u16,
+    pub ready: bool,
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+    next_avail: u16,
+    next_used: u16,
+}
+
+impl Queue
+{
+    pub fn new(max_size: u16) -> Self
+    {
+        Queue
+        {
+            max_size,
+            size: max_size,
+            ready: false,
+            desc_table: 0,
+            avail_ring: 0,
+            used_ring: 0,
+            next_avail: 0,
+            next_used: 0,
+        }
+    }
+
+    pub fn reset(&mut self)
+    {
+        *self = Queue::new(self.max_size);
+    }
+
+    /// Number of buffers returned to the driver so far (wrapping).
+    pub fn used_count(&self) -> u16
+    {
+        self.next_used
+    }
+
+    /// Takes the next available buffer, if the driver has published one.
+    pub fn pop(&mut self, ram: &Ram) -> Result<Option<DescriptorChain>, QueueError>
+    {
+        if !self.ready || self.size == 0
+        {
+            return Ok(None);
+        }
+        let avail_idx = ram.read_u16(ring_address(self.avail_ring, 2)?).ok_or(QueueError::RingOutOfRange)?;
+        if avail_idx == self.next_avail
+        {
+            return Ok(None);
+        }
+        let slot = ring_address(self.avail_ring, 4 + 2 * (self.next_avail % self.size) as u64)?;
+        let head = ram.read_u16(slot).ok_or(QueueError::RingOutOfRange)?;
+        self.next_avail = self.next_avail.wrapping_add(1);
+        self.walk_chain(ram, head).map(Some)
+    }
+
+    /// Puts a buffer the driver made available back so it can be popped again.
+    pub fn undo_pop(&mut self)
+    {
+        self.next_avail = self.next_avail.wrapping_sub(1);
+    }
+
+    fn read_descriptor(ram: &Ram, table: u64, index: u16) -> Result<(Descriptor, u16, u16), QueueError>
+    {
+        let addr = ring_address(table, index as u64 * DESCRIPTOR_SIZE)?;
+        // The whole descriptor must be addressable before its fields are.
+        ring_address(addr, DESCRIPTOR_SIZE - 1)?;
+        let buf_addr = ram.read_u64(addr).ok_or(QueueError::RingOutOfRange)?;
+        let len = ram.read_u32(addr + 8).ok_or(QueueError::RingOutOfRange)?;
+        let flags = ram.read_u16(addr + 12).ok_or(QueueError::RingOutOfRange)?;
+        let next = ram.read_u16(addr + 14).ok_or(QueueError::RingOutOfRange)?;
+        let descriptor = Descriptor { addr: buf_addr, len, writable: flags & VIRTQ_DESC_F_WRITE != 0 };
+        Ok((descriptor, flags, next))
+    }
+
+    fn walk_chain(&self, ram: &Ram, head: u16) -> Result<DescriptorChain, QueueError>
+    {
+        let mut descriptors = Vec::new();
+        let mut table = self.desc_table;
+        let mut table_size = self.size;
+        let mut index = head;
+        let mut indirect = false;
+        let mut remaining = table_size as usize;
+        loop
+        {
+            if index >= table_size
+            {
+                return Err(QueueError::DescriptorOutOfRange(index));
+            }
+            if remaining == 0
+            {
+                return Err(QueueError::ChainTooLong);
+            }
+            remaining -= 1;
+
+            let (descriptor, flags, next) = Self::read_descriptor(ram, table, index)?;
+            if flags & VIRTQ_DESC_F_INDIRECT != 0
+            {
+                if indirect || descriptor.len == 0 || !(descriptor.len as u64).is_multiple_of(DESCRIPTOR_SIZE)
+                {
+                    return Err(QueueError::InvalidIndirectTable);
+                }
+                let entries = descriptor.len as u64 / DESCRIPTOR_SIZE;
+                if entries > u16::MAX as u64
+                {
+                    return Err(QueueError::InvalidIndirectTable);
+                }
+                indirect = true;
+                table = descriptor.addr;
+                table_size = entries as u16;
+                remaining = entries as usize;
+                index = 0;
+                continue;
+            }
+
+            descriptors.push(descriptor);
+            if flags & VIRTQ_DESC_F_NEXT == 0
+            {
+                break;
+            }
+            index = next;
+        }
+        Ok(DescriptorChain { head, descriptors })
+    }
+
+    /// Returns a buffer to the driver, recording how many bytes were written.
+    pub fn add_used(&mut self, ram: &mut Ram, head: u16, len: u32) -> Result<(), QueueError>
+    {
+        let slot = ring_address(self.used_ring, 4 + 8 * (self.next_used % self.size) as u64)?;
+        let idx = ring_address(self.used_ring, 2)?;
+        ram.write_u32(slot, head as u32).ok_or(QueueError::RingOutOfRange)?;
+        ram.write_u32(ring_address(slot, 4)?, len).ok_or(QueueError::RingOutOfRange)?;
+        self.next_used = self.next_used.wrapping_add(1);
+        ram.write_u16(idx, self.next_used).ok_or(QueueError::RingOutOfRange)
+    }
+
+    /// Whether the driver asked not to be interrupted for used buffers.
+    pub fn interrupt_suppressed(&self, ram: &Ram) -> bool
+    {
+        ram.read_u16(self.avail_ring)
+            .map(|flags| flags & VIRTQ_AVAIL_F_NO_INTERRUPT != 0)
+            .unwrap_or(false)
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests
+{
+    use super::*;
+
+    pub const DESC_TABLE: u64 = 0x1000;
+    pub const AVAIL_RING: u64 = 0x2000;
+    pub const USED_RING: u64 = 0x3000;
+
+    pub fn write_descriptor(ram: &mut Ram, table: u64, index: u16, addr: u64, len: u32, flags: u16, next: u16)
+    {
+        let entry = table + index as u64 * DESCRIPTOR_SIZE;
+        ram.write_u64(entry, addr).unwrap();
+        ram.write_u32(entry + 8, len).unwrap();
+        ram.write_u16(entry + 12, flags).unwrap();
+        ram.write_u16(entry + 14, next).unwrap();
+    }
+
+    /// Publishes `head` as the next available buffer.
+    pub fn make_available(ram: &mut Ram, size: u16, head: u16)
+    {
+        let idx = ram.read_u16(AVAIL_RING + 2).unwrap();
+        ram.write_u16(AVAIL_RING + 4 + 2 * (idx % size) as u64, head).unwrap();
+        ram.write_u16(AVAIL_RING + 2, idx.wrapping_add(1)).unwrap();
+    }
+
+    pub fn ready_queue(size: u16) -> Queue
+    {
+        let mut queue = Queue::new(size);
+        queue.desc_table = DESC_TABLE;
+        queue.avail_ring = AVAIL_RING;
+        queue.used_ring = USED_RING;
+        queue.ready = true;
+        queue
+    }
+
+    #[test]
+    fn test_pop_chain_and_copy_data()
+    {
+        let mut ram = Ram::new(0, 0x10000);
+        let mut queue = ready_queue(8);
+        ram.write(0x4000, b"hello ").unwrap();
+        ram.write(0x5000, b"world").unwrap();
+        write_descriptor(&mut ram, DESC_TABLE, 3, 0x4000, 6, VIRTQ_DESC_F_NEXT, 5);
+        write_descriptor(&mut ram, DESC_TABLE, 5, 0x5000, 5, VIRTQ_DESC_F_NEXT, 1);
+        write_descriptor(&mut ram, DESC_TABLE, 1, 0x6000, 4, VIRTQ_DESC_F_WRITE, 0);
+        make_available(&mut ram, 8, 3);
+
+        let chain = queue.pop(&ram).unwrap().unwrap();
+        assert_eq!(chain.head, 3);
+        assert_eq!(chain.read_all(&ram, 11).unwrap(), b"hello world");
+        assert_eq!(chain.read_all(&ram, 10), Err(QueueError::BufferTooLong));
+        assert_eq!(chain.writable_len(), 4);
+        let mut tail = [0u8; 8];
+        assert_eq!(chain.read_at(&ram, 4, &mut tail).unwrap(), 7);
+        assert_eq!(&tail[..7], b"o world");
+        assert_eq!(chain.write_at(&mut ram, 1, b"abcdef").unwrap(), 3);
+        assert_eq!(ram.read_u32(0x6000), Some(u32::from_le_bytes([0, b'a', b'b', b'c'])));
+
+        queue.add_used(&mut ram, chain.head, 4).unwrap();
+        assert_eq!(ram.read_u16(USED_RING + 2), Some(1));
+        assert_eq!(ram.read_u32(USED_RING + 4), Some(3));
+        assert_eq!(ram.read_u32(USED_RING + 8), Some(4));
+        assert!(queue.pop(&ram).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_indirect_chain_and_loop_detection()
+    {
+        let mut ram = Ram::new(0, 0x10000);
+        let mut queue = ready_queue(4);
+        write_descriptor(&mut ram, 0x7000, 0, 0x4000, 16, VIRTQ_DESC_F_NEXT, 1);
+        write_descriptor(&mut ram, 0x7000, 1, 0x5000, 1, VIRTQ_DESC_F_WRITE, 0);
+        write_descriptor(&mut ram, DESC_TABLE, 0, 0x7000, 32, VIRTQ_DESC_F_INDIRECT, 0);
+        make_available(&mut ram, 4, 0);
+
+        let chain = queue.pop(&ram).unwrap().unwrap();
+        assert_eq!(chain.descriptors, vec![
+            Descriptor { addr: 0x4000, len: 16, writable: false },
+            Descriptor { addr: 0x5000, len: 1, writable: true },
+        ]);
+
+        write_descriptor(&mut ram, DESC_TABLE, 1, 0x4000, 1, VIRTQ_DESC_F_NEXT, 2);
+        write_descriptor(&mut ram, DESC_TABLE, 2, 0x4000, 1, VIRTQ_DESC_F_NEXT, 1);
+        make_available(&mut ram, 4, 1);
+        assert_eq!(queue.pop(&ram).unwrap_err(), QueueError::ChainTooLong);
+
+        write_descriptor(&mut ram, DESC_TABLE, 3, 0x4000, 1, VIRTQ_DESC_F_NEXT, 9);
+        make_available(&mut ram, 4, 3);
+        assert_eq!(queue.pop(&ram).unwrap_err(), QueueError::DescriptorOutOfRange(9));
+    }
+
+    #[test]
+    fn test_addresses_near_the_top_of_memory()
+    {
+        let mut ram = Ram::new(0, 0x10000);
+        let mut queue = ready_queue(4);
+        queue.avail_ring = u64::MAX - 1;
+        assert_eq!(queue.pop(&ram).unwrap_err(), QueueError::RingOutOfRange);
+        queue.used_ring = u64::MAX - 3;
+        assert_eq!(queue.add_used(&mut ram, 0, 0), Err(QueueError::RingOutOfRange));
+
+        queue.avail_ring = AVAIL_RING;
+        queue.desc_table = u64::MAX - 15;
+        make_available(&mut ram, 4, 1);
+        assert_eq!(queue.pop(&ram).unwrap_err(), QueueError::RingOutOfRange);
+
+        let chain = DescriptorChain
+        {
+            head: 0,
+            descriptors: vec![
+                Descriptor { addr: u64::MAX - 1, len: 4, writable: false },
+                Descriptor { addr: u64::MAX - 1, len: 4, writable: true },
+            ],
+        };
+        assert_eq!(chain.read_at(&ram, 2, &mut [0; 2]), Err(QueueError::BufferOutOfRange));
+        assert_eq!(chain.write_at(&mut ram, 2, &[0; 2]), Err(QueueError::BufferOutOfRange));
+    }
+}
@@ -0,0 +1,30 @@
+use std::io;
+use std::os::raw::{c_uint, c_void};
+
+extern "C"
+{
+    fn getrandom(buf: *mut c_void, buflen: usize, flags: c_uint) -> isize;
+}
+
+/// Fills `buf` with bytes from the host's getrandom(2).
+pub fn fill(buf: &mut [u8]) -> io::Result<()>
+{
+    let mut filled = 0;
+    while filled < buf.len()
+    {
+        let remaining = &mut buf[filled..];
+        // SAFETY: the pointer and length describe the unfilled tail of `buf`.
+        let result = unsafe { getrandom(remaining.as_mut_ptr() as *mut c_void, remaining.len(), 0) };
+        if result < 0
+        {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted
+            {
+                continue;
+            }
+            return Err(error);
+        }
+        filled += result as usize;
+    }
+    Ok(())
+}
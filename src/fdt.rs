@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+// Structure block tokens
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+/// Builds a flattened device tree blob (DTB), version 17.
+///
+/// Nodes are written depth first: every `begin_node` is matched by an
+/// `end_node`, and a node's properties come before its children.
+#[derive(Default)]
+pub struct FdtBuilder
+{
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+    reservations: Vec<(u64, u64)>,
+    depth: usize,
+}
+
+impl FdtBuilder
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    /// Adds a memory reservation entry the guest must not allocate from.
+    pub fn add_reservation(&mut self, address: u64, size: u64)
+    {
+        self.reservations.push((address, size));
+    }
+
+    /// Opens a node. The root node's name is the empty string.
+    pub fn begin_node(&mut self, name: &str)
+    {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.pad_structure();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self)
+    {
+        assert!(self.depth > 0, "end_node without a matching begin_node");
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8])
+    {
+        let name_offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.structure.extend_from_slice(value);
+        self.pad_structure();
+    }
+
+    /// An empty property, used for boolean flags such as `ranges`.
+    pub fn property_null(&mut self, name: &str)
+    {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32)
+    {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64)
+    {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    /// A list of 32-bit cells, such as `interrupts-extended`.
+    pub fn property_cells(&mut self, name: &str, cells: &[u32])
+    {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    /// A list of 64-bit values, each split into two cells, such as `reg`
+    /// with two address and two size cells.
+    pub fn property_u64s(&mut self, name: &str, values: &[u64])
+    {
+        let value: Vec<u8> = values.iter().flat_map(|value| value.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str)
+    {
+        self.property_strings(name, &[value]);
+    }
+
+    /// A string list, such as `compatible`, stored NUL separated.
+    pub fn property_strings(&mut self, name: &str, values: &[&str])
+    {
+        let mut value = Vec::new();
+        for string in values
+        {
+            value.extend_from_slice(string.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    /// Lays out the header, memory reservation map, structure block and
+    /// strings block, in that order.
+    pub fn finish(mut self, boot_cpuid: u32) -> Vec<u8>
+    {
+        assert_eq!(self.depth, 0, "device tree has unclosed nodes");
+        self.push_u32(FDT_END);
+
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        // The map is terminated by an all-zero entry.
+        let rsvmap_size = (self.reservations.len() + 1) * 16;
+        let off_dt_struct = off_mem_rsvmap + rsvmap_size;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            boot_cpuid,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ]
+        {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        for (address, size) in self.reservations.iter().chain(std::iter::once(&(0, 0)))
+        {
+            blob.extend_from_slice(&address.to_be_bytes());
+            blob.extend_from_slice(&size.to_be_bytes());
+        }
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+
+    fn push_u32(&mut self, value: u32)
+    {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn pad_structure(&mut self)
+    {
+        while !self.structure.len().is_multiple_of(4)
+        {
+            self.structure.push(0);
+        }
+    }
+
+    /// Property names are stored once in the strings block and shared.
+    fn string_offset(&mut self, name: &str) -> u32
+    {
+        if let Some(&offset) = self.string_offsets.get(name)
+        {
+            return offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn be32(blob: &[u8], offset: usize) -> u32
+    {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_blob_layout()
+    {
+        let mut fdt = FdtBuilder::new();
+        fdt.add_reservation(0x8000_0000, 0x1000);
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.begin_node("cpu@0");
+        fdt.property_u32("#address-cells", 1);
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish(0);
+
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        assert_eq!(be32(&blob, 20), FDT_VERSION);
+        // One reservation plus the terminator.
+        assert_eq!(be32(&blob, 16), 40);
+        assert_eq!(be32(&blob, 8), 40 + 32);
+        assert_eq!(&blob[40..48], &0x8000_0000u64.to_be_bytes());
+
+        // The repeated property name is stored once.
+        let strings = be32(&blob, 12) as usize;
+        assert_eq!(&blob[strings..], b"#address-cells\0");
+
+        let structure = be32(&blob, 8) as usize;
+        assert_eq!(be32(&blob, structure), FDT_BEGIN_NODE);
+        // Empty root name padded to four bytes, then the first property.
+        assert_eq!(be32(&blob, structure + 8), FDT_PROP);
+        assert_eq!(be32(&blob, structure + 12), 4);
+        assert_eq!(be32(&blob, structure + 16), 0);
+        assert_eq!(be32(&blob, structure + 20), 2);
+        assert_eq!(be32(&blob, strings - 4), FDT_END);
+    }
+}
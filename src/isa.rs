@@ -0,0 +1,318 @@
+use std::fmt;
+
+/// An ISA extension the interpreter knows by name. Each has a bit in Isa.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Extension
+{
+    I,
+    M,
+    A,
+    F,
+    D,
+    Q,
+    C,
+    V,
+    H,
+    Zicsr,
+    Zifencei,
+    Zicntr,
+    Zihpm,
+    Zicbom,
+    Zicboz,
+    Zihintpause,
+    Zawrs,
+    Zfh,
+    Zfhmin,
+    Zfa,
+    Zba,
+    Zbb,
+    Zbc,
+    Zbs,
+    Zbkb,
+    Zbkc,
+    Zbkx,
+    Zknd,
+    Zkne,
+    Zknh,
+    Zksed,
+    Zksh,
+    Zkr,
+    Zkt,
+    Zve32x,
+    Zve32f,
+    Zve64x,
+    Zve64f,
+    Zve64d,
+    Sstc,
+    Svinval,
+    Svnapot,
+    Svpbmt,
+}
+
+struct ExtensionInfo
+{
+    name: &'static str,
+    extension: Extension,
+    /// Whether the interpreter executes the extension's instructions.
+    implemented: bool,
+    requires: &'static [Extension],
+}
+
+const fn info(name: &'static str, extension: Extension, implemented: bool, requires: &'static [Extension]) -> ExtensionInfo
+{
+    ExtensionInfo { name, extension, implemented, requires }
+}
+
+/// Every known extension, in the canonical order of an ISA string.
+const EXTENSIONS: &[ExtensionInfo] = &[
+    info("i", Extension::I, true, &[]),
+    info("m", Extension::M, true, &[]),
+    info("a", Extension::A, true, &[]),
+    info("f", Extension::F, true, &[Extension::Zicsr]),
+    info("d", Extension::D, true, &[Extension::F]),
+    info("q", Extension::Q, false, &[Extension::D]),
+    info("c", Extension::C, true, &[]),
+    info("v", Extension::V, true, &[Extension::D, Extension::Zve64d]),
+    info("h", Extension::H, false, &[]),
+    info("zicsr", Extension::Zicsr, true, &[]),
+    info("zifencei", Extension::Zifencei, true, &[]),
+    info("zicntr", Extension::Zicntr, true, &[Extension::Zicsr]),
+    info("zihpm", Extension::Zihpm, false, &[Extension::Zicsr]),
+    info("zicbom", Extension::Zicbom, false, &[]),
+    info("zicboz", Extension::Zicboz, false, &[]),
+    info("zihintpause", Extension::Zihintpause, false, &[]),
+    info("zawrs", Extension::Zawrs, false, &[]),
+    info("zfh", Extension::Zfh, true, &[Extension::F]),
+    info("zfhmin", Extension::Zfhmin, true, &[Extension::F]),
+    info("zfa", Extension::Zfa, true, &[Extension::F]),
+    info("zba", Extension::Zba, true, &[]),
+    info("zbb", Extension::Zbb, true, &[]),
+    info("zbc", Extension::Zbc, true, &[]),
+    info("zbs", Extension::Zbs, true, &[]),
+    info("zbkb", Extension::Zbkb, true, &[]),
+    info("zbkc", Extension::Zbkc, true, &[]),
+    info("zbkx", Extension::Zbkx, true, &[]),
+    info("zknd", Extension::Zknd, true, &[]),
+    info("zkne", Extension::Zkne, true, &[]),
+    info("zknh", Extension::Zknh, true, &[]),
+    info("zksed", Extension::Zksed, true, &[]),
+    info("zksh", Extension::Zksh, true, &[]),
+    info("zkr", Extension::Zkr, true, &[Extension::Zicsr]),
+    info("zkt", Extension::Zkt, true, &[]),
+    info("zve32x", Extension::Zve32x, true, &[Extension::Zicsr]),
+    info("zve32f", Extension::Zve32f, true, &[Extension::Zve32x, Extension::F]),
+    info("zve64x", Extension::Zve64x, true, &[Extension::Zve32x]),
+    info("zve64f", Extension::Zve64f, true, &[Extension::Zve64x, Extension::Zve32f]),
+    info("zve64d", Extension::Zve64d, true, &[Extension::Zve64f, Extension::D]),
+    info("sstc", Extension::Sstc, false, &[Extension::Zicsr]),
+    info("svinval", Extension::Svinval, false, &[]),
+    info("svnapot", Extension::Svnapot, false, &[]),
+    info("svpbmt", Extension::Svpbmt, false, &[]),
+];
+
+/// Names that stand for a group of extensions.
+const SHORTHANDS: &[(&str, &[&str])] = &[
+    ("g", &["i", "m", "a", "f", "d", "zicsr", "zifencei"]),
+    ("b", &["zba", "zbb", "zbs"]),
+    ("zkn", &["zbkb", "zbkc", "zbkx", "zkne", "zknd", "zknh"]),
+    ("zks", &["zbkb", "zbkc", "zbkx", "zksed", "zksh"]),
+    ("zk", &["zbkb", "zbkc", "zbkx", "zkne", "zknd", "zknh", "zkr", "zkt"]),
+];
+
+// misa fields
+const MISA_MXL_64: u64 = 2 << 62;
+const MISA_S: u64 = 1 << (b's' - b'a');
+const MISA_U: u64 = 1 << (b'u' - b'a');
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum IsaError
+{
+    /// The string does not start with "rv" and a base.
+    Malformed,
+    /// Only RV64 is supported.
+    UnsupportedXlen(u32),
+    /// The name is not an extension at all.
+    UnknownExtension(String),
+    /// A real extension the interpreter cannot execute.
+    UnimplementedExtension(String),
+    /// An extension is named more than once.
+    DuplicateExtension(String),
+    /// An extension is given without one it depends on.
+    MissingDependency { extension: &'static str, requires: &'static str },
+}
+
+fn lookup(extension: Extension) -> &'static ExtensionInfo
+{
+    EXTENSIONS.iter().find(|info| info.extension == extension).unwrap()
+}
+
+/// The extensions a hart implements, parsed from an ISA string such as
+/// `rv64i_zicsr_zifencei`.
+///
+/// Parsing only accepts extensions the interpreter executes, so anything
+/// a guest sees in misa or the DTB works; instructions of extensions left
+/// out raise illegal-instruction exceptions. The default is every
+/// implemented extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Isa
+{
+    extensions: u64,
+}
+
+impl Isa
+{
+    pub fn parse(isa: &str) -> Result<Self, IsaError>
+    {
+        let isa = isa.to_ascii_lowercase();
+        let rest = isa.strip_prefix("rv").ok_or(IsaError::Malformed)?;
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let xlen: u32 = rest[..digits].parse().map_err(|_| IsaError::Malformed)?;
+        if xlen != 64
+        {
+            return Err(IsaError::UnsupportedXlen(xlen));
+        }
+        let rest = &rest[digits..];
+        match rest.chars().next()
+        {
+            Some('i') | Some('g') => {}
+            Some('e') => return Err(IsaError::UnimplementedExtension("e".to_string())),
+            _ => return Err(IsaError::Malformed),
+        }
+
+        // Single letters come first; a multi-letter name may follow them
+        // directly, and the rest are separated by underscores.
+        let mut names: Vec<String> = Vec::new();
+        for (index, part) in rest.split('_').enumerate()
+        {
+            if part.is_empty()
+            {
+                return Err(IsaError::Malformed);
+            }
+            if index > 0
+            {
+                names.push(part.to_string());
+                continue;
+            }
+            let multi = part.find(['z', 's', 'x']).unwrap_or(part.len());
+            names.extend(part[..multi].chars().map(String::from));
+            if multi < part.len()
+            {
+                names.push(part[multi..].to_string());
+            }
+        }
+
+        let mut extensions = 0;
+        for (index, name) in names.iter().enumerate()
+        {
+            if names[..index].contains(name)
+            {
+                return Err(IsaError::DuplicateExtension(name.clone()));
+            }
+            let expanded = match SHORTHANDS.iter().find(|(shorthand, _)| shorthand == name)
+            {
+                Some((_, members)) => members.to_vec(),
+                None => vec![name.as_str()],
+            };
+            for member in expanded
+            {
+                let info = EXTENSIONS.iter()
+                    .find(|info| info.name == member)
+                    .ok_or_else(|| IsaError::UnknownExtension(member.to_string()))?;
+                if !info.implemented
+                {
+                    return Err(IsaError::UnimplementedExtension(member.to_string()));
+                }
+                extensions |= 1 << info.extension as u32;
+            }
+        }
+
+        let isa = Isa { extensions };
+        for info in EXTENSIONS.iter().filter(|info| isa.has(info.extension))
+        {
+            if let Some(&missing) = info.requires.iter().find(|&&required| !isa.has(required))
+            {
+                return Err(IsaError::MissingDependency { extension: info.name, requires: lookup(missing).name });
+            }
+        }
+        Ok(isa)
+    }
+
+    pub fn has(&self, extension: Extension) -> bool
+    {
+        self.extensions & (1 << extension as u32) != 0
+    }
+
+    /// The names of the enabled extensions, in canonical order.
+    pub fn extension_names(&self) -> Vec<&'static str>
+    {
+        EXTENSIONS.iter().filter(|info| self.has(info.extension)).map(|info| info.name).collect()
+    }
+
+    /// The misa value: MXL = 64, a bit per single-letter extension, and
+    /// S and U since both privilege levels are always present.
+    pub fn misa(&self) -> u64
+    {
+        EXTENSIONS.iter()
+            .filter(|info| info.name.len() == 1 && self.has(info.extension))
+            .fold(MISA_MXL_64 | MISA_S | MISA_U, |misa, info| misa | 1 << (info.name.as_bytes()[0] - b'a'))
+    }
+}
+
+impl Default for Isa
+{
+    fn default() -> Self
+    {
+        let extensions = EXTENSIONS.iter()
+            .filter(|info| info.implemented)
+            .fold(0, |extensions, info| extensions | 1 << info.extension as u32);
+        Isa { extensions }
+    }
+}
+
+/// The canonical ISA string, as written to the DTB's riscv,isa.
+impl fmt::Display for Isa
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "rv64")?;
+        for name in self.extension_names()
+        {
+            if name.len() > 1
+            {
+                write!(f, "_")?;
+            }
+            write!(f, "{}", name)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_parse_isa_string()
+    {
+        let isa = Isa::parse("RV64I_Zicsr").unwrap();
+        assert!(isa.has(Extension::Zicsr));
+        assert!(!isa.has(Extension::Zifencei));
+        assert_eq!(isa.to_string(), "rv64i_zicsr");
+        let all = "rv64gcvb_zicntr_zfh_zfhmin_zfa_zbc_zk_zks_zve32x_zve32f_zve64x_zve64f_zve64d";
+        assert_eq!(Isa::parse(all), Ok(Isa::default()));
+        assert_eq!(Isa::default().to_string(), "rv64imafdcv_zicsr_zifencei_zicntr_zfh_zfhmin_zfa_zba_zbb_zbc_zbs_zbkb_zbkc_zbkx_zknd_zkne_zknh_zksed_zksh_zkr_zkt_zve32x_zve32f_zve64x_zve64f_zve64d");
+        assert_eq!(Isa::default().misa(), (2 << 62) | (1 << 21) | (1 << 20) | (1 << 18) | (1 << 12) | (1 << 8) | (1 << 5) | (1 << 3) | (1 << 2) | 1);
+        assert_eq!(Isa::parse("rv64i_zk"), Err(IsaError::MissingDependency { extension: "zkr", requires: "zicsr" }));
+        assert_eq!(Isa::parse("rv64iv_zicsr"), Err(IsaError::MissingDependency { extension: "v", requires: "d" }));
+
+        assert_eq!(Isa::parse("rv32i"), Err(IsaError::UnsupportedXlen(32)));
+        assert_eq!(Isa::parse("x86_64"), Err(IsaError::Malformed));
+        assert_eq!(Isa::parse("rv64i__zicsr"), Err(IsaError::Malformed));
+        assert_eq!(Isa::parse("rv64i_zfoo"), Err(IsaError::UnknownExtension("zfoo".to_string())));
+        assert_eq!(Isa::parse("rv64gc").unwrap().to_string(), "rv64imafdc_zicsr_zifencei");
+        assert_eq!(Isa::parse("rv64gq"), Err(IsaError::UnimplementedExtension("q".to_string())));
+        assert_eq!(Isa::parse("rv64i_zicsr_zicsr"), Err(IsaError::DuplicateExtension("zicsr".to_string())));
+    }
+}
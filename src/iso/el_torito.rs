@@ -0,0 +1,533 @@
+//! The El Torito boot catalog: a validation entry, the initial/default
+//! entry, then sections of further entries, one per platform.
+
+use super::{IsoError, BLOCK_SIZE};
+
+/// Every catalog entry is 32 bytes.
+pub const ENTRY_SIZE: usize = 32;
+
+// Header indicators
+const VALIDATION_HEADER: u8 = 0x01;
+const SECTION_HEADER: u8 = 0x90;
+const FINAL_SECTION_HEADER: u8 = 0x91;
+const SECTION_EXTENSION: u8 = 0x44;
+/// The last two bytes of the validation entry.
+const KEY_BYTES: [u8; 2] = [0x55, 0xaa];
+
+// Boot indicators
+pub const BOOTABLE: u8 = 0x88;
+pub const NOT_BOOTABLE: u8 = 0x00;
+
+/// The low nibble of the media type.
+const EMULATION_MASK: u8 = 0x0f;
+
+/// Emulated drives have 512-byte sectors.
+pub const VIRTUAL_SECTOR_SIZE: usize = 512;
+/// Bytes 510 and 511 of a master boot record.
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_PARTITION_TABLE: usize = 446;
+
+/// Set in a section entry's media type, or an extension's flags, when an
+/// extension entry follows.
+const EXTENSION_FOLLOWS: u8 = 1 << 5;
+
+fn u16_at(data: &[u8], offset: usize) -> u16
+{
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32
+{
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// An ID string, without the padding.
+fn id_string(field: &[u8]) -> String
+{
+    String::from_utf8_lossy(field).trim_end_matches(['\0', ' ']).to_string()
+}
+
+/// The platform a validation entry or section is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Platform
+{
+    X86,
+    PowerPc,
+    Mac,
+    Efi,
+    Other(u8),
+}
+
+impl Platform
+{
+    pub fn from_id(id: u8) -> Self
+    {
+        match id
+        {
+            0x00 => Platform::X86,
+            0x01 => Platform::PowerPc,
+            0x02 => Platform::Mac,
+            0xef => Platform::Efi,
+            other => Platform::Other(other),
+        }
+    }
+
+    /// A short name, for file names and listings.
+    pub fn name(self) -> String
+    {
+        match self
+        {
+            Platform::X86 => String::from("x86"),
+            Platform::PowerPc => String::from("PowerPC"),
+            Platform::Mac => String::from("Mac"),
+            Platform::Efi => String::from("EFI"),
+            Platform::Other(id) => format!("platform-{:02x}", id),
+        }
+    }
+
+    pub fn id(self) -> u8
+    {
+        match self
+        {
+            Platform::X86 => 0x00,
+            Platform::PowerPc => 0x01,
+            Platform::Mac => 0x02,
+            Platform::Efi => 0xef,
+            Platform::Other(id) => id,
+        }
+    }
+}
+
+/// How the BIOS presents a boot image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Emulation
+{
+    /// The image is loaded as is, `sector_count` virtual sectors of it.
+    NoEmulation,
+    Floppy1200,
+    Floppy1440,
+    Floppy2880,
+    /// The image is a hard disk with a master boot record and one partition.
+    HardDisk,
+}
+
+impl Emulation
+{
+    pub fn from_media_type(media_type: u8) -> Result<Self, IsoError>
+    {
+        match media_type & EMULATION_MASK
+        {
+            0 => Ok(Emulation::NoEmulation),
+            1 => Ok(Emulation::Floppy1200),
+            2 => Ok(Emulation::Floppy1440),
+            3 => Ok(Emulation::Floppy2880),
+            4 => Ok(Emulation::HardDisk),
+            _ => Err(IsoError::UnknownMediaType(media_type)),
+        }
+    }
+
+    /// The fixed geometry of an emulated floppy.
+    pub fn floppy_geometry(self) -> Option<Geometry>
+    {
+        let sectors_per_track = match self
+        {
+            Emulation::Floppy1200 => 15,
+            Emulation::Floppy1440 => 18,
+            Emulation::Floppy2880 => 36,
+            _ => return None,
+        };
+        Some(Geometry { cylinders: 80, heads: 2, sectors_per_track })
+    }
+}
+
+/// Cylinders, heads and sectors of an emulated drive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Geometry
+{
+    pub cylinders: u32,
+    pub heads: u32,
+    pub sectors_per_track: u32,
+}
+
+impl Geometry
+{
+    pub fn sectors(&self) -> u64
+    {
+        self.cylinders as u64 * self.heads as u64 * self.sectors_per_track as u64
+    }
+
+    /// The logical sector of a CHS address, whose sectors count from one.
+    pub fn lba(&self, cylinder: u32, head: u32, sector: u32) -> Option<u64>
+    {
+        if cylinder >= self.cylinders || head >= self.heads || sector == 0 || sector > self.sectors_per_track
+        {
+            return None;
+        }
+        Some((cylinder as u64 * self.heads as u64 + head as u64) * self.sectors_per_track as u64 + sector as u64 - 1)
+    }
+}
+
+/// A boot image as the drive the BIOS emulates with it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VirtualDrive<'a>
+{
+    pub emulation: Emulation,
+    /// None for images loaded without emulation.
+    pub geometry: Option<Geometry>,
+    pub data: &'a [u8],
+}
+
+impl<'a> VirtualDrive<'a>
+{
+    /// A 512-byte sector by logical address.
+    pub fn read_sector(&self, lba: u64) -> Option<&'a [u8]>
+    {
+        let start = (lba as usize).checked_mul(VIRTUAL_SECTOR_SIZE)?;
+        self.data.get(start..start.checked_add(VIRTUAL_SECTOR_SIZE)?)
+    }
+
+    /// A 512-byte sector by CHS address, as INT 13h reads them.
+    pub fn read_chs(&self, cylinder: u32, head: u32, sector: u32) -> Option<&'a [u8]>
+    {
+        self.read_sector(self.geometry?.lba(cylinder, head, sector)?)
+    }
+}
+
+/// The first entry of the catalog.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationEntry
+{
+    pub platform: Platform,
+    /// Identifies the manufacturer or developer of the CD.
+    pub id_string: String,
+    pub checksum: u16,
+}
+
+impl ValidationEntry
+{
+    pub fn parse(entry: &[u8]) -> Result<Self, IsoError>
+    {
+        if entry[0] != VALIDATION_HEADER
+        {
+            return Err(IsoError::BadValidationHeader(entry[0]));
+        }
+        if entry[2] != 0x00 || entry[3] != 0x00
+        {
+            return Err(IsoError::ValidationReservedNotZero);
+        }
+        if entry[30..32] != KEY_BYTES
+        {
+            return Err(IsoError::BadKeyBytes(entry[30], entry[31]));
+        }
+        // The checksum word makes all sixteen words sum to zero.
+        let sum = entry[..ENTRY_SIZE].chunks(2).fold(0u16, |sum, word| sum.wrapping_add(u16_at(word, 0)));
+        if sum != 0
+        {
+            return Err(IsoError::BadValidationChecksum(sum));
+        }
+        Ok(ValidationEntry
+        {
+            platform: Platform::from_id(entry[1]),
+            id_string: id_string(&entry[4..28]),
+            checksum: u16_at(entry, 28),
+        })
+    }
+}
+
+/// The initial/default entry or a section entry: where a boot image is and
+/// how to load it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BootEntry
+{
+    /// 0x88 for bootable, 0x00 for not bootable.
+    pub boot_indicator: u8,
+    /// The emulation in the low nibble; in section entries, bit 5 marks a
+    /// following extension, bit 6 an image with an ATAPI driver and bit 7
+    /// one with SCSI drivers.
+    pub media_type: u8,
+    /// The real-mode segment to load the image at; zero means 0x7c0.
+    pub load_segment: u16,
+    /// The partition type byte of the image's partition table.
+    pub system_type: u8,
+    /// The number of 512-byte virtual sectors loaded at boot, for images
+    /// without emulation.
+    pub sector_count: u16,
+    /// The image's first logical block.
+    pub load_rba: u32,
+    /// The kind of vendor selection criteria; always 0 for the initial entry.
+    pub selection_criteria_type: u8,
+    /// The vendor unique selection criteria, including that of extension
+    /// entries.
+    pub selection_criteria: Vec<u8>,
+}
+
+impl BootEntry
+{
+    /// An entry from its 32 bytes. The initial entry has no selection
+    /// criteria.
+    pub fn parse(entry: &[u8], initial: bool) -> Self
+    {
+        BootEntry
+        {
+            boot_indicator: entry[0],
+            media_type: entry[1],
+            load_segment: u16_at(entry, 2),
+            system_type: entry[4],
+            sector_count: u16_at(entry, 6),
+            load_rba: u32_at(entry, 8),
+            selection_criteria_type: if initial { 0 } else { entry[12] },
+            selection_criteria: if initial { Vec::new() } else { entry[13..ENTRY_SIZE].to_vec() },
+        }
+    }
+
+    pub fn is_bootable(&self) -> bool
+    {
+        self.boot_indicator == BOOTABLE
+    }
+
+    pub fn emulation(&self) -> Result<Emulation, IsoError>
+    {
+        Emulation::from_media_type(self.media_type)
+    }
+
+    /// The boot image in an ISO image, sized for its emulation: the sector
+    /// count for no emulation, the floppy's capacity, or the extent of the
+    /// partition in a hard disk image's master boot record.
+    pub fn drive<'a>(&self, iso: &'a [u8]) -> Result<VirtualDrive<'a>, IsoError>
+    {
+        let emulation = self.emulation()?;
+        let start = self.load_rba as usize * BLOCK_SIZE;
+        let image = iso.get(start..).ok_or(IsoError::Truncated)?;
+        let (sectors, geometry) = match emulation
+        {
+            Emulation::NoEmulation => (self.sector_count as u64, None),
+            Emulation::HardDisk =>
+            {
+                let geometry = Self::hard_disk_geometry(image)?;
+                (geometry.sectors(), Some(geometry))
+            }
+            _ =>
+            {
+                let geometry = emulation.floppy_geometry().unwrap();
+                (geometry.sectors(), Some(geometry))
+            }
+        };
+        let length = usize::try_from(sectors).ok().and_then(|sectors| sectors.checked_mul(VIRTUAL_SECTOR_SIZE)).ok_or(IsoError::Truncated)?;
+        let data = image.get(..length).ok_or(IsoError::Truncated)?;
+        Ok(VirtualDrive { emulation, geometry, data })
+    }
+
+    /// The geometry of an emulated hard disk, from the ending CHS and the
+    /// extent of the one partition in its master boot record.
+    fn hard_disk_geometry(image: &[u8]) -> Result<Geometry, IsoError>
+    {
+        let mbr = image.get(..VIRTUAL_SECTOR_SIZE).ok_or(IsoError::Truncated)?;
+        if mbr[510..512] != MBR_SIGNATURE
+        {
+            return Err(IsoError::BadMasterBootRecord);
+        }
+        let partition = mbr[MBR_PARTITION_TABLE..MBR_PARTITION_TABLE + 64]
+            .chunks(16)
+            .find(|partition| partition[4] != 0)
+            .ok_or(IsoError::BadMasterBootRecord)?;
+        let end = u32_at(partition, 8) as u64 + u32_at(partition, 12) as u64;
+        let heads = partition[5] as u32 + 1;
+        let sectors_per_track = (partition[6] & 0x3f) as u32;
+        if sectors_per_track == 0 || end == 0
+        {
+            return Err(IsoError::BadMasterBootRecord);
+        }
+        let cylinders = end.div_ceil(heads as u64 * sectors_per_track as u64) as u32;
+        Ok(Geometry { cylinders, heads, sectors_per_track })
+    }
+}
+
+/// A section header and its entries.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Section
+{
+    pub platform: Platform,
+    pub id_string: String,
+    pub entries: Vec<BootEntry>,
+}
+
+/// A whole boot catalog.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BootCatalog
+{
+    pub validation: ValidationEntry,
+    pub initial: BootEntry,
+    pub sections: Vec<Section>,
+}
+
+impl BootCatalog
+{
+    /// Parses the catalog at the start of `catalog`, which may run over
+    /// several sectors.
+    pub fn parse(catalog: &[u8]) -> Result<Self, IsoError>
+    {
+        let entry = |index: usize| catalog.get(index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE).ok_or(IsoError::Truncated);
+        let validation = ValidationEntry::parse(entry(0)?)?;
+        let initial = BootEntry::parse(entry(1)?, true);
+
+        let mut sections = Vec::new();
+        let mut index = 2;
+        // The catalog ends at the final header's entries, or at an unused
+        // entry in catalogs without one.
+        while let Some(header) = catalog.get(index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE)
+        {
+            index += 1;
+            match header[0]
+            {
+                SECTION_HEADER | FINAL_SECTION_HEADER => {}
+                NOT_BOOTABLE => break,
+                indicator => return Err(IsoError::UnknownCatalogEntry { index: index - 1, indicator }),
+            }
+            let mut section = Section
+            {
+                platform: Platform::from_id(header[1]),
+                id_string: id_string(&header[4..ENTRY_SIZE]),
+                entries: Vec::new(),
+            };
+            for _ in 0..u16_at(header, 2)
+            {
+                let mut boot_entry = BootEntry::parse(entry(index)?, false);
+                let mut more = boot_entry.media_type & EXTENSION_FOLLOWS != 0;
+                index += 1;
+                while more
+                {
+                    let extension = entry(index)?;
+                    if extension[0] != SECTION_EXTENSION
+                    {
+                        return Err(IsoError::UnknownCatalogEntry { index, indicator: extension[0] });
+                    }
+                    boot_entry.selection_criteria.extend_from_slice(&extension[2..ENTRY_SIZE]);
+                    more = extension[1] & EXTENSION_FOLLOWS != 0;
+                    index += 1;
+                }
+                section.entries.push(boot_entry);
+            }
+            sections.push(section);
+            if header[0] == FINAL_SECTION_HEADER
+            {
+                break;
+            }
+        }
+        Ok(BootCatalog { validation, initial, sections })
+    }
+
+    /// Every boot entry with the platform it is for, the initial entry
+    /// first.
+    pub fn entries(&self) -> impl Iterator<Item = (Platform, &BootEntry)>
+    {
+        std::iter::once((self.validation.platform, &self.initial))
+            .chain(self.sections.iter().flat_map(|section| section.entries.iter().map(move |entry| (section.platform, entry))))
+    }
+}
+
+
+#[cfg(test)]
+pub(super) mod tests
+{
+    use super::*;
+
+    /// A catalog for x86 with a section of an EFI entry, whose selection
+    /// criteria continue in an extension entry.
+    pub(in crate::iso) fn catalog() -> Vec<u8>
+    {
+        let mut catalog = vec![0u8; 2048];
+        catalog[0] = VALIDATION_HEADER;
+        catalog[4..12].copy_from_slice(b"TESTDISC");
+        catalog[30..32].copy_from_slice(&KEY_BYTES);
+        let words = catalog[..ENTRY_SIZE].chunks(2).fold(0u16, |sum, word| sum.wrapping_add(u16_at(word, 0)));
+        catalog[28..30].copy_from_slice(&0u16.wrapping_sub(words).to_le_bytes());
+
+        let initial = &mut catalog[32..64];
+        initial[0] = BOOTABLE;
+        initial[6..8].copy_from_slice(&4u16.to_le_bytes());
+        initial[8..12].copy_from_slice(&20u32.to_le_bytes());
+
+        let header = &mut catalog[64..96];
+        header[0] = FINAL_SECTION_HEADER;
+        header[1] = 0xef;
+        header[2..4].copy_from_slice(&1u16.to_le_bytes());
+        let section = &mut catalog[96..128];
+        section[0] = BOOTABLE;
+        section[1] = EXTENSION_FOLLOWS;
+        section[6..8].copy_from_slice(&8u16.to_le_bytes());
+        section[8..12].copy_from_slice(&21u32.to_le_bytes());
+        section[12] = 1;
+        section[13] = 0xaa;
+        let extension = &mut catalog[128..160];
+        extension[0] = SECTION_EXTENSION;
+        extension[2] = 0xbb;
+        catalog
+    }
+
+    #[test]
+    fn test_catalog_sections_and_extensions()
+    {
+        let catalog = BootCatalog::parse(&catalog()).unwrap();
+        assert_eq!(catalog.validation.platform, Platform::X86);
+        assert_eq!(catalog.validation.id_string, "TESTDISC");
+        assert_eq!((catalog.initial.load_rba, catalog.initial.sector_count), (20, 4));
+        assert!(catalog.initial.is_bootable());
+
+        let entries: Vec<(Platform, u32)> = catalog.entries().map(|(platform, entry)| (platform, entry.load_rba)).collect();
+        assert_eq!(entries, [(Platform::X86, 20), (Platform::Efi, 21)]);
+        let efi = &catalog.sections[0].entries[0];
+        assert_eq!(efi.selection_criteria_type, 1);
+        assert_eq!(efi.selection_criteria.len(), 19 + 30);
+        assert_eq!((efi.selection_criteria[0], efi.selection_criteria[19]), (0xaa, 0xbb));
+
+        let mut corrupt = self::catalog();
+        corrupt[3] = 1;
+        assert_eq!(BootCatalog::parse(&corrupt), Err(IsoError::ValidationReservedNotZero));
+        corrupt[3] = 0;
+        corrupt[31] = 0xab;
+        assert_eq!(BootCatalog::parse(&corrupt), Err(IsoError::BadKeyBytes(0x55, 0xab)));
+        corrupt[31] = 0xaa;
+        corrupt[4] ^= 1;
+        assert_eq!(BootCatalog::parse(&corrupt), Err(IsoError::BadValidationChecksum(1)));
+
+        let mut stray = self::catalog();
+        stray[64] = SECTION_HEADER;
+        stray[160] = 0x12;
+        assert_eq!(BootCatalog::parse(&stray), Err(IsoError::UnknownCatalogEntry { index: 5, indicator: 0x12 }));
+    }
+
+    #[test]
+    fn test_emulated_drives()
+    {
+        let mut iso = vec![0u8; 3000 * BLOCK_SIZE];
+        let mut entry = BootEntry::parse(&catalog()[32..64], true);
+        entry.load_rba = 10;
+        iso[10 * BLOCK_SIZE + 512] = 0xee;
+        let drive = entry.drive(&iso).unwrap();
+        assert_eq!((drive.emulation, drive.geometry, drive.data.len()), (Emulation::NoEmulation, None, 4 * 512));
+        assert_eq!(drive.read_sector(1).unwrap()[0], 0xee);
+        assert_eq!(drive.read_sector(u64::MAX / 512), None);
+
+        entry.media_type = 2;
+        let drive = entry.drive(&iso).unwrap();
+        assert_eq!(drive.data.len(), 1_474_560);
+        assert_eq!(drive.read_chs(0, 0, 2).unwrap()[0], 0xee);
+        assert_eq!(drive.read_chs(0, 0, 19), None);
+
+        // One partition from sector 63 to the end of cylinder 9 of 16 heads.
+        entry.media_type = 4;
+        assert_eq!(entry.drive(&iso), Err(IsoError::BadMasterBootRecord));
+        let mbr = &mut iso[10 * BLOCK_SIZE..];
+        mbr[510..512].copy_from_slice(&MBR_SIGNATURE);
+        mbr[446 + 4] = 0x06;
+        mbr[446 + 5..446 + 8].copy_from_slice(&[15, 63, 9]);
+        mbr[446 + 8..446 + 12].copy_from_slice(&63u32.to_le_bytes());
+        mbr[446 + 12..446 + 16].copy_from_slice(&(10 * 16 * 63 - 63u32).to_le_bytes());
+        let drive = entry.drive(&iso).unwrap();
+        assert_eq!(drive.geometry, Some(Geometry { cylinders: 10, heads: 16, sectors_per_track: 63 }));
+        assert_eq!(drive.data.len(), 10 * 16 * 63 * 512);
+
+        entry.media_type = 7;
+        assert_eq!(entry.drive(&iso), Err(IsoError::UnknownMediaType(7)));
+    }
+}
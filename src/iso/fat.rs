@@ -0,0 +1,560 @@
+//! FAT12, FAT16 and FAT32 with VFAT long filenames, as EFI system
+//! partitions and El Torito EFI boot images use them.
+
+use super::iso9660::DateTime;
+use super::{FileSystem, IsoError, Metadata};
+
+/// Every directory entry is 32 bytes.
+const ENTRY_SIZE: usize = 32;
+/// Clusters below this are free and reserved markers, not data.
+const FIRST_CLUSTER: u32 = 2;
+/// A long name of 255 characters takes 20 entries of 13.
+const MAX_LONG_NAME_ENTRIES: usize = 20;
+const LONG_NAME_PIECE: usize = 13;
+
+// Attributes
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+// The NT reserved byte keeps the case of short names.
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXTENSION: u8 = 0x10;
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+/// Stands for a first character of 0xe5, which would mean deleted.
+const ENTRY_KANJI_E5: u8 = 0x05;
+/// Set in the order byte of the last long name entry, which comes first.
+const LONG_NAME_LAST: u8 = 0x40;
+
+fn u16_at(data: &[u8], offset: usize) -> u16
+{
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32
+{
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// The width of the allocation table, which follows from the cluster count
+/// alone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FatType
+{
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType
+{
+    fn from_cluster_count(count: u32) -> Self
+    {
+        match count
+        {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        }
+    }
+
+    /// Table values from this one up end a chain.
+    fn end_of_chain(self) -> u32
+    {
+        match self
+        {
+            FatType::Fat12 => 0xff8,
+            FatType::Fat16 => 0xfff8,
+            FatType::Fat32 => 0x0fff_fff8,
+        }
+    }
+}
+
+/// The checksum of an 11-byte short name that its long name entries carry.
+pub fn short_name_checksum(name: &[u8]) -> u8
+{
+    name.iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// A date and time as directory entries record them, in local time.
+fn date_time(date: u16, time: u16) -> Option<DateTime>
+{
+    if date == 0
+    {
+        return None;
+    }
+    Some(DateTime
+    {
+        year: 1980 + (date >> 9),
+        month: (date >> 5 & 0xf) as u8,
+        day: (date & 0x1f) as u8,
+        hour: (time >> 11) as u8,
+        minute: (time >> 5 & 0x3f) as u8,
+        second: (time & 0x1f) as u8 * 2,
+        hundredths: 0,
+        gmt_offset: 0,
+    })
+}
+
+/// An 8.3 name, lowercased where the NT case flags say so.
+fn short_name(entry: &[u8]) -> String
+{
+    let mut raw = entry[..11].to_vec();
+    if raw[0] == ENTRY_KANJI_E5
+    {
+        raw[0] = ENTRY_DELETED;
+    }
+    let part = |bytes: &[u8], lower: bool|
+    {
+        let text: String = bytes.iter().map(|&byte| byte as char).collect::<String>().trim_end().to_string();
+        if lower { text.to_lowercase() } else { text }
+    };
+    let base = part(&raw[..8], entry[12] & CASE_LOWER_BASE != 0);
+    let extension = part(&raw[8..11], entry[12] & CASE_LOWER_EXTENSION != 0);
+    if extension.is_empty() { base } else { format!("{}.{}", base, extension) }
+}
+
+/// A file or directory from its directory entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FatEntry
+{
+    /// The long name, or the short one when there is none.
+    pub name: String,
+    pub short_name: String,
+    pub size: u32,
+    pub is_dir: bool,
+    pub hidden: bool,
+    pub read_only: bool,
+    pub modified: Option<DateTime>,
+    /// The first cluster; zero for empty files and the FAT12/16 root.
+    pub cluster: u32,
+}
+
+/// A FAT filesystem in an image held in memory.
+pub struct Fat<'a>
+{
+    data: &'a [u8],
+    pub fat_type: FatType,
+    pub bytes_per_sector: usize,
+    pub sectors_per_cluster: usize,
+    /// The first sector of the first table.
+    fat_start: usize,
+    /// The FAT12/16 root directory region, as (first sector, sectors).
+    root_region: (usize, usize),
+    first_data_sector: usize,
+    pub cluster_count: u32,
+    /// The first cluster of the FAT32 root directory.
+    root_cluster: u32,
+}
+
+impl<'a> Fat<'a>
+{
+    /// Reads the BIOS parameter block of the boot sector.
+    pub fn parse(data: &'a [u8]) -> Result<Self, IsoError>
+    {
+        let boot = data.get(..512).ok_or(IsoError::Truncated)?;
+        let bytes_per_sector = u16_at(boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as usize;
+        let reserved = u16_at(boot, 14) as usize;
+        let fat_count = boot[16] as usize;
+        let root_entries = u16_at(boot, 17) as usize;
+        let total = match u16_at(boot, 19)
+        {
+            0 => u32_at(boot, 32) as usize,
+            total => total as usize,
+        };
+        let fat_size = match u16_at(boot, 22)
+        {
+            0 => u32_at(boot, 36) as usize,
+            size => size as usize,
+        };
+        if boot[510..512] != [0x55, 0xaa]
+            || !bytes_per_sector.is_power_of_two() || !(512..=4096).contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two() || fat_count == 0 || fat_size == 0
+        {
+            return Err(IsoError::BadBootSector);
+        }
+        let root_sectors = (root_entries * ENTRY_SIZE).div_ceil(bytes_per_sector);
+        let root_start = reserved + fat_count * fat_size;
+        let first_data_sector = root_start + root_sectors;
+        let cluster_count = (total.checked_sub(first_data_sector).ok_or(IsoError::BadBootSector)? / sectors_per_cluster) as u32;
+        let fat_type = FatType::from_cluster_count(cluster_count);
+        if fat_type == FatType::Fat32 && root_entries != 0
+        {
+            return Err(IsoError::BadBootSector);
+        }
+        Ok(Fat
+        {
+            data,
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            fat_start: reserved,
+            root_region: (root_start, root_sectors),
+            first_data_sector,
+            cluster_count,
+            root_cluster: if fat_type == FatType::Fat32 { u32_at(boot, 44) } else { 0 },
+        })
+    }
+
+    fn sectors(&self, first: usize, count: usize) -> Result<&'a [u8], IsoError>
+    {
+        let start = first * self.bytes_per_sector;
+        self.data.get(start..start + count * self.bytes_per_sector).ok_or(IsoError::Truncated)
+    }
+
+    /// The first table's entry for a cluster.
+    fn next_cluster(&self, cluster: u32) -> Result<u32, IsoError>
+    {
+        let table = self.data.get(self.fat_start * self.bytes_per_sector..).ok_or(IsoError::Truncated)?;
+        let cluster = cluster as usize;
+        let entry = |offset: usize, size: usize| table.get(offset..offset + size).ok_or(IsoError::Truncated);
+        Ok(match self.fat_type
+        {
+            FatType::Fat12 =>
+            {
+                let pair = u16_at(entry(cluster + cluster / 2, 2)?, 0) as u32;
+                if cluster % 2 == 1 { pair >> 4 } else { pair & 0xfff }
+            }
+            FatType::Fat16 => u16_at(entry(cluster * 2, 2)?, 0) as u32,
+            FatType::Fat32 => u32_at(entry(cluster * 4, 4)?, 0) & 0x0fff_ffff,
+        })
+    }
+
+    /// The clusters of the chain starting at `first`.
+    pub fn chain(&self, first: u32) -> Result<Vec<u32>, IsoError>
+    {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 && cluster < self.fat_type.end_of_chain()
+        {
+            // A chain longer than the volume has looped.
+            if !(FIRST_CLUSTER..FIRST_CLUSTER + self.cluster_count).contains(&cluster) || clusters.len() as u32 >= self.cluster_count
+            {
+                return Err(IsoError::BadClusterChain(first));
+            }
+            clusters.push(cluster);
+            cluster = self.next_cluster(cluster)?;
+        }
+        Ok(clusters)
+    }
+
+    /// The contents of the clusters of a chain.
+    fn chain_data(&self, first: u32) -> Result<Vec<u8>, IsoError>
+    {
+        let mut contents = Vec::new();
+        for cluster in self.chain(first)?
+        {
+            let sector = self.first_data_sector + (cluster - FIRST_CLUSTER) as usize * self.sectors_per_cluster;
+            contents.extend_from_slice(self.sectors(sector, self.sectors_per_cluster)?);
+        }
+        Ok(contents)
+    }
+
+    pub fn root(&self) -> FatEntry
+    {
+        FatEntry
+        {
+            name: String::new(),
+            short_name: String::new(),
+            size: 0,
+            is_dir: true,
+            hidden: false,
+            read_only: false,
+            modified: None,
+            cluster: self.root_cluster,
+        }
+    }
+
+    /// The entries of a directory, without "." and "..".
+    pub fn read_dir(&self, dir: &FatEntry) -> Result<Vec<FatEntry>, IsoError>
+    {
+        if !dir.is_dir
+        {
+            return Err(IsoError::NotADirectory(dir.name.clone()));
+        }
+        let data = if dir.cluster == 0
+        {
+            self.sectors(self.root_region.0, self.root_region.1)?.to_vec()
+        }
+        else
+        {
+            self.chain_data(dir.cluster)?
+        };
+
+        let mut entries = Vec::new();
+        // Long name pieces gathered so far, with the checksum they carry.
+        let mut long_name: Vec<u16> = Vec::new();
+        let mut long_checksum = None;
+        for entry in data.chunks_exact(ENTRY_SIZE)
+        {
+            match entry[0]
+            {
+                ENTRY_END => break,
+                ENTRY_DELETED =>
+                {
+                    long_checksum = None;
+                    continue;
+                }
+                _ => {}
+            }
+            let attributes = entry[11];
+            if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME
+            {
+                // The pieces come last first.
+                if entry[0] & LONG_NAME_LAST != 0
+                {
+                    long_name.clear();
+                    long_checksum = Some(entry[13]);
+                }
+                else if long_checksum != Some(entry[13])
+                {
+                    long_checksum = None;
+                }
+                let piece: Vec<u16> = [1..11, 14..26, 28..32].into_iter()
+                    .flat_map(|range| entry[range].chunks(2).map(|pair| u16_at(pair, 0)).collect::<Vec<_>>())
+                    .collect();
+                long_name.splice(0..0, piece);
+                continue;
+            }
+            let checksum = long_checksum.take();
+            if attributes & ATTR_VOLUME_ID != 0 || entry[0] == b'.'
+            {
+                continue;
+            }
+            let short = short_name(entry);
+            let name = match checksum
+            {
+                Some(checksum) if checksum == short_name_checksum(&entry[..11]) && long_name.len() <= MAX_LONG_NAME_ENTRIES * LONG_NAME_PIECE =>
+                {
+                    let end = long_name.iter().position(|&unit| unit == 0).unwrap_or(long_name.len());
+                    String::from_utf16_lossy(&long_name[..end])
+                }
+                _ => short.clone(),
+            };
+            let high = if self.fat_type == FatType::Fat32 { (u16_at(entry, 20) as u32) << 16 } else { 0 };
+            entries.push(FatEntry
+            {
+                name,
+                short_name: short,
+                size: u32_at(entry, 28),
+                is_dir: attributes & ATTR_DIRECTORY != 0,
+                hidden: attributes & ATTR_HIDDEN != 0,
+                read_only: attributes & ATTR_READ_ONLY != 0,
+                modified: date_time(u16_at(entry, 24), u16_at(entry, 22)),
+                cluster: high | u16_at(entry, 26) as u32,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Finds the entry at a slash-separated path. Names match their long
+    /// or short forms, ignoring case, as FAT does.
+    pub fn lookup(&self, path: &str) -> Result<FatEntry, IsoError>
+    {
+        let mut entry = self.root();
+        let mut walked = String::new();
+        for component in path.split('/').filter(|component| !component.is_empty())
+        {
+            if !entry.is_dir
+            {
+                return Err(IsoError::NotADirectory(walked));
+            }
+            walked = format!("{}/{}", walked, component);
+            let wanted = component.to_lowercase();
+            entry = self.read_dir(&entry)?
+                .into_iter()
+                .find(|child| child.name.to_lowercase() == wanted || child.short_name.to_lowercase() == wanted)
+                .ok_or_else(|| IsoError::NotFound(walked.clone()))?;
+        }
+        Ok(entry)
+    }
+
+    /// The contents of a file.
+    pub fn read(&self, entry: &FatEntry) -> Result<Vec<u8>, IsoError>
+    {
+        if entry.is_dir
+        {
+            return Err(IsoError::IsADirectory(entry.name.clone()));
+        }
+        let mut contents = self.chain_data(entry.cluster)?;
+        if contents.len() < entry.size as usize
+        {
+            return Err(IsoError::BadClusterChain(entry.cluster));
+        }
+        contents.truncate(entry.size as usize);
+        Ok(contents)
+    }
+}
+
+impl FatEntry
+{
+    pub fn metadata(&self) -> Metadata
+    {
+        Metadata
+        {
+            size: self.size as u64,
+            is_dir: self.is_dir,
+            hidden: self.hidden,
+            modified: self.modified,
+            mode: None,
+            symlink: None,
+        }
+    }
+}
+
+impl FileSystem for Fat<'_>
+{
+    fn metadata(&self, path: &str) -> Result<Metadata, IsoError>
+    {
+        Ok(self.lookup(path)?.metadata())
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<(String, Metadata)>, IsoError>
+    {
+        Ok(self.read_dir(&self.lookup(path)?)?
+            .into_iter()
+            .map(|entry| (entry.name.clone(), entry.metadata()))
+            .collect())
+    }
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, IsoError>
+    {
+        self.read(&self.lookup(path)?)
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const SECTOR: usize = 512;
+    /// A 1.44M floppy: one reserved sector, two 9-sector tables and 14
+    /// sectors of root directory before cluster 2.
+    const FIRST_DATA: usize = 33;
+
+    fn set_fat12(image: &mut [u8], cluster: usize, value: u16)
+    {
+        let offset = SECTOR + cluster + cluster / 2;
+        let pair = u16_at(image, offset);
+        let pair = if cluster % 2 == 1 { pair & 0x000f | value << 4 } else { pair & 0xf000 | value };
+        image[offset..offset + 2].copy_from_slice(&pair.to_le_bytes());
+    }
+
+    fn short_entry(name: &[u8; 11], attributes: u8, cluster: u16, size: u32) -> [u8; 32]
+    {
+        let mut entry = [0u8; 32];
+        entry[..11].copy_from_slice(name);
+        entry[11] = attributes;
+        // 2024-03-14 12:30:10
+        entry[22..24].copy_from_slice(&(12u16 << 11 | 30 << 5 | 5).to_le_bytes());
+        entry[24..26].copy_from_slice(&(44u16 << 9 | 3 << 5 | 14).to_le_bytes());
+        entry[26..28].copy_from_slice(&cluster.to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    /// The long name entries for `name`, last piece first.
+    fn long_entries(name: &str, short: &[u8; 11]) -> Vec<u8>
+    {
+        let mut units: Vec<u16> = name.encode_utf16().collect();
+        units.push(0);
+        units.resize(units.len().next_multiple_of(13), 0xffff);
+        let pieces: Vec<&[u16]> = units.chunks(13).collect();
+        let mut entries = Vec::new();
+        for (index, piece) in pieces.iter().enumerate().rev()
+        {
+            let mut entry = [0u8; 32];
+            entry[0] = (index as u8 + 1) | if index == pieces.len() - 1 { LONG_NAME_LAST } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = short_name_checksum(short);
+            let bytes: Vec<u8> = piece.iter().flat_map(|unit| unit.to_le_bytes()).collect();
+            entry[1..11].copy_from_slice(&bytes[..10]);
+            entry[14..26].copy_from_slice(&bytes[10..22]);
+            entry[28..32].copy_from_slice(&bytes[22..26]);
+            entries.extend_from_slice(&entry);
+        }
+        entries
+    }
+
+    fn put(image: &mut [u8], sector: usize, entries: &[u8])
+    {
+        image[sector * SECTOR..sector * SECTOR + entries.len()].copy_from_slice(entries);
+    }
+
+    /// A FAT12 EFI boot floppy with /EFI/BOOT/BOOTX64.EFI fragmented over
+    /// clusters 4 and 6 and BOOTRISCV64.EFI under a long name.
+    fn image() -> Vec<u8>
+    {
+        let mut image = vec![0u8; 2880 * SECTOR];
+        image[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+        image[13] = 1;
+        image[14..16].copy_from_slice(&1u16.to_le_bytes());
+        image[16] = 2;
+        image[17..19].copy_from_slice(&224u16.to_le_bytes());
+        image[19..21].copy_from_slice(&2880u16.to_le_bytes());
+        image[21] = 0xf0;
+        image[22..24].copy_from_slice(&9u16.to_le_bytes());
+        image[510..512].copy_from_slice(&[0x55, 0xaa]);
+        for (cluster, next) in [(2, 0xfff), (3, 0xfff), (4, 6), (5, 0xfff), (6, 0xfff)]
+        {
+            set_fat12(&mut image, cluster, next);
+        }
+
+        let mut root = short_entry(b"ESP        ", ATTR_VOLUME_ID, 0, 0).to_vec();
+        root.extend(short_entry(b"EFI        ", ATTR_DIRECTORY, 2, 0));
+        put(&mut image, 19, &root);
+
+        let mut efi = short_entry(b".          ", ATTR_DIRECTORY, 2, 0).to_vec();
+        efi.extend(short_entry(b"..         ", ATTR_DIRECTORY, 0, 0));
+        efi.extend(short_entry(b"BOOT       ", ATTR_DIRECTORY, 3, 0));
+        put(&mut image, FIRST_DATA, &efi);
+
+        let mut boot = short_entry(b".          ", ATTR_DIRECTORY, 3, 0).to_vec();
+        boot.extend(short_entry(b"..         ", ATTR_DIRECTORY, 2, 0));
+        boot.extend(short_entry(b"BOOTX64 EFI", 0, 4, 600));
+        let mut deleted = short_entry(b"OLD     EFI", 0, 5, 10);
+        deleted[0] = ENTRY_DELETED;
+        boot.extend(deleted);
+        boot.extend(long_entries("BOOTRISCV64.EFI", b"BOOTRI~1EFI"));
+        boot.extend(short_entry(b"BOOTRI~1EFI", ATTR_READ_ONLY, 5, 10));
+        put(&mut image, FIRST_DATA + 1, &boot);
+
+        image[(FIRST_DATA + 2) * SECTOR..(FIRST_DATA + 3) * SECTOR].fill(0x64);
+        image[(FIRST_DATA + 4) * SECTOR..(FIRST_DATA + 5) * SECTOR].fill(0x86);
+        put(&mut image, FIRST_DATA + 3, b"riscv64!!\n");
+        image
+    }
+
+    #[test]
+    fn test_efi_boot_image()
+    {
+        let image = image();
+        let fat = Fat::parse(&image).unwrap();
+        assert_eq!(fat.fat_type, FatType::Fat12);
+        assert_eq!(fat.chain(4).unwrap(), [4, 6]);
+
+        let names: Vec<String> = fat.read_dir(&fat.lookup("/EFI/BOOT").unwrap()).unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["BOOTX64.EFI", "BOOTRISCV64.EFI"]);
+        let x64 = fat.lookup("/efi/boot/bootx64.efi").unwrap();
+        assert_eq!(x64.modified.unwrap().unix_time(), 1_710_419_410);
+        let contents = fat.read(&x64).unwrap();
+        assert_eq!((contents.len(), contents[511], contents[512]), (600, 0x64, 0x86));
+
+        let riscv = fat.lookup("/EFI/BOOT/BOOTRI~1.EFI").unwrap();
+        assert!(riscv.read_only);
+        assert_eq!(fat.read_file("/EFI/BOOT/BOOTRISCV64.EFI").unwrap(), b"riscv64!!\n");
+        assert_eq!(fat.list("/").unwrap()[0].0, "EFI");
+
+        // A chain that loops back on itself is rejected.
+        let mut looped = image.clone();
+        set_fat12(&mut looped, 6, 4);
+        assert_eq!(Fat::parse(&looped).unwrap().read(&x64), Err(IsoError::BadClusterChain(4)));
+    }
+}
@@ -0,0 +1,845 @@
+//! The ISO 9660 (ECMA-119) filesystem: the primary volume descriptor and
+//! the directory hierarchy it roots.
+
+use std::collections::HashSet;
+
+use super::joliet;
+use super::rock_ridge::{self, ExtensionReference, RockRidge, SystemUseEntry, SIGNATURE_ER};
+use super::{FileSystem, IsoError, Metadata, BLOCK_SIZE};
+
+/// The volume descriptor set starts after the 16-sector system area.
+const DESCRIPTOR_SET_START: usize = 16;
+const STANDARD_IDENTIFIER: &[u8; 5] = b"CD001";
+
+// Volume descriptor types
+pub const DESCRIPTOR_BOOT_RECORD: u8 = 0;
+pub const DESCRIPTOR_PRIMARY: u8 = 1;
+pub const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+pub const DESCRIPTOR_TERMINATOR: u8 = 255;
+
+// Directory record flags
+pub const FLAG_HIDDEN: u8 = 1 << 0;
+pub const FLAG_DIRECTORY: u8 = 1 << 1;
+pub const FLAG_ASSOCIATED: u8 = 1 << 2;
+/// Set on every record of a file but its last extent.
+pub const FLAG_MULTI_EXTENT: u8 = 1 << 7;
+
+/// The fixed part of a directory record, before the file identifier.
+const RECORD_HEADER_SIZE: usize = 33;
+const ROOT_RECORD_OFFSET: usize = 156;
+/// Continuation areas followed for one record before giving up on a loop.
+const MAX_CONTINUATIONS: usize = 64;
+
+fn u16_at(data: &[u8], offset: usize) -> u16
+{
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+/// The little-endian half of a both-endian field, or a little-endian one.
+fn u32_at(data: &[u8], offset: usize) -> u32
+{
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// A field of a- or d-characters without its space padding.
+fn text(field: &[u8]) -> String
+{
+    String::from_utf8_lossy(field).trim_end_matches(' ').to_string()
+}
+
+/// Strips the ";1" version from a file identifier, and the dot of an
+/// empty extension from a file name.
+pub fn strip_version(identifier: &str, directory: bool) -> String
+{
+    let name = identifier.split(';').next().unwrap_or_default();
+    let name = if directory { name } else { name.strip_suffix('.').unwrap_or(name) };
+    name.to_string()
+}
+
+/// The volume descriptors from sector 16 up to the set terminator.
+pub fn volume_descriptors(data: &[u8]) -> Result<Vec<&[u8]>, IsoError>
+{
+    let mut descriptors = Vec::new();
+    for sector in DESCRIPTOR_SET_START..
+    {
+        let descriptor = data.get(sector * BLOCK_SIZE..(sector + 1) * BLOCK_SIZE).ok_or(IsoError::Truncated)?;
+        if &descriptor[1..6] != STANDARD_IDENTIFIER
+        {
+            return Err(IsoError::BadStandardIdentifier);
+        }
+        if descriptor[0] == DESCRIPTOR_TERMINATOR
+        {
+            break;
+        }
+        descriptors.push(descriptor);
+    }
+    Ok(descriptors)
+}
+
+/// A recording time. The offset from GMT is in 15-minute intervals.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DateTime
+{
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub hundredths: u8,
+    pub gmt_offset: i8,
+}
+
+impl DateTime
+{
+    /// The 17-byte form in volume descriptors: sixteen ASCII digits and
+    /// the offset. None when the digits are all zero, meaning unset.
+    pub fn from_descriptor(field: &[u8]) -> Option<Self>
+    {
+        let number = |start: usize, end: usize| -> Option<u16> { std::str::from_utf8(&field[start..end]).ok()?.parse().ok() };
+        if field[..16].iter().all(|&digit| digit == b'0')
+        {
+            return None;
+        }
+        Some(DateTime
+        {
+            year: number(0, 4)?,
+            month: number(4, 6)? as u8,
+            day: number(6, 8)? as u8,
+            hour: number(8, 10)? as u8,
+            minute: number(10, 12)? as u8,
+            second: number(12, 14)? as u8,
+            hundredths: number(14, 16)? as u8,
+            gmt_offset: field[16] as i8,
+        })
+    }
+
+    /// The 7-byte form in directory records, counting years from 1900.
+    pub fn from_record(field: &[u8]) -> Self
+    {
+        DateTime
+        {
+            year: 1900 + field[0] as u16,
+            month: field[1],
+            day: field[2],
+            hour: field[3],
+            minute: field[4],
+            second: field[5],
+            hundredths: 0,
+            gmt_offset: field[6] as i8,
+        }
+    }
+
+    /// Seconds since the Unix epoch.
+    pub fn unix_time(&self) -> i64
+    {
+        // Days from the civil date, counting years from March so the leap
+        // day falls at the end.
+        let (month, day) = (self.month.clamp(1, 12) as i64, self.day.max(1) as i64);
+        let year = self.year as i64 - (month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+        let seconds = days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        seconds - self.gmt_offset as i64 * 15 * 60
+    }
+}
+
+/// A directory record as it is stored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirectoryRecord
+{
+    /// Blocks of extended attribute record before the data.
+    pub extended_attribute_length: u8,
+    pub extent: u32,
+    pub data_length: u32,
+    pub recorded: DateTime,
+    pub flags: u8,
+    pub file_unit_size: u8,
+    pub interleave_gap: u8,
+    pub identifier: Vec<u8>,
+    /// The system use area, where Rock Ridge keeps its entries.
+    pub system_use: Vec<u8>,
+}
+
+impl DirectoryRecord
+{
+    /// Parses the record at the start of `record`.
+    pub fn parse(record: &[u8]) -> Result<Self, IsoError>
+    {
+        let length = *record.first().ok_or(IsoError::MalformedDirectoryRecord)? as usize;
+        if length < RECORD_HEADER_SIZE || length > record.len()
+        {
+            return Err(IsoError::MalformedDirectoryRecord);
+        }
+        let name_end = RECORD_HEADER_SIZE + record[32] as usize;
+        if name_end > length
+        {
+            return Err(IsoError::MalformedDirectoryRecord);
+        }
+        // A padding byte keeps the system use area at an even offset.
+        let system_use_start = (name_end + name_end % 2).min(length);
+        Ok(DirectoryRecord
+        {
+            extended_attribute_length: record[1],
+            extent: u32_at(record, 2),
+            data_length: u32_at(record, 10),
+            recorded: DateTime::from_record(&record[18..25]),
+            flags: record[25],
+            file_unit_size: record[26],
+            interleave_gap: record[27],
+            identifier: record[RECORD_HEADER_SIZE..name_end].to_vec(),
+            system_use: record[system_use_start..length].to_vec(),
+        })
+    }
+
+    /// The first block of the data, past the extended attribute record.
+    pub fn data_start(&self) -> Result<u32, IsoError>
+    {
+        self.extent.checked_add(self.extended_attribute_length as u32).ok_or(IsoError::MalformedDirectoryRecord)
+    }
+
+    pub fn is_directory(&self) -> bool
+    {
+        self.flags & FLAG_DIRECTORY != 0
+    }
+
+    /// Identifier 0 names the directory itself and 1 its parent.
+    pub fn is_self_or_parent(&self) -> bool
+    {
+        self.identifier == [0] || self.identifier == [1]
+    }
+
+    /// The identifier without its ";1" version or the dot of an empty
+    /// extension.
+    pub fn name(&self) -> String
+    {
+        strip_version(&String::from_utf8_lossy(&self.identifier), self.is_directory())
+    }
+}
+
+/// The fields of the primary volume descriptor, which a Joliet
+/// supplementary volume descriptor shares.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrimaryVolumeDescriptor
+{
+    pub system_id: String,
+    pub volume_id: String,
+    /// The size of the volume in logical blocks.
+    pub volume_space_size: u32,
+    pub volume_set_size: u16,
+    pub volume_sequence_number: u16,
+    pub logical_block_size: u16,
+    pub path_table_size: u32,
+    pub root: DirectoryRecord,
+    pub volume_set_id: String,
+    pub publisher_id: String,
+    pub preparer_id: String,
+    pub application_id: String,
+    pub creation: Option<DateTime>,
+    pub modification: Option<DateTime>,
+    pub expiration: Option<DateTime>,
+    pub effective: Option<DateTime>,
+}
+
+impl PrimaryVolumeDescriptor
+{
+    pub fn parse(descriptor: &[u8]) -> Result<Self, IsoError>
+    {
+        Self::parse_with(descriptor, text)
+    }
+
+    /// Parses a Joliet supplementary volume descriptor, whose text is UCS-2.
+    pub fn parse_joliet(descriptor: &[u8]) -> Result<Self, IsoError>
+    {
+        Self::parse_with(descriptor, joliet::text)
+    }
+
+    fn parse_with(descriptor: &[u8], text: fn(&[u8]) -> String) -> Result<Self, IsoError>
+    {
+        let logical_block_size = u16_at(descriptor, 128);
+        if !logical_block_size.is_power_of_two() || !(512..=BLOCK_SIZE as u16).contains(&logical_block_size)
+        {
+            return Err(IsoError::UnsupportedBlockSize(logical_block_size));
+        }
+        Ok(PrimaryVolumeDescriptor
+        {
+            system_id: text(&descriptor[8..40]),
+            volume_id: text(&descriptor[40..72]),
+            volume_space_size: u32_at(descriptor, 80),
+            volume_set_size: u16_at(descriptor, 120),
+            volume_sequence_number: u16_at(descriptor, 124),
+            logical_block_size,
+            path_table_size: u32_at(descriptor, 132),
+            root: DirectoryRecord::parse(&descriptor[ROOT_RECORD_OFFSET..ROOT_RECORD_OFFSET + 34])?,
+            volume_set_id: text(&descriptor[190..318]),
+            publisher_id: text(&descriptor[318..446]),
+            preparer_id: text(&descriptor[446..574]),
+            application_id: text(&descriptor[574..702]),
+            creation: DateTime::from_descriptor(&descriptor[813..830]),
+            modification: DateTime::from_descriptor(&descriptor[830..847]),
+            expiration: DateTime::from_descriptor(&descriptor[847..864]),
+            effective: DateTime::from_descriptor(&descriptor[864..881]),
+        })
+    }
+}
+
+/// A file or directory, with the extents of a multi-extent file merged.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry
+{
+    pub name: String,
+    pub size: u64,
+    pub is_dir: bool,
+    pub hidden: bool,
+    pub modified: DateTime,
+    /// The data, as (first logical block, length in bytes) pieces.
+    pub extents: Vec<(u32, u32)>,
+    /// The first record, with the raw identifier and system use area.
+    pub record: DirectoryRecord,
+    /// POSIX attributes, when the volume uses Rock Ridge.
+    pub rock_ridge: Option<RockRidge>,
+}
+
+impl DirEntry
+{
+    fn from_record(record: DirectoryRecord) -> Result<Self, IsoError>
+    {
+        Ok(DirEntry
+        {
+            name: record.name(),
+            size: record.data_length as u64,
+            is_dir: record.is_directory(),
+            hidden: record.flags & FLAG_HIDDEN != 0,
+            modified: record.recorded,
+            extents: vec![(record.data_start()?, record.data_length)],
+            record,
+            rock_ridge: None,
+        })
+    }
+
+    /// Takes the name and times from Rock Ridge attributes.
+    fn with_rock_ridge(mut self, rock_ridge: RockRidge) -> Self
+    {
+        if let Some(name) = &rock_ridge.name
+        {
+            self.name = name.clone();
+        }
+        if let Some(modified) = rock_ridge.timestamps.modification
+        {
+            self.modified = modified;
+        }
+        self.rock_ridge = Some(rock_ridge);
+        self
+    }
+
+    /// The attributes the filesystems have in common.
+    pub fn metadata(&self) -> Metadata
+    {
+        Metadata
+        {
+            size: self.size,
+            is_dir: self.is_dir,
+            hidden: self.hidden,
+            modified: Some(self.modified),
+            mode: self.rock_ridge.as_ref().and_then(|rock_ridge| rock_ridge.mode),
+            symlink: self.rock_ridge.as_ref().and_then(|rock_ridge| rock_ridge.symlink.clone()),
+        }
+    }
+}
+
+/// The directory hierarchies a volume can record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hierarchy
+{
+    /// Plain ISO 9660 names, upper case and often 8.3.
+    Primary,
+    /// The UCS-2 names of a Joliet supplementary volume descriptor.
+    Joliet,
+    /// The primary hierarchy with POSIX names and attributes.
+    RockRidge,
+}
+
+/// An ISO 9660 filesystem in an image held in memory.
+pub struct Iso9660<'a>
+{
+    data: &'a [u8],
+    pub primary: PrimaryVolumeDescriptor,
+    /// The Joliet level and supplementary volume descriptor, if present.
+    pub joliet: Option<(u8, PrimaryVolumeDescriptor)>,
+    hierarchy: Hierarchy,
+    /// The extensions the root's ER entries declare.
+    pub extensions: Vec<ExtensionReference>,
+    /// Bytes before the SUSP entries of each record, if SUSP is in use.
+    susp_skip: Option<usize>,
+    /// The ES index of the Rock Ridge entries, if Rock Ridge is in use.
+    rock_ridge: Option<u8>,
+}
+
+impl<'a> Iso9660<'a>
+{
+    /// Opens the richest hierarchy present: Rock Ridge, then Joliet, then
+    /// the primary one.
+    pub fn parse(data: &'a [u8]) -> Result<Self, IsoError>
+    {
+        let descriptors = volume_descriptors(data)?;
+        let primary = descriptors.iter()
+            .find(|descriptor| descriptor[0] == DESCRIPTOR_PRIMARY)
+            .ok_or(IsoError::NoPrimaryVolumeDescriptor)?;
+        let primary = PrimaryVolumeDescriptor::parse(primary)?;
+        let joliet = descriptors.iter()
+            .filter(|descriptor| descriptor[0] == DESCRIPTOR_SUPPLEMENTARY)
+            .find_map(|descriptor| Some((joliet::level(descriptor)?, *descriptor)));
+        let joliet = match joliet
+        {
+            Some((level, descriptor)) => Some((level, PrimaryVolumeDescriptor::parse_joliet(descriptor)?)),
+            None => None,
+        };
+        let mut iso = Iso9660 { data, primary, joliet, hierarchy: Hierarchy::Primary, extensions: Vec::new(), susp_skip: None, rock_ridge: None };
+        iso.detect_rock_ridge()?;
+        iso.hierarchy = *iso.hierarchies().last().unwrap();
+        Ok(iso)
+    }
+
+    /// Opens a particular hierarchy.
+    pub fn open(data: &'a [u8], hierarchy: Hierarchy) -> Result<Self, IsoError>
+    {
+        let mut iso = Self::parse(data)?;
+        iso.select(hierarchy)?;
+        Ok(iso)
+    }
+
+    /// The hierarchies the volume records, poorest first.
+    pub fn hierarchies(&self) -> Vec<Hierarchy>
+    {
+        let mut hierarchies = vec![Hierarchy::Primary];
+        if self.joliet.is_some()
+        {
+            hierarchies.push(Hierarchy::Joliet);
+        }
+        if self.rock_ridge.is_some()
+        {
+            hierarchies.push(Hierarchy::RockRidge);
+        }
+        hierarchies
+    }
+
+    pub fn hierarchy(&self) -> Hierarchy
+    {
+        self.hierarchy
+    }
+
+    /// Switches to browsing another hierarchy.
+    pub fn select(&mut self, hierarchy: Hierarchy) -> Result<(), IsoError>
+    {
+        if !self.hierarchies().contains(&hierarchy)
+        {
+            return Err(IsoError::MissingHierarchy(hierarchy));
+        }
+        self.hierarchy = hierarchy;
+        Ok(())
+    }
+
+    /// Looks for SUSP's SP entry in the root's "." record and the ER
+    /// entries beside it. Discs from before ER existed are recognised by
+    /// the Rock Ridge entries of that record.
+    fn detect_rock_ridge(&mut self) -> Result<(), IsoError>
+    {
+        let root = DirEntry::from_record(self.primary.root.clone())?;
+        let Some(dot) = self.records(&root)?.into_iter().next()
+        else
+        {
+            return Ok(());
+        };
+        let Some(skip) = rock_ridge::sharing_protocol_skip(&dot.system_use)
+        else
+        {
+            return Ok(());
+        };
+        self.susp_skip = Some(skip);
+        let entries = self.system_use_from(&dot, 0)?;
+        self.extensions = entries.iter()
+            .filter(|entry| entry.signature == SIGNATURE_ER)
+            .filter_map(ExtensionReference::parse)
+            .collect();
+        self.rock_ridge = match self.extensions.iter().position(ExtensionReference::is_rock_ridge)
+        {
+            Some(index) => Some(index as u8),
+            None if self.extensions.is_empty() && RockRidge::from_entries(&entries, 0).is_some() => Some(0),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// The SUSP entries of a record, following CE continuation areas.
+    pub fn system_use(&self, record: &DirectoryRecord) -> Result<Vec<SystemUseEntry>, IsoError>
+    {
+        match self.susp_skip
+        {
+            Some(skip) => self.system_use_from(record, skip),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn system_use_from(&self, record: &DirectoryRecord, skip: usize) -> Result<Vec<SystemUseEntry>, IsoError>
+    {
+        let mut extension = 0;
+        let (mut entries, mut continuation) = rock_ridge::parse_entries(record.system_use.get(skip..).unwrap_or_default(), &mut extension);
+        for _ in 0..MAX_CONTINUATIONS
+        {
+            let Some(area) = continuation
+            else
+            {
+                break;
+            };
+            let start = area.block as usize * self.primary.logical_block_size as usize + area.offset as usize;
+            let bytes = self.data.get(start..start + area.length as usize).ok_or(IsoError::Truncated)?;
+            let (more, next) = rock_ridge::parse_entries(bytes, &mut extension);
+            entries.extend(more);
+            continuation = next;
+        }
+        Ok(entries)
+    }
+
+    /// The entry for a record in the current hierarchy.
+    fn entry(&self, record: DirectoryRecord) -> Result<DirEntry, IsoError>
+    {
+        match self.hierarchy
+        {
+            Hierarchy::Primary => DirEntry::from_record(record),
+            Hierarchy::Joliet =>
+            {
+                let name = joliet::name(&record);
+                Ok(DirEntry { name, ..DirEntry::from_record(record)? })
+            }
+            Hierarchy::RockRidge => self.rock_ridge_entry(record),
+        }
+    }
+
+    /// The entry for a record, with Rock Ridge attributes applied. A
+    /// relocated directory's placeholder (CL) becomes the directory.
+    fn rock_ridge_entry(&self, record: DirectoryRecord) -> Result<DirEntry, IsoError>
+    {
+        let Some(extension) = self.rock_ridge
+        else
+        {
+            return DirEntry::from_record(record);
+        };
+        let Some(attributes) = RockRidge::from_entries(&self.system_use(&record)?, extension)
+        else
+        {
+            return DirEntry::from_record(record);
+        };
+        let Some(child) = attributes.child_link
+        else
+        {
+            return Ok(DirEntry::from_record(record)?.with_rock_ridge(attributes));
+        };
+        // The relocated directory describes itself in its "." record, though
+        // the placeholder's attributes do if that has none.
+        let start = child as usize * self.primary.logical_block_size as usize;
+        let dot = DirectoryRecord::parse(self.data.get(start..).ok_or(IsoError::Truncated)?)?;
+        let name = attributes.name.clone().unwrap_or_else(|| record.name());
+        let own = RockRidge::from_entries(&self.system_use(&dot)?, extension);
+        let mut directory = DirEntry::from_record(dot)?.with_rock_ridge(own.unwrap_or(attributes));
+        directory.name = name;
+        directory.is_dir = true;
+        Ok(directory)
+    }
+
+    pub fn root(&self) -> Result<DirEntry, IsoError>
+    {
+        let descriptor = match (&self.joliet, self.hierarchy)
+        {
+            (Some((_, joliet)), Hierarchy::Joliet) => joliet,
+            _ => &self.primary,
+        };
+        let mut root = DirEntry::from_record(descriptor.root.clone())?;
+        root.name = String::new();
+        Ok(root)
+    }
+
+    /// The `length` bytes starting at logical block `block`.
+    fn extent(&self, block: u32, length: u32) -> Result<&'a [u8], IsoError>
+    {
+        let start = block as usize * self.primary.logical_block_size as usize;
+        self.data.get(start..start + length as usize).ok_or(IsoError::Truncated)
+    }
+
+    /// The records of a directory, including "." and "..".
+    pub fn records(&self, dir: &DirEntry) -> Result<Vec<DirectoryRecord>, IsoError>
+    {
+        let mut records = Vec::new();
+        for &(block, length) in &dir.extents
+        {
+            let extent = self.extent(block, length)?;
+            let mut offset = 0;
+            while offset < extent.len()
+            {
+                // Records never cross a sector; zeros pad out the rest of one.
+                if extent[offset] == 0
+                {
+                    offset = (offset / BLOCK_SIZE + 1) * BLOCK_SIZE;
+                    continue;
+                }
+                let record = DirectoryRecord::parse(&extent[offset..])?;
+                offset += extent[offset] as usize;
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+
+    /// The entries of a directory, without "." and "..".
+    pub fn read_dir(&self, dir: &DirEntry) -> Result<Vec<DirEntry>, IsoError>
+    {
+        if !dir.is_dir
+        {
+            return Err(IsoError::NotADirectory(dir.name.clone()));
+        }
+        let mut entries: Vec<DirEntry> = Vec::new();
+        let mut continues = false;
+        for record in self.records(dir)?
+        {
+            if record.is_self_or_parent()
+            {
+                continue;
+            }
+            let more = record.flags & FLAG_MULTI_EXTENT != 0;
+            match entries.last_mut()
+            {
+                Some(entry) if continues =>
+                {
+                    entry.extents.push((record.data_start()?, record.data_length));
+                    entry.size += record.data_length as u64;
+                }
+                _ => entries.push(self.entry(record)?),
+            }
+            continues = more;
+        }
+        // Relocated directories are listed where their CL placeholder is.
+        entries.retain(|entry| !entry.rock_ridge.as_ref().is_some_and(|attributes| attributes.relocated));
+        Ok(entries)
+    }
+
+    /// Finds the entry at a slash-separated path. Names match without regard
+    /// to case, as ISO 9660 names are upper case and Joliet comes from
+    /// Windows, except in the Rock Ridge hierarchy.
+    pub fn lookup(&self, path: &str) -> Result<DirEntry, IsoError>
+    {
+        let mut entry = self.root()?;
+        let mut walked = String::new();
+        for component in path.split('/').filter(|component| !component.is_empty())
+        {
+            if !entry.is_dir
+            {
+                return Err(IsoError::NotADirectory(walked));
+            }
+            walked = format!("{}/{}", walked, component);
+            entry = self.read_dir(&entry)?
+                .into_iter()
+                .find(|child| match self.hierarchy
+                {
+                    Hierarchy::RockRidge => child.name == component,
+                    _ => child.name.to_lowercase() == component.to_lowercase(),
+                })
+                .ok_or_else(|| IsoError::NotFound(walked.clone()))?;
+        }
+        Ok(entry)
+    }
+
+    /// The contents of a file.
+    pub fn read(&self, entry: &DirEntry) -> Result<Vec<u8>, IsoError>
+    {
+        if entry.is_dir
+        {
+            return Err(IsoError::IsADirectory(entry.name.clone()));
+        }
+        if entry.record.file_unit_size != 0
+        {
+            return Err(IsoError::InterleavedFile(entry.name.clone()));
+        }
+        let mut contents = Vec::with_capacity(entry.size as usize);
+        for &(block, length) in &entry.extents
+        {
+            contents.extend_from_slice(self.extent(block, length)?);
+        }
+        Ok(contents)
+    }
+
+    /// Every entry below the root with its full path, parents before their
+    /// children. A directory reached twice is only listed once.
+    pub fn walk(&self) -> Result<Vec<(String, DirEntry)>, IsoError>
+    {
+        let mut listing = Vec::new();
+        let mut visited = HashSet::new();
+        let mut pending = vec![(String::new(), self.root()?)];
+        while let Some((path, dir)) = pending.pop()
+        {
+            if !visited.insert(dir.extents[0].0)
+            {
+                continue;
+            }
+            let mut subdirectories = Vec::new();
+            for entry in self.read_dir(&dir)?
+            {
+                let child = format!("{}/{}", path, entry.name);
+                if entry.is_dir
+                {
+                    subdirectories.push((child.clone(), entry.clone()));
+                }
+                listing.push((child, entry));
+            }
+            pending.extend(subdirectories.into_iter().rev());
+        }
+        Ok(listing)
+    }
+}
+
+impl FileSystem for Iso9660<'_>
+{
+    fn metadata(&self, path: &str) -> Result<Metadata, IsoError>
+    {
+        Ok(self.lookup(path)?.metadata())
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<(String, Metadata)>, IsoError>
+    {
+        Ok(self.read_dir(&self.lookup(path)?)?
+            .into_iter()
+            .map(|entry| (entry.name.clone(), entry.metadata()))
+            .collect())
+    }
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, IsoError>
+    {
+        self.read(&self.lookup(path)?)
+    }
+}
+
+
+#[cfg(test)]
+pub(super) mod tests
+{
+    use super::*;
+
+    /// A directory record for the test images.
+    pub(in crate::iso) fn record(identifier: &[u8], extent: u32, length: u32, flags: u8, system_use: &[u8]) -> Vec<u8>
+    {
+        let name_end = RECORD_HEADER_SIZE + identifier.len();
+        let mut record = vec![0u8; name_end + name_end % 2];
+        record[2..6].copy_from_slice(&extent.to_le_bytes());
+        record[6..10].copy_from_slice(&extent.to_be_bytes());
+        record[10..14].copy_from_slice(&length.to_le_bytes());
+        record[14..18].copy_from_slice(&length.to_be_bytes());
+        record[18..25].copy_from_slice(&[124, 3, 14, 12, 30, 0, 0]);
+        record[25] = flags;
+        record[28..32].copy_from_slice(&[1, 0, 0, 1]);
+        record[32] = identifier.len() as u8;
+        record[RECORD_HEADER_SIZE..name_end].copy_from_slice(identifier);
+        record.extend_from_slice(system_use);
+        record.resize(record.len() + record.len() % 2, 0);
+        record[0] = record.len() as u8;
+        record
+    }
+
+    /// A volume descriptor of `kind` whose root directory is at `root`.
+    pub(in crate::iso) fn descriptor(kind: u8, root: u32) -> Vec<u8>
+    {
+        let mut descriptor = vec![0u8; BLOCK_SIZE];
+        descriptor[0] = kind;
+        descriptor[1..6].copy_from_slice(STANDARD_IDENTIFIER);
+        descriptor[6] = 1;
+        descriptor[8..40].fill(b' ');
+        descriptor[40..72].fill(b' ');
+        descriptor[40..47].copy_from_slice(b"TESTVOL");
+        descriptor[80..84].copy_from_slice(&32u32.to_le_bytes());
+        descriptor[128..130].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+        let root = record(&[0], root, BLOCK_SIZE as u32, FLAG_DIRECTORY, &[]);
+        descriptor[ROOT_RECORD_OFFSET..ROOT_RECORD_OFFSET + 34].copy_from_slice(&root);
+        descriptor[813..830].copy_from_slice(b"2024031412300000\x04");
+        descriptor[830..847].fill(b'0');
+        descriptor
+    }
+
+    /// Writes the records of a directory whose own extent is `block`.
+    pub(in crate::iso) fn directory(image: &mut [u8], block: u32, parent: u32, entries: &[Vec<u8>])
+    {
+        let mut records = record(&[0], block, BLOCK_SIZE as u32, FLAG_DIRECTORY, &[]);
+        records.extend(record(&[1], parent, BLOCK_SIZE as u32, FLAG_DIRECTORY, &[]));
+        for entry in entries
+        {
+            records.extend_from_slice(entry);
+        }
+        let start = block as usize * BLOCK_SIZE;
+        image[start..start + records.len()].copy_from_slice(&records);
+    }
+
+    /// An image with a descriptor set of `descriptors` followed by the
+    /// terminator, and room for 32 blocks.
+    pub(in crate::iso) fn image(descriptors: &[Vec<u8>]) -> Vec<u8>
+    {
+        let mut image = vec![0u8; 32 * BLOCK_SIZE];
+        for (index, descriptor) in descriptors.iter().enumerate()
+        {
+            let start = (DESCRIPTOR_SET_START + index) * BLOCK_SIZE;
+            image[start..start + BLOCK_SIZE].copy_from_slice(descriptor);
+        }
+        let terminator = (DESCRIPTOR_SET_START + descriptors.len()) * BLOCK_SIZE;
+        image[terminator] = DESCRIPTOR_TERMINATOR;
+        image[terminator + 1..terminator + 6].copy_from_slice(STANDARD_IDENTIFIER);
+        image
+    }
+
+    #[test]
+    fn test_directory_tree_and_files()
+    {
+        let mut image = image(&[descriptor(DESCRIPTOR_PRIMARY, 20)]);
+        directory(&mut image, 20, 20, &[
+            record(b"BOOT", 21, BLOCK_SIZE as u32, FLAG_DIRECTORY, &[]),
+            record(b"BIG.BIN;1", 23, BLOCK_SIZE as u32, FLAG_MULTI_EXTENT, &[]),
+            record(b"BIG.BIN;1", 24, 3, 0, &[]),
+            record(b"README.;1", 25, 5, FLAG_HIDDEN, &[]),
+        ]);
+        directory(&mut image, 21, 20, &[record(b"KERNEL;1", 22, 6, 0, &[])]);
+        image[22 * BLOCK_SIZE..22 * BLOCK_SIZE + 6].copy_from_slice(b"kernel");
+        image[23 * BLOCK_SIZE..24 * BLOCK_SIZE].fill(0xaa);
+        image[24 * BLOCK_SIZE..24 * BLOCK_SIZE + 3].copy_from_slice(b"end");
+
+        let iso = Iso9660::parse(&image).unwrap();
+        assert_eq!(iso.primary.volume_id, "TESTVOL");
+        assert_eq!(iso.primary.volume_space_size, 32);
+        assert_eq!(iso.primary.logical_block_size, 2048);
+        let creation = iso.primary.creation.unwrap();
+        assert_eq!((creation.year, creation.month, creation.day, creation.gmt_offset), (2024, 3, 14, 4));
+        assert_eq!(creation.unix_time(), 1710419400 - 3600);
+        assert_eq!(iso.primary.modification, None);
+
+        let paths: Vec<String> = iso.walk().unwrap().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, ["/BOOT", "/BIG.BIN", "/README", "/BOOT/KERNEL"]);
+        let kernel = iso.lookup("/boot/kernel").unwrap();
+        assert_eq!((kernel.size, kernel.is_dir), (6, false));
+        assert_eq!(kernel.modified.year, 2024);
+        assert_eq!(iso.read(&kernel).unwrap(), b"kernel");
+        let big = iso.lookup("BIG.BIN").unwrap();
+        assert_eq!(big.extents, [(23, 2048), (24, 3)]);
+        let contents = iso.read(&big).unwrap();
+        assert_eq!(contents.len(), 2051);
+        assert_eq!(&contents[2048..], b"end");
+        assert!(iso.lookup("README").unwrap().hidden);
+
+        assert_eq!(iso.lookup("/boot/missing"), Err(IsoError::NotFound("/boot/missing".to_string())));
+        assert_eq!(iso.lookup("/boot/kernel/x"), Err(IsoError::NotADirectory("/boot/kernel".to_string())));
+        assert_eq!(iso.read(&iso.root().unwrap()), Err(IsoError::IsADirectory(String::new())));
+        assert_eq!(Iso9660::parse(&image[..17 * BLOCK_SIZE]).err(), Some(IsoError::Truncated));
+
+        // An extended attribute record that would run past the last block.
+        let mut overflowing = record(b"BAD.;1", u32::MAX, 1, 0, &[]);
+        overflowing[1] = 1;
+        directory(&mut image, 21, 20, &[overflowing]);
+        let iso = Iso9660::parse(&image).unwrap();
+        assert_eq!(iso.read_dir(&iso.lookup("/boot").unwrap()), Err(IsoError::MalformedDirectoryRecord));
+    }
+}
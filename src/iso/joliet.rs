@@ -0,0 +1,80 @@
+//! Joliet: a supplementary volume descriptor whose hierarchy names files
+//! in UCS-2, for names longer and richer than ISO 9660 allows.
+
+use super::iso9660::{self, DirectoryRecord};
+
+/// The level of a supplementary volume descriptor's escape sequences,
+/// if they announce Joliet.
+pub fn level(descriptor: &[u8]) -> Option<u8>
+{
+    match &descriptor[88..91]
+    {
+        b"%/@" => Some(1),
+        b"%/C" => Some(2),
+        b"%/E" => Some(3),
+        _ => None,
+    }
+}
+
+/// Decodes big-endian UCS-2. Unpaired surrogates become U+FFFD.
+pub fn decode(bytes: &[u8]) -> String
+{
+    let units = bytes.chunks_exact(2).map(|unit| u16::from_be_bytes([unit[0], unit[1]]));
+    char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+}
+
+/// A descriptor text field without its padding.
+pub fn text(field: &[u8]) -> String
+{
+    decode(field).trim_end_matches([' ', '\0']).to_string()
+}
+
+/// The name a Joliet directory record gives.
+pub fn name(record: &DirectoryRecord) -> String
+{
+    iso9660::strip_version(&decode(&record.identifier), record.is_directory())
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::iso::iso9660::tests::{descriptor, directory, image, record};
+    use crate::iso::iso9660::{Hierarchy, Iso9660, DESCRIPTOR_PRIMARY, DESCRIPTOR_SUPPLEMENTARY};
+    use crate::iso::IsoError;
+
+    fn ucs2(text: &str) -> Vec<u8>
+    {
+        text.encode_utf16().flat_map(u16::to_be_bytes).collect()
+    }
+
+    #[test]
+    fn test_joliet_hierarchy()
+    {
+        let mut supplementary = descriptor(DESCRIPTOR_SUPPLEMENTARY, 21);
+        supplementary[88..91].copy_from_slice(b"%/E");
+        let volume_id = ucs2("Install Ünïcode");
+        supplementary[40..72].fill(0);
+        supplementary[40..40 + volume_id.len()].copy_from_slice(&volume_id);
+        let mut image = image(&[descriptor(DESCRIPTOR_PRIMARY, 20), supplementary]);
+        directory(&mut image, 20, 20, &[record(b"LONGFILE.TXT;1", 22, 4, 0, &[])]);
+        directory(&mut image, 21, 21, &[record(&ucs2("Long File Name.txt;1"), 22, 4, 0, &[])]);
+        image[22 * 2048..22 * 2048 + 4].copy_from_slice(b"data");
+
+        let iso = Iso9660::parse(&image).unwrap();
+        assert_eq!(iso.hierarchy(), Hierarchy::Joliet);
+        assert_eq!(iso.hierarchies(), [Hierarchy::Primary, Hierarchy::Joliet]);
+        let (level, joliet) = iso.joliet.as_ref().unwrap();
+        assert_eq!((*level, joliet.volume_id.as_str()), (3, "Install Ünïcode"));
+        let entry = iso.lookup("/long file name.TXT").unwrap();
+        assert_eq!(entry.name, "Long File Name.txt");
+        assert_eq!(iso.read(&entry).unwrap(), b"data");
+
+        let primary = Iso9660::open(&image, Hierarchy::Primary).unwrap();
+        let names: Vec<String> = primary.walk().unwrap().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(names, ["/LONGFILE.TXT"]);
+        assert_eq!(Iso9660::open(&image, Hierarchy::RockRidge).err(), Some(IsoError::MissingHierarchy(Hierarchy::RockRidge)));
+        assert_eq!(decode(&[0xd8, 0x00]), "\u{fffd}");
+    }
+}
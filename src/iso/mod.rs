@@ -0,0 +1,168 @@
+use std::io;
+
+pub mod el_torito;
+pub mod fat;
+pub mod iso9660;
+pub mod joliet;
+pub mod rock_ridge;
+pub mod udf;
+
+pub use el_torito::{BootCatalog, BootEntry, Emulation, Geometry, Platform, Section, ValidationEntry, VirtualDrive};
+pub use fat::{Fat, FatEntry, FatType};
+pub use iso9660::{DateTime, DirEntry, Hierarchy, Iso9660, PrimaryVolumeDescriptor};
+pub use rock_ridge::RockRidge;
+pub use udf::{Udf, UdfEntry};
+
+pub const BLOCK_SIZE: usize = 2048; // Size of a sector 
+
+/// Why a filesystem on an image could not be read.
+#[derive(Debug, PartialEq, Eq)]
+pub enum IsoError
+{
+    /// A structure runs past the end of the image.
+    Truncated,
+    /// A volume descriptor lacks the "CD001" standard identifier.
+    BadStandardIdentifier,
+    /// The descriptor set ends without a primary volume descriptor.
+    NoPrimaryVolumeDescriptor,
+    /// The volume does not record the requested directory hierarchy.
+    MissingHierarchy(Hierarchy),
+    /// The logical block size is not a power of two from 512 to 2048.
+    UnsupportedBlockSize(u16),
+    /// A directory record's lengths do not fit together.
+    MalformedDirectoryRecord,
+    /// The file is recorded in interleaved mode, which is not supported.
+    InterleavedFile(String),
+    /// Nothing exists at the path.
+    NotFound(String),
+    /// A path goes through something that is not a directory.
+    NotADirectory(String),
+    /// A directory was given where a file was expected.
+    IsADirectory(String),
+    /// No UDF anchor volume descriptor pointer at sector 256.
+    NoAnchorVolumeDescriptor,
+    /// A UDF descriptor has the wrong tag, checksum or CRC.
+    BadDescriptorTag { expected: u16, location: u32 },
+    /// The volume descriptor sequence has no logical volume or partition.
+    MissingLogicalVolume,
+    /// A partition map names a partition with no descriptor.
+    MissingPartition(u16),
+    /// An allocation refers past the logical volume's partition maps.
+    BadPartitionReference(u16),
+    /// A partition map kind that is not supported, such as virtual (VAT).
+    UnsupportedPartitionMap(String),
+    /// The boot catalog's validation entry does not start with header ID 0x01.
+    BadValidationHeader(u8),
+    /// The validation entry's reserved word is not zero.
+    ValidationReservedNotZero,
+    /// The validation entry does not end with the 0x55 0xAA key bytes.
+    BadKeyBytes(u8, u8),
+    /// The validation entry's words sum to this instead of zero.
+    BadValidationChecksum(u16),
+    /// A boot entry's media type names no emulation mode.
+    UnknownMediaType(u8),
+    /// A hard disk emulation image lacks a master boot record with a partition.
+    BadMasterBootRecord,
+    /// A FAT boot sector's BIOS parameter block does not describe a volume.
+    BadBootSector,
+    /// The FAT cluster chain starting here leaves the volume, loops or ends early.
+    BadClusterChain(u32),
+    /// A boot catalog entry is not the header or extension expected there.
+    UnknownCatalogEntry { index: usize, indicator: u8 },
+}
+
+/// The attributes of a file that every filesystem here can report.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata
+{
+    pub size: u64,
+    pub is_dir: bool,
+    pub hidden: bool,
+    pub modified: Option<DateTime>,
+    /// st_mode, when the filesystem records one.
+    pub mode: Option<u32>,
+    pub symlink: Option<String>,
+}
+
+/// Path-based access shared by the ISO 9660 and UDF readers.
+pub trait FileSystem
+{
+    /// The attributes of whatever is at a slash-separated path.
+    fn metadata(&self, path: &str) -> Result<Metadata, IsoError>;
+    /// The names and attributes of a directory's entries.
+    fn list(&self, path: &str) -> Result<Vec<(String, Metadata)>, IsoError>;
+    /// The contents of the file at a path.
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, IsoError>;
+}
+
+/// Opens the filesystem of an image, preferring the UDF side of a bridge
+/// disc over its ISO 9660 one.
+pub fn open_filesystem(data: &[u8]) -> Result<Box<dyn FileSystem + '_>, IsoError>
+{
+    match Udf::parse(data)
+    {
+        Ok(udf) => Ok(Box::new(udf)),
+        Err(_) => Ok(Box::new(Iso9660::parse(data)?)),
+    }
+}
+
+pub fn get_boot_catalog_location(data: &[u8]) -> Option<u32> 
+{
+
+    // Ensure the file is large enough to contain the volume descriptor set
+    if data.len() < BLOCK_SIZE {
+        println!("File is too small to contain a volume descriptor set.");
+        return None;
+    }
+
+    // The volume descriptor set starts after the system area (first 16 sectors)
+    let vds_offset = 16 * BLOCK_SIZE;
+
+    let vds = &data[vds_offset..];
+
+    let mut offset = 0;
+
+    while offset + BLOCK_SIZE <= vds.len() 
+    {
+        let descriptor = &vds[offset..offset + BLOCK_SIZE];
+        let descriptor_type = descriptor[0];
+        
+        if descriptor_type == 0 
+        {
+            println!("Boot Record Volume Descriptor found at offset {}", offset);
+            let identifier = &descriptor[1..6]; 
+            if identifier != b"CD001" {
+                println!("Invalid Boot Record Volume Descriptor.");
+                return None;
+            }
+            // Extract Boot Catalog location
+            let boot_catalog_location = u32::from_le_bytes([
+                descriptor[71], descriptor[72], descriptor[73], descriptor[74]
+            ]);
+            println!("Boot Record Volume Descriptor - El Torito Boot Catalog Location: {}", boot_catalog_location);
+            return Some(boot_catalog_location);
+        } 
+        
+        if descriptor_type == 255 
+        {
+            println!("Volume Descriptor Set Terminator found at offset {}", offset);
+            break; // End of descriptors
+        }
+        
+        offset += BLOCK_SIZE;
+    }
+
+    None
+}
+
+
+
+/// Copies the boot image of a catalog entry out of an ISO image, sized as
+/// the drive it emulates.
+pub fn copy_boot_image(data: &[u8], entry: &BootEntry) -> io::Result<Vec<u8>> 
+{
+    let drive = entry.drive(data)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", error)))?;
+
+    Ok(drive.data.to_vec())
+}
@@ -0,0 +1,358 @@
+//! The System Use Sharing Protocol (IEEE P1281) and the Rock Ridge
+//! Interchange Protocol (IEEE P1282) entries it carries, which give
+//! ISO 9660 files POSIX names and attributes.
+
+use super::iso9660::DateTime;
+
+/// The entry that announces SUSP, at the start of the root's "." record.
+pub const SIGNATURE_SP: [u8; 2] = *b"SP";
+/// The next part of a system use area is elsewhere.
+pub const SIGNATURE_CE: [u8; 2] = *b"CE";
+pub const SIGNATURE_ST: [u8; 2] = *b"ST";
+pub const SIGNATURE_ER: [u8; 2] = *b"ER";
+pub const SIGNATURE_ES: [u8; 2] = *b"ES";
+
+/// The check bytes of an SP entry.
+const SP_CHECK: [u8; 2] = [0xbe, 0xef];
+
+/// Extension identifiers of the Rock Ridge versions.
+const ROCK_RIDGE_IDENTIFIERS: &[&str] = &["RRIP_1991A", "IEEE_P1282", "IEEE_1282"];
+
+// NM and SL flags
+const FLAG_CONTINUE: u8 = 1 << 0;
+const FLAG_CURRENT: u8 = 1 << 1;
+const FLAG_PARENT: u8 = 1 << 2;
+const FLAG_ROOT: u8 = 1 << 3;
+
+// TF flags
+const TF_LONG_FORM: u8 = 1 << 7;
+
+/// One entry of a system use area.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SystemUseEntry
+{
+    pub signature: [u8; 2],
+    pub version: u8,
+    /// The extension the entry belongs to, as selected by the last ES
+    /// entry: an index into the root's ER entries.
+    pub extension: u8,
+    /// The entry after its four-byte header.
+    pub data: Vec<u8>,
+}
+
+/// Where a CE entry continues the system use area.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Continuation
+{
+    pub block: u32,
+    pub offset: u32,
+    pub length: u32,
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32
+{
+    data.get(offset..offset + 4).map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Splits a system use area into entries, stopping at ST or at an entry
+/// whose length does not fit. `extension` carries the ES selection from
+/// one area to its continuation. Also returns the CE entry, if any.
+pub fn parse_entries(area: &[u8], extension: &mut u8) -> (Vec<SystemUseEntry>, Option<Continuation>)
+{
+    let mut entries = Vec::new();
+    let mut continuation = None;
+    let mut offset = 0;
+    while offset + 4 <= area.len()
+    {
+        let length = area[offset + 2] as usize;
+        if length < 4 || offset + length > area.len()
+        {
+            break;
+        }
+        let entry = SystemUseEntry
+        {
+            signature: [area[offset], area[offset + 1]],
+            version: area[offset + 3],
+            extension: *extension,
+            data: area[offset + 4..offset + length].to_vec(),
+        };
+        offset += length;
+        match entry.signature
+        {
+            SIGNATURE_ST => break,
+            SIGNATURE_CE => continuation = Some(Continuation { block: u32_at(&entry.data, 0), offset: u32_at(&entry.data, 8), length: u32_at(&entry.data, 16) }),
+            SIGNATURE_ES => *extension = entry.data.first().copied().unwrap_or(0),
+            _ => {}
+        }
+        entries.push(entry);
+    }
+    (entries, continuation)
+}
+
+/// The bytes an SP entry says to skip at the start of every other system
+/// use area, if `area` starts with one.
+pub fn sharing_protocol_skip(area: &[u8]) -> Option<usize>
+{
+    let (entries, _) = parse_entries(area, &mut 0);
+    let entry = entries.first().filter(|entry| entry.signature == SIGNATURE_SP)?;
+    (entry.data.len() >= 3 && entry.data[..2] == SP_CHECK).then_some(entry.data[2] as usize)
+}
+
+/// An extension named by an ER entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtensionReference
+{
+    pub identifier: String,
+    pub descriptor: String,
+    pub source: String,
+    pub version: u8,
+}
+
+impl ExtensionReference
+{
+    pub fn parse(entry: &SystemUseEntry) -> Option<Self>
+    {
+        let data = &entry.data;
+        let (id, descriptor, source) = (*data.first()? as usize, *data.get(1)? as usize, *data.get(2)? as usize);
+        let text = |start: usize, length: usize| data.get(start..start + length).map(|bytes| String::from_utf8_lossy(bytes).into_owned());
+        Some(ExtensionReference
+        {
+            identifier: text(4, id)?,
+            descriptor: text(4 + id, descriptor)?,
+            source: text(4 + id + descriptor, source)?,
+            version: *data.get(3)?,
+        })
+    }
+
+    pub fn is_rock_ridge(&self) -> bool
+    {
+        ROCK_RIDGE_IDENTIFIERS.contains(&self.identifier.as_str())
+    }
+}
+
+/// The times a TF entry can record.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timestamps
+{
+    pub creation: Option<DateTime>,
+    pub modification: Option<DateTime>,
+    pub access: Option<DateTime>,
+    pub attributes: Option<DateTime>,
+    pub backup: Option<DateTime>,
+    pub expiration: Option<DateTime>,
+    pub effective: Option<DateTime>,
+}
+
+/// The POSIX view of a file from its Rock Ridge entries.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RockRidge
+{
+    /// st_mode, with the file type bits.
+    pub mode: Option<u32>,
+    pub links: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// st_ino, recorded by RRIP 1.12 only.
+    pub serial: Option<u32>,
+    /// The device number of a block or character device, (high, low).
+    pub device: Option<(u32, u32)>,
+    pub symlink: Option<String>,
+    pub name: Option<String>,
+    /// Where a relocated directory really is (CL).
+    pub child_link: Option<u32>,
+    /// The original parent of a relocated directory (PL).
+    pub parent_link: Option<u32>,
+    /// The directory has been moved here and is listed elsewhere (RE).
+    pub relocated: bool,
+    pub timestamps: Timestamps,
+    /// The size of a sparse file once expanded (SF).
+    pub sparse_size: Option<u64>,
+}
+
+impl RockRidge
+{
+    /// Collects the Rock Ridge entries of one file that belong to
+    /// `extension`. None if it has none.
+    pub fn from_entries(entries: &[SystemUseEntry], extension: u8) -> Option<Self>
+    {
+        let mut rock_ridge = RockRidge::default();
+        let mut found = false;
+        let mut name = String::new();
+        let mut target = String::new();
+        let mut separate = false;
+        for entry in entries.iter().filter(|entry| entry.extension == extension)
+        {
+            let data = &entry.data;
+            match &entry.signature
+            {
+                b"PX" =>
+                {
+                    rock_ridge.mode = Some(u32_at(data, 0));
+                    rock_ridge.links = Some(u32_at(data, 8));
+                    rock_ridge.uid = Some(u32_at(data, 16));
+                    rock_ridge.gid = Some(u32_at(data, 24));
+                    rock_ridge.serial = (data.len() >= 36).then(|| u32_at(data, 32));
+                }
+                b"PN" => rock_ridge.device = Some((u32_at(data, 0), u32_at(data, 8))),
+                b"NM" if !data.is_empty() =>
+                {
+                    match data[0] & (FLAG_CURRENT | FLAG_PARENT)
+                    {
+                        FLAG_CURRENT => name.push('.'),
+                        FLAG_PARENT => name.push_str(".."),
+                        _ => name.push_str(&String::from_utf8_lossy(&data[1..])),
+                    }
+                    rock_ridge.name = Some(name.clone());
+                }
+                b"SL" if !data.is_empty() =>
+                {
+                    let mut offset = 1;
+                    while offset + 2 <= data.len()
+                    {
+                        let (flags, length) = (data[offset], data[offset + 1] as usize);
+                        let content = data.get(offset + 2..offset + 2 + length).unwrap_or_default();
+                        offset += 2 + length;
+                        if flags & FLAG_ROOT != 0
+                        {
+                            target.push('/');
+                            separate = false;
+                            continue;
+                        }
+                        if separate
+                        {
+                            target.push('/');
+                        }
+                        match flags & (FLAG_CURRENT | FLAG_PARENT)
+                        {
+                            FLAG_CURRENT => target.push('.'),
+                            FLAG_PARENT => target.push_str(".."),
+                            _ => target.push_str(&String::from_utf8_lossy(content)),
+                        }
+                        separate = flags & FLAG_CONTINUE == 0;
+                    }
+                    rock_ridge.symlink = Some(target.clone());
+                }
+                b"CL" => rock_ridge.child_link = Some(u32_at(data, 0)),
+                b"PL" => rock_ridge.parent_link = Some(u32_at(data, 0)),
+                b"RE" => rock_ridge.relocated = true,
+                b"TF" if !data.is_empty() => rock_ridge.timestamps = Self::timestamps(data),
+                b"SF" => rock_ridge.sparse_size = Some((u32_at(data, 0) as u64) << 32 | u32_at(data, 8) as u64),
+                b"RR" => {}
+                _ => continue,
+            }
+            found = true;
+        }
+        found.then_some(rock_ridge)
+    }
+
+    /// The times present in a TF entry, in flag order, in the 7- or
+    /// 17-byte format.
+    fn timestamps(data: &[u8]) -> Timestamps
+    {
+        let flags = data[0];
+        let size = if flags & TF_LONG_FORM != 0 { 17 } else { 7 };
+        let mut times = data[1..].chunks_exact(size).map(|field| if size == 17 { DateTime::from_descriptor(field) } else { Some(DateTime::from_record(field)) });
+        let mut next = |bit: u8| if flags & (1 << bit) != 0 { times.next().flatten() } else { None };
+        Timestamps
+        {
+            creation: next(0),
+            modification: next(1),
+            access: next(2),
+            attributes: next(3),
+            backup: next(4),
+            expiration: next(5),
+            effective: next(6),
+        }
+    }
+
+    pub fn is_directory(&self) -> bool
+    {
+        self.mode.is_some_and(|mode| mode & 0o170000 == 0o040000)
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::iso::iso9660::tests::{descriptor, directory, image, record};
+    use crate::iso::iso9660::{Iso9660, DESCRIPTOR_PRIMARY, FLAG_DIRECTORY};
+    use crate::iso::BLOCK_SIZE;
+
+    fn entry(signature: &[u8; 2], data: &[u8]) -> Vec<u8>
+    {
+        let mut entry = vec![signature[0], signature[1], 4 + data.len() as u8, 1];
+        entry.extend_from_slice(data);
+        entry
+    }
+
+    fn both(value: u32) -> Vec<u8>
+    {
+        [value.to_le_bytes(), value.to_be_bytes()].concat()
+    }
+
+    fn posix(mode: u32) -> Vec<u8>
+    {
+        entry(b"PX", &[both(mode), both(1), both(1000), both(1000), both(7)].concat())
+    }
+
+    #[test]
+    fn test_rock_ridge_names_links_and_relocation()
+    {
+        let mut root_area = entry(b"SP", &[0xbe, 0xef, 0]);
+        root_area.extend(entry(b"CE", &[both(27), both(0), both(64)].concat()));
+        let extension = entry(b"ER", &[&[10, 4, 4, 1], &b"RRIP_1991AUNIXTEST"[..]].concat());
+        let mut image = image(&[descriptor(DESCRIPTOR_PRIMARY, 20)]);
+        image[27 * BLOCK_SIZE..27 * BLOCK_SIZE + extension.len()].copy_from_slice(&extension);
+
+        let mut link = entry(b"SL", &[FLAG_CONTINUE, FLAG_ROOT, 0, 0, 3, b'u', b's', b'r']);
+        link.extend(entry(b"SL", &[0, 0, 3, b'b', b'i', b'n']));
+        let mut long_name = entry(b"NM", &[FLAG_CONTINUE, b'l', b'o', b'n', b'g']);
+        long_name.extend(entry(b"NM", &[0, b'-', b'n', b'a', b'm', b'e', b'.', b't', b'x', b't']));
+        long_name.extend(posix(0o100644));
+        long_name.extend(entry(b"TF", &[0x02, 124, 3, 14, 12, 30, 0, 0]));
+        let mut device = entry(b"NM", &[0, b't', b't', b'y']);
+        device.extend(posix(0o020620));
+        device.extend(entry(b"PN", &[both(0), both(5)].concat()));
+        let mut moved = entry(b"NM", &[0, b'd', b'e', b'e', b'p']);
+        moved.extend(entry(b"CL", &both(22)));
+        moved.extend(posix(0o040755));
+        let mut relocated = entry(b"NM", &[0, b'd', b'e', b'e', b'p']);
+        relocated.extend(entry(b"RE", &[]));
+        relocated.extend(posix(0o040755));
+        // The root's "." record carries SP and continues to the ER entry.
+        let mut root = record(&[0], 20, BLOCK_SIZE as u32, FLAG_DIRECTORY, &root_area);
+        root.extend(record(&[1], 20, BLOCK_SIZE as u32, FLAG_DIRECTORY, &[]));
+        for entry in [
+            record(b"LONG_NAM.TXT;1", 23, 2, 0, &long_name),
+            record(b"BIN;1", 0, 0, 0, &[entry(b"NM", &[0, b'b', b'i', b'n']), link, posix(0o120777)].concat()),
+            record(b"TTY;1", 0, 0, 0, &device),
+            record(b"DEEP", 0, 0, 0, &moved),
+            record(b"RR_MOVED", 21, BLOCK_SIZE as u32, FLAG_DIRECTORY, &entry(b"NM", &[0, b'r', b'r', b'_', b'm', b'o', b'v', b'e', b'd'])),
+        ]
+        {
+            root.extend(entry);
+        }
+        image[20 * BLOCK_SIZE..20 * BLOCK_SIZE + root.len()].copy_from_slice(&root);
+        directory(&mut image, 21, 20, &[record(b"DEEP", 22, BLOCK_SIZE as u32, FLAG_DIRECTORY, &relocated)]);
+        directory(&mut image, 22, 21, &[record(b"FILE.;1", 23, 2, 0, &entry(b"NM", &[0, b'f']))]);
+
+        let iso = Iso9660::parse(&image).unwrap();
+        assert!(iso.extensions[0].is_rock_ridge());
+        assert_eq!(iso.extensions[0].source, "TEST");
+        let paths: Vec<String> = iso.walk().unwrap().into_iter().map(|(path, _)| path).collect();
+        assert_eq!(paths, ["/long-name.txt", "/bin", "/tty", "/deep", "/rr_moved", "/deep/f"]);
+
+        let file = iso.lookup("/long-name.txt").unwrap();
+        let attributes = file.rock_ridge.unwrap();
+        assert_eq!((attributes.mode, attributes.uid, attributes.serial), (Some(0o100644), Some(1000), Some(7)));
+        assert_eq!(attributes.timestamps.modification.unwrap().year, 2024);
+        assert_eq!(iso.lookup("/bin").unwrap().rock_ridge.unwrap().symlink.as_deref(), Some("/usr/bin"));
+        assert_eq!(iso.lookup("/tty").unwrap().rock_ridge.unwrap().device, Some((0, 5)));
+        let deep = iso.lookup("/deep").unwrap();
+        assert!(deep.is_dir);
+        assert!(deep.rock_ridge.unwrap().is_directory());
+        assert!(iso.lookup("/LONG-NAME.TXT").is_err());
+    }
+}
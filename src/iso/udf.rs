@@ -0,0 +1,823 @@
+//! UDF (ECMA-167 as profiled by OSTA UDF 1.02 to 2.60), the filesystem of
+//! DVDs and of the UDF side of ISO/UDF bridge discs.
+
+use super::iso9660::DateTime;
+use super::{joliet, FileSystem, IsoError, Metadata};
+
+/// The anchor volume descriptor pointer is always at sector 256.
+const ANCHOR_SECTOR: usize = 256;
+/// Sector sizes to look for the anchor with, most likely first.
+const SECTOR_SIZES: [usize; 3] = [2048, 512, 4096];
+/// Descriptor sequences and allocation extents followed before giving up
+/// on a loop.
+const MAX_CHAINED_EXTENTS: usize = 64;
+
+// Descriptor tag identifiers
+pub const TAG_PRIMARY_VOLUME: u16 = 1;
+pub const TAG_ANCHOR: u16 = 2;
+pub const TAG_POINTER: u16 = 3;
+pub const TAG_PARTITION: u16 = 5;
+pub const TAG_LOGICAL_VOLUME: u16 = 6;
+pub const TAG_TERMINATING: u16 = 8;
+pub const TAG_FILE_SET: u16 = 256;
+pub const TAG_FILE_IDENTIFIER: u16 = 257;
+pub const TAG_ALLOCATION_EXTENT: u16 = 258;
+pub const TAG_FILE_ENTRY: u16 = 261;
+pub const TAG_EXTENDED_FILE_ENTRY: u16 = 266;
+
+// ICB file types
+pub const FILE_TYPE_DIRECTORY: u8 = 4;
+pub const FILE_TYPE_REGULAR: u8 = 5;
+pub const FILE_TYPE_BLOCK_DEVICE: u8 = 6;
+pub const FILE_TYPE_CHARACTER_DEVICE: u8 = 7;
+pub const FILE_TYPE_FIFO: u8 = 9;
+pub const FILE_TYPE_SOCKET: u8 = 10;
+pub const FILE_TYPE_SYMLINK: u8 = 12;
+
+// ICB flags
+const ICB_ALLOCATION_MASK: u16 = 0b111;
+const ICB_SHORT_AD: u16 = 0;
+const ICB_LONG_AD: u16 = 1;
+const ICB_EXTENDED_AD: u16 = 2;
+const ICB_EMBEDDED: u16 = 3;
+const ICB_SETUID: u16 = 1 << 6;
+const ICB_SETGID: u16 = 1 << 7;
+const ICB_STICKY: u16 = 1 << 8;
+
+// File characteristics
+const FID_HIDDEN: u8 = 1 << 0;
+const FID_DELETED: u8 = 1 << 2;
+const FID_PARENT: u8 = 1 << 3;
+
+// Allocation descriptor extent types, in the top bits of the length
+const EXTENT_RECORDED: u32 = 0;
+const EXTENT_NEXT: u32 = 3;
+const EXTENT_LENGTH_MASK: u32 = (1 << 30) - 1;
+
+/// The fixed part of a file identifier descriptor.
+const FID_HEADER_SIZE: usize = 38;
+
+/// The `N` bytes at `offset`, or Truncated if the data ends first.
+fn bytes_at<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], IsoError>
+{
+    data.get(offset..offset.checked_add(N).ok_or(IsoError::Truncated)?)
+        .map(|bytes| bytes.try_into().unwrap())
+        .ok_or(IsoError::Truncated)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, IsoError>
+{
+    bytes_at(data, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, IsoError>
+{
+    bytes_at(data, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Result<u64, IsoError>
+{
+    bytes_at(data, offset).map(u64::from_le_bytes)
+}
+
+/// CRC-ITU-T, as descriptor tags use it.
+pub fn crc(bytes: &[u8]) -> u16
+{
+    bytes.iter().fold(0, |crc, &byte|
+    {
+        (0..8).fold(crc ^ (byte as u16) << 8, |crc, _| if crc & 0x8000 != 0 { crc << 1 ^ 0x1021 } else { crc << 1 })
+    })
+}
+
+/// The identifier of the descriptor at the start of `descriptor`, if its
+/// tag checksum and CRC are right.
+pub fn tag_identifier(descriptor: &[u8]) -> Option<u16>
+{
+    let tag = descriptor.get(..16)?;
+    let checksum = tag.iter().enumerate()
+        .filter(|&(index, _)| index != 4)
+        .fold(0u8, |sum, (_, &byte)| sum.wrapping_add(byte));
+    let crc_length = u16_at(tag, 10).ok()? as usize;
+    let covered = descriptor.get(16..16 + crc_length)?;
+    (checksum == tag[4] && crc(covered) == u16_at(tag, 8).ok()?).then_some(u16_at(tag, 0).ok()?)
+}
+
+/// An OSTA compressed Unicode string: a compression ID of 8 for 8-bit
+/// characters or 16 for UCS-2, then the characters.
+fn osta_string(bytes: &[u8]) -> String
+{
+    match bytes.first()
+    {
+        Some(8) | Some(254) => bytes[1..].iter().map(|&byte| byte as char).collect(),
+        Some(16) | Some(255) => joliet::decode(&bytes[1..]),
+        _ => String::new(),
+    }
+}
+
+/// A fixed-size dstring field, whose last byte is the length used.
+fn dstring(field: &[u8]) -> String
+{
+    let length = *field.last().unwrap_or(&0) as usize;
+    osta_string(&field[..length.min(field.len().saturating_sub(1))])
+}
+
+/// A 12-byte timestamp. None when the year is zero.
+fn timestamp(field: &[u8]) -> Option<DateTime>
+{
+    let year = u16_at(field, 2).ok()?;
+    if year == 0
+    {
+        return None;
+    }
+    // The low 12 bits are a signed offset in minutes; -2047 means none.
+    let zone = ((u16_at(field, 0).ok()? << 4) as i16) >> 4;
+    Some(DateTime
+    {
+        year,
+        month: field[4],
+        day: field[5],
+        hour: field[6],
+        minute: field[7],
+        second: field[8],
+        hundredths: field[9],
+        gmt_offset: if zone == -2047 { 0 } else { (zone / 15) as i8 },
+    })
+}
+
+/// A run of logical blocks holding part of a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent
+{
+    /// The partition reference: an index into the logical volume's maps.
+    pub partition: u16,
+    pub block: u32,
+    pub length: u32,
+    /// Allocated but unrecorded extents read as zeros.
+    pub recorded: bool,
+}
+
+/// Where a file's data is kept.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Allocation
+{
+    Extents(Vec<Extent>),
+    /// Small files live inside their ICB.
+    Embedded(Vec<u8>),
+}
+
+/// How a partition reference maps to sectors.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Partition
+{
+    /// Blocks of a partition descriptor's space, including the sparable
+    /// partitions of rewritable discs, read without remapping.
+    Physical { start: u32 },
+    /// The UDF 2.50 metadata partition: blocks of the metadata file, whose
+    /// extents lie in the physical partition starting at `start`.
+    Metadata { start: u32, extents: Vec<Extent> },
+}
+
+/// A file or directory, from its file identifier and ICB.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UdfEntry
+{
+    pub name: String,
+    pub size: u64,
+    pub file_type: u8,
+    pub hidden: bool,
+    /// st_mode, with the file type bits.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub links: u16,
+    pub modified: Option<DateTime>,
+    pub allocation: Allocation,
+}
+
+impl UdfEntry
+{
+    pub fn is_dir(&self) -> bool
+    {
+        self.file_type == FILE_TYPE_DIRECTORY
+    }
+}
+
+/// The partition descriptors, by partition number and starting sector,
+/// and the logical volume descriptor of a volume descriptor sequence.
+type VolumeDescriptors<'a> = (Vec<(u16, u32)>, Option<&'a [u8]>);
+
+/// A UDF filesystem in an image held in memory.
+pub struct Udf<'a>
+{
+    data: &'a [u8],
+    pub sector_size: usize,
+    pub logical_block_size: u32,
+    /// The logical volume identifier.
+    pub volume_id: String,
+    /// The UDF revision from the domain identifier, as BCD: 0x0250 is 2.50.
+    pub revision: u16,
+    partitions: Vec<Partition>,
+    root: UdfEntry,
+}
+
+impl<'a> Udf<'a>
+{
+    pub fn parse(data: &'a [u8]) -> Result<Self, IsoError>
+    {
+        let sector_size = SECTOR_SIZES.into_iter()
+            .find(|&size| data.get(ANCHOR_SECTOR * size..).and_then(tag_identifier) == Some(TAG_ANCHOR))
+            .ok_or(IsoError::NoAnchorVolumeDescriptor)?;
+        let anchor = &data[ANCHOR_SECTOR * sector_size..];
+        // Fall back to the reserve sequence if the main one is unusable.
+        let (partitions, logical_volume) = [16, 24].into_iter()
+            .map(|offset| Self::volume_descriptors(data, sector_size, u32_at(anchor, offset + 4)?, u32_at(anchor, offset)?))
+            .find(|sequence| sequence.as_ref().map_or(true, |(partitions, logical_volume)| !partitions.is_empty() && logical_volume.is_some()))
+            .ok_or(IsoError::MissingLogicalVolume)??;
+        let logical_volume = logical_volume.unwrap();
+
+        let mut udf = Udf
+        {
+            data,
+            sector_size,
+            logical_block_size: u32_at(logical_volume, 212)?,
+            volume_id: dstring(&logical_volume[84..212]),
+            revision: u16_at(logical_volume, 216 + 24)?,
+            partitions: Vec::new(),
+            root: UdfEntry
+            {
+                name: String::new(),
+                size: 0,
+                file_type: FILE_TYPE_DIRECTORY,
+                hidden: false,
+                mode: 0,
+                uid: 0,
+                gid: 0,
+                links: 0,
+                modified: None,
+                allocation: Allocation::Extents(Vec::new()),
+            },
+        };
+        if udf.logical_block_size as usize != sector_size
+        {
+            return Err(IsoError::UnsupportedBlockSize(udf.logical_block_size as u16));
+        }
+        udf.map_partitions(logical_volume, &partitions)?;
+
+        // The logical volume's contents use field locates the file set.
+        let file_set = udf.block(u16_at(logical_volume, 256)?, u32_at(logical_volume, 252)?)?;
+        udf.expect_tag(file_set, TAG_FILE_SET)?;
+        let mut root = udf.file_entry(u16_at(file_set, 408)?, u32_at(file_set, 404)?)?;
+        root.name = String::new();
+        udf.root = root;
+        Ok(udf)
+    }
+
+    /// Reads the volume descriptor sequence at `location`.
+    fn volume_descriptors(data: &'a [u8], sector_size: usize, mut location: u32, mut length: u32) -> Result<VolumeDescriptors<'a>, IsoError>
+    {
+        let mut partitions = Vec::new();
+        let mut logical_volume = None;
+        'sequence: for _ in 0..MAX_CHAINED_EXTENTS
+        {
+            let (start, sectors) = (location as usize, length as usize / sector_size);
+            for sector in start..start + sectors
+            {
+                let Some(descriptor) = data.get(sector * sector_size..(sector + 1) * sector_size)
+                else
+                {
+                    break 'sequence;
+                };
+                match tag_identifier(descriptor)
+                {
+                    Some(TAG_PARTITION) => partitions.push((u16_at(descriptor, 22)?, u32_at(descriptor, 188)?)),
+                    Some(TAG_LOGICAL_VOLUME) => logical_volume = Some(descriptor),
+                    Some(TAG_POINTER) =>
+                    {
+                        (length, location) = (u32_at(descriptor, 20)?, u32_at(descriptor, 24)?);
+                        continue 'sequence;
+                    }
+                    Some(TAG_TERMINATING) | None => break 'sequence,
+                    _ => {}
+                }
+            }
+            break;
+        }
+        Ok((partitions, logical_volume))
+    }
+
+    /// Resolves the logical volume's partition maps against the partition
+    /// descriptors.
+    fn map_partitions(&mut self, logical_volume: &[u8], partitions: &[(u16, u32)]) -> Result<(), IsoError>
+    {
+        let start_of = |number: u16| partitions.iter()
+            .find(|&&(partition, _)| partition == number)
+            .map(|&(_, start)| start)
+            .ok_or(IsoError::MissingPartition(number));
+        let count = u32_at(logical_volume, 268)?;
+        let mut offset = 440;
+        for _ in 0..count
+        {
+            let map = logical_volume.get(offset..).filter(|map| map.len() >= 2).ok_or(IsoError::Truncated)?;
+            let length = map[1] as usize;
+            if length < 6 || map.len() < length
+            {
+                return Err(IsoError::Truncated);
+            }
+            match map[0]
+            {
+                1 => self.partitions.push(Partition::Physical { start: start_of(u16_at(map, 4)?)? }),
+                2 if length >= 64 =>
+                {
+                    let identifier = String::from_utf8_lossy(&map[5..28]).trim_end_matches('\0').to_string();
+                    let start = start_of(u16_at(map, 38)?)?;
+                    match identifier.as_str()
+                    {
+                        "*UDF Sparable Partition" => self.partitions.push(Partition::Physical { start }),
+                        "*UDF Metadata Partition" =>
+                        {
+                            // The metadata file's ICB is in the physical partition.
+                            self.partitions.push(Partition::Physical { start });
+                            let reference = self.partitions.len() as u16 - 1;
+                            let extents = match self.file_entry(reference, u32_at(map, 40)?)?.allocation
+                            {
+                                Allocation::Extents(extents) => extents,
+                                Allocation::Embedded(_) => return Err(IsoError::UnsupportedPartitionMap(identifier)),
+                            };
+                            *self.partitions.last_mut().unwrap() = Partition::Metadata { start, extents };
+                        }
+                        _ => return Err(IsoError::UnsupportedPartitionMap(identifier)),
+                    }
+                }
+                kind => return Err(IsoError::UnsupportedPartitionMap(format!("type {}", kind))),
+            }
+            offset += length;
+        }
+        Ok(())
+    }
+
+    /// The sector holding logical block `block` of a partition.
+    fn sector(&self, partition: u16, block: u32) -> Result<u64, IsoError>
+    {
+        match self.partitions.get(partition as usize).ok_or(IsoError::BadPartitionReference(partition))?
+        {
+            Partition::Physical { start } => Ok(*start as u64 + block as u64),
+            Partition::Metadata { start, extents } =>
+            {
+                let mut offset = block as u64 * self.logical_block_size as u64;
+                for extent in extents
+                {
+                    if offset < extent.length as u64
+                    {
+                        return Ok(*start as u64 + extent.block as u64 + offset / self.logical_block_size as u64);
+                    }
+                    offset -= extent.length as u64;
+                }
+                Err(IsoError::Truncated)
+            }
+        }
+    }
+
+    /// The logical block `block` of a partition.
+    fn block(&self, partition: u16, block: u32) -> Result<&'a [u8], IsoError>
+    {
+        let start = self.sector(partition, block)? as usize * self.sector_size;
+        self.data.get(start..start + self.sector_size).ok_or(IsoError::Truncated)
+    }
+
+    fn expect_tag(&self, descriptor: &[u8], expected: u16) -> Result<(), IsoError>
+    {
+        if tag_identifier(descriptor) != Some(expected)
+        {
+            return Err(IsoError::BadDescriptorTag { expected, location: u32_at(descriptor, 12)? });
+        }
+        Ok(())
+    }
+
+    /// Reads a file entry or extended file entry, as an entry without a name.
+    fn file_entry(&self, partition: u16, block: u32) -> Result<UdfEntry, IsoError>
+    {
+        let descriptor = self.block(partition, block)?;
+        let (attributes_length, descriptors_length, modified, start) = match tag_identifier(descriptor)
+        {
+            Some(TAG_FILE_ENTRY) => (u32_at(descriptor, 168)?, u32_at(descriptor, 172)?, 84, 176),
+            Some(TAG_EXTENDED_FILE_ENTRY) => (u32_at(descriptor, 208)?, u32_at(descriptor, 212)?, 92, 216),
+            _ => return Err(IsoError::BadDescriptorTag { expected: TAG_FILE_ENTRY, location: block }),
+        };
+        let start = start + attributes_length as usize;
+        let descriptors = descriptor.get(start..start + descriptors_length as usize).ok_or(IsoError::Truncated)?;
+        let file_type = descriptor[27];
+        let flags = u16_at(descriptor, 34)?;
+        let size = u64_at(descriptor, 56)?;
+        let allocation = match flags & ICB_ALLOCATION_MASK
+        {
+            ICB_EMBEDDED => Allocation::Embedded(descriptors[..(size as usize).min(descriptors.len())].to_vec()),
+            kind => Allocation::Extents(self.allocation_descriptors(descriptors, kind, partition)?),
+        };
+
+        // Permissions keep other, group and owner in 5-bit groups whose low
+        // three bits are execute, write and read, as in st_mode.
+        let permissions = u32_at(descriptor, 44)?;
+        let class = |shift: u32| (permissions >> shift) & 0b111;
+        let kind = match file_type
+        {
+            FILE_TYPE_DIRECTORY => 0o040000,
+            FILE_TYPE_SYMLINK => 0o120000,
+            FILE_TYPE_BLOCK_DEVICE => 0o060000,
+            FILE_TYPE_CHARACTER_DEVICE => 0o020000,
+            FILE_TYPE_FIFO => 0o010000,
+            FILE_TYPE_SOCKET => 0o140000,
+            _ => 0o100000,
+        };
+        let special = [(ICB_SETUID, 0o4000), (ICB_SETGID, 0o2000), (ICB_STICKY, 0o1000)].iter()
+            .filter(|&&(flag, _)| flags & flag != 0)
+            .fold(0, |mode, &(_, bit)| mode | bit);
+        Ok(UdfEntry
+        {
+            name: String::new(),
+            size,
+            file_type,
+            hidden: false,
+            mode: kind | special | class(10) << 6 | class(5) << 3 | class(0),
+            uid: u32_at(descriptor, 36)?,
+            gid: u32_at(descriptor, 40)?,
+            links: u16_at(descriptor, 48)?,
+            modified: timestamp(&descriptor[modified..modified + 12]),
+            allocation,
+        })
+    }
+
+    /// Parses short, long or extended allocation descriptors, following
+    /// allocation extent descriptors where the list continues.
+    fn allocation_descriptors(&self, mut descriptors: &'a [u8], kind: u16, partition: u16) -> Result<Vec<Extent>, IsoError>
+    {
+        let size = match kind
+        {
+            ICB_SHORT_AD => 8,
+            ICB_LONG_AD => 16,
+            ICB_EXTENDED_AD => 20,
+            _ => return Err(IsoError::MalformedDirectoryRecord),
+        };
+        let mut extents = Vec::new();
+        for _ in 0..MAX_CHAINED_EXTENTS
+        {
+            let mut next = None;
+            for descriptor in descriptors.chunks_exact(size)
+            {
+                let length = u32_at(descriptor, 0)?;
+                let (block, reference) = match kind
+                {
+                    ICB_SHORT_AD => (u32_at(descriptor, 4)?, partition),
+                    ICB_LONG_AD => (u32_at(descriptor, 4)?, u16_at(descriptor, 8)?),
+                    _ => (u32_at(descriptor, 12)?, u16_at(descriptor, 16)?),
+                };
+                if length & EXTENT_LENGTH_MASK == 0
+                {
+                    break;
+                }
+                if length >> 30 == EXTENT_NEXT
+                {
+                    next = Some((reference, block));
+                    break;
+                }
+                extents.push(Extent { partition: reference, block, length: length & EXTENT_LENGTH_MASK, recorded: length >> 30 == EXTENT_RECORDED });
+            }
+            let Some((reference, block)) = next
+            else
+            {
+                return Ok(extents);
+            };
+            let continuation = self.block(reference, block)?;
+            self.expect_tag(continuation, TAG_ALLOCATION_EXTENT)?;
+            descriptors = continuation.get(24..24 + u32_at(continuation, 20)? as usize).ok_or(IsoError::Truncated)?;
+        }
+        Ok(extents)
+    }
+
+    pub fn root(&self) -> UdfEntry
+    {
+        self.root.clone()
+    }
+
+    /// The contents of a file or directory.
+    pub fn read(&self, entry: &UdfEntry) -> Result<Vec<u8>, IsoError>
+    {
+        let extents = match &entry.allocation
+        {
+            Allocation::Embedded(data) => return Ok(data.clone()),
+            Allocation::Extents(extents) => extents,
+        };
+        let block_size = self.logical_block_size as usize;
+        let mut contents = Vec::with_capacity(entry.size as usize);
+        for extent in extents
+        {
+            let blocks = (extent.length as usize).div_ceil(block_size);
+            for index in 0..blocks
+            {
+                let wanted = (extent.length as usize - index * block_size).min(block_size);
+                if extent.recorded
+                {
+                    contents.extend_from_slice(&self.block(extent.partition, extent.block + index as u32)?[..wanted]);
+                }
+                else
+                {
+                    contents.resize(contents.len() + wanted, 0);
+                }
+            }
+        }
+        contents.truncate(entry.size as usize);
+        Ok(contents)
+    }
+
+    /// The entries of a directory, without the parent.
+    pub fn read_dir(&self, dir: &UdfEntry) -> Result<Vec<UdfEntry>, IsoError>
+    {
+        if !dir.is_dir()
+        {
+            return Err(IsoError::NotADirectory(dir.name.clone()));
+        }
+        let data = self.read(dir)?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + FID_HEADER_SIZE <= data.len()
+        {
+            let descriptor = &data[offset..];
+            if tag_identifier(descriptor) != Some(TAG_FILE_IDENTIFIER)
+            {
+                return Err(IsoError::BadDescriptorTag { expected: TAG_FILE_IDENTIFIER, location: u32_at(descriptor, 12)? });
+            }
+            let characteristics = descriptor[18];
+            let name_length = descriptor[19] as usize;
+            let use_length = u16_at(descriptor, 36)? as usize;
+            let name_start = FID_HEADER_SIZE + use_length;
+            let name = descriptor.get(name_start..name_start + name_length).ok_or(IsoError::Truncated)?;
+            offset += (name_start + name_length).next_multiple_of(4);
+            if characteristics & (FID_DELETED | FID_PARENT) != 0
+            {
+                continue;
+            }
+            let mut entry = self.file_entry(u16_at(descriptor, 28)?, u32_at(descriptor, 24)?)?;
+            entry.name = osta_string(name);
+            entry.hidden = characteristics & FID_HIDDEN != 0;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Finds the entry at a slash-separated path.
+    pub fn lookup(&self, path: &str) -> Result<UdfEntry, IsoError>
+    {
+        let mut entry = self.root();
+        let mut walked = String::new();
+        for component in path.split('/').filter(|component| !component.is_empty())
+        {
+            if !entry.is_dir()
+            {
+                return Err(IsoError::NotADirectory(walked));
+            }
+            walked = format!("{}/{}", walked, component);
+            entry = self.read_dir(&entry)?
+                .into_iter()
+                .find(|child| child.name == component)
+                .ok_or_else(|| IsoError::NotFound(walked.clone()))?;
+        }
+        Ok(entry)
+    }
+
+    /// Where a symbolic link points, from its path components.
+    pub fn symlink(&self, entry: &UdfEntry) -> Result<Option<String>, IsoError>
+    {
+        if entry.file_type != FILE_TYPE_SYMLINK
+        {
+            return Ok(None);
+        }
+        let data = self.read(entry)?;
+        let mut target = String::new();
+        let mut offset = 0;
+        while offset + 4 <= data.len()
+        {
+            let (kind, length) = (data[offset], data[offset + 1] as usize);
+            let identifier = data.get(offset + 4..offset + 4 + length).ok_or(IsoError::Truncated)?;
+            offset += 4 + length;
+            let component = match kind
+            {
+                1 | 2 =>
+                {
+                    target = String::from("/");
+                    continue;
+                }
+                3 => String::from(".."),
+                4 => String::from("."),
+                _ => osta_string(identifier),
+            };
+            if !target.is_empty() && !target.ends_with('/')
+            {
+                target.push('/');
+            }
+            target.push_str(&component);
+        }
+        Ok(Some(target))
+    }
+
+    fn metadata_of(&self, entry: &UdfEntry) -> Result<Metadata, IsoError>
+    {
+        Ok(Metadata
+        {
+            size: entry.size,
+            is_dir: entry.is_dir(),
+            hidden: entry.hidden,
+            modified: entry.modified,
+            mode: Some(entry.mode),
+            symlink: self.symlink(entry)?,
+        })
+    }
+}
+
+impl FileSystem for Udf<'_>
+{
+    fn metadata(&self, path: &str) -> Result<Metadata, IsoError>
+    {
+        self.metadata_of(&self.lookup(path)?)
+    }
+
+    fn list(&self, path: &str) -> Result<Vec<(String, Metadata)>, IsoError>
+    {
+        self.read_dir(&self.lookup(path)?)?
+            .iter()
+            .map(|entry| Ok((entry.name.clone(), self.metadata_of(entry)?)))
+            .collect()
+    }
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>, IsoError>
+    {
+        let entry = self.lookup(path)?;
+        if entry.is_dir()
+        {
+            return Err(IsoError::IsADirectory(path.to_string()));
+        }
+        self.read(&entry)
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::iso::open_filesystem;
+
+    const SECTOR: usize = 2048;
+    /// Where the partition starts, and the physical blocks of the
+    /// metadata file within it.
+    const PARTITION_START: usize = 300;
+    const METADATA_BLOCK: usize = 10;
+
+    /// Fills in the tag of the `length`-byte descriptor at `sector`.
+    fn tag(image: &mut [u8], sector: usize, identifier: u16, location: u32, length: usize)
+    {
+        let descriptor = &mut image[sector * SECTOR..sector * SECTOR + length];
+        descriptor[0..2].copy_from_slice(&identifier.to_le_bytes());
+        descriptor[2..4].copy_from_slice(&3u16.to_le_bytes());
+        descriptor[10..12].copy_from_slice(&(length as u16 - 16).to_le_bytes());
+        descriptor[12..16].copy_from_slice(&location.to_le_bytes());
+        let crc = crc(&descriptor[16..]);
+        descriptor[8..10].copy_from_slice(&crc.to_le_bytes());
+        descriptor[4] = descriptor[..16].iter().enumerate().filter(|&(index, _)| index != 4).fold(0u8, |sum, (_, &byte)| sum.wrapping_add(byte));
+    }
+
+    /// A file entry at `sector` with the given type, flags and allocation
+    /// descriptors, or embedded data.
+    fn file_entry(image: &mut [u8], sector: usize, file_type: u8, flags: u16, size: u64, descriptors: &[u8])
+    {
+        let entry = &mut image[sector * SECTOR..];
+        entry[27] = file_type;
+        entry[34..36].copy_from_slice(&flags.to_le_bytes());
+        entry[44..48].copy_from_slice(&(0b111u32 << 10 | 0b101 << 5 | 0b001).to_le_bytes());
+        entry[48..50].copy_from_slice(&1u16.to_le_bytes());
+        entry[56..64].copy_from_slice(&size.to_le_bytes());
+        entry[84..96].copy_from_slice(&[0x3c, 0x10, 0xe8, 0x07, 3, 14, 12, 30, 0, 0, 0, 0]);
+        entry[172..176].copy_from_slice(&(descriptors.len() as u32).to_le_bytes());
+        entry[176..176 + descriptors.len()].copy_from_slice(descriptors);
+        tag(image, sector, TAG_FILE_ENTRY, 0, 176 + descriptors.len());
+    }
+
+    fn identifier(name: &str, characteristics: u8, block: u32) -> Vec<u8>
+    {
+        let mut name_bytes = vec![];
+        if !name.is_empty()
+        {
+            name_bytes.push(8);
+            name_bytes.extend_from_slice(name.as_bytes());
+        }
+        let mut fid = vec![0u8; FID_HEADER_SIZE];
+        fid[0..2].copy_from_slice(&TAG_FILE_IDENTIFIER.to_le_bytes());
+        fid[18] = characteristics;
+        fid[19] = name_bytes.len() as u8;
+        fid[20..24].copy_from_slice(&(SECTOR as u32).to_le_bytes());
+        fid[24..28].copy_from_slice(&block.to_le_bytes());
+        // The ICB is in the metadata partition, reference 1.
+        fid[28..30].copy_from_slice(&1u16.to_le_bytes());
+        fid.extend(name_bytes);
+        fid.resize(fid.len().next_multiple_of(4), 0);
+        let crc = crc(&fid[16..]);
+        fid[8..10].copy_from_slice(&crc.to_le_bytes());
+        let crc_length = fid.len() as u16 - 16;
+        fid[10..12].copy_from_slice(&crc_length.to_le_bytes());
+        fid[4] = fid[..16].iter().enumerate().filter(|&(index, _)| index != 4).fold(0u8, |sum, (_, &byte)| sum.wrapping_add(byte));
+        fid
+    }
+
+    /// A UDF 2.50 image with a metadata partition holding the file set
+    /// and a root directory of a file, a symlink and a deleted name.
+    fn image() -> Vec<u8>
+    {
+        let mut image = vec![0u8; 400 * SECTOR];
+        let anchor = ANCHOR_SECTOR * SECTOR;
+        image[anchor + 16..anchor + 20].copy_from_slice(&(3 * SECTOR as u32).to_le_bytes());
+        image[anchor + 20..anchor + 24].copy_from_slice(&32u32.to_le_bytes());
+        tag(&mut image, ANCHOR_SECTOR, TAG_ANCHOR, ANCHOR_SECTOR as u32, 512);
+
+        let partition = 32 * SECTOR;
+        image[partition + 188..partition + 192].copy_from_slice(&(PARTITION_START as u32).to_le_bytes());
+        tag(&mut image, 32, TAG_PARTITION, 32, 512);
+
+        let volume = &mut image[33 * SECTOR..34 * SECTOR];
+        let name = b"\x08UDFTEST";
+        volume[84..84 + name.len()].copy_from_slice(name);
+        volume[211] = name.len() as u8;
+        volume[212..216].copy_from_slice(&(SECTOR as u32).to_le_bytes());
+        volume[240..242].copy_from_slice(&0x0250u16.to_le_bytes());
+        // The file set is block 0 of the metadata partition, reference 1.
+        volume[248..252].copy_from_slice(&(SECTOR as u32).to_le_bytes());
+        volume[256..258].copy_from_slice(&1u16.to_le_bytes());
+        volume[264..268].copy_from_slice(&70u32.to_le_bytes());
+        volume[268..272].copy_from_slice(&2u32.to_le_bytes());
+        volume[440..446].copy_from_slice(&[1, 6, 1, 0, 0, 0]);
+        volume[446] = 2;
+        volume[447] = 64;
+        volume[451..474].copy_from_slice(b"*UDF Metadata Partition");
+        volume[486..490].copy_from_slice(&1u32.to_le_bytes());
+        tag(&mut image, 33, TAG_LOGICAL_VOLUME, 33, 512);
+        tag(&mut image, 34, TAG_TERMINATING, 34, 512);
+
+        // The metadata file maps metadata blocks 0-3 to physical blocks 10-13.
+        let mut metadata = (4 * SECTOR as u32).to_le_bytes().to_vec();
+        metadata.extend((METADATA_BLOCK as u32).to_le_bytes());
+        file_entry(&mut image, PARTITION_START + 1, 250, ICB_SHORT_AD, 4 * SECTOR as u64, &metadata);
+
+        let metadata_sector = PARTITION_START + METADATA_BLOCK;
+        let file_set = metadata_sector * SECTOR;
+        image[file_set + 400..file_set + 404].copy_from_slice(&(SECTOR as u32).to_le_bytes());
+        image[file_set + 404..file_set + 408].copy_from_slice(&1u32.to_le_bytes());
+        image[file_set + 408..file_set + 410].copy_from_slice(&1u16.to_le_bytes());
+        tag(&mut image, metadata_sector, TAG_FILE_SET, 0, 512);
+
+        let mut directory = identifier("", FID_PARENT | 2, 1);
+        directory.extend(identifier("hello.txt", 0, 2));
+        directory.extend(identifier("gone", FID_DELETED, 2));
+        directory.extend(identifier("link", 0, 3));
+        file_entry(&mut image, metadata_sector + 1, FILE_TYPE_DIRECTORY, ICB_EMBEDDED, directory.len() as u64, &directory);
+
+        // The file's data is in the physical partition, reference 0, with
+        // an unrecorded extent after it.
+        let mut long = vec![0u8; 32];
+        long[0..4].copy_from_slice(&5u32.to_le_bytes());
+        long[4..8].copy_from_slice(&50u32.to_le_bytes());
+        long[16..20].copy_from_slice(&(1 << 30 | 3u32).to_le_bytes());
+        file_entry(&mut image, metadata_sector + 2, FILE_TYPE_REGULAR, ICB_LONG_AD | ICB_SETUID, 8, &long);
+        image[(PARTITION_START + 50) * SECTOR..(PARTITION_START + 50) * SECTOR + 5].copy_from_slice(b"hello");
+
+        let link = [2, 0, 0, 0, 5, 4, 0, 0, 8, b'u', b's', b'r'];
+        file_entry(&mut image, metadata_sector + 3, FILE_TYPE_SYMLINK, ICB_EMBEDDED, link.len() as u64, &link);
+        image
+    }
+
+    #[test]
+    fn test_metadata_partition_and_files()
+    {
+        assert_eq!(crc(&[0x70, 0x6a, 0x77]), 0x3299);
+        let image = image();
+        let udf = Udf::parse(&image).unwrap();
+        assert_eq!((udf.volume_id.as_str(), udf.revision, udf.sector_size), ("UDFTEST", 0x0250, 2048));
+        let names: Vec<String> = udf.read_dir(&udf.root()).unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, ["hello.txt", "link"]);
+
+        let hello = udf.lookup("/hello.txt").unwrap();
+        assert_eq!(hello.mode, 0o104751);
+        assert_eq!(hello.modified.unwrap().gmt_offset, 4);
+        assert_eq!(udf.read(&hello).unwrap(), b"hello\0\0\0");
+        assert_eq!(udf.lookup("/hello.txt/x"), Err(IsoError::NotADirectory("/hello.txt".to_string())));
+
+        // The common interface picks UDF when it is present.
+        let filesystem = open_filesystem(&image).unwrap();
+        assert_eq!(filesystem.metadata("/link").unwrap().symlink.as_deref(), Some("/usr"));
+        assert_eq!(filesystem.read_file("hello.txt").unwrap().len(), 8);
+        assert_eq!(filesystem.list("/").unwrap().len(), 2);
+        assert_eq!(Udf::parse(&image[..100 * SECTOR]).err(), Some(IsoError::NoAnchorVolumeDescriptor));
+
+        // An anchor whose tag is valid but whose extents are cut off.
+        let mut truncated = image[..(ANCHOR_SECTOR + 1) * SECTOR].to_vec();
+        tag(&mut truncated, ANCHOR_SECTOR, TAG_ANCHOR, ANCHOR_SECTOR as u32, 16);
+        truncated.truncate(ANCHOR_SECTOR * SECTOR + 16);
+        assert_eq!(Udf::parse(&truncated).err(), Some(IsoError::Truncated));
+    }
+}
@@ -0,0 +1,15 @@
+pub mod bitmanip;
+pub mod boot;
+pub mod crypto;
+pub mod csr;
+pub mod devices;
+pub mod entropy;
+pub mod fdt;
+pub mod isa;
+pub mod iso;
+pub mod machine;
+pub mod ram;
+pub mod sbi;
+pub mod tlb;
+pub mod trap;
+pub mod v_cpu;
\ No newline at end of file
@@ -0,0 +1,1077 @@
+use std::thread;
+use std::time::Duration;
+
+use crate::boot::{self, BootError, FwDynamicInfo, ImageHeader, NEXT_MODE_S};
+use crate::csr::{MEDELEG, MIDELEG, MIP_MEIP, MIP_MSIP, MIP_MTIP, MIP_SEIP, MIP_SSIP, MIP_STIP, MSTATUS_SIE, SATP, SSTATUS, TIME};
+use crate::devices::chardev::CharBackend;
+use crate::devices::clint::{Clint, Clock, CLINT_MMIO_SIZE};
+use crate::devices::htif::Htif;
+use crate::devices::plic::Plic;
+use crate::devices::rom::Rom;
+use crate::devices::sifive_uart::SifiveUart;
+use crate::devices::uart::Uart16550;
+use crate::devices::virtio::mmio::VirtioMmio;
+use crate::devices::virtio::VirtioDevice;
+use crate::devices::{IrqLine, MmioDevice};
+use crate::fdt::FdtBuilder;
+use crate::isa::Isa;
+use crate::sbi::{HartStatus, Sbi, SbiRequest, SystemReset};
+use crate::tlb::PAGE_SIZE;
+use crate::v_cpu::{HartContext, Privilege, VirtualCPU, VmExit};
+
+/// Guest physical addresses of the board's devices and RAM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryMap
+{
+    /// The reset vector ROM, where every hart starts.
+    pub rom_base: u64,
+    pub rom_size: u64,
+    pub clint_base: u64,
+    pub clint_size: u64,
+    /// None on boards without a PLIC, which then have no device interrupts.
+    pub plic_base: Option<u64>,
+    pub plic_size: u64,
+    /// The console device, whichever kind of UART the board has.
+    pub uart_base: u64,
+    pub uart_size: u64,
+    pub uart_irq: u32,
+    /// The first virtio-mmio slot; the rest follow every `virtio_stride` bytes.
+    pub virtio_base: u64,
+    pub virtio_stride: u64,
+    /// Interrupt source of the first slot; slot N uses `virtio_irq_base + N`.
+    pub virtio_irq_base: u32,
+    pub ram_base: u64,
+}
+
+impl MemoryMap
+{
+    /// The layout of QEMU's virt board, which stock kernels and firmware
+    /// already know how to drive.
+    pub const VIRT: MemoryMap = MemoryMap
+    {
+        rom_base: 0x1000,
+        rom_size: 0xf000,
+        clint_base: 0x0200_0000,
+        clint_size: 0x1_0000,
+        plic_base: Some(0x0c00_0000),
+        plic_size: 0x60_0000,
+        uart_base: 0x1000_0000,
+        uart_size: 0x100,
+        uart_irq: 10,
+        virtio_base: 0x1000_1000,
+        virtio_stride: 0x1000,
+        virtio_irq_base: 1,
+        ram_base: 0x8000_0000,
+    };
+
+    /// Spike's layout: the CLINT and an HTIF console, with no PLIC or
+    /// virtio. Spike finds HTIF through the ELF's tohost symbol; here it
+    /// sits at a fixed address that the DTB gives.
+    pub const SPIKE: MemoryMap = MemoryMap
+    {
+        rom_base: 0x1000,
+        rom_size: 0xf000,
+        clint_base: 0x0200_0000,
+        clint_size: 0x1_0000,
+        plic_base: None,
+        plic_size: 0,
+        uart_base: 0x0100_0000,
+        uart_size: 0x1000,
+        uart_irq: 0,
+        virtio_base: 0,
+        virtio_stride: 0,
+        virtio_irq_base: 0,
+        ram_base: 0x8000_0000,
+    };
+
+    /// The parts of the FU540's layout that QEMU's sifive_u models:
+    /// CLINT, PLIC and UART0.
+    pub const SIFIVE_U: MemoryMap = MemoryMap
+    {
+        rom_base: 0x1000,
+        rom_size: 0xf000,
+        clint_base: 0x0200_0000,
+        clint_size: 0x1_0000,
+        plic_base: Some(0x0c00_0000),
+        plic_size: 0x400_0000,
+        uart_base: 0x1001_0000,
+        uart_size: 0x1000,
+        uart_irq: 4,
+        virtio_base: 0,
+        virtio_stride: 0,
+        virtio_irq_base: 0,
+        ram_base: 0x8000_0000,
+    };
+
+    pub fn virtio_slot_base(&self, slot: usize) -> u64
+    {
+        self.virtio_base + slot as u64 * self.virtio_stride
+    }
+}
+
+/// The board a MachineConfig models, named as on QEMU's -machine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MachineProfile
+{
+    Virt,
+    Spike,
+    SifiveU,
+}
+
+impl MachineProfile
+{
+    pub fn from_name(name: &str) -> Option<Self>
+    {
+        match name
+        {
+            "virt" => Some(MachineProfile::Virt),
+            "spike" => Some(MachineProfile::Spike),
+            "sifive_u" => Some(MachineProfile::SifiveU),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            MachineProfile::Virt => "virt",
+            MachineProfile::Spike => "spike",
+            MachineProfile::SifiveU => "sifive_u",
+        }
+    }
+
+    /// The root node's compatible and model, the same as QEMU's so that
+    /// firmware picks the matching platform code.
+    fn compatible(&self) -> (&'static [&'static str], &'static str)
+    {
+        match self
+        {
+            MachineProfile::Virt => (&["riscv-virtio"], "riscv-virtio,qemu"),
+            MachineProfile::Spike => (&["ucb,spike-bare-dev"], "ucb,spike-bare,qemu"),
+            MachineProfile::SifiveU => (&["sifive,hifive-unleashed-a00"], "SiFive HiFive Unleashed A00"),
+        }
+    }
+}
+
+/// The console device at MemoryMap::uart_base.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UartKind
+{
+    Ns16550a,
+    SiFive,
+    /// Spike's host-target interface, which also powers the machine off.
+    Htif,
+}
+
+/// Number of PLIC interrupt sources, including the reserved source 0.
+pub const PLIC_NUM_SOURCES: u32 = 32;
+
+// Interrupt cause numbers used in interrupts-extended
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
+
+const PLIC_PHANDLE: u32 = 1;
+/// The fixed clock feeding the SiFive UART.
+const UART_CLOCK_PHANDLE: u32 = 2;
+const SIFIVE_UART_CLOCK: u32 = 33_333_333;
+
+/// Hart N's interrupt controller, which the CLINT and PLIC point at.
+fn cpu_intc_phandle(hart: usize) -> u32
+{
+    3 + hart as u32
+}
+
+/// Alignment of the DTB when it is placed at the top of RAM.
+const FDT_ALIGN: u64 = 0x1000;
+
+/// Exceptions a directly booted kernel handles itself: everything except
+/// ecalls from S-mode and above, which go to the SBI.
+const KERNEL_MEDELEG: u64 = 0xb1ff;
+/// Supervisor software, timer and external interrupts.
+const KERNEL_MIDELEG: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+/// Where payloads without a Linux `Image` header, such as U-Boot, are
+/// loaded, relative to the start of RAM. This is also OpenSBI fw_jump's
+/// default jump address.
+const RAW_PAYLOAD_OFFSET: u64 = 0x20_0000;
+
+/// Instructions a hart runs before the devices are polled and the next
+/// hart gets a turn.
+const TIME_SLICE: u64 = 1024;
+/// Longest host sleep while every hart waits for an interrupt, so host
+/// input is still picked up promptly.
+const MAX_IDLE_SLEEP: Duration = Duration::from_millis(1);
+
+/// Everything the device tree describes about the virtual machine.
+#[derive(Clone, Debug)]
+pub struct MachineConfig
+{
+    pub profile: MachineProfile,
+    pub memory_map: MemoryMap,
+    pub uart: UartKind,
+    pub harts: usize,
+    pub isa: Isa,
+    /// Width of a vector register in bits, for the V extension.
+    pub vlen: usize,
+    pub ram_size: u64,
+    pub virtio_slots: usize,
+    /// Frequency of the `time` CSR and the CLINT's mtime, in Hz.
+    pub timebase_frequency: u32,
+    pub bootargs: String,
+    /// Start and end (exclusive) guest addresses of the initrd.
+    pub initrd: Option<(u64, u64)>,
+}
+
+impl Default for MachineConfig
+{
+    fn default() -> Self
+    {
+        MachineConfig::for_profile(MachineProfile::Virt)
+    }
+}
+
+impl MachineConfig
+{
+    /// The board's devices and hart count, with 128 MiB of RAM and every
+    /// extension the interpreter implements.
+    ///
+    /// sifive_u has two harts like QEMU's default, but both are described
+    /// as U54 application cores.
+    pub fn for_profile(profile: MachineProfile) -> Self
+    {
+        let (memory_map, uart, harts, virtio_slots) = match profile
+        {
+            MachineProfile::Virt => (MemoryMap::VIRT, UartKind::Ns16550a, 1, 8),
+            MachineProfile::Spike => (MemoryMap::SPIKE, UartKind::Htif, 1, 0),
+            MachineProfile::SifiveU => (MemoryMap::SIFIVE_U, UartKind::SiFive, 2, 0),
+        };
+        MachineConfig
+        {
+            profile,
+            memory_map,
+            uart,
+            harts,
+            isa: Isa::default(),
+            vlen: 128,
+            ram_size: 128 * 1024 * 1024,
+            virtio_slots,
+            timebase_frequency: 10_000_000,
+            bootargs: String::new(),
+            initrd: None,
+        }
+    }
+
+    /// Builds the DTB for this configuration.
+    pub fn device_tree(&self) -> Vec<u8>
+    {
+        let map = &self.memory_map;
+        let mut fdt = FdtBuilder::new();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        let (compatible, model) = self.profile.compatible();
+        fdt.property_strings("compatible", compatible);
+        fdt.property_string("model", model);
+
+        fdt.begin_node("chosen");
+        if !self.bootargs.is_empty()
+        {
+            fdt.property_string("bootargs", &self.bootargs);
+        }
+        fdt.property_string("stdout-path", &self.console_path());
+        if let Some((start, end)) = self.initrd
+        {
+            fdt.property_u64("linux,initrd-start", start);
+            fdt.property_u64("linux,initrd-end", end);
+        }
+        fdt.end_node();
+
+        fdt.begin_node(&format!("memory@{:x}", map.ram_base));
+        fdt.property_string("device_type", "memory");
+        fdt.property_u64s("reg", &[map.ram_base, self.ram_size]);
+        fdt.end_node();
+
+        self.write_cpus(&mut fdt);
+
+        match self.uart
+        {
+            UartKind::Htif =>
+            {
+                // fromhost first, then tohost, as OpenSBI reads them.
+                fdt.begin_node("htif");
+                fdt.property_string("compatible", "ucb,htif0");
+                fdt.property_u64s("reg", &[map.uart_base + 8, 8, map.uart_base, 8]);
+                fdt.end_node();
+            }
+            UartKind::SiFive =>
+            {
+                fdt.begin_node("hfclk");
+                fdt.property_string("compatible", "fixed-clock");
+                fdt.property_u32("#clock-cells", 0);
+                fdt.property_u32("clock-frequency", SIFIVE_UART_CLOCK);
+                fdt.property_u32("phandle", UART_CLOCK_PHANDLE);
+                fdt.end_node();
+            }
+            UartKind::Ns16550a => {}
+        }
+
+        fdt.begin_node("soc");
+        fdt.property_u32("#address-cells", 2);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_string("compatible", "simple-bus");
+        fdt.property_null("ranges");
+        self.write_interrupt_controllers(&mut fdt);
+
+        if self.uart != UartKind::Htif
+        {
+            fdt.begin_node(&format!("serial@{:x}", map.uart_base));
+            if self.uart == UartKind::SiFive
+            {
+                fdt.property_strings("compatible", &["sifive,fu540-c000-uart", "sifive,uart0"]);
+                fdt.property_u32("clocks", UART_CLOCK_PHANDLE);
+            }
+            else
+            {
+                fdt.property_string("compatible", "ns16550a");
+                fdt.property_u32("clock-frequency", 3_686_400);
+            }
+            fdt.property_u64s("reg", &[map.uart_base, map.uart_size]);
+            fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+            fdt.property_u32("interrupts", map.uart_irq);
+            fdt.end_node();
+        }
+
+        for slot in 0..self.virtio_slots
+        {
+            let base = map.virtio_slot_base(slot);
+            fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+            fdt.property_string("compatible", "virtio,mmio");
+            fdt.property_u64s("reg", &[base, map.virtio_stride]);
+            fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+            fdt.property_u32("interrupts", map.virtio_irq_base + slot as u32);
+            fdt.end_node();
+        }
+        fdt.end_node();
+
+        fdt.end_node();
+        fdt.finish(0)
+    }
+
+    fn console_path(&self) -> String
+    {
+        match self.uart
+        {
+            UartKind::Htif => "/htif".to_string(),
+            _ => format!("/soc/serial@{:x}", self.memory_map.uart_base),
+        }
+    }
+
+    fn write_cpus(&self, fdt: &mut FdtBuilder)
+    {
+        fdt.begin_node("cpus");
+        fdt.property_u32("#address-cells", 1);
+        fdt.property_u32("#size-cells", 0);
+        fdt.property_u32("timebase-frequency", self.timebase_frequency);
+        for hart in 0..self.harts
+        {
+            fdt.begin_node(&format!("cpu@{}", hart));
+            fdt.property_string("device_type", "cpu");
+            fdt.property_u32("reg", hart as u32);
+            fdt.property_string("status", "okay");
+            fdt.property_string("compatible", "riscv");
+            fdt.property_string("riscv,isa", &self.isa.to_string());
+            fdt.property_string("riscv,isa-base", "rv64i");
+            fdt.property_strings("riscv,isa-extensions", &self.isa.extension_names());
+            fdt.property_string("mmu-type", "riscv,sv39");
+
+            fdt.begin_node("interrupt-controller");
+            fdt.property_u32("#interrupt-cells", 1);
+            fdt.property_null("interrupt-controller");
+            fdt.property_string("compatible", "riscv,cpu-intc");
+            fdt.property_u32("phandle", cpu_intc_phandle(hart));
+            fdt.end_node();
+
+            fdt.end_node();
+        }
+        fdt.end_node();
+    }
+
+    fn write_interrupt_controllers(&self, fdt: &mut FdtBuilder)
+    {
+        let map = &self.memory_map;
+        let per_hart = |causes: [u32; 2]| -> Vec<u32>
+        {
+            (0..self.harts)
+                .flat_map(|hart| causes.iter().flat_map(move |&cause| [cpu_intc_phandle(hart), cause]))
+                .collect()
+        };
+
+        fdt.begin_node(&format!("clint@{:x}", map.clint_base));
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_u64s("reg", &[map.clint_base, map.clint_size]);
+        fdt.property_cells("interrupts-extended", &per_hart([IRQ_M_SOFT, IRQ_M_TIMER]));
+        fdt.end_node();
+
+        let Some(plic_base) = map.plic_base else { return };
+        fdt.begin_node(&format!("plic@{:x}", plic_base));
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_u64s("reg", &[plic_base, map.plic_size]);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        // One M-mode and one S-mode context per hart.
+        fdt.property_cells("interrupts-extended", &per_hart([IRQ_M_EXT, IRQ_S_EXT]));
+        fdt.property_u32("riscv,ndev", PLIC_NUM_SOURCES - 1);
+        fdt.property_u32("phandle", PLIC_PHANDLE);
+        fdt.end_node();
+    }
+
+    /// Copies the DTB to the top of guest RAM and passes its address to
+    /// the boot hart in a1. Returns the address, or None if RAM is too
+    /// small to hold it.
+    pub fn load_device_tree(&self, cpu: &mut VirtualCPU) -> Option<u64>
+    {
+        let dtb = self.device_tree();
+        let address = cpu.ram.end().checked_sub(dtb.len() as u64)? & !(FDT_ALIGN - 1);
+        if address < cpu.ram.base()
+        {
+            return None;
+        }
+        cpu.ram.write(address, &dtb)?;
+        cpu.regs[11] = address;
+        Some(address)
+    }
+}
+
+/// A virtual machine: its harts, RAM and devices laid out by a
+/// MachineConfig.
+///
+/// The harts take turns on one VirtualCPU. `cpu` holds the running hart
+/// and `harts` the saved state of the others, indexed by hart ID.
+pub struct Machine
+{
+    pub config: MachineConfig,
+    pub cpu: VirtualCPU,
+    harts: Vec<HartContext>,
+    current: usize,
+    /// Harts idle in wfi or hart_suspend until an interrupt is pending.
+    waiting: Vec<bool>,
+    sbi: Sbi,
+    clock: Clock,
+    /// CLINT outputs per hart, copied into mip.MSIP and mip.MTIP.
+    software_irqs: Vec<IrqLine>,
+    timer_irqs: Vec<IrqLine>,
+    /// PLIC inputs, indexed by interrupt source.
+    irq_lines: Vec<IrqLine>,
+    /// PLIC outputs, M-mode then S-mode context for each hart, copied
+    /// into mip.MEIP and mip.SEIP.
+    external_irqs: Vec<IrqLine>,
+    /// Raised by HTIF when the guest asks to power off.
+    poweroff: IrqLine,
+}
+
+impl Machine
+{
+    /// Creates the machine with zeroed RAM, the CLINT, the PLIC if the
+    /// board has one and its UART connected to `console`. Virtio devices
+    /// are added afterwards with `attach_virtio`.
+    pub fn new(config: MachineConfig, console: CharBackend) -> Self
+    {
+        let map = config.memory_map;
+        let mut cpu = VirtualCPU::with_ram(map.ram_base, config.ram_size as usize);
+        cpu.set_isa(config.isa);
+        cpu.set_vlen(config.vlen);
+        let clock = Clock::new(config.timebase_frequency as u64);
+        let lines = |count| -> Vec<IrqLine> { (0..count).map(|_| IrqLine::new()).collect() };
+        let software_irqs = lines(config.harts);
+        let timer_irqs = lines(config.harts);
+        let irq_lines = lines(PLIC_NUM_SOURCES as usize);
+        let external_irqs = lines(2 * config.harts);
+        let poweroff = IrqLine::new();
+
+        let clint = Clint::new(clock, software_irqs.clone(), timer_irqs.clone());
+        cpu.mmio.attach(map.clint_base, CLINT_MMIO_SIZE, Box::new(clint));
+        if let Some(plic_base) = map.plic_base
+        {
+            let plic = Plic::new(irq_lines.clone(), external_irqs.clone());
+            cpu.mmio.attach(plic_base, map.plic_size, Box::new(plic));
+        }
+        let uart_irq = irq_lines[map.uart_irq as usize].clone();
+        let uart: Box<dyn MmioDevice> = match config.uart
+        {
+            UartKind::Ns16550a => Box::new(Uart16550::new(console, uart_irq)),
+            UartKind::SiFive => Box::new(SifiveUart::new(console, uart_irq)),
+            UartKind::Htif => Box::new(Htif::new(console, poweroff.clone())),
+        };
+        cpu.mmio.attach(map.uart_base, map.uart_size, uart);
+        Machine
+        {
+            harts: (0..config.harts).map(|hart| HartContext::new(hart as u64)).collect(),
+            current: 0,
+            waiting: vec![false; config.harts],
+            sbi: Sbi::new(config.harts, config.uart, map.uart_base),
+            clock,
+            software_irqs,
+            timer_irqs,
+            irq_lines,
+            external_irqs,
+            poweroff,
+            config,
+            cpu,
+        }
+    }
+
+    /// Connects a virtio device to a virtio-mmio slot and its PLIC
+    /// source. Returns false if the board has no such slot.
+    pub fn attach_virtio(&mut self, slot: usize, device: Box<dyn VirtioDevice>) -> bool
+    {
+        let map = self.config.memory_map;
+        if slot >= self.config.virtio_slots || map.plic_base.is_none()
+        {
+            return false;
+        }
+        let Some(irq) = self.irq_lines.get(map.virtio_irq_base as usize + slot) else { return false };
+        let transport = VirtioMmio::new(device, irq.clone());
+        self.cpu.mmio.attach(map.virtio_slot_base(slot), map.virtio_stride, Box::new(transport));
+        true
+    }
+
+    /// The ID of the hart currently loaded in `cpu`.
+    pub fn current_hart(&self) -> usize
+    {
+        self.current
+    }
+
+    /// Loads a Linux `Image` with an optional initrd and a generated DTB,
+    /// and sets hart 0 up to enter the kernel in S-mode with a0 = hartid
+    /// and a1 = DTB. SBI calls are serviced by the VMM, and the other
+    /// harts wait for the kernel to start them through HSM.
+    ///
+    /// The kernel goes at its text offset from the start of RAM, the
+    /// initrd on the next page after the kernel's memory and the DTB at
+    /// the top of RAM.
+    pub fn load_linux(&mut self, kernel: &[u8], initrd: Option<&[u8]>) -> Result<(), BootError>
+    {
+        let header = ImageHeader::parse(kernel)?;
+        let kernel_base = self.load_images(kernel, Some(header), initrd, self.cpu.ram.base())?;
+        self.cpu.intercept_sbi = true;
+        for hart in 0..self.config.harts
+        {
+            self.with_hart(hart, |cpu|
+            {
+                cpu.write_csr(MEDELEG, KERNEL_MEDELEG);
+                cpu.write_csr(MIDELEG, KERNEL_MIDELEG);
+            });
+        }
+        self.cpu.regs[10] = 0;
+        self.cpu.pc = kernel_base;
+        self.cpu.set_privilege(Privilege::Supervisor);
+        Ok(())
+    }
+
+    /// Loads M-mode firmware such as OpenSBI at the start of RAM and a
+    /// reset vector ROM that enters it on every hart with a0 = hartid,
+    /// a1 = DTB and a2 = fw_dynamic_info. Call it once per machine.
+    ///
+    /// The next stage is a Linux `Image`, placed at its text offset, or
+    /// a raw binary such as U-Boot, placed 2 MiB into RAM. It is entered
+    /// in S-mode. Without one, the firmware is still pointed at 2 MiB in.
+    pub fn load_firmware(&mut self, firmware: &[u8], next: Option<&[u8]>, initrd: Option<&[u8]>) -> Result<(), BootError>
+    {
+        let ram_base = self.cpu.ram.base();
+        let firmware_end = ram_base + firmware.len() as u64;
+        self.cpu.ram.write(ram_base, firmware).ok_or(BootError::FirmwareTooLarge)?;
+
+        let next_addr = match next
+        {
+            Some(image) => self.load_images(image, ImageHeader::parse(image).ok(), initrd, firmware_end)?,
+            None =>
+            {
+                self.switch_to(0);
+                let address = self.config.load_device_tree(&mut self.cpu).ok_or(BootError::DeviceTreeTooLarge)?;
+                if address < firmware_end
+                {
+                    return Err(BootError::FirmwareTooLarge);
+                }
+                ram_base + RAW_PAYLOAD_OFFSET
+            }
+        };
+        let info = FwDynamicInfo { next_addr, next_mode: NEXT_MODE_S, options: 0, boot_hart: 0 };
+        let rom = boot::reset_vector(ram_base, self.cpu.regs[11], &info);
+        let map = self.config.memory_map;
+        self.cpu.mmio.attach(map.rom_base, map.rom_size, Box::new(Rom::new(rom)));
+
+        self.cpu.intercept_sbi = false;
+        for hart in 0..self.config.harts
+        {
+            self.sbi.mark_started(hart);
+            self.with_hart(hart, |cpu|
+            {
+                cpu.pc = map.rom_base;
+                cpu.set_privilege(Privilege::Machine);
+            });
+        }
+        Ok(())
+    }
+
+    /// Loads the next stage, an optional initrd and the DTB, leaving the
+    /// DTB address in hart 0's a1. A payload with an `Image` header goes
+    /// at its text offset and anything else at RAW_PAYLOAD_OFFSET; either
+    /// way it must start at or after `low`. Returns the payload address.
+    ///
+    /// The initrd goes on the next page after the payload's memory and
+    /// the DTB at the top of RAM.
+    fn load_images(&mut self, payload: &[u8], header: Option<ImageHeader>, initrd: Option<&[u8]>, low: u64) -> Result<u64, BootError>
+    {
+        let ram_base = self.cpu.ram.base();
+        let (offset, size) = match header
+        {
+            Some(header) => (header.text_offset, header.memory_size(payload.len())),
+            None => (RAW_PAYLOAD_OFFSET, payload.len() as u64),
+        };
+        let payload_base = ram_base.checked_add(offset).ok_or(BootError::KernelTooLarge)?;
+        if payload_base < low
+        {
+            return Err(BootError::FirmwareTooLarge);
+        }
+        let payload_end = payload_base.checked_add(size)
+            .filter(|&end| end <= self.cpu.ram.end())
+            .ok_or(BootError::KernelTooLarge)?;
+        self.cpu.ram.write(payload_base, payload).ok_or(BootError::KernelTooLarge)?;
+
+        let mut used_end = payload_end;
+        self.config.initrd = None;
+        if let Some(initrd) = initrd
+        {
+            let start = payload_end.next_multiple_of(PAGE_SIZE);
+            let end = start + initrd.len() as u64;
+            self.cpu.ram.write(start, initrd).ok_or(BootError::InitrdTooLarge)?;
+            self.config.initrd = Some((start, end));
+            used_end = end;
+        }
+
+        self.switch_to(0);
+        match self.config.load_device_tree(&mut self.cpu)
+        {
+            Some(address) if address >= used_end => Ok(payload_base),
+            _ => Err(BootError::DeviceTreeTooLarge),
+        }
+    }
+
+    /// Runs the harts in turn for up to `max_instructions`, servicing SBI
+    /// calls, timers and devices as it goes. Time spent with every hart
+    /// idle counts one slice per poll. Returns the reset the guest asked
+    /// for, if it did.
+    pub fn run(&mut self, max_instructions: u64) -> Option<SystemReset>
+    {
+        let mut executed = 0;
+        while executed < max_instructions
+        {
+            if self.runnable(self.current)
+            {
+                let now = self.clock.now();
+                self.cpu.write_csr(TIME, now);
+                for _ in 0..TIME_SLICE.min(max_instructions - executed)
+                {
+                    executed += 1;
+                    match self.cpu.step()
+                    {
+                        None => continue,
+                        Some(VmExit::SupervisorCall) =>
+                        {
+                            if let Some(request) = self.sbi.handle_call(&mut self.cpu)
+                            {
+                                if let Some(reset) = self.handle_request(request)
+                                {
+                                    return Some(reset);
+                                }
+                            }
+                        }
+                        Some(VmExit::WaitForInterrupt) => self.waiting[self.current] = true,
+                    }
+                    if !self.runnable(self.current)
+                    {
+                        break;
+                    }
+                }
+            }
+            else
+            {
+                executed += TIME_SLICE;
+            }
+
+            self.cpu.poll_devices();
+            if self.poweroff.is_raised()
+            {
+                return Some(SystemReset::Shutdown);
+            }
+            self.update_interrupts();
+            if !self.schedule()
+            {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Moves to the next runnable hart after the current one. Returns
+    /// false if no hart is started, so nothing could ever run again.
+    fn schedule(&mut self) -> bool
+    {
+        let count = self.config.harts;
+        for offset in 1..=count
+        {
+            let hart = (self.current + offset) % count;
+            if self.runnable(hart)
+            {
+                self.switch_to(hart);
+                return true;
+            }
+        }
+        if (0..count).all(|hart| self.sbi.hart_status(hart) != HartStatus::Started)
+        {
+            return false;
+        }
+        // Every started hart is waiting for an interrupt.
+        let next_timer = (0..count).map(|hart| self.sbi.timer_deadline(hart)).min().unwrap_or(u64::MAX);
+        thread::sleep(self.clock.until(next_timer).min(MAX_IDLE_SLEEP));
+        true
+    }
+
+    fn runnable(&self, hart: usize) -> bool
+    {
+        self.sbi.hart_status(hart) == HartStatus::Started && !self.waiting[hart]
+    }
+
+    /// Copies the CLINT and PLIC lines into each hart's mip, raises STIP on harts
+    /// whose SBI timer has expired and wakes waiting harts that now have
+    /// an interrupt pending.
+    fn update_interrupts(&mut self)
+    {
+        let now = self.clock.now();
+        for hart in 0..self.config.harts
+        {
+            let expired = now >= self.sbi.timer_deadline(hart);
+            let software = self.software_irqs[hart].is_raised();
+            let timer = self.timer_irqs[hart].is_raised();
+            let machine_external = self.external_irqs[2 * hart].is_raised();
+            let supervisor_external = self.external_irqs[2 * hart + 1].is_raised();
+            let woken = self.with_hart(hart, |cpu|
+            {
+                cpu.set_interrupt_pending(MIP_MSIP, software);
+                cpu.set_interrupt_pending(MIP_MTIP, timer);
+                cpu.set_interrupt_pending(MIP_MEIP, machine_external);
+                cpu.set_interrupt_pending(MIP_SEIP, supervisor_external);
+                if expired
+                {
+                    cpu.set_interrupt_pending(MIP_STIP, true);
+                }
+                cpu.interrupt_waiting()
+            });
+            if woken
+            {
+                self.waiting[hart] = false;
+            }
+        }
+    }
+
+    /// Carries out the part of an SBI call that involves other harts or
+    /// the whole machine. Returns the reset the guest asked for, if any.
+    fn handle_request(&mut self, request: SbiRequest) -> Option<SystemReset>
+    {
+        match request
+        {
+            SbiRequest::SendIpi(harts) =>
+            {
+                for hart in harts
+                {
+                    self.with_hart(hart, |cpu| cpu.set_interrupt_pending(MIP_SSIP, true));
+                    self.waiting[hart] = false;
+                }
+            }
+            SbiRequest::RemoteSfenceVma(harts) =>
+            {
+                for hart in harts
+                {
+                    self.with_hart(hart, |cpu| cpu.flush_tlb());
+                }
+            }
+            SbiRequest::StartHart { hart, start_addr, opaque } =>
+            {
+                self.with_hart(hart, |cpu|
+                {
+                    cpu.regs = [0; 32];
+                    cpu.regs[10] = hart as u64;
+                    cpu.regs[11] = opaque;
+                    cpu.pc = start_addr;
+                    cpu.write_csr(SATP, 0);
+                    let sstatus = cpu.read_csr(SSTATUS);
+                    cpu.write_csr(SSTATUS, sstatus & !MSTATUS_SIE);
+                    cpu.set_privilege(Privilege::Supervisor);
+                });
+                self.waiting[hart] = false;
+            }
+            SbiRequest::StopHart => {}
+            SbiRequest::Suspend => self.waiting[self.current] = true,
+            SbiRequest::Reset(reset) => return Some(reset),
+        }
+        None
+    }
+
+    /// Runs `f` on a hart's state, swapping it in if it is not current.
+    fn with_hart<R>(&mut self, hart: usize, f: impl FnOnce(&mut VirtualCPU) -> R) -> R
+    {
+        let current = self.current;
+        self.switch_to(hart);
+        let result = f(&mut self.cpu);
+        self.switch_to(current);
+        result
+    }
+
+    fn switch_to(&mut self, hart: usize)
+    {
+        if hart != self.current
+        {
+            // The running hart's state lands in the slot of the incoming
+            // one, then moves to its own slot.
+            self.cpu.swap_context(&mut self.harts[hart]);
+            self.harts.swap(hart, self.current);
+            self.current = hart;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::boot::tests::fake_image;
+    use crate::csr::MIP;
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool
+    {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn test_device_tree_describes_machine()
+    {
+        let config = MachineConfig
+        {
+            harts: 2,
+            isa: Isa::parse("rv64i_zicsr").unwrap(),
+            bootargs: "console=ttyS0".to_string(),
+            initrd: Some((0x8400_0000, 0x8410_0000)),
+            ..MachineConfig::default()
+        };
+        let dtb = config.device_tree();
+        assert_eq!(&dtb[0..4], &0xd00d_feedu32.to_be_bytes());
+        for name in ["cpu@0", "cpu@1", "memory@80000000", "clint@2000000", "plic@c000000",
+                     "serial@10000000", "virtio_mmio@10001000", "virtio_mmio@10008000"]
+        {
+            assert!(contains(&dtb, format!("{}\0", name).as_bytes()), "missing {}", name);
+        }
+        assert!(!contains(&dtb, b"virtio_mmio@10009000"));
+        assert!(contains(&dtb, b"rv64i_zicsr\0"));
+        assert!(contains(&dtb, b"i\0zicsr\0"));
+        assert!(contains(&dtb, b"console=ttyS0\0"));
+        assert!(contains(&dtb, b"linux,initrd-start\0"));
+        assert!(contains(&dtb, &0x8410_0000u64.to_be_bytes()));
+        // Both harts' M-soft and M-timer interrupts go to the CLINT.
+        let clint_irqs: Vec<u8> = [3u32, 3, 3, 7, 4, 3, 4, 7].iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        assert!(contains(&dtb, &clint_irqs));
+    }
+
+    #[test]
+    fn test_load_device_tree_sets_a1()
+    {
+        let config = MachineConfig::default();
+        let mut cpu = VirtualCPU::with_ram(0x8000_0000, 0x10_0000);
+        let address = config.load_device_tree(&mut cpu).unwrap();
+        assert_eq!(cpu.regs[11], address);
+        assert_eq!(address % FDT_ALIGN, 0);
+        assert!(address + config.device_tree().len() as u64 <= cpu.ram.end());
+        assert_eq!(cpu.ram.read_u32(address), Some(u32::from_be(0xd00d_feed)));
+
+        let mut tiny = VirtualCPU::with_ram(0x8000_0000, 0x100);
+        assert_eq!(config.load_device_tree(&mut tiny), None);
+    }
+
+    #[test]
+    fn test_boot_linux_image()
+    {
+        let config = MachineConfig { ram_size: 0x40_0000, bootargs: "console=hvc0".to_string(), ..MachineConfig::default() };
+        let mut machine = Machine::new(config, CharBackend::new(Box::new(std::io::sink()), None));
+        let kernel = fake_image(&[
+            0x0100_0893, // addi a7, zero, 0x10
+            0x0000_0813, // addi a6, zero, 0
+            0x0000_0073, // ecall
+            0x0000_006f, // j .
+        ], 0x2000);
+        machine.load_linux(&kernel, Some(b"initramfs")).unwrap();
+
+        let kernel_base = 0x8020_0000;
+        assert_eq!(machine.cpu.pc, kernel_base);
+        assert_eq!(machine.cpu.privilege, Privilege::Supervisor);
+        assert_eq!(machine.cpu.regs[10], 0);
+        let mut initrd = [0u8; 9];
+        machine.cpu.ram.read(kernel_base + 0x2000, &mut initrd).unwrap();
+        assert_eq!(&initrd, b"initramfs");
+        assert_eq!(machine.config.initrd, Some((kernel_base + 0x2000, kernel_base + 0x2009)));
+        let dtb = machine.cpu.regs[11];
+        assert_eq!(machine.cpu.ram.read_u32(dtb), Some(u32::from_be(0xd00d_feed)));
+
+        // The ecall lands in the VMM's SBI, which reports spec version 2.0.
+        machine.run(5);
+        assert_eq!(machine.cpu.pc, kernel_base + 64 + 12);
+        assert_eq!(machine.cpu.regs[10], 0);
+        assert_eq!(machine.cpu.regs[11], 2 << 24);
+        assert_eq!(machine.cpu.privilege, Privilege::Supervisor);
+    }
+
+    #[test]
+    fn test_boot_rejects_oversized_kernel()
+    {
+        let config = MachineConfig { ram_size: 0x20_1000, ..MachineConfig::default() };
+        let mut machine = Machine::new(config, CharBackend::new(Box::new(std::io::sink()), None));
+        let kernel = fake_image(&[], 0x2000);
+        assert_eq!(machine.load_linux(&kernel, None), Err(BootError::KernelTooLarge));
+        assert_eq!(machine.load_linux(&kernel[..16], None), Err(BootError::ImageTooSmall));
+    }
+
+    #[test]
+    fn test_secondary_hart_start_and_shutdown()
+    {
+        let config = MachineConfig { harts: 2, ram_size: 0x40_0000, ..MachineConfig::default() };
+        let mut machine = Machine::new(config, CharBackend::new(Box::new(std::io::sink()), None));
+        let kernel = fake_image(&[
+            // Hart 0: hart_start(1, label, 42), then spin.
+            0x0010_0513, // addi a0, zero, 1
+            0x0000_0597, // auipc a1, 0
+            0x0205_8593, // addi a1, a1, 32
+            0x02a0_0613, // addi a2, zero, 42
+            0x0048_58b7, // lui a7, 0x485
+            0x34d8_8893, // addi a7, a7, 0x34d
+            0x0000_0813, // addi a6, zero, 0
+            0x0000_0073, // ecall
+            0x0000_006f, // j .
+            // Hart 1: keep a1 and a0, then system_reset(shutdown).
+            0x0005_8913, // addi s2, a1, 0
+            0x0005_0993, // addi s3, a0, 0
+            0x5352_58b7, // lui a7, 0x53525
+            0x3548_8893, // addi a7, a7, 0x354
+            0x0000_0513, // addi a0, zero, 0
+            0x0000_0593, // addi a1, zero, 0
+            0x0000_0813, // addi a6, zero, 0
+            0x0000_0073, // ecall
+            0x0000_006f, // j .
+        ], 0x1000);
+        machine.load_linux(&kernel, None).unwrap();
+
+        assert_eq!(machine.run(10_000), Some(SystemReset::Shutdown));
+        assert_eq!(machine.current_hart(), 1);
+        assert_eq!(machine.cpu.regs[18], 42);
+        assert_eq!(machine.cpu.regs[19], 1);
+        assert_eq!(machine.cpu.privilege, Privilege::Supervisor);
+        assert_eq!(machine.sbi.hart_status(0), HartStatus::Started);
+    }
+
+    #[test]
+    fn test_firmware_boot_through_reset_vector()
+    {
+        let config = MachineConfig { harts: 2, ram_size: 0x40_0000, ..MachineConfig::default() };
+        let mut machine = Machine::new(config, CharBackend::new(Box::new(std::io::sink()), None));
+        let firmware: Vec<u8> = [
+            0x0200_42b7u32, // lui t0, 0x2004 (CLINT mtimecmp)
+            0x0002_b023,    // sd zero, 0(t0)
+            0x0000_006f,    // j .
+        ].iter().flat_map(|word| word.to_le_bytes()).collect();
+        let kernel = fake_image(&[], 0x1000);
+        machine.load_firmware(&firmware, Some(&kernel), None).unwrap();
+        let dtb = machine.cpu.regs[11];
+
+        // The ROM describes the kernel to the firmware through fw_dynamic_info.
+        let rom = MemoryMap::VIRT.rom_base;
+        assert_eq!(machine.cpu.mmio.read(rom + 40, 8), Some(0x4942_534f));
+        assert_eq!(machine.cpu.mmio.read(rom + 56, 8), Some(0x8020_0000));
+        assert_eq!(machine.cpu.mmio.read(rom + 64, 8), Some(NEXT_MODE_S));
+
+        machine.run(3 * TIME_SLICE);
+        for hart in 0..2
+        {
+            let (regs, pc, privilege, mip) = machine.with_hart(hart, |cpu| (cpu.regs, cpu.pc, cpu.privilege, cpu.read_csr(MIP)));
+            assert_eq!(pc, 0x8000_0008);
+            assert_eq!(privilege, Privilege::Machine);
+            assert_eq!(regs[10], hart as u64);
+            assert_eq!(regs[11], dtb);
+            assert_eq!(regs[12], rom + 40);
+            // Hart 0's mtimecmp of zero has already expired.
+            assert_eq!(mip & MIP_MTIP != 0, hart == 0);
+        }
+    }
+
+    #[test]
+    fn test_profiles_describe_their_boards()
+    {
+        for name in ["virt", "spike", "sifive_u"]
+        {
+            assert_eq!(MachineProfile::from_name(name).unwrap().name(), name);
+        }
+        assert_eq!(MachineProfile::from_name("sifive_e"), None);
+
+        let spike = MachineConfig::for_profile(MachineProfile::Spike).device_tree();
+        assert!(contains(&spike, b"ucb,spike-bare-dev\0"));
+        assert!(contains(&spike, b"ucb,htif0\0"));
+        assert!(contains(&spike, b"/htif\0"));
+        assert!(!contains(&spike, b"plic@"));
+        assert!(!contains(&spike, b"serial@"));
+
+        let sifive_u = MachineConfig::for_profile(MachineProfile::SifiveU).device_tree();
+        for name in ["cpu@1", "plic@c000000", "serial@10010000", "sifive,uart0", "fixed-clock"]
+        {
+            assert!(contains(&sifive_u, format!("{}\0", name).as_bytes()), "missing {}", name);
+        }
+        assert!(!contains(&sifive_u, b"virtio_mmio@"));
+    }
+
+    #[test]
+    fn test_spike_htif_poweroff()
+    {
+        let config = MachineConfig { ram_size: 0x40_0000, ..MachineConfig::for_profile(MachineProfile::Spike) };
+        let mut machine = Machine::new(config, CharBackend::new(Box::new(std::io::sink()), None));
+        let firmware: Vec<u8> = [
+            0x0100_02b7u32, // lui t0, 0x1000 (HTIF tohost)
+            0x0010_0313,    // addi t1, zero, 1
+            0x0062_b023,    // sd t1, 0(t0)
+            0x0000_006f,    // j .
+        ].iter().flat_map(|word| word.to_le_bytes()).collect();
+        machine.load_firmware(&firmware, None, None).unwrap();
+        assert_eq!(machine.run(10 * TIME_SLICE), Some(SystemReset::Shutdown));
+    }
+
+    #[test]
+    fn test_sifive_uart_interrupt_reaches_supervisor()
+    {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let config = MachineConfig { ram_size: 0x10_0000, ..MachineConfig::for_profile(MachineProfile::SifiveU) };
+        let mut machine = Machine::new(config, CharBackend::new(Box::new(std::io::sink()), Some(receiver)));
+        let map = MemoryMap::SIFIVE_U;
+        let plic = map.plic_base.unwrap();
+        let cpu = &mut machine.cpu;
+        cpu.mmio.write(plic + 4 * map.uart_irq as u64, 4, 1, &mut cpu.ram);
+        // Hart 0's S-mode context.
+        cpu.mmio.write(plic + 0x2080, 4, 1 << map.uart_irq, &mut cpu.ram);
+        cpu.mmio.write(map.uart_base + 0x10, 4, 2, &mut cpu.ram);
+
+        sender.send(b'x').unwrap();
+        machine.cpu.poll_devices();
+        machine.cpu.poll_devices();
+        machine.update_interrupts();
+        assert_eq!(machine.cpu.read_csr(MIP) & (MIP_SEIP | MIP_MEIP), MIP_SEIP);
+        assert_eq!(machine.cpu.mmio.read(plic + 0x20_1004, 4), Some(map.uart_irq as u64));
+    }
+}
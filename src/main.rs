@@ -1,13 +1,16 @@
-use std::fs::File;
+use std::env;
+use std::fs::{self, File};
 use std::io::{self, Read, Write};
-use iso::{BLOCK_SIZE, get_boot_catalog_location, get_boot_img_start_block_and_sector_count, copy_boot_image};
-
-mod v_cpu;
-mod iso;
+use std::path::Path;
+use rust_vmm::iso::{BLOCK_SIZE, BootCatalog, get_boot_catalog_location, copy_boot_image};
 
 fn main() -> io::Result<()> 
 {
-    let mut file = File::open("freebsd.iso")?;
+    // Usage: rust_vmm [image.iso] [output directory]
+    let iso_path = env::args().nth(1).unwrap_or_else(|| String::from("freebsd.iso"));
+    let output = env::args().nth(2).unwrap_or_else(|| String::from("bootimages"));
+
+    let mut file = File::open(&iso_path)?;
     let mut data = Vec::new();
     file.read_to_end(&mut data)?;
 
@@ -28,28 +31,101 @@ fn main() -> io::Result<()>
         return Ok(()); // or Err(e) if you prefer to handle errors
     }
 
-    let boot_catalog = &data[boot_catalog_start..boot_catalog_start + BLOCK_SIZE];
-    let (boot_image_start_block, boot_image_sector_count) = match get_boot_img_start_block_and_sector_count(boot_catalog) 
+    // The catalog may run past its first sector when it has many sections.
+    let catalog = match BootCatalog::parse(&data[boot_catalog_start..]) 
     {
-        Some((start_block, sector_count)) => 
-        {
-            println!("Boot Image Start Block: {}", start_block);
-            println!("Sector Count: {}", sector_count);
-            (start_block, sector_count)
-        },
-        None => 
+        Ok(catalog) => catalog,
+        Err(error) => 
         {
-            println!("Failed to parse El Torito Boot Catalog.");
-            (0, 0)
+            println!("Failed to parse El Torito Boot Catalog: {:?}", error);
+            return Ok(());
         }
     };
 
-    let mut boot_image = vec![0; (boot_image_sector_count as usize) * BLOCK_SIZE];
-    copy_boot_image(&data, boot_image_start_block, boot_image_sector_count, &mut boot_image)?;
+    extract_boot_images(&iso_path, &data, &catalog, Path::new(&output))
+}
 
-    let mut save_file = File::create("bootimg")?;
-    save_file.write_all(&boot_image)?;
-    println!("Boot image saved to {}", "bootimg");
+/// Writes every boot image in `catalog` to `output` as `<index>-<platform>.img`,
+/// with a manifest.txt describing each entry.
+fn extract_boot_images(iso_path: &str, data: &[u8], catalog: &BootCatalog, output: &Path) -> io::Result<()>
+{
+    fs::create_dir_all(output)?;
+    let mut manifest = File::create(output.join("manifest.txt"))?;
+    writeln!(manifest, "# {} ({})", iso_path, catalog.validation.id_string)?;
+
+    for (index, (platform, entry)) in catalog.entries().enumerate() 
+    {
+        write!(manifest, "entry={} platform={} bootable={} media_type=0x{:02x} load_segment=0x{:04x} system_type=0x{:02x} sector_count={} load_rba={}",
+            index, platform.name(), entry.is_bootable(), entry.media_type, entry.load_segment, entry.system_type, entry.sector_count, entry.load_rba)?;
+
+        let emulation = entry.emulation().map(|emulation| format!("{:?}", emulation)).unwrap_or_else(|_| String::from("unknown"));
+        match copy_boot_image(data, entry) 
+        {
+            Ok(boot_image) => 
+            {
+                let name = format!("{}-{}.img", index, platform.name());
+                File::create(output.join(&name))?.write_all(&boot_image)?;
+                writeln!(manifest, " emulation={} size={} file={}", emulation, boot_image.len(), name)?;
+                println!("Boot image {} ({}, {}) saved to {}", index, platform.name(), emulation, output.join(&name).display());
+            },
+            Err(error) => 
+            {
+                writeln!(manifest, " emulation={} error=\"{}\"", emulation, error)?;
+                println!("Boot image {} ({}) could not be extracted: {}", index, platform.name(), error);
+            }
+        }
+    }
+    println!("Manifest saved to {}", output.join("manifest.txt").display());
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use rust_vmm::iso::{BootEntry, Platform, Section, ValidationEntry};
+
+    fn entry(load_rba: u32, sector_count: u16) -> BootEntry
+    {
+        BootEntry
+        {
+            boot_indicator: 0x88,
+            media_type: 0,
+            load_segment: 0,
+            system_type: 0,
+            sector_count,
+            load_rba,
+            selection_criteria_type: 0,
+            selection_criteria: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_extract_boot_images()
+    {
+        // A BIOS no-emulation image at block 2 and an EFI image at block 3.
+        let mut data = vec![0u8; 4 * BLOCK_SIZE];
+        data[2 * BLOCK_SIZE..3 * BLOCK_SIZE].fill(0xb1);
+        data[3 * BLOCK_SIZE..].fill(0xef);
+        let catalog = BootCatalog
+        {
+            validation: ValidationEntry { platform: Platform::X86, id_string: String::from("TESTDISC"), checksum: 0 },
+            initial: entry(2, 4),
+            sections: vec![Section { platform: Platform::Efi, id_string: String::new(), entries: vec![entry(3, 1)] }],
+        };
+
+        let output = env::temp_dir().join(format!("rust_vmm-extract-{}", std::process::id()));
+        extract_boot_images("test.iso", &data, &catalog, &output).unwrap();
+        assert_eq!(fs::read(output.join("0-x86.img")).unwrap(), vec![0xb1; 2048]);
+        assert_eq!(fs::read(output.join("1-EFI.img")).unwrap(), vec![0xef; 512]);
+        let manifest = fs::read_to_string(output.join("manifest.txt")).unwrap();
+        let lines: Vec<&str> = manifest.lines().collect();
+        assert_eq!(lines, [
+            "# test.iso (TESTDISC)",
+            "entry=0 platform=x86 bootable=true media_type=0x00 load_segment=0x0000 system_type=0x00 sector_count=4 load_rba=2 emulation=NoEmulation size=2048 file=0-x86.img",
+            "entry=1 platform=EFI bootable=true media_type=0x00 load_segment=0x0000 system_type=0x00 sector_count=1 load_rba=3 emulation=NoEmulation size=512 file=1-EFI.img",
+        ]);
+        fs::remove_dir_all(&output).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+/// A contiguous block of guest physical RAM backed by a host buffer.
+///
+/// Offsets into the backing buffer are what the software TLB caches as
+/// "host pointers", so the buffer is allocated once and never resized.
+pub struct Ram
+{
+    base: u64,
+    data: Vec<u8>,
+}
+
+impl Ram
+{
+    pub fn new(base: u64, size: usize) -> Self
+    {
+        Ram
+        {
+            base,
+            data: vec![0; size],
+        }
+    }
+
+    pub fn base(&self) -> u64
+    {
+        self.base
+    }
+
+    pub fn size(&self) -> u64
+    {
+        self.data.len() as u64
+    }
+
+    pub fn end(&self) -> u64
+    {
+        self.base + self.size()
+    }
+
+    /// Host offset of `addr` if the whole `len` byte range lies inside RAM.
+    pub fn offset_of(&self, addr: u64, len: u64) -> Option<usize>
+    {
+        let offset = addr.checked_sub(self.base)?;
+        if offset.checked_add(len)? > self.size()
+        {
+            return None;
+        }
+        Some(offset as usize)
+    }
+
+    pub fn as_slice(&self) -> &[u8]
+    {
+        &self.data
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8]
+    {
+        &mut self.data
+    }
+
+    /// Little-endian load of `size` bytes (1, 2, 4 or 8) at a host offset.
+    #[inline]
+    pub fn load_host(&self, offset: usize, size: usize) -> u64
+    {
+        let bytes = &self.data[offset..offset + size];
+        match size
+        {
+            1 => bytes[0] as u64,
+            2 => u16::from_le_bytes([bytes[0], bytes[1]]) as u64,
+            4 => u32::from_le_bytes(bytes.try_into().unwrap()) as u64,
+            _ => u64::from_le_bytes(bytes.try_into().unwrap()),
+        }
+    }
+
+    /// Little-endian store of the low `size` bytes of `value` at a host offset.
+    #[inline]
+    pub fn store_host(&mut self, offset: usize, size: usize, value: u64)
+    {
+        self.data[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+    }
+
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> Option<()>
+    {
+        let offset = self.offset_of(addr, buf.len() as u64)?;
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+        Some(())
+    }
+
+    pub fn write(&mut self, addr: u64, data: &[u8]) -> Option<()>
+    {
+        let offset = self.offset_of(addr, data.len() as u64)?;
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+        Some(())
+    }
+
+    pub fn read_u16(&self, addr: u64) -> Option<u16>
+    {
+        let offset = self.offset_of(addr, 2)?;
+        Some(self.load_host(offset, 2) as u16)
+    }
+
+    pub fn read_u32(&self, addr: u64) -> Option<u32>
+    {
+        let offset = self.offset_of(addr, 4)?;
+        Some(self.load_host(offset, 4) as u32)
+    }
+
+    pub fn read_u64(&self, addr: u64) -> Option<u64>
+    {
+        let offset = self.offset_of(addr, 8)?;
+        Some(self.load_host(offset, 8))
+    }
+
+    pub fn write_u16(&mut self, addr: u64, value: u16) -> Option<()>
+    {
+        let offset = self.offset_of(addr, 2)?;
+        self.store_host(offset, 2, value as u64);
+        Some(())
+    }
+
+    pub fn write_u32(&mut self, addr: u64, value: u32) -> Option<()>
+    {
+        let offset = self.offset_of(addr, 4)?;
+        self.store_host(offset, 4, value as u64);
+        Some(())
+    }
+
+    pub fn write_u64(&mut self, addr: u64, value: u64) -> Option<()>
+    {
+        let offset = self.offset_of(addr, 8)?;
+        self.store_host(offset, 8, value);
+        Some(())
+    }
+}
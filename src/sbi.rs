@@ -0,0 +1,522 @@
+use crate::csr::{MHARTID, MIP_STIP};
+use crate::machine::UartKind;
+use crate::v_cpu::VirtualCPU;
+
+// Error codes returned in a0
+pub const SBI_SUCCESS: i64 = 0;
+pub const SBI_ERR_FAILED: i64 = -1;
+pub const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+pub const SBI_ERR_INVALID_PARAM: i64 = -3;
+pub const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+// Extension IDs, passed in a7
+pub const EXT_LEGACY_PUTCHAR: u64 = 0x01;
+pub const EXT_LEGACY_GETCHAR: u64 = 0x02;
+pub const EXT_BASE: u64 = 0x10;
+pub const EXT_TIME: u64 = 0x5449_4d45;
+pub const EXT_IPI: u64 = 0x0073_5049;
+pub const EXT_RFENCE: u64 = 0x5246_4e43;
+pub const EXT_HSM: u64 = 0x0048_534d;
+pub const EXT_SRST: u64 = 0x5352_5354;
+
+/// Extensions reported by probe_extension.
+const EXTENSIONS: [u64; 8] = [
+    EXT_LEGACY_PUTCHAR, EXT_LEGACY_GETCHAR, EXT_BASE, EXT_TIME, EXT_IPI, EXT_RFENCE, EXT_HSM, EXT_SRST,
+];
+
+/// SBI specification version 2.0, major in bits 30:24.
+const SPEC_VERSION: u64 = 2 << 24;
+/// Implementation ID reported by the base extension ("RVMM").
+const IMPL_ID: u64 = 0x4d4d_5652;
+const IMPL_VERSION: u64 = 1;
+
+// Machine ID CSRs reported through the base extension
+const MVENDORID: u16 = 0xf11;
+const MARCHID: u16 = 0xf12;
+const MIMPID: u16 = 0xf13;
+
+// hart_suspend types
+const SUSPEND_DEFAULT_RETENTIVE: u64 = 0;
+const SUSPEND_DEFAULT_NON_RETENTIVE: u64 = 0x8000_0000;
+
+/// A hart mask base that selects every hart, ignoring the mask.
+const HART_MASK_ALL: u64 = u64::MAX;
+
+// 16550 registers the console functions drive, as firmware would
+const UART_THR: u64 = 0;
+const UART_LSR: u64 = 5;
+const UART_LSR_DR: u64 = 1;
+
+// SiFive UART registers
+const SIFIVE_TXDATA: u64 = 0x0;
+const SIFIVE_RXDATA: u64 = 0x4;
+const SIFIVE_RXDATA_EMPTY: u64 = 1 << 31;
+
+// HTIF registers and console requests
+const HTIF_TOHOST: u64 = 0x0;
+const HTIF_FROMHOST: u64 = 0x8;
+const HTIF_CONSOLE_GETCHAR: u64 = 1 << 56;
+const HTIF_CONSOLE_PUTCHAR: u64 = (1 << 56) | (1 << 48);
+
+const REG_A0: usize = 10;
+const REG_A1: usize = 11;
+const REG_A2: usize = 12;
+const REG_A6: usize = 16;
+const REG_A7: usize = 17;
+
+/// HSM hart states, numbered as hart_get_status reports them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HartStatus
+{
+    Started = 0,
+    Stopped = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SystemReset
+{
+    Shutdown,
+    ColdReboot,
+    WarmReboot,
+}
+
+/// Work an SBI call leaves for the machine, because it involves other
+/// harts or the machine as a whole.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SbiRequest
+{
+    /// Raise a supervisor software interrupt on each hart.
+    SendIpi(Vec<usize>),
+    /// Flush each hart's TLB.
+    RemoteSfenceVma(Vec<usize>),
+    /// Start a stopped hart in S-mode at `start_addr` with a0 = hartid and
+    /// a1 = `opaque`.
+    StartHart { hart: usize, start_addr: u64, opaque: u64 },
+    /// The calling hart has stopped and must not run again until started.
+    StopHart,
+    /// The calling hart is idle until an interrupt is pending.
+    Suspend,
+    Reset(SystemReset),
+}
+
+struct HartState
+{
+    status: HartStatus,
+    /// The mtime value at which STIP is raised.
+    timer: u64,
+}
+
+/// The SBI implementation the VMM provides when a kernel is booted
+/// without M-mode firmware.
+pub struct Sbi
+{
+    uart: UartKind,
+    uart_base: u64,
+    harts: Vec<HartState>,
+    request: Option<SbiRequest>,
+}
+
+impl Sbi
+{
+    /// Hart 0 starts running and the rest wait for hart_start. The legacy
+    /// console functions use the `uart` at `uart_base`.
+    pub fn new(harts: usize, uart: UartKind, uart_base: u64) -> Self
+    {
+        Sbi
+        {
+            uart,
+            uart_base,
+            harts: (0..harts)
+                .map(|hart| HartState
+                {
+                    status: if hart == 0 { HartStatus::Started } else { HartStatus::Stopped },
+                    timer: u64::MAX,
+                })
+                .collect(),
+            request: None,
+        }
+    }
+
+    pub fn hart_status(&self, hart: usize) -> HartStatus
+    {
+        self.harts[hart].status
+    }
+
+    /// Records a hart as running without a hart_start call, for harts
+    /// that all come out of reset together under M-mode firmware.
+    pub fn mark_started(&mut self, hart: usize)
+    {
+        self.harts[hart].status = HartStatus::Started;
+    }
+
+    /// The mtime value at which the hart's supervisor timer fires.
+    pub fn timer_deadline(&self, hart: usize) -> u64
+    {
+        self.harts[hart].timer
+    }
+
+    fn console_putchar(&self, cpu: &mut VirtualCPU, byte: u64)
+    {
+        let (offset, size, value) = match self.uart
+        {
+            UartKind::Ns16550a => (UART_THR, 1, byte),
+            UartKind::SiFive => (SIFIVE_TXDATA, 4, byte),
+            UartKind::Htif => (HTIF_TOHOST, 8, HTIF_CONSOLE_PUTCHAR | byte),
+        };
+        cpu.mmio.write(self.uart_base + offset, size, value, &mut cpu.ram);
+        if self.uart == UartKind::Htif
+        {
+            // Take the acknowledgement so fromhost is free for input.
+            cpu.mmio.write(self.uart_base + HTIF_FROMHOST, 8, 0, &mut cpu.ram);
+        }
+    }
+
+    fn console_getchar(&self, cpu: &mut VirtualCPU) -> Option<u64>
+    {
+        match self.uart
+        {
+            UartKind::Ns16550a =>
+            {
+                let ready = cpu.mmio.read(self.uart_base + UART_LSR, 1)? & UART_LSR_DR != 0;
+                if ready { cpu.mmio.read(self.uart_base + UART_THR, 1) } else { None }
+            }
+            UartKind::SiFive =>
+            {
+                let data = cpu.mmio.read(self.uart_base + SIFIVE_RXDATA, 4)?;
+                if data & SIFIVE_RXDATA_EMPTY == 0 { Some(data & 0xff) } else { None }
+            }
+            UartKind::Htif =>
+            {
+                // The write polls the host, so a waiting byte is in
+                // fromhost straight away; otherwise it arrives for a later call.
+                cpu.mmio.write(self.uart_base + HTIF_TOHOST, 8, HTIF_CONSOLE_GETCHAR, &mut cpu.ram);
+                let reply = cpu.mmio.read(self.uart_base + HTIF_FROMHOST, 8)?;
+                if reply == 0
+                {
+                    return None;
+                }
+                cpu.mmio.write(self.uart_base + HTIF_FROMHOST, 8, 0, &mut cpu.ram);
+                Some(reply & 0xff)
+            }
+        }
+    }
+
+    /// Services the ecall `cpu` has just made, with the extension in a7,
+    /// the function in a6 and arguments from a0. The error goes back in
+    /// a0 and the value in a1; legacy calls only return a0.
+    pub fn handle_call(&mut self, cpu: &mut VirtualCPU) -> Option<SbiRequest>
+    {
+        let extension = cpu.regs[REG_A7];
+        let function = cpu.regs[REG_A6];
+        match extension
+        {
+            EXT_LEGACY_PUTCHAR =>
+            {
+                self.console_putchar(cpu, cpu.regs[REG_A0] & 0xff);
+                cpu.regs[REG_A0] = 0;
+                return None;
+            }
+            EXT_LEGACY_GETCHAR =>
+            {
+                cpu.regs[REG_A0] = self.console_getchar(cpu).unwrap_or(-1i64 as u64);
+                return None;
+            }
+            _ => {}
+        }
+        let (error, value) = match extension
+        {
+            EXT_BASE => self.base(cpu, function),
+            EXT_TIME => self.time(cpu, function),
+            EXT_IPI => self.ipi(cpu, function),
+            EXT_RFENCE => self.rfence(cpu, function),
+            EXT_HSM => self.hsm(cpu, function),
+            EXT_SRST => self.srst(cpu, function),
+            _ => (SBI_ERR_NOT_SUPPORTED, 0),
+        };
+        cpu.regs[REG_A0] = error as u64;
+        cpu.regs[REG_A1] = value;
+        self.request.take()
+    }
+
+    fn base(&self, cpu: &VirtualCPU, function: u64) -> (i64, u64)
+    {
+        match function
+        {
+            0 => (SBI_SUCCESS, SPEC_VERSION),
+            1 => (SBI_SUCCESS, IMPL_ID),
+            2 => (SBI_SUCCESS, IMPL_VERSION),
+            3 => (SBI_SUCCESS, EXTENSIONS.contains(&cpu.regs[REG_A0]) as u64), // probe_extension
+            4 => (SBI_SUCCESS, cpu.read_csr(MVENDORID)),
+            5 => (SBI_SUCCESS, cpu.read_csr(MARCHID)),
+            6 => (SBI_SUCCESS, cpu.read_csr(MIMPID)),
+            _ => (SBI_ERR_NOT_SUPPORTED, 0),
+        }
+    }
+
+    fn time(&mut self, cpu: &mut VirtualCPU, function: u64) -> (i64, u64)
+    {
+        if function != 0
+        {
+            return (SBI_ERR_NOT_SUPPORTED, 0);
+        }
+        // set_timer: the pending timer is cleared until the new deadline.
+        let hart = cpu.read_csr(MHARTID) as usize;
+        self.harts[hart].timer = cpu.regs[REG_A0];
+        cpu.set_interrupt_pending(MIP_STIP, false);
+        (SBI_SUCCESS, 0)
+    }
+
+    fn ipi(&mut self, cpu: &VirtualCPU, function: u64) -> (i64, u64)
+    {
+        if function != 0
+        {
+            return (SBI_ERR_NOT_SUPPORTED, 0);
+        }
+        // send_ipi
+        match self.decode_hart_mask(cpu.regs[REG_A0], cpu.regs[REG_A1])
+        {
+            Ok(harts) =>
+            {
+                self.request = Some(SbiRequest::SendIpi(harts));
+                (SBI_SUCCESS, 0)
+            }
+            Err(error) => (error, 0),
+        }
+    }
+
+    fn rfence(&mut self, cpu: &VirtualCPU, function: u64) -> (i64, u64)
+    {
+        let harts = match self.decode_hart_mask(cpu.regs[REG_A0], cpu.regs[REG_A1])
+        {
+            Ok(harts) => harts,
+            Err(error) => return (error, 0),
+        };
+        match function
+        {
+            // remote_fence_i: instructions are always fetched from RAM.
+            0 => (SBI_SUCCESS, 0),
+            // remote_sfence_vma and remote_sfence_vma_asid flush the whole
+            // TLB, since it is not tagged by ASID.
+            1 | 2 =>
+            {
+                self.request = Some(SbiRequest::RemoteSfenceVma(harts));
+                (SBI_SUCCESS, 0)
+            }
+            // The hfence functions need the hypervisor extension.
+            _ => (SBI_ERR_NOT_SUPPORTED, 0),
+        }
+    }
+
+    fn hsm(&mut self, cpu: &VirtualCPU, function: u64) -> (i64, u64)
+    {
+        let caller = cpu.read_csr(MHARTID) as usize;
+        match function
+        {
+            0 =>
+            {
+                // hart_start
+                let hart = cpu.regs[REG_A0] as usize;
+                match self.harts.get_mut(hart)
+                {
+                    None => (SBI_ERR_INVALID_PARAM, 0),
+                    Some(state) if state.status != HartStatus::Stopped => (SBI_ERR_ALREADY_AVAILABLE, 0),
+                    Some(state) =>
+                    {
+                        state.status = HartStatus::Started;
+                        self.request = Some(SbiRequest::StartHart
+                        {
+                            hart,
+                            start_addr: cpu.regs[REG_A1],
+                            opaque: cpu.regs[REG_A2],
+                        });
+                        (SBI_SUCCESS, 0)
+                    }
+                }
+            }
+            1 =>
+            {
+                // hart_stop
+                self.harts[caller].status = HartStatus::Stopped;
+                self.request = Some(SbiRequest::StopHart);
+                (SBI_SUCCESS, 0)
+            }
+            2 =>
+            {
+                // hart_get_status
+                match self.harts.get(cpu.regs[REG_A0] as usize)
+                {
+                    Some(state) => (SBI_SUCCESS, state.status as u64),
+                    None => (SBI_ERR_INVALID_PARAM, 0),
+                }
+            }
+            3 =>
+            {
+                // hart_suspend: only the retentive default, which is a wfi.
+                match cpu.regs[REG_A0]
+                {
+                    SUSPEND_DEFAULT_RETENTIVE =>
+                    {
+                        self.request = Some(SbiRequest::Suspend);
+                        (SBI_SUCCESS, 0)
+                    }
+                    SUSPEND_DEFAULT_NON_RETENTIVE | 0x1000_0000..=0x7fff_ffff | 0x9000_0000..=0xffff_ffff =>
+                    {
+                        (SBI_ERR_NOT_SUPPORTED, 0)
+                    }
+                    _ => (SBI_ERR_INVALID_PARAM, 0),
+                }
+            }
+            _ => (SBI_ERR_NOT_SUPPORTED, 0),
+        }
+    }
+
+    fn srst(&mut self, cpu: &VirtualCPU, function: u64) -> (i64, u64)
+    {
+        if function != 0
+        {
+            return (SBI_ERR_NOT_SUPPORTED, 0);
+        }
+        // system_reset
+        let reset = match cpu.regs[REG_A0]
+        {
+            0 => SystemReset::Shutdown,
+            1 => SystemReset::ColdReboot,
+            2 => SystemReset::WarmReboot,
+            0xf000_0000..=0xffff_ffff => return (SBI_ERR_NOT_SUPPORTED, 0),
+            _ => return (SBI_ERR_INVALID_PARAM, 0),
+        };
+        match cpu.regs[REG_A1]
+        {
+            // No reason, system failure, or an SBI or vendor specific one.
+            0 | 1 | 0xe000_0000..=0xffff_ffff => {}
+            _ => return (SBI_ERR_INVALID_PARAM, 0),
+        }
+        self.request = Some(SbiRequest::Reset(reset));
+        // Only reached if the machine fails to reset.
+        (SBI_ERR_FAILED, 0)
+    }
+
+    /// Turns a hart mask and base into hart indices.
+    fn decode_hart_mask(&self, mask: u64, base: u64) -> Result<Vec<usize>, i64>
+    {
+        if base == HART_MASK_ALL
+        {
+            return Ok((0..self.harts.len()).collect());
+        }
+        let mut harts = Vec::new();
+        for bit in 0..64
+        {
+            if mask & (1 << bit) == 0
+            {
+                continue;
+            }
+            match base.checked_add(bit)
+            {
+                Some(hart) if (hart as usize) < self.harts.len() => harts.push(hart as usize),
+                _ => return Err(SBI_ERR_INVALID_PARAM),
+            }
+        }
+        Ok(harts)
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::devices::chardev::CharBackend;
+    use crate::devices::uart::{Uart16550, UART_MMIO_SIZE};
+    use crate::devices::IrqLine;
+    use crate::csr::MIP;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer
+    {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize>
+        {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()>
+        {
+            Ok(())
+        }
+    }
+
+    fn call(sbi: &mut Sbi, cpu: &mut VirtualCPU, extension: u64, function: u64, args: &[u64]) -> (i64, u64)
+    {
+        cpu.regs[REG_A7] = extension;
+        cpu.regs[REG_A6] = function;
+        cpu.regs[REG_A0..REG_A0 + args.len()].copy_from_slice(args);
+        sbi.request = sbi.handle_call(cpu);
+        (cpu.regs[REG_A0] as i64, cpu.regs[REG_A1])
+    }
+
+    #[test]
+    fn test_base_and_time()
+    {
+        let mut sbi = Sbi::new(1, UartKind::Ns16550a, 0x1000_0000);
+        let mut cpu = VirtualCPU::new();
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_BASE, 0, &[]), (SBI_SUCCESS, SPEC_VERSION));
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_BASE, 3, &[EXT_HSM]), (SBI_SUCCESS, 1));
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_BASE, 3, &[0x1234]), (SBI_SUCCESS, 0));
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_BASE, 99, &[]).0, SBI_ERR_NOT_SUPPORTED);
+        assert_eq!(call(&mut sbi, &mut cpu, 0x1234, 0, &[]).0, SBI_ERR_NOT_SUPPORTED);
+
+        cpu.set_interrupt_pending(MIP_STIP, true);
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_TIME, 0, &[5000]), (SBI_SUCCESS, 0));
+        assert_eq!(sbi.timer_deadline(0), 5000);
+        assert_eq!(cpu.read_csr(MIP) & MIP_STIP, 0);
+    }
+
+    #[test]
+    fn test_hart_management_and_ipis()
+    {
+        let mut sbi = Sbi::new(3, UartKind::Ns16550a, 0x1000_0000);
+        let mut cpu = VirtualCPU::new();
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_HSM, 2, &[1]), (SBI_SUCCESS, HartStatus::Stopped as u64));
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_HSM, 0, &[1, 0x8020_0000, 42]), (SBI_SUCCESS, 0));
+        assert_eq!(sbi.request, Some(SbiRequest::StartHart { hart: 1, start_addr: 0x8020_0000, opaque: 42 }));
+        assert_eq!(sbi.hart_status(1), HartStatus::Started);
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_HSM, 0, &[1, 0, 0]).0, SBI_ERR_ALREADY_AVAILABLE);
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_HSM, 0, &[7, 0, 0]).0, SBI_ERR_INVALID_PARAM);
+
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_IPI, 0, &[0b101, 0]).0, SBI_SUCCESS);
+        assert_eq!(sbi.request, Some(SbiRequest::SendIpi(vec![0, 2])));
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_IPI, 0, &[0b1, 3]).0, SBI_ERR_INVALID_PARAM);
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_RFENCE, 1, &[0, HART_MASK_ALL, 0, 0]).0, SBI_SUCCESS);
+        assert_eq!(sbi.request, Some(SbiRequest::RemoteSfenceVma(vec![0, 1, 2])));
+
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_HSM, 1, &[]).0, SBI_SUCCESS);
+        assert_eq!(sbi.request, Some(SbiRequest::StopHart));
+        assert_eq!(sbi.hart_status(0), HartStatus::Stopped);
+
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_SRST, 0, &[0, 0]).0, SBI_ERR_FAILED);
+        assert_eq!(sbi.request, Some(SbiRequest::Reset(SystemReset::Shutdown)));
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_SRST, 0, &[5, 0]).0, SBI_ERR_INVALID_PARAM);
+    }
+
+    #[test]
+    fn test_legacy_console()
+    {
+        let output = SharedBuffer::default();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let backend = CharBackend::new(Box::new(output.clone()), Some(receiver));
+        let mut cpu = VirtualCPU::new();
+        cpu.mmio.attach(0x1000_0000, UART_MMIO_SIZE, Box::new(Uart16550::new(backend, IrqLine::new())));
+        let mut sbi = Sbi::new(1, UartKind::Ns16550a, 0x1000_0000);
+
+        call(&mut sbi, &mut cpu, EXT_LEGACY_PUTCHAR, 0, &[b'k' as u64]);
+        assert_eq!(*output.0.lock().unwrap(), b"k");
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_LEGACY_GETCHAR, 0, &[]).0, -1);
+        sender.send(b'x').unwrap();
+        cpu.poll_devices();
+        assert_eq!(call(&mut sbi, &mut cpu, EXT_LEGACY_GETCHAR, 0, &[]).0, b'x' as i64);
+    }
+}
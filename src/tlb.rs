@@ -0,0 +1,155 @@
+pub const PAGE_SHIFT: u64 = 12;
+pub const PAGE_SIZE: u64 = 1 << PAGE_SHIFT;
+pub const PAGE_MASK: u64 = PAGE_SIZE - 1;
+
+const TLB_ENTRIES: usize = 256;
+const INVALID_VPN: u64 = u64::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessType
+{
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Copy)]
+struct TlbEntry
+{
+    vpn: u64,
+    host_page: usize,
+}
+
+const EMPTY_ENTRY: TlbEntry = TlbEntry { vpn: INVALID_VPN, host_page: 0 };
+
+/// Per-hart software TLB caching guest-virtual pages that map to RAM.
+///
+/// Each access type has its own direct-mapped table, so a page is only
+/// cached for the kinds of access its PTE allowed when it was walked.
+/// Entries hold the page's offset into the RAM backing buffer; MMIO pages
+/// are never inserted and always take the slow path.
+pub struct Tlb
+{
+    read: [TlbEntry; TLB_ENTRIES],
+    write: [TlbEntry; TLB_ENTRIES],
+    exec: [TlbEntry; TLB_ENTRIES],
+}
+
+impl Default for Tlb
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Tlb
+{
+    pub fn new() -> Self
+    {
+        Tlb
+        {
+            read: [EMPTY_ENTRY; TLB_ENTRIES],
+            write: [EMPTY_ENTRY; TLB_ENTRIES],
+            exec: [EMPTY_ENTRY; TLB_ENTRIES],
+        }
+    }
+
+    fn table(&self, access: AccessType) -> &[TlbEntry; TLB_ENTRIES]
+    {
+        match access
+        {
+            AccessType::Read => &self.read,
+            AccessType::Write => &self.write,
+            AccessType::Execute => &self.exec,
+        }
+    }
+
+    fn table_mut(&mut self, access: AccessType) -> &mut [TlbEntry; TLB_ENTRIES]
+    {
+        match access
+        {
+            AccessType::Read => &mut self.read,
+            AccessType::Write => &mut self.write,
+            AccessType::Execute => &mut self.exec,
+        }
+    }
+
+    /// Host offset of `vaddr` if its page is cached for `access`.
+    #[inline]
+    pub fn lookup(&self, access: AccessType, vaddr: u64) -> Option<usize>
+    {
+        let vpn = vaddr >> PAGE_SHIFT;
+        let entry = &self.table(access)[vpn as usize % TLB_ENTRIES];
+        if entry.vpn == vpn
+        {
+            Some(entry.host_page + (vaddr & PAGE_MASK) as usize)
+        }
+        else
+        {
+            None
+        }
+    }
+
+    /// Caches the page containing `vaddr`, whose first byte is at `host_page`.
+    pub fn insert(&mut self, access: AccessType, vaddr: u64, host_page: usize)
+    {
+        let vpn = vaddr >> PAGE_SHIFT;
+        self.table_mut(access)[vpn as usize % TLB_ENTRIES] = TlbEntry { vpn, host_page };
+    }
+
+    pub fn flush(&mut self)
+    {
+        self.read = [EMPTY_ENTRY; TLB_ENTRIES];
+        self.write = [EMPTY_ENTRY; TLB_ENTRIES];
+        self.exec = [EMPTY_ENTRY; TLB_ENTRIES];
+    }
+
+    pub fn flush_page(&mut self, vaddr: u64)
+    {
+        let vpn = vaddr >> PAGE_SHIFT;
+        let index = vpn as usize % TLB_ENTRIES;
+        for table in [&mut self.read, &mut self.write, &mut self.exec]
+        {
+            if table[index].vpn == vpn
+            {
+                table[index] = EMPTY_ENTRY;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_lookup_hits_only_inserted_access_type()
+    {
+        let mut tlb = Tlb::new();
+        tlb.insert(AccessType::Read, 0x8000_1234, 0x1000);
+        assert_eq!(tlb.lookup(AccessType::Read, 0x8000_1ff8), Some(0x1ff8));
+        assert_eq!(tlb.lookup(AccessType::Write, 0x8000_1ff8), None);
+        assert_eq!(tlb.lookup(AccessType::Execute, 0x8000_1ff8), None);
+        assert_eq!(tlb.lookup(AccessType::Read, 0x8000_2000), None);
+    }
+
+    #[test]
+    fn test_flush_page_and_flush_all()
+    {
+        let mut tlb = Tlb::new();
+        tlb.insert(AccessType::Read, 0x1000, 0);
+        tlb.insert(AccessType::Write, 0x1000, 0);
+        tlb.insert(AccessType::Execute, 0x2000, 0x1000);
+
+        tlb.flush_page(0x1abc);
+        assert_eq!(tlb.lookup(AccessType::Read, 0x1000), None);
+        assert_eq!(tlb.lookup(AccessType::Write, 0x1000), None);
+        assert_eq!(tlb.lookup(AccessType::Execute, 0x2000), Some(0x1000));
+
+        tlb.flush();
+        assert_eq!(tlb.lookup(AccessType::Execute, 0x2000), None);
+    }
+}
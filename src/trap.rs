@@ -0,0 +1,27 @@
+/// Synchronous exceptions, numbered as in the mcause/scause encoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exception
+{
+    InstructionAddressMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadAddressMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreAddressMisaligned = 6,
+    StoreAccessFault = 7,
+    EnvironmentCallFromU = 8,
+    EnvironmentCallFromS = 9,
+    EnvironmentCallFromM = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15,
+}
+
+impl Exception
+{
+    pub fn code(self) -> u64
+    {
+        self as u64
+    }
+}
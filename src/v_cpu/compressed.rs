@@ -0,0 +1,279 @@
+//! The C extension: 16-bit encodings of common instructions, each of which
+//! stands for a 32-bit instruction that executes in its place.
+
+use super::{OPCODE_B, OPCODE_I, OPCODE_I_32, OPCODE_I_JALR, OPCODE_I_LOAD, OPCODE_JAL, OPCODE_LOAD_FP, OPCODE_LUI};
+use super::{OPCODE_R, OPCODE_R_32, OPCODE_S, OPCODE_STORE_FP};
+
+/// The all-zero word, which is an illegal instruction, for reserved
+/// encodings.
+const ILLEGAL: u32 = 0;
+
+/// ebreak, which c.ebreak expands to.
+const EBREAK: u32 = 0x0010_0073;
+
+fn bit(half: u32, n: u32) -> u32
+{
+    (half >> n) & 1
+}
+
+fn bits(half: u32, high: u32, low: u32) -> u32
+{
+    (half >> low) & ((1 << (high - low + 1)) - 1)
+}
+
+/// Sign-extends the low `width` bits of `value`.
+fn sign_extend(value: u32, width: u32) -> u32
+{
+    let shift = 32 - width;
+    ((value << shift) as i32 >> shift) as u32
+}
+
+/// One of x8-x15, as the three-bit register fields name them.
+fn short_register(field: u32) -> u32
+{
+    field + 8
+}
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u8) -> u32
+{
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode as u32
+}
+
+fn i_type(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u8) -> u32
+{
+    ((imm & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode as u32
+}
+
+fn s_type(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u8) -> u32
+{
+    (bits(imm, 11, 5) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (bits(imm, 4, 0) << 7) | opcode as u32
+}
+
+fn b_type(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32
+{
+    (bit(imm, 12) << 31) | (bits(imm, 10, 5) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12)
+        | (bits(imm, 4, 1) << 8) | (bit(imm, 11) << 7) | OPCODE_B as u32
+}
+
+fn j_type(imm: u32, rd: u32) -> u32
+{
+    (bit(imm, 20) << 31) | (bits(imm, 10, 1) << 21) | (bit(imm, 11) << 20) | (bits(imm, 19, 12) << 12) | (rd << 7) | OPCODE_JAL as u32
+}
+
+/// The six-bit immediate of c.addi, c.li, c.andi and friends: imm[5] in
+/// bit 12 and imm[4:0] in bits 6:2.
+fn ci_immediate(half: u32) -> u32
+{
+    (bit(half, 12) << 5) | bits(half, 6, 2)
+}
+
+/// The offset of c.j: imm[11|4|9:8|10|6|7|3:1|5] in bits 12:2.
+fn cj_offset(half: u32) -> u32
+{
+    let offset = (bit(half, 12) << 11) | (bit(half, 11) << 4) | (bits(half, 10, 9) << 8) | (bit(half, 8) << 10)
+        | (bit(half, 7) << 6) | (bit(half, 6) << 7) | (bits(half, 5, 3) << 1) | (bit(half, 2) << 5);
+    sign_extend(offset, 12)
+}
+
+/// The offset of c.beqz and c.bnez: imm[8|4:3] in bits 12:10 and
+/// imm[7:6|2:1|5] in bits 6:2.
+fn cb_offset(half: u32) -> u32
+{
+    let offset = (bit(half, 12) << 8) | (bits(half, 11, 10) << 3) | (bits(half, 6, 5) << 6) | (bits(half, 4, 3) << 1) | (bit(half, 2) << 5);
+    sign_extend(offset, 9)
+}
+
+/// The offset of the word accesses c.lw and c.sw: uimm[5:3] in bits
+/// 12:10, uimm[2] in bit 6 and uimm[6] in bit 5.
+fn word_offset(half: u32) -> u32
+{
+    (bits(half, 12, 10) << 3) | (bit(half, 6) << 2) | (bit(half, 5) << 6)
+}
+
+/// The offset of the doubleword accesses c.ld, c.sd, c.fld and c.fsd:
+/// uimm[5:3] in bits 12:10 and uimm[7:6] in bits 6:5.
+fn doubleword_offset(half: u32) -> u32
+{
+    (bits(half, 12, 10) << 3) | (bits(half, 6, 5) << 6)
+}
+
+/// The 32-bit instruction a 16-bit one stands for, or an illegal
+/// instruction for reserved encodings. Hints expand to the no-ops they are.
+pub(super) fn expand(half: u16) -> u32
+{
+    let half = half as u32;
+    let funct3 = bits(half, 15, 13);
+    // The full-width register fields, and the x8-x15 ones.
+    let rd = bits(half, 11, 7);
+    let rs2 = bits(half, 6, 2);
+    let rd_short = short_register(bits(half, 4, 2));
+    let rs1_short = short_register(bits(half, 9, 7));
+    match (bits(half, 1, 0), funct3)
+    {
+        (0b00, 0b000) =>
+        {
+            // c.addi4spn: nzuimm[5:4|9:6|2|3] in bits 12:5.
+            let imm = (bits(half, 12, 11) << 4) | (bits(half, 10, 7) << 6) | (bit(half, 6) << 2) | (bit(half, 5) << 3);
+            if imm == 0
+            {
+                return ILLEGAL;
+            }
+            i_type(imm, 2, 0x0, rd_short, OPCODE_I)
+        }
+        (0b00, 0b001) => i_type(doubleword_offset(half), rs1_short, 0x3, rd_short, OPCODE_LOAD_FP), // c.fld
+        (0b00, 0b010) => i_type(word_offset(half), rs1_short, 0x2, rd_short, OPCODE_I_LOAD), // c.lw
+        (0b00, 0b011) => i_type(doubleword_offset(half), rs1_short, 0x3, rd_short, OPCODE_I_LOAD), // c.ld
+        (0b00, 0b101) => s_type(doubleword_offset(half), rd_short, rs1_short, 0x3, OPCODE_STORE_FP), // c.fsd
+        (0b00, 0b110) => s_type(word_offset(half), rd_short, rs1_short, 0x2, OPCODE_S), // c.sw
+        (0b00, 0b111) => s_type(doubleword_offset(half), rd_short, rs1_short, 0x3, OPCODE_S), // c.sd
+
+        (0b01, 0b000) => i_type(sign_extend(ci_immediate(half), 6), rd, 0x0, rd, OPCODE_I), // c.addi, c.nop
+        (0b01, 0b001) if rd != 0 => i_type(sign_extend(ci_immediate(half), 6), rd, 0x0, rd, OPCODE_I_32), // c.addiw
+        (0b01, 0b010) => i_type(sign_extend(ci_immediate(half), 6), 0, 0x0, rd, OPCODE_I), // c.li
+        (0b01, 0b011) if rd == 2 =>
+        {
+            // c.addi16sp: nzimm[9] in bit 12 and nzimm[4|6|8:7|5] in bits 6:2.
+            let imm = (bit(half, 12) << 9) | (bit(half, 6) << 4) | (bit(half, 5) << 6) | (bits(half, 4, 3) << 7) | (bit(half, 2) << 5);
+            if imm == 0
+            {
+                return ILLEGAL;
+            }
+            i_type(sign_extend(imm, 10), 2, 0x0, 2, OPCODE_I)
+        }
+        (0b01, 0b011) =>
+        {
+            // c.lui: nzimm[17:12].
+            let imm = ci_immediate(half);
+            if imm == 0
+            {
+                return ILLEGAL;
+            }
+            (sign_extend(imm, 6) << 12) | (rd << 7) | OPCODE_LUI as u32
+        }
+        (0b01, 0b100) =>
+        {
+            let rd = rs1_short;
+            let rs2 = rd_short;
+            match (bits(half, 11, 10), bit(half, 12), bits(half, 6, 5))
+            {
+                (0b00, _, _) => i_type(ci_immediate(half), rd, 0x5, rd, OPCODE_I), // c.srli
+                (0b01, _, _) => i_type(0x400 | ci_immediate(half), rd, 0x5, rd, OPCODE_I), // c.srai
+                (0b10, _, _) => i_type(sign_extend(ci_immediate(half), 6), rd, 0x7, rd, OPCODE_I), // c.andi
+                (0b11, 0, 0b00) => r_type(0x20, rs2, rd, 0x0, rd, OPCODE_R), // c.sub
+                (0b11, 0, 0b01) => r_type(0x00, rs2, rd, 0x4, rd, OPCODE_R), // c.xor
+                (0b11, 0, 0b10) => r_type(0x00, rs2, rd, 0x6, rd, OPCODE_R), // c.or
+                (0b11, 0, 0b11) => r_type(0x00, rs2, rd, 0x7, rd, OPCODE_R), // c.and
+                (0b11, 1, 0b00) => r_type(0x20, rs2, rd, 0x0, rd, OPCODE_R_32), // c.subw
+                (0b11, 1, 0b01) => r_type(0x00, rs2, rd, 0x0, rd, OPCODE_R_32), // c.addw
+                _ => ILLEGAL,
+            }
+        }
+        (0b01, 0b101) => j_type(cj_offset(half), 0), // c.j
+        (0b01, 0b110) => b_type(cb_offset(half), 0, rs1_short, 0x0), // c.beqz
+        (0b01, 0b111) => b_type(cb_offset(half), 0, rs1_short, 0x1), // c.bnez
+
+        (0b10, 0b000) => i_type(ci_immediate(half), rd, 0x1, rd, OPCODE_I), // c.slli
+        (0b10, 0b001) =>
+        {
+            // c.fldsp: uimm[5] in bit 12 and uimm[4:3|8:6] in bits 6:2.
+            let imm = (bit(half, 12) << 5) | (bits(half, 6, 5) << 3) | (bits(half, 4, 2) << 6);
+            i_type(imm, 2, 0x3, rd, OPCODE_LOAD_FP)
+        }
+        (0b10, 0b010) if rd != 0 =>
+        {
+            // c.lwsp: uimm[5] in bit 12 and uimm[4:2|7:6] in bits 6:2.
+            let imm = (bit(half, 12) << 5) | (bits(half, 6, 4) << 2) | (bits(half, 3, 2) << 6);
+            i_type(imm, 2, 0x2, rd, OPCODE_I_LOAD)
+        }
+        (0b10, 0b011) if rd != 0 =>
+        {
+            // c.ldsp
+            let imm = (bit(half, 12) << 5) | (bits(half, 6, 5) << 3) | (bits(half, 4, 2) << 6);
+            i_type(imm, 2, 0x3, rd, OPCODE_I_LOAD)
+        }
+        (0b10, 0b100) => match (bit(half, 12), rd, rs2)
+        {
+            (0, 0, 0) => ILLEGAL,
+            (0, _, 0) => i_type(0, rd, 0x0, 0, OPCODE_I_JALR), // c.jr
+            (0, _, _) => r_type(0x00, rs2, 0, 0x0, rd, OPCODE_R), // c.mv
+            (1, 0, 0) => EBREAK, // c.ebreak
+            (1, _, 0) => i_type(0, rd, 0x0, 1, OPCODE_I_JALR), // c.jalr
+            _ => r_type(0x00, rs2, rd, 0x0, rd, OPCODE_R), // c.add
+        },
+        (0b10, 0b101) =>
+        {
+            // c.fsdsp: uimm[5:3|8:6] in bits 12:7.
+            let imm = (bits(half, 12, 10) << 3) | (bits(half, 9, 7) << 6);
+            s_type(imm, rs2, 2, 0x3, OPCODE_STORE_FP)
+        }
+        (0b10, 0b110) =>
+        {
+            // c.swsp: uimm[5:2|7:6] in bits 12:7.
+            let imm = (bits(half, 12, 9) << 2) | (bits(half, 8, 7) << 6);
+            s_type(imm, rs2, 2, 0x2, OPCODE_S)
+        }
+        (0b10, 0b111) =>
+        {
+            // c.sdsp
+            let imm = (bits(half, 12, 10) << 3) | (bits(half, 9, 7) << 6);
+            s_type(imm, rs2, 2, 0x3, OPCODE_S)
+        }
+        _ => ILLEGAL,
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_expand_compressed_instructions()
+    {
+        // Pairs assembled with and without C.
+        let cases: &[(u16, u32)] = &[
+            (0x0808, 0x0101_0513), // addi a0, sp, 16 (c.addi4spn)
+            (0x6518, 0x0085_3703), // ld a4, 8(a0)
+            (0x4198, 0x0005_a703), // lw a4, 0(a1)
+            (0xe518, 0x00e5_3423), // sd a4, 8(a0)
+            (0x2518, 0x0085_3707), // fld fa4, 8(a0)
+            (0x0505, 0x0015_0513), // addi a0, a0, 1
+            (0x357d, 0xfff5_051b), // addiw a0, a0, -1
+            (0x5579, 0xffe0_0513), // li a0, -2
+            (0x7179, 0xfd01_0113), // addi sp, sp, -48 (c.addi16sp)
+            (0x6505, 0x0000_1537), // lui a0, 1
+            (0x757d, 0xffff_f537), // lui a0, 0xfffff
+            (0x8505, 0x4015_5513), // srai a0, a0, 1
+            (0x893d, 0x00f5_7513), // andi a0, a0, 15
+            (0x8d0d, 0x40b5_0533), // sub a0, a0, a1
+            (0x9d2d, 0x00b5_053b), // addw a0, a0, a1
+            (0xbff5, 0xffdf_f06f), // j -4
+            (0xc111, 0x0005_0263), // beqz a0, 4
+            (0xfd75, 0xfe05_1ee3), // bnez a0, -4
+            (0x050e, 0x0035_1513), // slli a0, a0, 3
+            (0x6522, 0x0081_3503), // ld a0, 8(sp)
+            (0x8082, 0x0000_8067), // ret
+            (0x852e, 0x00b0_0533), // mv a0, a1, as add a0, zero, a1
+            (0x9502, 0x0005_00e7), // jalr a0
+            (0x952e, 0x00b5_0533), // add a0, a0, a1
+            (0x9002, 0x0010_0073), // ebreak
+            (0xe42a, 0x00a1_3423), // sd a0, 8(sp)
+            (0xc62a, 0x00a1_2623), // sw a0, 12(sp)
+            (0xa82a, 0x00a1_3827), // fsd fa0, 16(sp)
+            (0x2542, 0x0101_3507), // fld fa0, 16(sp)
+            (0x4532, 0x00c1_2503), // lw a0, 12(sp)
+            (0x9185, 0x0215_d593), // srli a1, a1, 33
+        ];
+        for &(half, word) in cases
+        {
+            assert_eq!(expand(half), word, "{:04x}", half);
+        }
+
+        // The all-zero parcel and reserved encodings are illegal.
+        assert_eq!(expand(0x0000), ILLEGAL);
+        assert_eq!(expand(0x6101), ILLEGAL); // c.addi16sp with a zero immediate
+        assert_eq!(expand(0x4002), ILLEGAL); // c.lwsp x0
+        assert_eq!(expand(0x8002), ILLEGAL); // c.jr x0
+    }
+}
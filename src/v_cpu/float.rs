@@ -0,0 +1,927 @@
+use super::{DecodedInstruction, VirtualCPU};
+use crate::csr::{FCSR, MSTATUS, MSTATUS_FS, MSTATUS_SD};
+use crate::isa::Extension;
+
+// Rounding modes, as in the rm field and frm
+pub(super) const RNE: u8 = 0;
+pub(super) const RTZ: u8 = 1;
+pub(super) const RDN: u8 = 2;
+pub(super) const RUP: u8 = 3;
+pub(super) const RMM: u8 = 4;
+pub(super) const DYNAMIC: u8 = 7;
+/// Round to odd, only used by vfncvt.rod.f.f.w.
+pub(super) const ROD: u8 = 8;
+
+// fflags bits
+pub(super) const FLAG_NX: u8 = 1 << 0;
+pub(super) const FLAG_UF: u8 = 1 << 1;
+pub(super) const FLAG_OF: u8 = 1 << 2;
+pub(super) const FLAG_DZ: u8 = 1 << 3;
+pub(super) const FLAG_NV: u8 = 1 << 4;
+
+pub(super) const OPCODE_FMADD: u8 = 0b1000011;
+pub(super) const OPCODE_FMSUB: u8 = 0b1000111;
+pub(super) const OPCODE_FNMSUB: u8 = 0b1001011;
+pub(super) const OPCODE_FNMADD: u8 = 0b1001111;
+
+/// The constants fli loads, by rs1. Entry 1 stands for the format's
+/// smallest normal number; the rest are rounded into the format.
+const FLI_VALUES: [f64; 32] = [
+    -1.0, 0.0, 1.0 / 65536.0, 1.0 / 32768.0, 1.0 / 256.0, 1.0 / 128.0, 0.0625, 0.125,
+    0.25, 0.3125, 0.375, 0.4375, 0.5, 0.625, 0.75, 0.875,
+    1.0, 1.25, 1.5, 1.75, 2.0, 2.5, 3.0, 4.0,
+    8.0, 16.0, 128.0, 256.0, 32768.0, 65536.0, f64::INFINITY, f64::NAN,
+];
+
+/// An IEEE 754 binary format held in the f registers.
+///
+/// Arithmetic is carried out on f64 and rounded into the format, which
+/// is exact for half and single precision operands. Double precision
+/// arithmetic therefore always rounds to nearest and never reports NX,
+/// OF or UF; conversions honour every rounding mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Format
+{
+    Half,
+    Single,
+    Double,
+}
+
+impl Format
+{
+    /// The format of an element `bytes` wide.
+    pub(super) fn from_bytes(bytes: usize) -> Option<Self>
+    {
+        match bytes
+        {
+            2 => Some(Format::Half),
+            4 => Some(Format::Single),
+            8 => Some(Format::Double),
+            _ => None,
+        }
+    }
+
+    pub(super) fn width(self) -> u32
+    {
+        match self
+        {
+            Format::Half => 16,
+            Format::Single => 32,
+            Format::Double => 64,
+        }
+    }
+
+    fn fraction_bits(self) -> u32
+    {
+        match self
+        {
+            Format::Half => 10,
+            Format::Single => 23,
+            Format::Double => 52,
+        }
+    }
+
+    fn exponent_bits(self) -> u32
+    {
+        self.width() - 1 - self.fraction_bits()
+    }
+
+    fn bias(self) -> i32
+    {
+        (1 << (self.exponent_bits() - 1)) - 1
+    }
+
+    pub(super) fn sign_bit(self) -> u64
+    {
+        1 << (self.width() - 1)
+    }
+
+    fn exponent_mask(self) -> u64
+    {
+        ((1 << self.exponent_bits()) - 1) << self.fraction_bits()
+    }
+
+    fn fraction_mask(self) -> u64
+    {
+        (1 << self.fraction_bits()) - 1
+    }
+
+    fn mask(self) -> u64
+    {
+        u64::MAX >> (64 - self.width())
+    }
+
+    pub(super) fn canonical_nan(self) -> u64
+    {
+        self.exponent_mask() | (1 << (self.fraction_bits() - 1))
+    }
+
+    /// The value of an f register in this format: narrower values must be
+    /// NaN-boxed, with every upper bit set, or they read as the canonical NaN.
+    pub(super) fn unbox(self, register: u64) -> u64
+    {
+        if self == Format::Double || register | self.mask() == u64::MAX
+        {
+            register & self.mask()
+        }
+        else
+        {
+            self.canonical_nan()
+        }
+    }
+
+    pub(super) fn nan_box(self, bits: u64) -> u64
+    {
+        bits | !self.mask()
+    }
+}
+
+pub(super) fn is_nan(format: Format, bits: u64) -> bool
+{
+    bits & format.exponent_mask() == format.exponent_mask() && bits & format.fraction_mask() != 0
+}
+
+fn is_signaling_nan(format: Format, bits: u64) -> bool
+{
+    is_nan(format, bits) && bits & (1 << (format.fraction_bits() - 1)) == 0
+}
+
+/// The exact value of `bits` as an f64.
+pub(super) fn unpack(format: Format, bits: u64) -> f64
+{
+    if format == Format::Double
+    {
+        return f64::from_bits(bits);
+    }
+    let negative = bits & format.sign_bit() != 0;
+    let exponent = ((bits & format.exponent_mask()) >> format.fraction_bits()) as i32;
+    let fraction = bits & format.fraction_mask();
+    let magnitude = if exponent == (1 << format.exponent_bits()) - 1
+    {
+        if fraction == 0 { f64::INFINITY } else { f64::NAN }
+    }
+    else if exponent == 0
+    {
+        fraction as f64 * 2f64.powi(1 - format.bias() - format.fraction_bits() as i32)
+    }
+    else
+    {
+        (fraction | 1 << format.fraction_bits()) as f64 * 2f64.powi(exponent - format.bias() - format.fraction_bits() as i32)
+    };
+    if negative { -magnitude } else { magnitude }
+}
+
+/// Rounds `magnitude * 2^exponent`, with the given sign, into `format`.
+fn round_pack(format: Format, negative: bool, magnitude: u128, exponent: i32, rm: u8, flags: &mut u8) -> u64
+{
+    let sign = if negative { format.sign_bit() } else { 0 };
+    if magnitude == 0
+    {
+        return sign;
+    }
+    let fraction_bits = format.fraction_bits() as i32;
+    let min_exponent = 1 - format.bias();
+    let leading = exponent + 127 - magnitude.leading_zeros() as i32;
+    let mut unbiased = leading.max(min_exponent);
+    // Bits of `magnitude` below the result's last place.
+    let shift = unbiased - fraction_bits - exponent;
+    let (mut significand, inexact) = if shift <= 0
+    {
+        (magnitude << -shift, false)
+    }
+    else
+    {
+        let shift = shift as u32;
+        let truncated = magnitude.checked_shr(shift).unwrap_or(0);
+        let round = magnitude.checked_shr(shift - 1).unwrap_or(0) & 1 != 0;
+        let below = shift - 1;
+        let sticky = below > 0 && magnitude & (u128::MAX >> (128 - below.min(128))) != 0;
+        let increment = match rm
+        {
+            RTZ | ROD => false,
+            RDN => negative && (round || sticky),
+            RUP => !negative && (round || sticky),
+            RMM => round,
+            _ => round && (sticky || truncated & 1 != 0),
+        };
+        let mut rounded = truncated + increment as u128;
+        if rm == ROD && (round || sticky)
+        {
+            rounded |= 1;
+        }
+        (rounded, round || sticky)
+    };
+    if significand >> (fraction_bits + 1) != 0
+    {
+        significand >>= 1;
+        unbiased += 1;
+    }
+    if inexact
+    {
+        *flags |= FLAG_NX;
+    }
+    if unbiased > format.bias()
+    {
+        *flags |= FLAG_OF | FLAG_NX;
+        let to_infinity = match rm
+        {
+            RTZ | ROD => false,
+            RDN => negative,
+            RUP => !negative,
+            _ => true,
+        };
+        let largest = (format.exponent_mask() - (1 << fraction_bits)) | format.fraction_mask();
+        return sign | if to_infinity { format.exponent_mask() } else { largest };
+    }
+    let biased = if significand >> fraction_bits == 0 { 0 } else { (unbiased + format.bias()) as u64 };
+    if inexact && biased == 0
+    {
+        *flags |= FLAG_UF;
+    }
+    sign | biased << fraction_bits | (significand as u64 & format.fraction_mask())
+}
+
+/// Rounds an f64 into `format`; NaNs become the canonical NaN.
+pub(super) fn pack(format: Format, value: f64, rm: u8, flags: &mut u8) -> u64
+{
+    if value.is_nan()
+    {
+        return format.canonical_nan();
+    }
+    let sign = if value.is_sign_negative() { format.sign_bit() } else { 0 };
+    if value.is_infinite()
+    {
+        return sign | format.exponent_mask();
+    }
+    let bits = value.to_bits();
+    let exponent = ((bits >> 52) & 0x7ff) as i32;
+    let fraction = bits & ((1 << 52) - 1);
+    let (magnitude, exponent) = if exponent == 0 { (fraction, -1074) } else { (fraction | 1 << 52, exponent - 1075) };
+    round_pack(format, sign != 0, magnitude as u128, exponent, rm, flags)
+}
+
+/// NV for signalling NaN operands; a NaN result is made canonical.
+fn finish(format: Format, operands: &[u64], result: f64, rm: u8, flags: &mut u8) -> u64
+{
+    if operands.iter().any(|&operand| is_signaling_nan(format, operand))
+    {
+        *flags |= FLAG_NV;
+    }
+    if result.is_nan()
+    {
+        // A NaN from ordinary operands is an invalid operation.
+        if !operands.iter().any(|&operand| is_nan(format, operand))
+        {
+            *flags |= FLAG_NV;
+        }
+        return format.canonical_nan();
+    }
+    pack(format, result, rm, flags)
+}
+
+pub(super) fn add(format: Format, a: u64, b: u64, rm: u8, flags: &mut u8) -> u64
+{
+    finish(format, &[a, b], unpack(format, a) + unpack(format, b), rm, flags)
+}
+
+pub(super) fn sub(format: Format, a: u64, b: u64, rm: u8, flags: &mut u8) -> u64
+{
+    finish(format, &[a, b], unpack(format, a) - unpack(format, b), rm, flags)
+}
+
+pub(super) fn mul(format: Format, a: u64, b: u64, rm: u8, flags: &mut u8) -> u64
+{
+    finish(format, &[a, b], unpack(format, a) * unpack(format, b), rm, flags)
+}
+
+pub(super) fn div(format: Format, a: u64, b: u64, rm: u8, flags: &mut u8) -> u64
+{
+    let (x, y) = (unpack(format, a), unpack(format, b));
+    if y == 0.0 && x.is_finite() && x != 0.0
+    {
+        *flags |= FLAG_DZ;
+    }
+    finish(format, &[a, b], x / y, rm, flags)
+}
+
+pub(super) fn sqrt(format: Format, a: u64, rm: u8, flags: &mut u8) -> u64
+{
+    finish(format, &[a], unpack(format, a).sqrt(), rm, flags)
+}
+
+/// a * b + c with a single rounding for double precision.
+pub(super) fn fma(format: Format, a: u64, b: u64, c: u64, rm: u8, flags: &mut u8) -> u64
+{
+    let (x, y, z) = (unpack(format, a), unpack(format, b), unpack(format, c));
+    // Zero times infinity is invalid even when the addend is a quiet NaN.
+    if (x.is_infinite() && y == 0.0) || (x == 0.0 && y.is_infinite())
+    {
+        *flags |= FLAG_NV;
+        return format.canonical_nan();
+    }
+    finish(format, &[a, b, c], x.mul_add(y, z), rm, flags)
+}
+
+/// fmin and fmax: a NaN operand loses to a number, and -0 is below +0.
+pub(super) fn min_max(format: Format, a: u64, b: u64, max: bool, flags: &mut u8) -> u64
+{
+    if is_signaling_nan(format, a) || is_signaling_nan(format, b)
+    {
+        *flags |= FLAG_NV;
+    }
+    match (is_nan(format, a), is_nan(format, b))
+    {
+        (true, true) => return format.canonical_nan(),
+        (true, false) => return b,
+        (false, true) => return a,
+        _ => {}
+    }
+    let (x, y) = (unpack(format, a), unpack(format, b));
+    if x == y
+    {
+        // Equal values that differ in bits are zeros of opposite sign.
+        let negative_wins = !max;
+        return if (a & format.sign_bit() != 0) == negative_wins { a } else { b };
+    }
+    if (x < y) != max { a } else { b }
+}
+
+/// fminm and fmaxm: like fmin and fmax, except that a NaN operand gives
+/// the canonical NaN.
+pub(super) fn min_max_propagating(format: Format, a: u64, b: u64, max: bool, flags: &mut u8) -> u64
+{
+    if is_nan(format, a) || is_nan(format, b)
+    {
+        if is_signaling_nan(format, a) || is_signaling_nan(format, b)
+        {
+            *flags |= FLAG_NV;
+        }
+        return format.canonical_nan();
+    }
+    min_max(format, a, b, max, flags)
+}
+
+/// feq (quiet), flt and fle (signalling).
+pub(super) fn compare(format: Format, a: u64, b: u64, less: bool, equal: bool, flags: &mut u8) -> bool
+{
+    let unordered = is_nan(format, a) || is_nan(format, b);
+    if less && unordered
+    {
+        *flags |= FLAG_NV;
+    }
+    compare_quiet(format, a, b, less, equal, flags)
+}
+
+/// feq, fltq and fleq: only signalling NaNs are invalid.
+pub(super) fn compare_quiet(format: Format, a: u64, b: u64, less: bool, equal: bool, flags: &mut u8) -> bool
+{
+    if is_signaling_nan(format, a) || is_signaling_nan(format, b)
+    {
+        *flags |= FLAG_NV;
+    }
+    let (x, y) = (unpack(format, a), unpack(format, b));
+    (less && x < y) || (equal && x == y)
+}
+
+/// fclass: one bit for the category of the value.
+pub(super) fn classify(format: Format, bits: u64) -> u64
+{
+    let negative = bits & format.sign_bit() != 0;
+    let exponent = bits & format.exponent_mask();
+    let fraction = bits & format.fraction_mask();
+    let bit = if is_nan(format, bits)
+    {
+        if is_signaling_nan(format, bits) { 8 } else { 9 }
+    }
+    else if exponent == format.exponent_mask()
+    {
+        if negative { 0 } else { 7 }
+    }
+    else if exponent == 0 && fraction == 0
+    {
+        if negative { 3 } else { 4 }
+    }
+    else if exponent == 0
+    {
+        if negative { 2 } else { 5 }
+    }
+    else if negative { 1 } else { 6 };
+    1 << bit
+}
+
+/// fsgnj (0), fsgnjn (1) and fsgnjx (2): a's magnitude with a sign from b.
+pub(super) fn inject_sign(format: Format, a: u64, b: u64, kind: u8) -> u64
+{
+    let sign = format.sign_bit();
+    let new_sign = match kind
+    {
+        0 => b & sign,
+        1 => !b & sign,
+        _ => (a ^ b) & sign,
+    };
+    (a & !sign) | new_sign
+}
+
+/// Converts to a `bits`-wide integer, saturating out-of-range values
+/// with NV. The result is sign-extended to 64 bits.
+pub(super) fn to_int(format: Format, value: u64, signed: bool, bits: u32, rm: u8, flags: &mut u8) -> u64
+{
+    let (min, max) = if signed
+    {
+        (-(2f64.powi(bits as i32 - 1)), 2f64.powi(bits as i32 - 1) - 1.0)
+    }
+    else
+    {
+        (0.0, 2f64.powi(bits as i32) - 1.0)
+    };
+    let saturate = |to_max: bool| -> u64
+    {
+        let result = match (signed, to_max)
+        {
+            (true, true) => (1i64 << (bits - 1)) as u64 - 1,
+            (true, false) => (-1i64 << (bits - 1)) as u64,
+            (false, true) => u64::MAX >> (64 - bits),
+            (false, false) => 0,
+        };
+        sign_extend(result, bits)
+    };
+    if is_nan(format, value)
+    {
+        *flags |= FLAG_NV;
+        return saturate(true);
+    }
+    let x = unpack(format, value);
+    let rounded = round_to_integer(x, rm);
+    // The maximum may not be exact in f64, so compare against 2^n.
+    if rounded < min || rounded > max || rounded >= max + 1.0
+    {
+        *flags |= FLAG_NV;
+        return saturate(rounded > 0.0);
+    }
+    if rounded != x
+    {
+        *flags |= FLAG_NX;
+    }
+    let result = if rounded < 0.0 { rounded as i64 as u64 } else { rounded as u64 };
+    sign_extend(result & (u64::MAX >> (64 - bits)), bits)
+}
+
+fn round_to_integer(x: f64, rm: u8) -> f64
+{
+    match rm
+    {
+        RNE => x.round_ties_even(),
+        RTZ => x.trunc(),
+        RDN => x.floor(),
+        RUP => x.ceil(),
+        _ => x.round(),
+    }
+}
+
+/// fround and froundnx: rounds to an integral value in the same format.
+/// Only froundnx (`exact`) reports NX.
+pub(super) fn round_to_integral(format: Format, value: u64, rm: u8, exact: bool, flags: &mut u8) -> u64
+{
+    if is_nan(format, value)
+    {
+        if is_signaling_nan(format, value)
+        {
+            *flags |= FLAG_NV;
+        }
+        return format.canonical_nan();
+    }
+    let x = unpack(format, value);
+    let rounded = round_to_integer(x, rm);
+    if exact && rounded != x
+    {
+        *flags |= FLAG_NX;
+    }
+    pack(format, rounded, RNE, &mut 0)
+}
+
+/// fcvtmod.w.d: truncates a double and keeps the low 32 bits of the
+/// integer, sign-extended. Values outside the int32 range still raise NV.
+pub(super) fn to_int_modular(value: u64, flags: &mut u8) -> u64
+{
+    let x = f64::from_bits(value);
+    if !x.is_finite()
+    {
+        *flags |= FLAG_NV;
+        return 0;
+    }
+    let exponent = ((value >> 52) & 0x7ff) as i32;
+    let fraction = value & ((1 << 52) - 1);
+    let (significand, exponent) = if exponent == 0 { (fraction, -1074) } else { (fraction | 1 << 52, exponent - 1075) };
+    let low = match exponent
+    {
+        32.. => 0,
+        0.. => significand << exponent,
+        -63.. => significand >> -exponent,
+        _ => 0,
+    } as u32;
+    let truncated = x.trunc();
+    if !(-2147483648.0..=2147483647.0).contains(&truncated)
+    {
+        *flags |= FLAG_NV;
+    }
+    else if truncated != x
+    {
+        *flags |= FLAG_NX;
+    }
+    let low = if x < 0.0 { low.wrapping_neg() } else { low };
+    sign_extend(low as u64, 32)
+}
+
+/// fli: entry `index` of the constant table in `format`.
+pub(super) fn load_immediate(format: Format, index: usize) -> u64
+{
+    if index == 1
+    {
+        return 1 << format.fraction_bits();
+    }
+    pack(format, FLI_VALUES[index], RNE, &mut 0)
+}
+
+/// Converts the low `bits` of `value`, read as a signed or unsigned integer.
+pub(super) fn from_int(format: Format, value: u64, signed: bool, bits: u32, rm: u8, flags: &mut u8) -> u64
+{
+    let value = value & (u64::MAX >> (64 - bits));
+    let negative = signed && value >> (bits - 1) != 0;
+    let magnitude = if negative { sign_extend(value, bits).wrapping_neg() } else { value };
+    round_pack(format, negative, magnitude as u128, 0, rm, flags)
+}
+
+/// Converts between formats; NaNs become the target's canonical NaN.
+pub(super) fn convert(from: Format, to: Format, value: u64, rm: u8, flags: &mut u8) -> u64
+{
+    finish_conversion(from, to, value, unpack(from, value), rm, flags)
+}
+
+fn finish_conversion(from: Format, to: Format, value: u64, result: f64, rm: u8, flags: &mut u8) -> u64
+{
+    if is_nan(from, value)
+    {
+        if is_signaling_nan(from, value)
+        {
+            *flags |= FLAG_NV;
+        }
+        return to.canonical_nan();
+    }
+    pack(to, result, rm, flags)
+}
+
+/// vfrec7 (reciprocal) and vfrsqrt7 (reciprocal square root) estimates:
+/// the exact result truncated to seven fraction bits.
+pub(super) fn estimate(format: Format, value: u64, square_root: bool, flags: &mut u8) -> u64
+{
+    let x = unpack(format, value);
+    if x == 0.0
+    {
+        *flags |= FLAG_DZ;
+    }
+    let result = if square_root { 1.0 / x.sqrt() } else { 1.0 / x };
+    let bits = finish(format, &[value], result, RTZ, flags);
+    if is_nan(format, bits) || bits & format.exponent_mask() == format.exponent_mask()
+    {
+        return bits;
+    }
+    // Only the estimate's precision matters, not whether it was exact.
+    *flags &= !(FLAG_NX | FLAG_UF);
+    bits & !((1 << (format.fraction_bits() - 7)) - 1)
+}
+
+fn sign_extend(value: u64, bits: u32) -> u64
+{
+    let shift = 64 - bits;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+impl VirtualCPU
+{
+    /// Whether mstatus.FS lets the FPU be used.
+    pub(super) fn fpu_enabled(&self) -> bool
+    {
+        self.csrs[MSTATUS as usize] & MSTATUS_FS != 0
+    }
+
+    /// Marks the FP state dirty, as every instruction that writes it does.
+    pub(super) fn mark_fpu_dirty(&mut self)
+    {
+        self.csrs[MSTATUS as usize] |= MSTATUS_FS | MSTATUS_SD;
+    }
+
+    /// ORs exception flags into fflags.
+    pub(super) fn accrue_fp_flags(&mut self, flags: u8)
+    {
+        if flags != 0
+        {
+            self.csrs[FCSR as usize] |= flags as u64;
+            self.mark_fpu_dirty();
+        }
+    }
+
+    /// The rounding mode to use for an rm field, or None if it or frm
+    /// holds a reserved value.
+    pub(super) fn rounding_mode(&self, rm: u8) -> Option<u8>
+    {
+        let rm = if rm == DYNAMIC { (self.csrs[FCSR as usize] >> 5) as u8 & 0x7 } else { rm };
+        (rm <= RMM).then_some(rm)
+    }
+
+    /// The scalar format of an fmt field, if its extension is enabled.
+    /// Half precision is available with just Zfhmin, which only moves and
+    /// converts it.
+    fn scalar_format(&self, fmt: u8) -> Option<Format>
+    {
+        match fmt
+        {
+            0 if self.isa.has(Extension::F) => Some(Format::Single),
+            1 if self.isa.has(Extension::D) => Some(Format::Double),
+            2 if self.isa.has(Extension::Zfh) || self.isa.has(Extension::Zfhmin) => Some(Format::Half),
+            _ => None,
+        }
+    }
+
+    /// Whether arithmetic in `format` is enabled, not just moves and
+    /// conversions.
+    fn arithmetic_format(&self, format: Format) -> bool
+    {
+        format != Format::Half || self.isa.has(Extension::Zfh)
+    }
+
+    pub(super) fn read_freg(&self, format: Format, reg: usize) -> u64
+    {
+        format.unbox(self.fregs[reg])
+    }
+
+    pub(super) fn write_freg(&mut self, format: Format, reg: usize, bits: u64)
+    {
+        self.fregs[reg] = format.nan_box(bits);
+        self.mark_fpu_dirty();
+    }
+
+    /// flh, flw and fld. Returns None if the instruction is illegal.
+    pub(super) fn execute_load_fp(&mut self, instruction: &DecodedInstruction) -> Option<()>
+    {
+        let format = self.memory_format(instruction.funct3)?;
+        let addr = self.regs[instruction.rs1 as usize].wrapping_add(instruction.imm as i32 as i64 as u64);
+        match self.load(addr, format.width() as usize / 8)
+        {
+            Ok(value) => self.write_freg(format, instruction.rd as usize, value),
+            Err(exception) => self.raise_exception(exception, addr),
+        }
+        Some(())
+    }
+
+    /// fsh, fsw and fsd, which store the register's low bits as they are.
+    pub(super) fn execute_store_fp(&mut self, instruction: &DecodedInstruction) -> Option<()>
+    {
+        let format = self.memory_format(instruction.funct3)?;
+        let addr = self.regs[instruction.rs1 as usize].wrapping_add(instruction.imm as i32 as i64 as u64);
+        let value = self.fregs[instruction.rs2 as usize];
+        if let Err(exception) = self.store(addr, format.width() as usize / 8, value)
+        {
+            self.raise_exception(exception, addr);
+        }
+        Some(())
+    }
+
+    fn memory_format(&self, width: u8) -> Option<Format>
+    {
+        if !self.fpu_enabled()
+        {
+            return None;
+        }
+        match width
+        {
+            0x1 => self.scalar_format(2),
+            0x2 => self.scalar_format(0),
+            0x3 => self.scalar_format(1),
+            _ => None,
+        }
+    }
+
+    /// The fused multiply-add opcodes, with rs3 and fmt in funct7.
+    pub(super) fn execute_fma(&mut self, instruction: &DecodedInstruction) -> Option<()>
+    {
+        if !self.fpu_enabled()
+        {
+            return None;
+        }
+        let format = self.scalar_format(instruction.funct7 & 0x3).filter(|&format| self.arithmetic_format(format))?;
+        let rm = self.rounding_mode(instruction.funct3)?;
+        let a = self.read_freg(format, instruction.rs1 as usize);
+        let b = self.read_freg(format, instruction.rs2 as usize);
+        let c = self.read_freg(format, (instruction.funct7 >> 2) as usize);
+        let sign = format.sign_bit();
+        let (negate_product, negate_addend) = match instruction.opcode
+        {
+            OPCODE_FMADD => (false, false),
+            OPCODE_FMSUB => (false, true),
+            OPCODE_FNMSUB => (true, false),
+            _ => (true, true),
+        };
+        let a = if negate_product { a ^ sign } else { a };
+        let c = if negate_addend { c ^ sign } else { c };
+        let mut flags = 0;
+        let result = fma(format, a, b, c, rm, &mut flags);
+        self.write_freg(format, instruction.rd as usize, result);
+        self.accrue_fp_flags(flags);
+        Some(())
+    }
+
+    /// OP-FP: funct7 holds the operation in bits 6:2 and fmt in bits 1:0.
+    pub(super) fn execute_op_fp(&mut self, instruction: &DecodedInstruction) -> Option<()>
+    {
+        if !self.fpu_enabled()
+        {
+            return None;
+        }
+        let format = self.scalar_format(instruction.funct7 & 0x3)?;
+        let (rd, rs1, rs2) = (instruction.rd as usize, instruction.rs1 as usize, instruction.rs2 as usize);
+        let funct3 = instruction.funct3;
+        let operation = instruction.funct7 >> 2;
+        let zfa = self.isa.has(Extension::Zfa);
+        let moves_or_converts = match operation
+        {
+            0x08 => rs2 <= 2,
+            0x1c => funct3 == 0,
+            // fli.h shares the fmv.h.x encoding but is arithmetic, needing Zfh.
+            0x1e => funct3 == 0 && rs2 == 0,
+            _ => false,
+        };
+        if !moves_or_converts && !self.arithmetic_format(format)
+        {
+            return None;
+        }
+        let a = self.read_freg(format, rs1);
+        let b = self.read_freg(format, rs2);
+        let mut flags = 0;
+        match operation
+        {
+            0x00..=0x03 =>
+            {
+                let rm = self.rounding_mode(funct3)?;
+                let operation = [add, sub, mul, div][operation as usize];
+                let result = operation(format, a, b, rm, &mut flags);
+                self.write_freg(format, rd, result);
+            }
+            0x0b if rs2 == 0 =>
+            {
+                let rm = self.rounding_mode(funct3)?;
+                let result = sqrt(format, a, rm, &mut flags);
+                self.write_freg(format, rd, result);
+            }
+            0x04 if funct3 <= 2 => self.write_freg(format, rd, inject_sign(format, a, b, funct3)),
+            0x05 if funct3 <= 1 =>
+            {
+                let result = min_max(format, a, b, funct3 == 1, &mut flags);
+                self.write_freg(format, rd, result);
+            }
+            0x05 if zfa && funct3 <= 3 =>
+            {
+                // fminm, fmaxm
+                let result = min_max_propagating(format, a, b, funct3 == 3, &mut flags);
+                self.write_freg(format, rd, result);
+            }
+            0x08 if zfa && (rs2 == 4 || rs2 == 5) =>
+            {
+                // fround, froundnx
+                let rm = self.rounding_mode(funct3)?;
+                let result = round_to_integral(format, a, rm, rs2 == 5, &mut flags);
+                self.write_freg(format, rd, result);
+            }
+            0x08 =>
+            {
+                // fcvt between formats; rs2 holds the source fmt.
+                let rm = self.rounding_mode(funct3)?;
+                let from = self.scalar_format(rs2 as u8).filter(|&from| from != format)?;
+                let result = convert(from, format, self.read_freg(from, rs1), rm, &mut flags);
+                self.write_freg(format, rd, result);
+            }
+            0x14 if funct3 <= 2 =>
+            {
+                // fle, flt, feq
+                let result = compare(format, a, b, funct3 <= 1, funct3 != 1, &mut flags);
+                self.regs[rd] = result as u64;
+            }
+            0x14 if zfa && (funct3 == 4 || funct3 == 5) =>
+            {
+                // fleq, fltq
+                let result = compare_quiet(format, a, b, true, funct3 == 4, &mut flags);
+                self.regs[rd] = result as u64;
+            }
+            0x18 if rs2 <= 3 =>
+            {
+                // fcvt.w, fcvt.wu, fcvt.l, fcvt.lu
+                let rm = self.rounding_mode(funct3)?;
+                let bits = if rs2 < 2 { 32 } else { 64 };
+                self.regs[rd] = to_int(format, a, rs2 & 1 == 0, bits, rm, &mut flags);
+            }
+            0x18 if zfa && rs2 == 8 && funct3 == RTZ && format == Format::Double =>
+            {
+                // fcvtmod.w.d
+                self.regs[rd] = to_int_modular(a, &mut flags);
+            }
+            0x1a if rs2 <= 3 =>
+            {
+                // fcvt from w, wu, l, lu
+                let rm = self.rounding_mode(funct3)?;
+                let bits = if rs2 < 2 { 32 } else { 64 };
+                let result = from_int(format, self.regs[rs1], rs2 & 1 == 0, bits, rm, &mut flags);
+                self.write_freg(format, rd, result);
+            }
+            0x1c if rs2 == 0 && funct3 == 0 =>
+            {
+                // fmv.x.h, fmv.x.w and fmv.x.d move the raw low bits.
+                self.regs[rd] = sign_extend(self.fregs[rs1], format.width());
+            }
+            0x1c if rs2 == 0 && funct3 == 1 => self.regs[rd] = classify(format, a),
+            0x1e if rs2 == 0 && funct3 == 0 => self.write_freg(format, rd, self.regs[rs1] & format.mask()),
+            0x1e if zfa && rs2 == 1 && funct3 == 0 => self.write_freg(format, rd, load_immediate(format, rs1)), // fli
+            _ => return None,
+        }
+        self.accrue_fp_flags(flags);
+        Some(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_rounding_and_flags()
+    {
+        let mut flags = 0;
+        let one = 0x3f80_0000;
+        let third = div(Format::Single, one, 0x4040_0000, RNE, &mut flags);
+        assert_eq!(third, 0x3eaa_aaab);
+        assert_eq!(flags, FLAG_NX);
+        assert_eq!(pack(Format::Single, 1.0 / 3.0, RTZ, &mut flags), 0x3eaa_aaaa);
+
+        // Overflow, the smallest subnormal and division by zero.
+        flags = 0;
+        assert_eq!(mul(Format::Single, 0x7f00_0000, 0x7f00_0000, RNE, &mut flags), 0x7f80_0000);
+        assert_eq!(flags, FLAG_OF | FLAG_NX);
+        assert_eq!(pack(Format::Single, 1e30 * 1e30, RTZ, &mut flags), 0x7f7f_ffff);
+        assert_eq!(pack(Format::Single, 2f64.powi(-149), RNE, &mut flags), 1);
+        assert_eq!(pack(Format::Half, 65504.0, RNE, &mut flags), 0x7bff);
+        assert_eq!(unpack(Format::Half, 0x0001), 2f64.powi(-24));
+        flags = 0;
+        assert_eq!(div(Format::Double, 1f64.to_bits(), 0, RNE, &mut flags), f64::INFINITY.to_bits());
+        assert_eq!(flags, FLAG_DZ);
+
+        // Invalid operations give the canonical NaN.
+        flags = 0;
+        assert_eq!(sqrt(Format::Single, 0xbf80_0000, RNE, &mut flags), 0x7fc0_0000);
+        assert_eq!(flags, FLAG_NV);
+        assert_eq!(Format::Single.unbox(0x3f80_0000), 0x7fc0_0000);
+        assert_eq!(Format::Single.unbox(Format::Single.nan_box(one)), one);
+    }
+
+    #[test]
+    fn test_conversions_and_min_max()
+    {
+        let mut flags = 0;
+        let minus_2_5 = (-2.5f64).to_bits();
+        assert_eq!(to_int(Format::Double, minus_2_5, true, 32, RNE, &mut flags), -2i64 as u64);
+        assert_eq!(to_int(Format::Double, minus_2_5, true, 32, RMM, &mut flags), -3i64 as u64);
+        assert_eq!(to_int(Format::Double, minus_2_5, true, 32, RUP, &mut flags), -2i64 as u64);
+        assert_eq!(flags, FLAG_NX);
+        flags = 0;
+        assert_eq!(to_int(Format::Double, minus_2_5, false, 64, RTZ, &mut flags), 0);
+        assert_eq!(to_int(Format::Double, 1e10f64.to_bits(), true, 32, RTZ, &mut flags), 0x7fff_ffff);
+        assert_eq!(to_int(Format::Single, 0x7fc0_0000, false, 32, RTZ, &mut flags), u64::MAX);
+        assert_eq!(flags, FLAG_NV);
+
+        assert_eq!(from_int(Format::Single, -1i64 as u64, true, 64, RNE, &mut flags), 0xbf80_0000);
+        assert_eq!(from_int(Format::Double, u64::MAX, false, 64, RNE, &mut flags), 2f64.powi(64).to_bits());
+        assert_eq!(convert(Format::Double, Format::Single, 0.1f64.to_bits(), RNE, &mut flags), 0x3dcc_cccd);
+
+        let (zero, minus_zero) = (0, Format::Single.sign_bit());
+        assert_eq!(min_max(Format::Single, zero, minus_zero, false, &mut flags), minus_zero);
+        assert_eq!(min_max(Format::Single, minus_zero, zero, true, &mut flags), zero);
+        assert_eq!(min_max(Format::Single, 0x7fc0_0000, 0x3f80_0000, false, &mut flags), 0x3f80_0000);
+        assert_eq!(classify(Format::Single, 0x7f80_0001), 1 << 8);
+        assert_eq!(classify(Format::Double, minus_2_5), 1 << 1);
+
+        // The Zfa additions.
+        flags = 0;
+        assert_eq!(round_to_integral(Format::Double, minus_2_5, RUP, false, &mut flags), (-2.0f64).to_bits());
+        assert_eq!(flags, 0);
+        assert_eq!(round_to_integral(Format::Half, 0xb4cd, RUP, true, &mut flags), 0x8000); // -0.3 to -0
+        assert_eq!(flags, FLAG_NX);
+        flags = 0;
+        assert_eq!(to_int_modular((2f64.powi(32) + 5.75).to_bits(), &mut flags), 5);
+        assert_eq!(flags, FLAG_NV);
+        assert_eq!(to_int_modular((-3.0f64).to_bits(), &mut flags), -3i64 as u64);
+        assert_eq!(load_immediate(Format::Half, 1), 0x0400);
+        assert_eq!(load_immediate(Format::Half, 2), 0x0100);
+        assert_eq!(load_immediate(Format::Half, 29), 0x7c00);
+        assert_eq!(load_immediate(Format::Single, 9), 0x3ea0_0000);
+        assert_eq!(min_max_propagating(Format::Single, 0x7fc0_0000, 0x3f80_0000, false, &mut flags), 0x7fc0_0000);
+    }
+}
@@ -0,0 +1,2145 @@
+use std::collections::HashMap;
+
+use crate::csr::{self, MHARTID, MISA, MEDELEG, MEPC, MCAUSE, MSTATUS, MTVAL, MTVEC, SATP, SCAUSE, SEPC, SIE, SIP, SSTATUS, STVAL, STVEC};
+use crate::csr::{MIDELEG, MIE, MIP, MIP_MEIP, MIP_MSIP, MIP_MTIP, MIP_SEIP, MIP_SSIP, MIP_STIP, MSTATUS_MIE, MSTATUS_MPIE, MSTATUS_MPP, MSTATUS_MPP_SHIFT, MSTATUS_MPRV};
+use crate::csr::{MSTATUS_MXR, MSTATUS_SIE, MSTATUS_SPIE, MSTATUS_SPP, MSTATUS_SUM, SSTATUS_MASK};
+use crate::csr::{FCSR, FFLAGS, FRM, MSTATUS_FS, MSTATUS_SD, MSTATUS_VS, VCSR, VL, VLENB, VSTART, VTYPE, VXRM, VXSAT};
+use crate::csr::{CYCLE, INSTRET, MCYCLE, MINSTRET, MSECCFG, MSECCFG_SSEED, MSECCFG_USEED, SEED};
+use crate::bitmanip;
+use crate::crypto;
+use crate::devices::MmioBus;
+use crate::entropy;
+use crate::isa::{Extension, Isa};
+use crate::ram::Ram;
+use crate::tlb::{AccessType, Tlb, PAGE_MASK, PAGE_SHIFT, PAGE_SIZE};
+use crate::trap::Exception;
+
+mod compressed;
+mod float;
+mod vector;
+
+use vector::VTYPE_VILL;
+
+pub struct DecodedInstruction {
+    opcode: u8,
+    rd: u8,
+    funct3: u8,
+    rs1: u8,
+    rs2: u8,
+    funct7: u8,
+    imm: u32,
+    /// 2 for a compressed instruction, otherwise 4.
+    length: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege
+{
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege
+{
+    fn from_bits(bits: u64) -> Self
+    {
+        match bits & 0b11
+        {
+            0 => Privilege::User,
+            1 => Privilege::Supervisor,
+            _ => Privilege::Machine,
+        }
+    }
+}
+
+/// Where a guest-physical access ends up after translation.
+enum Resolved
+{
+    /// Offset into the RAM backing buffer.
+    Host(usize),
+    /// Physical address outside RAM, served by a device or the sparse `memory` map.
+    Mmio(u64),
+}
+
+/// Events that stop `step` and need handling outside the CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmExit
+{
+    /// An ecall from S-mode that the VMM services as the SBI implementation.
+    SupervisorCall,
+    /// A wfi with no interrupt pending; the hart can be descheduled.
+    WaitForInterrupt,
+}
+
+/// Sv39 page tables map to 56-bit physical addresses, the widest RISC-V has.
+const PHYSICAL_ADDRESS_BITS: u32 = 56;
+
+/// Interrupts in the order they are taken when several are pending.
+const INTERRUPT_PRIORITY: [u64; 6] = [MIP_MEIP, MIP_MSIP, MIP_MTIP, MIP_SEIP, MIP_SSIP, MIP_STIP];
+
+/// The state of a hart that is not currently loaded into the VirtualCPU.
+///
+/// Harts share one VirtualCPU, and so its RAM and devices; the machine
+/// swaps a hart's context in with `swap_context` to run it.
+pub struct HartContext
+{
+    pub regs: [u64; 32],
+    pub pc: u64,
+    pub privilege: Privilege,
+    fregs: [u64; 32],
+    vregs: Vec<u8>,
+    csrs: Vec<u64>,
+    tlb: Tlb,
+}
+
+impl HartContext
+{
+    /// A hart in M-mode at reset, with only mhartid and vtype.vill set.
+    pub fn new(hart_id: u64) -> Self
+    {
+        let mut csrs = vec![0; 4096];
+        csrs[MHARTID as usize] = hart_id;
+        csrs[VTYPE as usize] = VTYPE_VILL;
+        HartContext
+        {
+            regs: [0; 32],
+            pc: 0,
+            privilege: Privilege::Machine,
+            fregs: [0; 32],
+            // Sized to VLEN when the hart is first swapped in.
+            vregs: Vec::new(),
+            csrs,
+            tlb: Tlb::new(),
+        }
+    }
+}
+
+pub struct VirtualCPU {
+    pub regs: [u64; 32],
+    pub pc: u64,
+    /// Sparse byte-addressed storage for physical addresses outside `ram`.
+    pub memory: HashMap<u64, u8>,
+    pub ram: Ram,
+    pub mmio: MmioBus,
+    pub privilege: Privilege,
+    /// Makes S-mode ecalls exit to the VMM instead of trapping, for booting
+    /// a kernel without M-mode firmware.
+    pub intercept_sbi: bool,
+    pub fregs: [u64; 32],
+    /// Under a tail- or mask-agnostic vector policy, overwrite the elements
+    /// it covers with all ones instead of leaving them undisturbed. Both are
+    /// allowed; ones catch guests that wrongly rely on the old values.
+    pub agnostic_ones: bool,
+    /// Extensions every hart implements; the rest are illegal instructions.
+    isa: Isa,
+    /// The 32 vector registers, VLEN / 8 bytes each.
+    vregs: Vec<u8>,
+    vlenb: usize,
+    csrs: Vec<u64>,
+    tlb: Tlb,
+    /// Set by instructions and traps that write pc, so it is not advanced.
+    jumped: bool,
+    /// Set when the current instruction raises an exception, so it does
+    /// not count as retired.
+    trapped: bool,
+    /// The address an lr.w or lr.d reserved, until an sc or a hart switch.
+    reservation: Option<u64>,
+    exit: Option<VmExit>,
+}
+
+const OPCODE_R: u8 = 0b0110011;
+const OPCODE_R_32: u8 = 0b0111011;
+const OPCODE_I: u8 = 0b0010011;
+const OPCODE_I_32: u8 = 0b0011011;
+const OPCODE_I_LOAD: u8 = 0b0000011;
+const OPCODE_I_ENV: u8 = 0b1110011;
+const OPCODE_S: u8 = 0b0100011;
+const OPCODE_LUI: u8 = 0b0110111;
+const OPCODE_AUIPC: u8 = 0b0010111;
+const OPCODE_B: u8 = 0b1100011;
+const OPCODE_JAL: u8 = 0b1101111;
+const OPCODE_I_JALR: u8 = 0b1100111;
+const OPCODE_MISC_MEM: u8 = 0b0001111;
+const OPCODE_AMO: u8 = 0b0101111;
+const OPCODE_LOAD_FP: u8 = 0b0000111;
+const OPCODE_STORE_FP: u8 = 0b0100111;
+const OPCODE_OP_FP: u8 = 0b1010011;
+
+/// VLEN unless the machine configures another.
+const DEFAULT_VLEN: usize = 128;
+
+// Sv39 page table entry bits
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+const PTE_PPN_MASK: u64 = (1 << 44) - 1;
+
+/// Sign-extends the 32-bit result of a W instruction.
+fn sign_extend_word(word: u32) -> u64
+{
+    word as i32 as i64 as u64
+}
+
+impl Default for VirtualCPU
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl VirtualCPU 
+{
+    pub fn new() -> Self 
+    {
+        Self::with_ram(0, 0)
+    }
+
+    pub fn with_ram(ram_base: u64, ram_size: usize) -> Self
+    {
+        let mut csrs = vec![0; 4096];
+        csrs[VTYPE as usize] = VTYPE_VILL;
+        VirtualCPU 
+        {
+            regs: [0; 32],
+            pc: 0,
+            memory: HashMap::new(),
+            ram: Ram::new(ram_base, ram_size),
+            mmio: MmioBus::new(),
+            privilege: Privilege::Machine,
+            intercept_sbi: false,
+            fregs: [0; 32],
+            agnostic_ones: false,
+            isa: Isa::default(),
+            vregs: vec![0; 32 * DEFAULT_VLEN / 8],
+            vlenb: DEFAULT_VLEN / 8,
+            csrs,
+            tlb: Tlb::new(),
+            jumped: false,
+            trapped: false,
+            reservation: None,
+            exit: None,
+        }
+    }
+
+    pub fn isa(&self) -> Isa
+    {
+        self.isa
+    }
+
+    /// Restricts the harts to `isa`, which also sets what misa reads.
+    pub fn set_isa(&mut self, isa: Isa)
+    {
+        self.isa = isa;
+    }
+
+    /// Sets VLEN, the width of a vector register in bits, and clears the
+    /// vector registers.
+    pub fn set_vlen(&mut self, bits: usize)
+    {
+        assert!(bits.is_power_of_two() && (128..=65536).contains(&bits), "VLEN must be a power of two from 128 to 65536");
+        self.vlenb = bits / 8;
+        self.vregs = vec![0; 32 * self.vlenb];
+    }
+
+    /// Fetches the instruction at pc. On failure, returns the exception and
+    /// the address that faulted.
+    pub fn fetch(&mut self) -> Result<u32, (Exception, u64)>
+    {
+        if !self.isa.has(Extension::C)
+        {
+            return self.read_mem(self.pc, 4, AccessType::Execute).map(|word| word as u32).map_err(|exception| (exception, self.pc));
+        }
+        // With C, instructions are only 16-bit aligned, so a 32-bit one is
+        // fetched a half at a time: its second half may be on a page the
+        // first does not need.
+        let low = self.read_mem(self.pc, 2, AccessType::Execute).map_err(|exception| (exception, self.pc))? as u32;
+        if low & 0b11 != 0b11
+        {
+            return Ok(low);
+        }
+        let upper = self.pc.wrapping_add(2);
+        let high = self.read_mem(upper, 2, AccessType::Execute).map_err(|exception| (exception, upper))? as u32;
+        Ok(low | (high << 16))
+    }
+
+    /// Decodes an instruction, expanding a compressed one in the low half
+    /// of `instruction` first when C is enabled.
+    pub fn decode(&self, instruction: u32) -> DecodedInstruction 
+    {
+        let (instruction, length) = match instruction & 0b11
+        {
+            0b11 => (instruction, 4),
+            _ if self.isa.has(Extension::C) => (compressed::expand(instruction as u16), 2),
+            // Illegal without C; the low bits make it so.
+            _ => (instruction, 4),
+        };
+        let opcode = (instruction & 0x7f) as u8;
+        let rd = ((instruction >> 7) & 0x1f) as u8;
+        let funct3 = ((instruction >> 12) & 0x07) as u8;
+        let rs1 = ((instruction >> 15) & 0x1f) as u8;
+        let rs2 = ((instruction >> 20) & 0x1f) as u8;
+        let funct7 = ((instruction >> 25) & 0x7f) as u8;
+        let imm = self.decode_immediate(opcode, instruction);
+
+        DecodedInstruction 
+        {
+            opcode,
+            rd,
+            funct3,
+            rs1,
+            rs2,
+            funct7,
+            imm,
+            length,
+        }
+    }
+
+    fn decode_immediate(&self, opcode: u8, instruction: u32) -> u32 
+    {
+        match opcode 
+        {
+            OPCODE_I | OPCODE_I_32 | OPCODE_I_LOAD | OPCODE_LOAD_FP | OPCODE_I_ENV | OPCODE_I_JALR => 
+            {
+                self.sign_extend(instruction >> 20, 12)
+            }
+            OPCODE_S | OPCODE_STORE_FP => 
+            {
+                let imm = ((instruction >> 25) << 5) | ((instruction >> 7) & 0x1f);
+                self.sign_extend(imm, 12)
+            }
+            OPCODE_B => 
+            {
+                let imm = (((instruction >> 31) & 0x1) << 12)
+                    | (((instruction >> 7) & 0x1) << 11)
+                    | (((instruction >> 25) & 0x3f) << 5)
+                    | (((instruction >> 8) & 0xf) << 1);       
+                self.sign_extend(imm & !0x1, 13)
+            }
+            OPCODE_LUI | OPCODE_AUIPC => 
+            {
+                instruction & 0xfffff000
+            }
+            OPCODE_JAL => 
+            {
+                let imm = (((instruction >> 31) & 0x1) << 20)
+                    | (((instruction >> 21) & 0x3ff) << 1)
+                    | (((instruction >> 20) & 0x1) << 11)
+                    | (((instruction >> 12) & 0xff) << 12);
+                self.sign_extend(imm, 21)
+            }
+            _ => 0, // Handle other opcodes if needed
+        }
+    }
+
+    fn sign_extend(&self, imm: u32, bits: u32) -> u32 
+    {
+        let shift = 32 - bits;
+        ((imm << shift) as i32 >> shift) as u32
+    }
+
+    /// Fetches, decodes and executes one instruction, taking any trap it
+    /// raises. Returns the event if the instruction needs the VMM.
+    pub fn step(&mut self) -> Option<VmExit>
+    {
+        self.exit = None;
+        if self.take_interrupt()
+        {
+            return None;
+        }
+        match self.fetch()
+        {
+            Ok(instruction) =>
+            {
+                let decoded = self.decode(instruction);
+                self.execute(decoded);
+            }
+            Err((exception, tval)) => self.raise_exception(exception, tval),
+        }
+        self.csrs[MCYCLE as usize] = self.csrs[MCYCLE as usize].wrapping_add(1);
+        self.exit.take()
+    }
+
+    /// Executes one instruction and moves pc past it unless it jumped or trapped.
+    pub fn execute(&mut self, instruction: DecodedInstruction)
+    {
+        let length = instruction.length;
+        self.jumped = false;
+        self.trapped = false;
+        self.execute_instruction(instruction);
+        if !self.jumped
+        {
+            self.pc = self.pc.wrapping_add(length);
+        }
+        if !self.trapped
+        {
+            self.csrs[MINSTRET as usize] = self.csrs[MINSTRET as usize].wrapping_add(1);
+        }
+        self.regs[0] = 0;
+    }
+
+    /// Jumps to `target` once the current instruction finishes.
+    fn jump(&mut self, target: u64)
+    {
+        self.pc = target;
+        self.jumped = true;
+    }
+
+    fn execute_instruction(&mut self, instruction: DecodedInstruction)
+    {
+        let rd = instruction.rd as usize;
+        let rs1 = instruction.rs1 as usize;
+        let rs2 = instruction.rs2 as usize;
+        let imm = instruction.imm as i32 as i64 as u64;
+        match instruction.opcode 
+        {
+            OPCODE_R | OPCODE_R_32 | OPCODE_I | OPCODE_I_32 =>
+            {
+                let value = match instruction.opcode
+                {
+                    OPCODE_R => self.execute_op(&instruction, self.regs[rs1], self.regs[rs2]),
+                    OPCODE_R_32 => self.execute_op_32(&instruction, self.regs[rs1], self.regs[rs2]),
+                    OPCODE_I => self.execute_op_imm(&instruction, self.regs[rs1], imm),
+                    _ => self.execute_op_imm_32(&instruction, self.regs[rs1], imm),
+                };
+                match value
+                {
+                    Some(value) => self.regs[rd] = value,
+                    None => self.raise_exception(Exception::IllegalInstruction, 0),
+                }
+            }
+
+            OPCODE_I_LOAD =>
+            {
+                let addr = self.regs[rs1].wrapping_add(imm);
+                let value = match instruction.funct3
+                {
+                    0x0 => self.load(addr, 1).map(|byte| byte as i8 as i64 as u64), // lb
+                    0x1 => self.load(addr, 2).map(|half| half as i16 as i64 as u64), // lh
+                    0x2 => self.load(addr, 4).map(|word| word as i32 as i64 as u64), // lw
+                    0x3 => self.load(addr, 8), // ld
+                    0x4 => self.load(addr, 1), // lbu
+                    0x5 => self.load(addr, 2), // lhu
+                    0x6 => self.load(addr, 4), // lwu
+                    _ => return self.raise_exception(Exception::IllegalInstruction, 0),
+                };
+                match value
+                {
+                    Ok(value) => self.regs[rd] = value,
+                    Err(exception) => self.raise_exception(exception, addr),
+                }
+            }
+                    
+            OPCODE_S => 
+            {
+                let addr = self.regs[rs1].wrapping_add(imm);
+                let size = match instruction.funct3 
+                {
+                    0x0 => 1, // sb
+                    0x1 => 2, // sh
+                    0x2 => 4, // sw
+                    0x3 => 8, // sd
+                    _ => return self.raise_exception(Exception::IllegalInstruction, 0),
+                };
+                if let Err(exception) = self.store(addr, size, self.regs[rs2])
+                {
+                    self.raise_exception(exception, addr);
+                }
+            }
+            OPCODE_B => 
+            {
+                let taken = match instruction.funct3 
+                {
+                    0x0 => self.regs[rs1] == self.regs[rs2], // beq
+                    0x1 => self.regs[rs1] != self.regs[rs2], // bne
+                    0x4 => (self.regs[rs1] as i64) < (self.regs[rs2] as i64), // blt
+                    0x5 => (self.regs[rs1] as i64) >= (self.regs[rs2] as i64), // bge
+                    0x6 => self.regs[rs1] < self.regs[rs2], // bltu
+                    0x7 => self.regs[rs1] >= self.regs[rs2], // bgeu
+                    _ => return self.raise_exception(Exception::IllegalInstruction, 0),
+                };
+                if taken
+                {
+                    self.jump(self.pc.wrapping_add(imm));
+                }
+            }
+            OPCODE_JAL => 
+            {
+                // jal
+                self.regs[rd] = self.pc.wrapping_add(instruction.length);
+                self.jump(self.pc.wrapping_add(imm));
+            }
+            OPCODE_I_JALR if instruction.funct3 == 0 => 
+            {
+                // jalr
+                let target = self.regs[rs1].wrapping_add(imm) & !1;
+                self.regs[rd] = self.pc.wrapping_add(instruction.length);
+                self.jump(target);
+            }
+            OPCODE_LUI => 
+            {
+                // lui
+                self.regs[rd] = imm;
+            }
+            OPCODE_AUIPC => 
+            {
+                // auipc
+                self.regs[rd] = self.pc.wrapping_add(imm);
+            }
+            OPCODE_MISC_MEM =>
+            {
+                match instruction.funct3
+                {
+                    // Accesses complete in order and nothing caches
+                    // instructions, so both fences have nothing to wait for.
+                    0x0 => {} // fence
+                    0x1 if self.isa.has(Extension::Zifencei) => {} // fence.i
+                    _ => self.raise_exception(Exception::IllegalInstruction, 0),
+                }
+            }
+            OPCODE_I_ENV =>
+            {
+                self.execute_system(&instruction);
+            }
+            OPCODE_AMO =>
+            {
+                self.execute_amo(&instruction);
+            }
+            OPCODE_LOAD_FP | OPCODE_STORE_FP | OPCODE_OP_FP | float::OPCODE_FMADD | float::OPCODE_FMSUB
+                | float::OPCODE_FNMSUB | float::OPCODE_FNMADD | vector::OPCODE_V =>
+            {
+                // Widths 1-4 are scalar; the others are vector accesses.
+                let scalar_width = (0x1..=0x4).contains(&instruction.funct3);
+                let executed = match instruction.opcode
+                {
+                    OPCODE_LOAD_FP if scalar_width => self.execute_load_fp(&instruction),
+                    OPCODE_LOAD_FP => self.execute_vector_memory(&instruction, false),
+                    OPCODE_STORE_FP if scalar_width => self.execute_store_fp(&instruction),
+                    OPCODE_STORE_FP => self.execute_vector_memory(&instruction, true),
+                    OPCODE_OP_FP => self.execute_op_fp(&instruction),
+                    vector::OPCODE_V => self.execute_op_v(&instruction),
+                    _ => self.execute_fma(&instruction),
+                };
+                if executed.is_none()
+                {
+                    self.raise_exception(Exception::IllegalInstruction, 0);
+                }
+            }
+            _ => self.raise_exception(Exception::IllegalInstruction, 0),
+        }
+    }
+
+    /// Register-register ALU instructions, or None if the encoding is not
+    /// one of an enabled extension.
+    fn execute_op(&self, instruction: &DecodedInstruction, a: u64, b: u64) -> Option<u64>
+    {
+        let m = self.isa.has(Extension::M);
+        let zba = self.isa.has(Extension::Zba);
+        let zbb = self.isa.has(Extension::Zbb);
+        let zbc = self.isa.has(Extension::Zbc);
+        let zbs = self.isa.has(Extension::Zbs);
+        let zbkb = self.isa.has(Extension::Zbkb);
+        let zbkc = self.isa.has(Extension::Zbkc);
+        let zbkx = self.isa.has(Extension::Zbkx);
+        let zknd = self.isa.has(Extension::Zknd);
+        let zkne = self.isa.has(Extension::Zkne);
+        let zksed = self.isa.has(Extension::Zksed);
+        let shamt = (b & 0x3f) as u32;
+        // sm4ed and sm4ks keep a byte select in funct7[6:5].
+        let bs = (instruction.funct7 >> 5) as u32;
+        let value = match (instruction.funct7, instruction.funct3)
+        {
+            (0x00, 0x0) => a.wrapping_add(b), // add
+            (0x20, 0x0) => a.wrapping_sub(b), // sub
+            (0x00, 0x1) => a << shamt, // sll
+            (0x00, 0x2) => ((a as i64) < (b as i64)) as u64, // slt
+            (0x00, 0x3) => (a < b) as u64, // sltu
+            (0x00, 0x4) => a ^ b, // xor
+            (0x00, 0x5) => a >> shamt, // srl
+            (0x20, 0x5) => ((a as i64) >> shamt) as u64, // sra
+            (0x00, 0x6) => a | b, // or
+            (0x00, 0x7) => a & b, // and
+            (0x01, 0x0) if m => a.wrapping_mul(b), // mul
+            (0x01, 0x1) if m => ((a as i64 as i128 * b as i64 as i128) >> 64) as u64, // mulh
+            (0x01, 0x2) if m => ((a as i64 as i128 * b as i128) >> 64) as u64, // mulhsu
+            (0x01, 0x3) if m => ((a as u128 * b as u128) >> 64) as u64, // mulhu
+            // Division by zero gives all ones and the dividend as the
+            // remainder; the one overflowing division wraps.
+            (0x01, 0x4) if m => if b == 0 { u64::MAX } else { (a as i64).wrapping_div(b as i64) as u64 }, // div
+            (0x01, 0x5) if m => a.checked_div(b).unwrap_or(u64::MAX), // divu
+            (0x01, 0x6) if m => if b == 0 { a } else { (a as i64).wrapping_rem(b as i64) as u64 }, // rem
+            (0x01, 0x7) if m => a.checked_rem(b).unwrap_or(a), // remu
+            (0x10, 0x2) if zba => (a << 1).wrapping_add(b), // sh1add
+            (0x10, 0x4) if zba => (a << 2).wrapping_add(b), // sh2add
+            (0x10, 0x6) if zba => (a << 3).wrapping_add(b), // sh3add
+            (0x20, 0x7) if zbb || zbkb => a & !b, // andn
+            (0x20, 0x6) if zbb || zbkb => a | !b, // orn
+            (0x20, 0x4) if zbb || zbkb => !(a ^ b), // xnor
+            (0x05, 0x4) if zbb => (a as i64).min(b as i64) as u64, // min
+            (0x05, 0x5) if zbb => a.min(b), // minu
+            (0x05, 0x6) if zbb => (a as i64).max(b as i64) as u64, // max
+            (0x05, 0x7) if zbb => a.max(b), // maxu
+            (0x30, 0x1) if zbb || zbkb => a.rotate_left(shamt), // rol
+            (0x30, 0x5) if zbb || zbkb => a.rotate_right(shamt), // ror
+            (0x04, 0x4) if zbkb => (b << 32) | (a & 0xffff_ffff), // pack
+            (0x04, 0x7) if zbkb => ((b & 0xff) << 8) | (a & 0xff), // packh
+            (0x05, 0x1) if zbc || zbkc => bitmanip::clmul(a, b),
+            (0x05, 0x3) if zbc || zbkc => bitmanip::clmulh(a, b),
+            (0x05, 0x2) if zbc => bitmanip::clmulr(a, b),
+            (0x14, 0x1) if zbs => a | (1 << shamt), // bset
+            (0x24, 0x1) if zbs => a & !(1 << shamt), // bclr
+            (0x34, 0x1) if zbs => a ^ (1 << shamt), // binv
+            (0x24, 0x5) if zbs => (a >> shamt) & 1, // bext
+            (0x14, 0x2) if zbkx => bitmanip::xperm4(a, b),
+            (0x14, 0x4) if zbkx => bitmanip::xperm8(a, b),
+            (0x19, 0x0) if zkne => crypto::aes64es(a, b),
+            (0x1b, 0x0) if zkne => crypto::aes64esm(a, b),
+            (0x1d, 0x0) if zknd => crypto::aes64ds(a, b),
+            (0x1f, 0x0) if zknd => crypto::aes64dsm(a, b),
+            (0x3f, 0x0) if zknd || zkne => crypto::aes64ks2(a, b),
+            (0x18 | 0x38 | 0x58 | 0x78, 0x0) if zksed => crypto::sm4ed(a, b, bs),
+            (0x1a | 0x3a | 0x5a | 0x7a, 0x0) if zksed => crypto::sm4ks(a, b, bs),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Register-register instructions on the low 32 bits (OP-32).
+    fn execute_op_32(&self, instruction: &DecodedInstruction, a: u64, b: u64) -> Option<u64>
+    {
+        let m = self.isa.has(Extension::M);
+        let zba = self.isa.has(Extension::Zba);
+        let zbb = self.isa.has(Extension::Zbb);
+        let zbkb = self.isa.has(Extension::Zbkb);
+        let shamt = (b & 0x1f) as u32;
+        let word = a as u32;
+        let divisor = b as u32;
+        let uw = a & 0xffff_ffff;
+        let value = match (instruction.funct7, instruction.funct3)
+        {
+            (0x00, 0x0) => sign_extend_word(word.wrapping_add(b as u32)), // addw
+            (0x20, 0x0) => sign_extend_word(word.wrapping_sub(b as u32)), // subw
+            (0x00, 0x1) => sign_extend_word(word << shamt), // sllw
+            (0x00, 0x5) => sign_extend_word(word >> shamt), // srlw
+            (0x20, 0x5) => ((word as i32) >> shamt) as i64 as u64, // sraw
+            (0x01, 0x0) if m => sign_extend_word(word.wrapping_mul(divisor)), // mulw
+            (0x01, 0x4) if m => if divisor == 0 { u64::MAX } else { sign_extend_word((word as i32).wrapping_div(divisor as i32) as u32) }, // divw
+            (0x01, 0x5) if m => word.checked_div(divisor).map_or(u64::MAX, sign_extend_word), // divuw
+            (0x01, 0x6) if m => sign_extend_word(if divisor == 0 { word } else { (word as i32).wrapping_rem(divisor as i32) as u32 }), // remw
+            (0x01, 0x7) if m => sign_extend_word(word.checked_rem(divisor).unwrap_or(word)), // remuw
+            (0x04, 0x0) if zba => uw.wrapping_add(b), // add.uw
+            (0x10, 0x2) if zba => (uw << 1).wrapping_add(b), // sh1add.uw
+            (0x10, 0x4) if zba => (uw << 2).wrapping_add(b), // sh2add.uw
+            (0x10, 0x6) if zba => (uw << 3).wrapping_add(b), // sh3add.uw
+            (0x04, 0x4) if zbb && instruction.rs2 == 0 => a & 0xffff, // zext.h
+            (0x04, 0x4) if zbkb => sign_extend_word(((b as u32) << 16) | (word & 0xffff)), // packw
+            (0x30, 0x1) if zbb || zbkb => sign_extend_word(word.rotate_left(shamt)), // rolw
+            (0x30, 0x5) if zbb || zbkb => sign_extend_word(word.rotate_right(shamt)), // rorw
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Register-immediate ALU instructions. Shifts and the unary bitmanip
+    /// instructions keep a funct6 in imm[11:6].
+    fn execute_op_imm(&self, instruction: &DecodedInstruction, a: u64, imm: u64) -> Option<u64>
+    {
+        let zbb = self.isa.has(Extension::Zbb);
+        let zbs = self.isa.has(Extension::Zbs);
+        let zbkb = self.isa.has(Extension::Zbkb);
+        let zknd = self.isa.has(Extension::Zknd);
+        let zkne = self.isa.has(Extension::Zkne);
+        let zknh = self.isa.has(Extension::Zknh);
+        let zksh = self.isa.has(Extension::Zksh);
+        let funct6 = (imm >> 6) & 0x3f;
+        let shamt = (imm & 0x3f) as u32;
+        let value = match (instruction.funct3, funct6)
+        {
+            (0x0, _) => a.wrapping_add(imm), // addi
+            (0x2, _) => ((a as i64) < (imm as i64)) as u64, // slti
+            (0x3, _) => (a < imm) as u64, // sltiu
+            (0x4, _) => a ^ imm, // xori
+            (0x6, _) => a | imm, // ori
+            (0x7, _) => a & imm, // andi
+            (0x1, 0x00) => a << shamt, // slli
+            (0x5, 0x00) => a >> shamt, // srli
+            (0x5, 0x10) => ((a as i64) >> shamt) as u64, // srai
+            (0x1, 0x18) if zbb => match shamt
+            {
+                0x0 => a.leading_zeros() as u64, // clz
+                0x1 => a.trailing_zeros() as u64, // ctz
+                0x2 => a.count_ones() as u64, // cpop
+                0x4 => a as i8 as i64 as u64, // sext.b
+                0x5 => a as i16 as i64 as u64, // sext.h
+                _ => return None,
+            },
+            (0x5, 0x18) if zbb || zbkb => a.rotate_right(shamt), // rori
+            (0x5, 0x0a) if zbb && shamt == 0x07 => bitmanip::orc_b(a),
+            (0x5, 0x1a) if (zbb || zbkb) && shamt == 0x38 => a.swap_bytes(), // rev8
+            (0x5, 0x1a) if zbkb && shamt == 0x07 => bitmanip::brev8(a),
+            (0x1, 0x04) => match shamt
+            {
+                0x0 if zknh => crypto::sha256sum0(a),
+                0x1 if zknh => crypto::sha256sum1(a),
+                0x2 if zknh => crypto::sha256sig0(a),
+                0x3 if zknh => crypto::sha256sig1(a),
+                0x4 if zknh => crypto::sha512sum0(a),
+                0x5 if zknh => crypto::sha512sum1(a),
+                0x6 if zknh => crypto::sha512sig0(a),
+                0x7 if zknh => crypto::sha512sig1(a),
+                0x8 if zksh => crypto::sm3p0(a),
+                0x9 if zksh => crypto::sm3p1(a),
+                _ => return None,
+            },
+            (0x1, 0x0c) if zknd && shamt == 0x00 => crypto::aes64im(a),
+            // aes64ks1i keeps its round number in imm[3:0].
+            (0x1, 0x0c) if (zknd || zkne) && shamt & 0x30 == 0x10 => crypto::aes64ks1i(a, shamt & 0xf)?,
+            (0x1, 0x0a) if zbs => a | (1 << shamt), // bseti
+            (0x1, 0x12) if zbs => a & !(1 << shamt), // bclri
+            (0x1, 0x1a) if zbs => a ^ (1 << shamt), // binvi
+            (0x5, 0x12) if zbs => (a >> shamt) & 1, // bexti
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Register-immediate instructions on the low 32 bits (OP-IMM-32).
+    fn execute_op_imm_32(&self, instruction: &DecodedInstruction, a: u64, imm: u64) -> Option<u64>
+    {
+        let zba = self.isa.has(Extension::Zba);
+        let zbb = self.isa.has(Extension::Zbb);
+        let zbkb = self.isa.has(Extension::Zbkb);
+        let funct7 = (imm >> 5) & 0x7f;
+        let shamt = (imm & 0x1f) as u32;
+        let word = a as u32;
+        let value = match (instruction.funct3, funct7)
+        {
+            (0x0, _) => sign_extend_word(word.wrapping_add(imm as u32)), // addiw
+            (0x1, 0x00) => sign_extend_word(word << shamt), // slliw
+            (0x5, 0x00) => sign_extend_word(word >> shamt), // srliw
+            (0x5, 0x20) => ((word as i32) >> shamt) as i64 as u64, // sraiw
+            // slli.uw has a 6-bit shift amount, so funct6 is 0x02.
+            (0x1, 0x04 | 0x05) if zba => (a & 0xffff_ffff) << (imm & 0x3f), // slli.uw
+            (0x1, 0x30) if zbb => match shamt
+            {
+                0x0 => word.leading_zeros() as u64, // clzw
+                0x1 => word.trailing_zeros() as u64, // ctzw
+                0x2 => word.count_ones() as u64, // cpopw
+                _ => return None,
+            },
+            (0x5, 0x30) if zbb || zbkb => sign_extend_word(word.rotate_right(shamt)), // roriw
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// lr, sc and the AMOs on naturally aligned words and doublewords.
+    /// Harts take turns on one thread, so every access is atomic.
+    fn execute_amo(&mut self, instruction: &DecodedInstruction)
+    {
+        let size = match instruction.funct3
+        {
+            0x2 => 4,
+            0x3 => 8,
+            _ => return self.raise_exception(Exception::IllegalInstruction, 0),
+        };
+        let funct5 = instruction.funct7 >> 2;
+        let (rd, rs1, rs2) = (instruction.rd as usize, instruction.rs1 as usize, instruction.rs2 as usize);
+        let lr = funct5 == 0x02;
+        if !self.isa.has(Extension::A) || (lr && rs2 != 0)
+        {
+            return self.raise_exception(Exception::IllegalInstruction, 0);
+        }
+        let addr = self.regs[rs1];
+        if !addr.is_multiple_of(size as u64)
+        {
+            let exception = if lr { Exception::LoadAddressMisaligned } else { Exception::StoreAddressMisaligned };
+            return self.raise_exception(exception, addr);
+        }
+        let sign_extend = |value: u64| if size == 4 { sign_extend_word(value as u32) } else { value };
+        let unsigned = |value: u64| if size == 4 { value & 0xffff_ffff } else { value };
+        match funct5
+        {
+            0x02 => match self.load(addr, size)
+            {
+                Ok(value) =>
+                {
+                    self.regs[rd] = sign_extend(value);
+                    self.reservation = Some(addr);
+                }
+                Err(exception) => self.raise_exception(exception, addr),
+            },
+            0x03 =>
+            {
+                // sc
+                let reserved = self.reservation.take() == Some(addr);
+                if reserved
+                {
+                    if let Err(exception) = self.store(addr, size, self.regs[rs2])
+                    {
+                        return self.raise_exception(exception, addr);
+                    }
+                }
+                self.regs[rd] = !reserved as u64;
+            }
+            0x00 | 0x01 | 0x04 | 0x08 | 0x0c | 0x10 | 0x14 | 0x18 | 0x1c =>
+            {
+                // Read for writing, so a read-only page raises a store fault.
+                let old = match self.read_mem(addr, size, AccessType::Write)
+                {
+                    Ok(old) => sign_extend(old),
+                    Err(exception) => return self.raise_exception(exception, addr),
+                };
+                let operand = sign_extend(self.regs[rs2]);
+                let value = match funct5
+                {
+                    0x00 => old.wrapping_add(operand), // amoadd
+                    0x01 => operand, // amoswap
+                    0x04 => old ^ operand, // amoxor
+                    0x08 => old | operand, // amoor
+                    0x0c => old & operand, // amoand
+                    0x10 => (old as i64).min(operand as i64) as u64, // amomin
+                    0x14 => (old as i64).max(operand as i64) as u64, // amomax
+                    0x18 => if unsigned(old) <= unsigned(operand) { old } else { operand }, // amominu
+                    _ => if unsigned(old) >= unsigned(operand) { old } else { operand }, // amomaxu
+                };
+                if let Err(exception) = self.store(addr, size, value)
+                {
+                    return self.raise_exception(exception, addr);
+                }
+                self.regs[rd] = old;
+            }
+            _ => self.raise_exception(Exception::IllegalInstruction, 0),
+        }
+    }
+
+    fn execute_system(&mut self, instruction: &DecodedInstruction)
+    {
+        let rd = instruction.rd as usize;
+        let rs1 = instruction.rs1 as usize;
+        let csr = (instruction.imm & 0xfff) as u16;
+        match instruction.funct3
+        {
+            0x0 =>
+            {
+                if instruction.funct7 == 0x09
+                {
+                    // sfence.vma
+                    if self.privilege == Privilege::User
+                    {
+                        self.raise_exception(Exception::IllegalInstruction, 0);
+                    }
+                    else if rs1 == 0
+                    {
+                        self.tlb.flush();
+                    }
+                    else
+                    {
+                        self.tlb.flush_page(self.regs[rs1]);
+                    }
+                    return;
+                }
+                match csr
+                {
+                    0x000 =>
+                    {
+                        // ecall
+                        if self.intercept_sbi && self.privilege == Privilege::Supervisor
+                        {
+                            self.exit = Some(VmExit::SupervisorCall);
+                            return;
+                        }
+                        let exception = match self.privilege
+                        {
+                            Privilege::User => Exception::EnvironmentCallFromU,
+                            Privilege::Supervisor => Exception::EnvironmentCallFromS,
+                            Privilege::Machine => Exception::EnvironmentCallFromM,
+                        };
+                        self.raise_exception(exception, 0);
+                    }
+                    0x001 => self.raise_exception(Exception::Breakpoint, self.pc), // ebreak
+                    0x102 => self.sret(),
+                    0x302 => self.mret(),
+                    0x105 =>
+                    {
+                        // wfi
+                        if self.csrs[MIP as usize] & self.csrs[MIE as usize] == 0
+                        {
+                            self.exit = Some(VmExit::WaitForInterrupt);
+                        }
+                    }
+                    _ => self.raise_exception(Exception::IllegalInstruction, 0),
+                }
+            }
+            0x1 | 0x2 | 0x3 | 0x5 | 0x6 | 0x7 =>
+            {
+                // csrrw, csrrs, csrrc and their immediate forms
+                if !self.isa.has(Extension::Zicsr) || (self.privilege as u8) < csr::min_privilege(csr) || !self.csr_enabled(csr)
+                {
+                    self.raise_exception(Exception::IllegalInstruction, 0);
+                    return;
+                }
+                let source = if instruction.funct3 & 0x4 != 0 { rs1 as u64 } else { self.regs[rs1] };
+                let writes = instruction.funct3 & 0x3 == 0x1 || rs1 != 0;
+                // seed can only be read by an instruction that also writes it,
+                // so a polled value is never silently discarded.
+                if (writes && csr::is_read_only(csr)) || (csr == SEED && !writes)
+                {
+                    self.raise_exception(Exception::IllegalInstruction, 0);
+                    return;
+                }
+                let old = self.read_csr(csr);
+                if writes
+                {
+                    let new = match instruction.funct3 & 0x3
+                    {
+                        0x1 => source,
+                        0x2 => old | source,
+                        _ => old & !source,
+                    };
+                    self.write_csr(csr, new);
+                }
+                self.regs[rd] = old;
+            }
+            _ => self.raise_exception(Exception::IllegalInstruction, 0),
+        }
+    }
+
+    /// Whether the extension a CSR belongs to is present and switched on.
+    fn csr_enabled(&self, csr: u16) -> bool
+    {
+        match csr
+        {
+            FFLAGS | FRM | FCSR => self.isa.has(Extension::F) && self.fpu_enabled(),
+            VSTART | VXSAT | VXRM | VCSR | VL | VTYPE | VLENB => self.vector_enabled(),
+            SEED =>
+            {
+                let mseccfg = self.csrs[MSECCFG as usize];
+                self.isa.has(Extension::Zkr) && match self.privilege
+                {
+                    Privilege::Machine => true,
+                    Privilege::Supervisor => mseccfg & MSECCFG_SSEED != 0,
+                    Privilege::User => mseccfg & MSECCFG_USEED != 0,
+                }
+            }
+            _ => true,
+        }
+    }
+
+    /// Clears the FS and VS fields of extensions the hart lacks and sets SD
+    /// if either field is dirty.
+    fn legalize_mstatus(&self, mut mstatus: u64) -> u64
+    {
+        if !self.isa.has(Extension::F)
+        {
+            mstatus &= !MSTATUS_FS;
+        }
+        if !self.isa.has(Extension::V) && !self.isa.has(Extension::Zve32x)
+        {
+            mstatus &= !MSTATUS_VS;
+        }
+        let dirty = mstatus & MSTATUS_FS == MSTATUS_FS || mstatus & MSTATUS_VS == MSTATUS_VS;
+        if dirty { mstatus | MSTATUS_SD } else { mstatus & !MSTATUS_SD }
+    }
+
+    pub fn read_csr(&self, csr: u16) -> u64
+    {
+        match csr
+        {
+            FFLAGS => self.csrs[FCSR as usize] & 0x1f,
+            FRM => (self.csrs[FCSR as usize] >> 5) & 0x7,
+            VCSR => (self.csrs[VXRM as usize] << 1) | self.csrs[VXSAT as usize],
+            VLENB => self.vlenb as u64,
+            CYCLE => self.csrs[MCYCLE as usize],
+            INSTRET => self.csrs[MINSTRET as usize],
+            SEED =>
+            {
+                let mut entropy = [0u8; 2];
+                match entropy::fill(&mut entropy)
+                {
+                    Ok(()) => csr::SEED_OPST_ES16 | u16::from_le_bytes(entropy) as u64,
+                    Err(_) => csr::SEED_OPST_DEAD,
+                }
+            }
+            SSTATUS => self.csrs[MSTATUS as usize] & SSTATUS_MASK,
+            SIE => self.csrs[MIE as usize] & self.csrs[MIDELEG as usize],
+            SIP => self.csrs[MIP as usize] & self.csrs[MIDELEG as usize],
+            MISA => self.isa.misa(),
+            _ => self.csrs[csr as usize],
+        }
+    }
+
+    pub fn write_csr(&mut self, csr: u16, value: u64)
+    {
+        match csr
+        {
+            FFLAGS | FRM | FCSR =>
+            {
+                let fcsr = self.csrs[FCSR as usize];
+                self.csrs[FCSR as usize] = match csr
+                {
+                    FFLAGS => (fcsr & !0x1f) | (value & 0x1f),
+                    FRM => (fcsr & 0x1f) | (value & 0x7) << 5,
+                    _ => value & 0xff,
+                };
+                self.mark_fpu_dirty();
+            }
+            VSTART | VXSAT | VXRM | VCSR =>
+            {
+                match csr
+                {
+                    // WARL: only indices of the widest group fit.
+                    VSTART => self.csrs[VSTART as usize] = value & (self.vlenb as u64 * 8 - 1),
+                    VXSAT => self.csrs[VXSAT as usize] = value & 1,
+                    VXRM => self.csrs[VXRM as usize] = value & 0x3,
+                    _ =>
+                    {
+                        self.csrs[VXSAT as usize] = value & 1;
+                        self.csrs[VXRM as usize] = (value >> 1) & 0x3;
+                    }
+                }
+                self.mark_vector_dirty();
+            }
+            SSTATUS =>
+            {
+                let mstatus = self.csrs[MSTATUS as usize];
+                self.csrs[MSTATUS as usize] = self.legalize_mstatus((mstatus & !SSTATUS_MASK) | (value & SSTATUS_MASK));
+                // SUM and MXR change what the cached translations permit.
+                self.tlb.flush();
+            }
+            MSTATUS =>
+            {
+                self.csrs[MSTATUS as usize] = self.legalize_mstatus(value);
+                self.tlb.flush();
+            }
+            SIE | SIP =>
+            {
+                let target = if csr == SIE { MIE as usize } else { MIP as usize };
+                let mask = self.csrs[MIDELEG as usize];
+                self.csrs[target] = (self.csrs[target] & !mask) | (value & mask);
+            }
+            // WARL: the extensions are fixed by the configured ISA.
+            MISA => {}
+            // Writes to seed are ignored; only the read has an effect.
+            SEED => {}
+            MSECCFG => self.csrs[MSECCFG as usize] = value & (MSECCFG_SSEED | MSECCFG_USEED),
+            SATP =>
+            {
+                // WARL: writes selecting an unsupported mode are ignored.
+                let mode = value >> csr::SATP_MODE_SHIFT;
+                if mode == csr::SATP_MODE_BARE || mode == csr::SATP_MODE_SV39
+                {
+                    self.csrs[SATP as usize] = value;
+                    self.tlb.flush();
+                }
+            }
+            _ => self.csrs[csr as usize] = value,
+        }
+    }
+
+    /// Gives MMIO devices a chance to pick up host input and finish work.
+    pub fn poll_devices(&mut self)
+    {
+        self.mmio.tick(&mut self.ram);
+    }
+
+    /// Swaps the running hart's state with `context`, so the hart it
+    /// described runs next and the current one is saved into it.
+    pub fn swap_context(&mut self, context: &mut HartContext)
+    {
+        std::mem::swap(&mut self.regs, &mut context.regs);
+        std::mem::swap(&mut self.pc, &mut context.pc);
+        std::mem::swap(&mut self.privilege, &mut context.privilege);
+        std::mem::swap(&mut self.fregs, &mut context.fregs);
+        std::mem::swap(&mut self.vregs, &mut context.vregs);
+        std::mem::swap(&mut self.csrs, &mut context.csrs);
+        std::mem::swap(&mut self.tlb, &mut context.tlb);
+        // Another hart may have stored to the reserved address meanwhile.
+        self.reservation = None;
+        if self.vregs.len() != 32 * self.vlenb
+        {
+            self.vregs = vec![0; 32 * self.vlenb];
+        }
+    }
+
+    /// Raises or clears interrupt-pending bits in mip.
+    pub fn set_interrupt_pending(&mut self, bits: u64, pending: bool)
+    {
+        if pending
+        {
+            self.csrs[MIP as usize] |= bits;
+        }
+        else
+        {
+            self.csrs[MIP as usize] &= !bits;
+        }
+    }
+
+    /// Whether wfi would stop waiting: some interrupt is both pending and
+    /// enabled in mie, whatever the global enables say.
+    pub fn interrupt_waiting(&self) -> bool
+    {
+        self.csrs[MIP as usize] & self.csrs[MIE as usize] != 0
+    }
+
+    pub fn flush_tlb(&mut self)
+    {
+        self.tlb.flush();
+    }
+
+    /// Takes the highest priority interrupt that is pending, enabled and
+    /// not masked at the current privilege level. Returns whether one was taken.
+    fn take_interrupt(&mut self) -> bool
+    {
+        let pending = self.csrs[MIP as usize] & self.csrs[MIE as usize];
+        if pending == 0
+        {
+            return false;
+        }
+        let mstatus = self.csrs[MSTATUS as usize];
+        let delegated = self.csrs[MIDELEG as usize];
+        let m_enabled = self.privilege != Privilege::Machine || mstatus & MSTATUS_MIE != 0;
+        let s_enabled = match self.privilege
+        {
+            Privilege::User => true,
+            Privilege::Supervisor => mstatus & MSTATUS_SIE != 0,
+            Privilege::Machine => false,
+        };
+        for bit in INTERRUPT_PRIORITY
+        {
+            let enabled = if delegated & bit != 0 { s_enabled } else { m_enabled };
+            if pending & bit != 0 && enabled
+            {
+                self.trap(bit.trailing_zeros() as u64, 0, true);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Changes the current privilege level, invalidating cached translations.
+    pub fn set_privilege(&mut self, privilege: Privilege)
+    {
+        if privilege != self.privilege
+        {
+            self.privilege = privilege;
+            self.tlb.flush();
+        }
+    }
+
+    /// Takes a synchronous trap, delegating it to S-mode if medeleg asks for it.
+    pub fn raise_exception(&mut self, exception: Exception, tval: u64)
+    {
+        self.trapped = true;
+        self.trap(exception.code(), tval, false);
+    }
+
+    /// Enters the trap handler for `cause`, in S-mode if medeleg or mideleg
+    /// delegates it there. Vectored mode only applies to interrupts.
+    fn trap(&mut self, cause: u64, tval: u64, interrupt: bool)
+    {
+        let delegation = if interrupt { self.csrs[MIDELEG as usize] } else { self.csrs[MEDELEG as usize] };
+        let cause_value = if interrupt { cause | (1 << 63) } else { cause };
+        let handler = |tvec: u64| if interrupt && tvec & 0b11 == 1
+        {
+            (tvec & !0b11) + 4 * cause
+        }
+        else
+        {
+            tvec & !0b11
+        };
+        let mut mstatus = self.csrs[MSTATUS as usize];
+        if self.privilege != Privilege::Machine && (delegation >> cause) & 1 != 0
+        {
+            self.csrs[SEPC as usize] = self.pc;
+            self.csrs[SCAUSE as usize] = cause_value;
+            self.csrs[STVAL as usize] = tval;
+            mstatus &= !(MSTATUS_SPP | MSTATUS_SPIE);
+            if self.privilege == Privilege::Supervisor
+            {
+                mstatus |= MSTATUS_SPP;
+            }
+            if mstatus & MSTATUS_SIE != 0
+            {
+                mstatus |= MSTATUS_SPIE;
+            }
+            mstatus &= !MSTATUS_SIE;
+            self.csrs[MSTATUS as usize] = mstatus;
+            self.jump(handler(self.csrs[STVEC as usize]));
+            self.set_privilege(Privilege::Supervisor);
+        }
+        else
+        {
+            self.csrs[MEPC as usize] = self.pc;
+            self.csrs[MCAUSE as usize] = cause_value;
+            self.csrs[MTVAL as usize] = tval;
+            mstatus &= !(MSTATUS_MPP | MSTATUS_MPIE);
+            mstatus |= (self.privilege as u64) << MSTATUS_MPP_SHIFT;
+            if mstatus & MSTATUS_MIE != 0
+            {
+                mstatus |= MSTATUS_MPIE;
+            }
+            mstatus &= !MSTATUS_MIE;
+            self.csrs[MSTATUS as usize] = mstatus;
+            self.jump(handler(self.csrs[MTVEC as usize]));
+            self.set_privilege(Privilege::Machine);
+        }
+    }
+
+    fn mret(&mut self)
+    {
+        if self.privilege != Privilege::Machine
+        {
+            self.raise_exception(Exception::IllegalInstruction, 0);
+            return;
+        }
+        let mut mstatus = self.csrs[MSTATUS as usize];
+        let previous = Privilege::from_bits(mstatus >> MSTATUS_MPP_SHIFT);
+        mstatus &= !MSTATUS_MIE;
+        if mstatus & MSTATUS_MPIE != 0
+        {
+            mstatus |= MSTATUS_MIE;
+        }
+        mstatus |= MSTATUS_MPIE;
+        mstatus &= !MSTATUS_MPP;
+        if previous != Privilege::Machine
+        {
+            mstatus &= !MSTATUS_MPRV;
+        }
+        self.csrs[MSTATUS as usize] = mstatus;
+        self.jump(self.csrs[MEPC as usize]);
+        // Always flush: MPRV may have been cleared even if privilege is unchanged.
+        self.privilege = previous;
+        self.tlb.flush();
+    }
+
+    fn sret(&mut self)
+    {
+        if self.privilege == Privilege::User
+        {
+            self.raise_exception(Exception::IllegalInstruction, 0);
+            return;
+        }
+        let mut mstatus = self.csrs[MSTATUS as usize];
+        let previous = if mstatus & MSTATUS_SPP != 0 { Privilege::Supervisor } else { Privilege::User };
+        mstatus &= !MSTATUS_SIE;
+        if mstatus & MSTATUS_SPIE != 0
+        {
+            mstatus |= MSTATUS_SIE;
+        }
+        mstatus |= MSTATUS_SPIE;
+        mstatus &= !(MSTATUS_SPP | MSTATUS_MPRV);
+        self.csrs[MSTATUS as usize] = mstatus;
+        self.jump(self.csrs[SEPC as usize]);
+        // Always flush: MPRV may have been cleared even if privilege is unchanged.
+        self.privilege = previous;
+        self.tlb.flush();
+    }
+
+    fn load(&mut self, vaddr: u64, size: usize) -> Result<u64, Exception>
+    {
+        self.read_mem(vaddr, size, AccessType::Read)
+    }
+
+    /// Reads `size` bytes at a guest-virtual address for a load or a fetch.
+    ///
+    /// A TLB hit reads straight out of the RAM buffer; anything else goes
+    /// through `read_mem_slow`.
+    #[inline]
+    fn read_mem(&mut self, vaddr: u64, size: usize, access: AccessType) -> Result<u64, Exception>
+    {
+        if (vaddr & PAGE_MASK) + size as u64 <= PAGE_SIZE
+        {
+            if let Some(offset) = self.tlb.lookup(access, vaddr)
+            {
+                return Ok(self.ram.load_host(offset, size));
+            }
+        }
+        self.read_mem_slow(vaddr, size, access)
+    }
+
+    fn read_mem_slow(&mut self, vaddr: u64, size: usize, access: AccessType) -> Result<u64, Exception>
+    {
+        if (vaddr & PAGE_MASK) + size as u64 > PAGE_SIZE
+        {
+            // Split accesses that straddle a page boundary into bytes.
+            let mut value = 0;
+            for i in 0..size
+            {
+                value |= self.read_mem(vaddr.wrapping_add(i as u64), 1, access)? << (8 * i);
+            }
+            return Ok(value);
+        }
+        match self.resolve(vaddr, size, access)?
+        {
+            Resolved::Host(offset) => Ok(self.ram.load_host(offset, size)),
+            Resolved::Mmio(paddr) =>
+            {
+                if let Some(value) = self.mmio.read(paddr, size)
+                {
+                    return Ok(value);
+                }
+                let mut value = 0;
+                for i in 0..size
+                {
+                    let byte = *self.memory.get(&(paddr + i as u64)).unwrap_or(&0);
+                    value |= (byte as u64) << (8 * i);
+                }
+                Ok(value)
+            }
+        }
+    }
+
+    /// Writes the low `size` bytes of `value` at a guest-virtual address.
+    #[inline]
+    fn store(&mut self, vaddr: u64, size: usize, value: u64) -> Result<(), Exception>
+    {
+        if (vaddr & PAGE_MASK) + size as u64 <= PAGE_SIZE
+        {
+            if let Some(offset) = self.tlb.lookup(AccessType::Write, vaddr)
+            {
+                self.ram.store_host(offset, size, value);
+                return Ok(());
+            }
+        }
+        self.store_slow(vaddr, size, value)
+    }
+
+    fn store_slow(&mut self, vaddr: u64, size: usize, value: u64) -> Result<(), Exception>
+    {
+        if (vaddr & PAGE_MASK) + size as u64 > PAGE_SIZE
+        {
+            // Translate every byte before writing any, so a fault on the
+            // second page leaves memory untouched.
+            for i in 0..size
+            {
+                self.resolve(vaddr.wrapping_add(i as u64), 1, AccessType::Write)?;
+            }
+            for i in 0..size
+            {
+                self.store(vaddr.wrapping_add(i as u64), 1, value >> (8 * i))?;
+            }
+            return Ok(());
+        }
+        match self.resolve(vaddr, size, AccessType::Write)?
+        {
+            Resolved::Host(offset) => self.ram.store_host(offset, size, value),
+            Resolved::Mmio(paddr) =>
+            {
+                if self.mmio.write(paddr, size, value, &mut self.ram)
+                {
+                    return Ok(());
+                }
+                for i in 0..size
+                {
+                    self.memory.insert(paddr + i as u64, (value >> (8 * i)) as u8);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Translates an access that stays within one page and, when the page is
+    /// RAM, caches it in the TLB for later accesses of the same type.
+    fn resolve(&mut self, vaddr: u64, size: usize, access: AccessType) -> Result<Resolved, Exception>
+    {
+        let paddr = self.translate(vaddr, access)?;
+        // Bare and M-mode addresses past the physical address space fault.
+        if paddr >> PHYSICAL_ADDRESS_BITS != 0
+        {
+            return Err(match access
+            {
+                AccessType::Read => Exception::LoadAccessFault,
+                AccessType::Write => Exception::StoreAccessFault,
+                AccessType::Execute => Exception::InstructionAccessFault,
+            });
+        }
+        if let Some(host_page) = self.ram.offset_of(paddr & !PAGE_MASK, PAGE_SIZE)
+        {
+            self.tlb.insert(access, vaddr, host_page);
+            return Ok(Resolved::Host(host_page + (paddr & PAGE_MASK) as usize));
+        }
+        match self.ram.offset_of(paddr, size as u64)
+        {
+            Some(offset) => Ok(Resolved::Host(offset)),
+            None => Ok(Resolved::Mmio(paddr)),
+        }
+    }
+
+    /// Translates a guest-virtual address with the Sv39 page tables in satp.
+    fn translate(&mut self, vaddr: u64, access: AccessType) -> Result<u64, Exception>
+    {
+        let mstatus = self.csrs[MSTATUS as usize];
+        let privilege = if access != AccessType::Execute && mstatus & MSTATUS_MPRV != 0
+        {
+            Privilege::from_bits(mstatus >> MSTATUS_MPP_SHIFT)
+        }
+        else
+        {
+            self.privilege
+        };
+        let satp = self.csrs[SATP as usize];
+        if privilege == Privilege::Machine || satp >> csr::SATP_MODE_SHIFT == csr::SATP_MODE_BARE
+        {
+            return Ok(vaddr);
+        }
+
+        let (page_fault, access_fault) = match access
+        {
+            AccessType::Read => (Exception::LoadPageFault, Exception::LoadAccessFault),
+            AccessType::Write => (Exception::StorePageFault, Exception::StoreAccessFault),
+            AccessType::Execute => (Exception::InstructionPageFault, Exception::InstructionAccessFault),
+        };
+
+        // Bits 63:39 must all equal bit 38.
+        if (((vaddr << 25) as i64) >> 25) as u64 != vaddr
+        {
+            return Err(page_fault);
+        }
+
+        let mut table = (satp & csr::SATP_PPN_MASK) << PAGE_SHIFT;
+        for level in (0..3).rev()
+        {
+            let vpn = (vaddr >> (PAGE_SHIFT + 9 * level)) & 0x1ff;
+            let pte_addr = table + vpn * 8;
+            let pte = self.ram.read_u64(pte_addr).ok_or(access_fault)?;
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0)
+            {
+                return Err(page_fault);
+            }
+            let ppn = (pte >> 10) & PTE_PPN_MASK;
+            if pte & (PTE_R | PTE_X) == 0
+            {
+                table = ppn << PAGE_SHIFT;
+                continue;
+            }
+
+            let user_page = pte & PTE_U != 0;
+            let privilege_ok = match privilege
+            {
+                Privilege::User => user_page,
+                _ => !user_page || (access != AccessType::Execute && mstatus & MSTATUS_SUM != 0),
+            };
+            let permitted = match access
+            {
+                AccessType::Read => pte & PTE_R != 0 || (mstatus & MSTATUS_MXR != 0 && pte & PTE_X != 0),
+                AccessType::Write => pte & PTE_W != 0,
+                AccessType::Execute => pte & PTE_X != 0,
+            };
+            // Superpages must be aligned to their size.
+            let misaligned = ppn & ((1 << (9 * level)) - 1) != 0;
+            if !privilege_ok || !permitted || misaligned
+            {
+                return Err(page_fault);
+            }
+
+            let mut updated = pte | PTE_A;
+            if access == AccessType::Write
+            {
+                updated |= PTE_D;
+            }
+            if updated != pte
+            {
+                self.ram.write_u64(pte_addr, updated).ok_or(access_fault)?;
+            }
+
+            let offset_mask = (1 << (PAGE_SHIFT + 9 * level)) - 1;
+            return Ok(((ppn << PAGE_SHIFT) & !offset_mask) | (vaddr & offset_mask));
+        }
+        Err(page_fault)
+    }
+}
+
+
+#[cfg(test)]
+mod tests 
+{
+    use super::*;
+    use crate::devices::rom::Rom;
+
+    /// Steps one instruction placed at 0x8000_0000 and returns mcause,
+    /// which is 0 if it did not trap.
+    fn run(cpu: &mut VirtualCPU, instruction: u32) -> u64
+    {
+        cpu.ram.write_u32(0x8000_0000, instruction).unwrap();
+        cpu.pc = 0x8000_0000;
+        cpu.write_csr(MCAUSE, 0);
+        cpu.step();
+        cpu.read_csr(MCAUSE)
+    }
+
+    #[test]
+    fn test_decode_i_type() 
+    {
+        // Example: ADDI x1, x2, 5 -> 0000_0000_0101 00010 000 00001 0010011
+        let instruction: u32 = 0b00000000010100010000000010010011;
+        let cpu = VirtualCPU::new();
+        let decoded = cpu.decode(instruction);
+        assert_eq!(decoded.rd, 1);   // rd = 00001
+        assert_eq!(decoded.rs1, 2);  // rs1 = 00010
+        assert_eq!(decoded.imm, 5);  // imm = 000000000101
+    }
+
+    #[test]
+    #[allow(clippy::unusual_byte_groupings)] // grouped by instruction field
+    fn test_decode_s_type() 
+    {
+        // Example: SW x5, 8(x2) -> 000_0000 00101 00010 100 01000 0100011
+        let instruction: u32 = 0b0_000000_00101_00010_100_01000_0100011;
+        let cpu = VirtualCPU::new();
+        let decoded = cpu.decode(instruction);
+        assert_eq!(decoded.rs1, 2);  // rs1 = 00010
+        assert_eq!(decoded.rs2, 5);  // rs2 = 00101
+        assert_eq!(decoded.imm, 8);  // imm = 0000000001000
+    }
+
+    #[test]
+    fn test_decode_b_type() 
+    {
+        // Example: BEQ x1, x2, 16 -> 0 100000 00010 00001 000 0001 0 1100011
+        let instruction: u32 = 0b01000000001000001000000101100011;
+        let cpu = VirtualCPU::new();
+        let decoded = cpu.decode(instruction);
+        assert_eq!(decoded.rs1, 1);  // rs1 = 00001
+        assert_eq!(decoded.rs2, 2);  // rs2 = 00010
+        assert_eq!(decoded.imm, 1026); // imm = 0_100000_0001_0 (with LSB=0)
+    }
+
+    #[test]
+    fn test_decode_j_type() 
+    {
+        // Example: JAL 1452 -> 0 0000000010 1 00000000 00000 1101111
+        let instruction: u32 = 0b00000000010100000000000001101111;
+        let cpu = VirtualCPU::new();
+        let decoded = cpu.decode(instruction);
+        assert_eq!(decoded.imm, 2052); // imm = 100000000100 (with LSB=0)
+    }
+
+    #[test]
+    fn test_load_store_instructions() 
+    {
+        let mut cpu = VirtualCPU::new();
+        
+        // Initialize memory
+        cpu.memory.insert(0x1000, 0x12);   // byte
+        cpu.memory.insert(0x1001, 0x34);   // byte
+        cpu.memory.insert(0x1002, 0x55);   // halfword (16 bits)
+        cpu.memory.insert(0x1003, 0x56);
+        cpu.memory.insert(0x1004, 0x78);   // word (32 bits)
+
+        // Set up registers
+        cpu.regs[1] = 0x1000; // rs1 for load/store base address
+        cpu.regs[2] = 0x00;   // rs2 for load/store offset
+
+        // `lw` instruction
+        let lw_instruction = DecodedInstruction {
+            opcode: OPCODE_I_LOAD,
+            funct3: 0x2, // lw
+            funct7: 0x00,
+            rd: 3,
+            rs1: 1,
+            rs2: 0,
+            imm: 0x4, // Offset for the word
+            length: 4,
+        };
+        cpu.execute(lw_instruction);
+        assert_eq!(cpu.regs[3], 0x78); // Load word at address 0x1004
+
+        // `lh` instruction
+        let lh_instruction = DecodedInstruction {
+            opcode: OPCODE_I_LOAD,
+            funct3: 0x1, // lh
+            funct7: 0x00,
+            rd: 4,
+            rs1: 1,
+            rs2: 0,
+            imm: 0x2, // Offset for the halfword
+            length: 4,
+        };
+        cpu.execute(lh_instruction);
+        assert_eq!(cpu.regs[4], 0x5655); // Load halfword at address 0x1002 (little-endian)
+
+        // `lb` instruction
+        let lb_instruction = DecodedInstruction {
+            opcode: OPCODE_I_LOAD,
+            funct3: 0x0, // lb
+            funct7: 0x00,
+            rd: 5,
+            rs1: 1,
+            rs2: 0,
+            imm: 0x1, // Offset for the byte
+            length: 4,
+        };
+        cpu.execute(lb_instruction);
+        assert_eq!(cpu.regs[5], 0x34); // Load byte at address 0x1001
+
+        // `sw` instruction
+        cpu.regs[6] = 0xABCD1234; // Value to store
+        // store from regs[6] into memory address 0x1004
+        let sw_instruction = DecodedInstruction {
+            opcode: OPCODE_S,
+            funct3: 0x2, // sw
+            funct7: 0x00,
+            rd: 0,
+            rs1: 1,
+            rs2: 6,
+            imm: 0x4, // Offset for the word
+            length: 4,
+        };
+        cpu.execute(sw_instruction);
+        assert_eq!(cpu.memory.get(&0x1004).unwrap(), &0x34); // Check stored value
+
+        // `sh` instruction
+        cpu.regs[7] = 0x1234; // Value to store
+        let sh_instruction = DecodedInstruction {
+            opcode: OPCODE_S,
+            funct3: 0x1, // sh
+            funct7: 0x00,
+            rd: 0,
+            rs1: 1,
+            rs2: 7,
+            imm: 0x2, // Offset for the halfword
+            length: 4,
+        };
+        cpu.execute(sh_instruction);
+        assert_eq!(cpu.memory.get(&0x1002).unwrap(), &0x34); // Check stored halfword
+    }
+
+
+    #[test]
+    fn test_ram_accesses_use_tlb()
+    {
+        let mut cpu = VirtualCPU::with_ram(0x8000_0000, 0x10000);
+        cpu.regs[1] = 0x8000_0100;
+        cpu.regs[2] = 0x1122_3344_5566_7788;
+
+        // `sd` instruction
+        let sd_instruction = DecodedInstruction {
+            opcode: OPCODE_S,
+            funct3: 0x3, // sd
+            funct7: 0x00,
+            rd: 0,
+            rs1: 1,
+            rs2: 2,
+            imm: 0x8,
+            length: 4,
+        };
+        cpu.execute(sd_instruction);
+        assert_eq!(cpu.ram.read_u64(0x8000_0108), Some(0x1122_3344_5566_7788));
+        assert_eq!(cpu.tlb.lookup(AccessType::Write, 0x8000_0108), Some(0x108));
+        assert_eq!(cpu.tlb.lookup(AccessType::Read, 0x8000_0108), None);
+
+        // `lw` instruction, sign extended
+        let lw_instruction = DecodedInstruction {
+            opcode: OPCODE_I_LOAD,
+            funct3: 0x2, // lw
+            funct7: 0x00,
+            rd: 3,
+            rs1: 1,
+            rs2: 0,
+            imm: 0xc,
+            length: 4,
+        };
+        cpu.execute(lw_instruction);
+        assert_eq!(cpu.regs[3], 0x1122_3344);
+        assert_eq!(cpu.tlb.lookup(AccessType::Read, 0x8000_010c), Some(0x10c));
+
+        cpu.set_privilege(Privilege::Supervisor);
+        assert_eq!(cpu.tlb.lookup(AccessType::Read, 0x8000_010c), None);
+    }
+
+    #[test]
+    fn test_mmio_access_at_the_top_of_memory()
+    {
+        let mut cpu = VirtualCPU::with_ram(0x8000_0000, 0x10000);
+        cpu.write_csr(MTVEC, 0x8000_1000);
+        cpu.mmio.attach(0x1000, 0x100, Box::new(Rom::new(vec![0x5a; 0x100])));
+
+        cpu.regs[2] = 0x1000;
+        assert_eq!(run(&mut cpu, 0x0001_3083), 0); // ld x1, 0(x2)
+        assert_eq!(cpu.regs[1], 0x5a5a_5a5a_5a5a_5a5a);
+        // The last doubleword of the address space is in no region, and
+        // past the physical address space.
+        assert_eq!(cpu.mmio.read(u64::MAX - 7, 8), None);
+        cpu.regs[1] = 0;
+        assert_eq!(run(&mut cpu, 0xff80_3083), Exception::LoadAccessFault.code()); // ld x1, -8(x0)
+        assert_eq!(cpu.regs[1], 0);
+        assert_eq!(cpu.read_csr(MTVAL), u64::MAX - 7);
+    }
+
+    #[test]
+    fn test_sv39_translation_and_satp_flush()
+    {
+        let mut cpu = VirtualCPU::with_ram(0x8000_0000, 0x10000);
+        // Root table at 0x8000_1000 with a 1 GiB leaf mapping VA 0x4000_0000
+        // to PA 0x8000_0000 (readable, not writable).
+        let root = 0x8000_1000u64;
+        cpu.ram.write_u64(root + 8, ((0x8000_0000 >> 12) << 10) | PTE_V | PTE_R).unwrap();
+        cpu.ram.write_u64(0x8000_0010, 0xdead_beef).unwrap();
+        cpu.csrs[MTVEC as usize] = 0x8000_4000;
+        cpu.set_privilege(Privilege::Supervisor);
+        cpu.write_csr(SATP, (csr::SATP_MODE_SV39 << csr::SATP_MODE_SHIFT) | (root >> 12));
+
+        cpu.regs[1] = 0x4000_0000;
+        let ld_instruction = DecodedInstruction {
+            opcode: OPCODE_I_LOAD,
+            funct3: 0x3, // ld
+            funct7: 0x00,
+            rd: 2,
+            rs1: 1,
+            rs2: 0,
+            imm: 0x10,
+            length: 4,
+        };
+        cpu.execute(ld_instruction);
+        assert_eq!(cpu.regs[2], 0xdead_beef);
+        assert_eq!(cpu.tlb.lookup(AccessType::Read, 0x4000_0010), Some(0x10));
+        // The walk marks the leaf accessed.
+        assert_ne!(cpu.ram.read_u64(root + 8).unwrap() & PTE_A, 0);
+
+        cpu.write_csr(SATP, (csr::SATP_MODE_SV39 << csr::SATP_MODE_SHIFT) | (root >> 12));
+        assert_eq!(cpu.tlb.lookup(AccessType::Read, 0x4000_0010), None);
+
+        // Stores to the read-only page fault into M-mode.
+        cpu.pc = 0x1234;
+        let sw_instruction = DecodedInstruction {
+            opcode: OPCODE_S,
+            funct3: 0x2, // sw
+            funct7: 0x00,
+            rd: 0,
+            rs1: 1,
+            rs2: 2,
+            imm: 0x10,
+            length: 4,
+        };
+        cpu.execute(sw_instruction);
+        assert_eq!(cpu.read_csr(MCAUSE), Exception::StorePageFault.code());
+        assert_eq!(cpu.read_csr(MTVAL), 0x4000_0010);
+        assert_eq!(cpu.read_csr(MEPC), 0x1234);
+        assert_eq!(cpu.pc, 0x8000_4000);
+        assert_eq!(cpu.privilege, Privilege::Machine);
+        assert_eq!(cpu.ram.read_u64(0x8000_0010), Some(0xdead_beef));
+    }
+
+    #[test]
+    fn test_interrupts_and_wfi()
+    {
+        let mut cpu = VirtualCPU::with_ram(0x8000_0000, 0x10000);
+        cpu.ram.write_u32(0x8000_0000, 0x1050_0073).unwrap(); // wfi
+        cpu.pc = 0x8000_0000;
+        cpu.write_csr(MIDELEG, MIP_STIP);
+        cpu.write_csr(STVEC, 0x8000_1001); // vectored
+        cpu.set_privilege(Privilege::Supervisor);
+
+        // Nothing pending: wfi exits to the VMM and moves on.
+        assert_eq!(cpu.step(), Some(VmExit::WaitForInterrupt));
+        assert_eq!(cpu.pc, 0x8000_0004);
+
+        // Pending and enabled in sie, but masked by sstatus.SIE.
+        cpu.pc = 0x8000_0000;
+        cpu.write_csr(SIE, MIP_STIP);
+        cpu.set_interrupt_pending(MIP_STIP, true);
+        assert!(cpu.interrupt_waiting());
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.pc, 0x8000_0004);
+
+        cpu.pc = 0x8000_0000;
+        cpu.write_csr(SSTATUS, MSTATUS_SIE);
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.read_csr(SCAUSE), (1 << 63) | 5);
+        assert_eq!(cpu.read_csr(SEPC), 0x8000_0000);
+        assert_eq!(cpu.pc, 0x8000_1000 + 4 * 5);
+        assert_eq!(cpu.read_csr(SSTATUS) & (MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP), MSTATUS_SPIE | MSTATUS_SPP);
+    }
+
+    #[test]
+    fn test_swap_context()
+    {
+        let mut cpu = VirtualCPU::new();
+        let mut other = HartContext::new(1);
+        other.pc = 0x2000;
+        cpu.regs[5] = 7;
+        cpu.swap_context(&mut other);
+        assert_eq!(cpu.read_csr(MHARTID), 1);
+        assert_eq!(cpu.pc, 0x2000);
+        assert_eq!(cpu.regs[5], 0);
+        assert_eq!(other.regs[5], 7);
+    }
+
+    #[test]
+    fn test_disabled_extensions_are_illegal()
+    {
+        let mut cpu = VirtualCPU::with_ram(0x8000_0000, 0x10000);
+        cpu.write_csr(MTVEC, 0x8000_1000);
+        let illegal = Exception::IllegalInstruction.code();
+
+        assert_eq!(run(&mut cpu, 0x3010_2573), 0); // csrr a0, misa
+        assert_eq!(cpu.regs[10], Isa::default().misa());
+        assert_eq!(run(&mut cpu, 0x0000_100f), 0); // fence.i
+        assert_eq!(cpu.pc, 0x8000_0004);
+
+        // M, A, C and fence.i are switched off.
+        cpu.set_isa(Isa::parse("rv64i_zicsr").unwrap());
+        assert_eq!(run(&mut cpu, 0x02b5_0533), illegal); // mul a0, a0, a1
+        assert_eq!(run(&mut cpu, 0x00b5_35af), illegal); // amoadd.d a1, a1, (a0)
+        assert_eq!(run(&mut cpu, 0x0000_4505), illegal); // c.li a0, 1
+        assert_eq!(run(&mut cpu, 0x0000_100f), illegal); // fence.i
+        assert_eq!(cpu.pc, 0x8000_1000);
+        assert_eq!(run(&mut cpu, 0x0000_000f), 0); // fence
+        assert_eq!(run(&mut cpu, 0x6005_5513), illegal); // right shift with an undefined funct6
+        assert_eq!(run(&mut cpu, 0x0000_0000), illegal);
+
+        cpu.set_isa(Isa::parse("rv64i").unwrap());
+        assert_eq!(run(&mut cpu, 0x3010_2573), illegal); // csrr a0, misa
+    }
+
+    #[test]
+    fn test_vector_and_float_instructions()
+    {
+        let mut cpu = VirtualCPU::with_ram(0x8000_0000, 0x10000);
+        cpu.write_csr(MTVEC, 0x8000_1000);
+        let op_v = |funct6: u32, vm: u32, vs2: u32, vs1: u32, funct3: u32, vd: u32| (funct6 << 26) | (vm << 25) | (vs2 << 20) | (vs1 << 15) | (funct3 << 12) | (vd << 7) | 0x57;
+        let illegal = Exception::IllegalInstruction.code();
+        let data = 0x8000_2000;
+        for (i, word) in [1u32, 2, 3, 4].iter().enumerate()
+        {
+            cpu.ram.write_u32(data + 4 * i as u64, *word).unwrap();
+        }
+        cpu.regs[10] = 3;
+        cpu.regs[12] = data;
+
+        // Both units start off.
+        assert_eq!(run(&mut cpu, 0x0d05_75d7), illegal); // vsetvli a1, a0, e32, m1, ta, ma
+        cpu.write_csr(MSTATUS, (1 << 9) | (1 << 13));
+
+        assert_eq!(run(&mut cpu, 0x0d05_75d7), 0);
+        assert_eq!(cpu.regs[11], 3);
+        assert_eq!(cpu.read_csr(VL), 3);
+        assert_eq!(cpu.read_csr(VLENB), 16);
+        assert_eq!(cpu.read_csr(MSTATUS) & (MSTATUS_VS | MSTATUS_SD), MSTATUS_VS | MSTATUS_SD);
+        assert_eq!(run(&mut cpu, 0x0206_6087), 0); // vle32.v v1, (a2)
+        assert_eq!(run(&mut cpu, op_v(0x00, 1, 1, 5, 3, 2)), 0); // vadd.vi v2, v1, 5
+        assert_eq!(run(&mut cpu, op_v(0x18, 1, 1, 2, 3, 0)), 0); // vmseq.vi v0, v1, 2
+        assert_eq!(run(&mut cpu, op_v(0x00, 0, 1, 1, 0, 2)), 0); // vadd.vv v2, v1, v1, v0.t
+        assert_eq!(run(&mut cpu, op_v(0x00, 1, 2, 1, 2, 3)), 0); // vredsum.vs v3, v2, v1
+        assert_eq!(run(&mut cpu, op_v(0x10, 1, 3, 0, 2, 13)), 0); // vmv.x.s a3, v3
+        assert_eq!(cpu.regs[13], 1 + 6 + 4 + 8);
+        assert_eq!(run(&mut cpu, 0x0206_6127), 0); // vse32.v v2, (a2)
+        let stored: Vec<u32> = (0..4).map(|i| cpu.ram.read_u32(data + 4 * i).unwrap()).collect();
+        assert_eq!(stored, [6, 4, 8, 4]);
+
+        assert_eq!(run(&mut cpu, 0xd005_70d3), 0); // fcvt.s.w f1, a0
+        assert_eq!(run(&mut cpu, op_v(0x12, 1, 1, 3, 1, 4)), 0); // vfcvt.f.x.v v4, v1
+        assert_eq!(run(&mut cpu, op_v(0x00, 1, 4, 1, 5, 4)), 0); // vfadd.vf v4, v4, f1
+        assert_eq!(run(&mut cpu, op_v(0x10, 1, 4, 0, 1, 2)), 0); // vfmv.f.s f2, v4
+        assert_eq!(cpu.fregs[2], 0xffff_ffff_4080_0000);
+        assert_eq!(run(&mut cpu, 0x0026_2827), 0); // fsw f2, 16(a2)
+        assert_eq!(cpu.ram.read_u32(data + 16).unwrap(), 0x4080_0000);
+        assert_eq!(run(&mut cpu, 0x0020_f0d3), 0); // fadd.s f1, f1, f2
+        assert_eq!(cpu.fregs[1], 0xffff_ffff_40e0_0000);
+
+        // Reserved rounding modes and vtype settings.
+        cpu.write_csr(FRM, 5);
+        assert_eq!(run(&mut cpu, 0x0020_f0d3), illegal);
+        assert_eq!(run(&mut cpu, 0x01d5_75d7), 0); // vsetvli a1, a0, e64, mf8
+        assert_eq!(cpu.read_csr(VTYPE), VTYPE_VILL);
+        assert_eq!(run(&mut cpu, op_v(0x00, 1, 1, 5, 3, 2)), illegal);
+
+        // Half precision values are NaN-boxed; Zfhmin only converts them.
+        cpu.write_csr(FRM, 0);
+        cpu.ram.write_u16(data + 20, 0x3c00).unwrap();
+        cpu.regs[12] = data + 20;
+        assert_eq!(run(&mut cpu, 0x0006_1187), 0); // flh f3, 0(a2)
+        assert_eq!(cpu.fregs[3], 0xffff_ffff_ffff_3c00);
+        assert_eq!(run(&mut cpu, 0xf018_02d3), 0); // fli.s f5, 1.0
+        assert_eq!(cpu.fregs[5], 0xffff_ffff_3f80_0000);
+        cpu.set_isa(Isa::parse("rv64if_zicsr_zfhmin").unwrap());
+        assert_eq!(run(&mut cpu, 0x4021_f253), 0); // fcvt.s.h f4, f3
+        assert_eq!(cpu.fregs[4], 0xffff_ffff_3f80_0000);
+        assert_eq!(run(&mut cpu, 0x0420_f0d3), illegal); // fadd.h f1, f1, f2
+        assert_eq!(run(&mut cpu, 0xf018_02d3), illegal); // fli.s needs Zfa
+
+        // fli.h needs Zfh as well as Zfa; fmv.h.x only needs Zfhmin.
+        cpu.set_isa(Isa::parse("rv64if_zicsr_zfhmin_zfa").unwrap());
+        assert_eq!(run(&mut cpu, 0xf418_02d3), illegal); // fli.h f5, 1.0
+        assert_eq!(run(&mut cpu, 0xf400_82d3), 0); // fmv.h.x f5, ra
+        cpu.set_isa(Isa::parse("rv64if_zicsr_zfh_zfa").unwrap());
+        assert_eq!(run(&mut cpu, 0xf418_02d3), 0);
+        assert_eq!(cpu.fregs[5], 0xffff_ffff_ffff_3c00);
+    }
+
+    #[test]
+    fn test_vector_permutations_and_memory_accesses()
+    {
+        let mut cpu = VirtualCPU::with_ram(0x8000_0000, 0x10000);
+        cpu.write_csr(MTVEC, 0x8000_1000);
+        cpu.write_csr(MSTATUS, (1 << 9) | (1 << 13));
+        let op_v = |funct6: u32, vm: u32, vs2: u32, vs1: u32, funct3: u32, vd: u32| (funct6 << 26) | (vm << 25) | (vs2 << 20) | (vs1 << 15) | (funct3 << 12) | (vd << 7) | 0x57;
+        // e32 accesses: nf, mop, vm, rs2/vs2, rs1, vd/vs3 and the opcode.
+        let vmem = |nf: u32, mop: u32, vm: u32, rs2: u32, rs1: u32, vd: u32, opcode: u32| (nf << 29) | (mop << 26) | (vm << 25) | (rs2 << 20) | (rs1 << 15) | (6 << 12) | (vd << 7) | opcode;
+        let vsetvli = |zimm: u32| (zimm << 20) | (10 << 15) | (7 << 12) | (11 << 7) | 0x57; // vsetvli a1, a0, zimm
+        let (e32_ta_ma, e32_tu_mu) = (0x0d0, 0x010);
+        let elements = |cpu: &VirtualCPU, reg: usize| -> Vec<u32>
+        {
+            (0..4).map(|i| u32::from_le_bytes(cpu.vregs[reg * 16 + 4 * i..reg * 16 + 4 * i + 4].try_into().unwrap())).collect()
+        };
+        let words = |cpu: &VirtualCPU, address: u64, count: u64| -> Vec<u32> { (0..count).map(|i| cpu.ram.read_u32(address + 4 * i).unwrap()).collect() };
+        let (data, out) = (0x8000_2000, 0x8000_3000);
+        for i in 0..8
+        {
+            cpu.ram.write_u32(data + 4 * i, i as u32 + 1).unwrap();
+        }
+        cpu.regs[12] = data;
+        cpu.regs[13] = 8;
+        cpu.regs[14] = out;
+
+        cpu.regs[10] = 4;
+        assert_eq!(run(&mut cpu, vsetvli(e32_ta_ma)), 0);
+        assert_eq!(run(&mut cpu, vmem(0, 0, 1, 0, 12, 1, 0x07)), 0); // vle32.v v1, (a2)
+        assert_eq!(elements(&cpu, 1), [1, 2, 3, 4]);
+
+        // vslideup.vi with offsets of 0, below vl and at least vl; the
+        // elements below the offset keep their old values.
+        let vmv_v_i = |vd: u32, imm: u32| op_v(0x17, 1, 0, imm, 3, vd);
+        let slide = |funct6: u32, offset: u32| op_v(funct6, 1, 1, offset, 3, 3);
+        assert_eq!(run(&mut cpu, slide(0x0e, 0)), 0);
+        assert_eq!(elements(&cpu, 3), [1, 2, 3, 4]);
+        assert_eq!(run(&mut cpu, vmv_v_i(3, 9)), 0);
+        assert_eq!(run(&mut cpu, slide(0x0e, 2)), 0);
+        assert_eq!(elements(&cpu, 3), [9, 9, 1, 2]);
+        assert_eq!(run(&mut cpu, vmv_v_i(3, 9)), 0);
+        assert_eq!(run(&mut cpu, slide(0x0e, 5)), 0);
+        assert_eq!(elements(&cpu, 3), [9, 9, 9, 9]);
+        // The offset may also pass vl when vl is below VLMAX.
+        cpu.regs[10] = 3;
+        assert_eq!(run(&mut cpu, vsetvli(e32_ta_ma)), 0);
+        assert_eq!(run(&mut cpu, slide(0x0e, 2)), 0);
+        assert_eq!(elements(&cpu, 3), [9, 9, 1, 9]);
+        cpu.regs[10] = 4;
+        assert_eq!(run(&mut cpu, vsetvli(e32_ta_ma)), 0);
+
+        // vslidedown.vi reads zeros past VLMAX.
+        assert_eq!(run(&mut cpu, slide(0x0f, 0)), 0);
+        assert_eq!(elements(&cpu, 3), [1, 2, 3, 4]);
+        assert_eq!(run(&mut cpu, slide(0x0f, 1)), 0);
+        assert_eq!(elements(&cpu, 3), [2, 3, 4, 0]);
+        assert_eq!(run(&mut cpu, slide(0x0f, 4)), 0);
+        assert_eq!(elements(&cpu, 3), [0, 0, 0, 0]);
+
+        // vrgather.vv and vrgather.vi, with an index past VLMAX.
+        for (i, index) in [3u32, 0, 9, 1].iter().enumerate()
+        {
+            cpu.ram.write_u32(out + 4 * i as u64, *index).unwrap();
+        }
+        assert_eq!(run(&mut cpu, vmem(0, 0, 1, 0, 14, 2, 0x07)), 0); // vle32.v v2, (a4)
+        assert_eq!(run(&mut cpu, op_v(0x0c, 1, 1, 2, 0, 4)), 0); // vrgather.vv v4, v1, v2
+        assert_eq!(elements(&cpu, 4), [4, 1, 0, 2]);
+        assert_eq!(run(&mut cpu, op_v(0x0c, 1, 1, 2, 3, 4)), 0); // vrgather.vi v4, v1, 2
+        assert_eq!(elements(&cpu, 4), [3, 3, 3, 3]);
+
+        // Strided accesses with an 8-byte stride.
+        assert_eq!(run(&mut cpu, vmem(0, 2, 1, 13, 12, 5, 0x07)), 0); // vlse32.v v5, (a2), a3
+        assert_eq!(elements(&cpu, 5), [1, 3, 5, 7]);
+        assert_eq!(run(&mut cpu, vmem(0, 2, 1, 13, 14, 1, 0x27)), 0); // vsse32.v v1, (a4), a3
+        assert_eq!(words(&cpu, out, 8), [1, 0, 2, 1, 3, 0, 4, 0]);
+
+        // Indexed accesses with byte offsets in v7.
+        for (i, offset) in [12u32, 0, 4, 8].iter().enumerate()
+        {
+            cpu.ram.write_u32(out + 0x100 + 4 * i as u64, *offset).unwrap();
+        }
+        cpu.regs[15] = out + 0x100;
+        assert_eq!(run(&mut cpu, vmem(0, 0, 1, 0, 15, 7, 0x07)), 0); // vle32.v v7, (a5)
+        assert_eq!(run(&mut cpu, vmem(0, 1, 1, 7, 12, 6, 0x07)), 0); // vluxei32.v v6, (a2), v7
+        assert_eq!(elements(&cpu, 6), [4, 1, 2, 3]);
+        assert_eq!(run(&mut cpu, vmem(0, 3, 1, 7, 14, 1, 0x27)), 0); // vsoxei32.v v1, (a4), v7
+        assert_eq!(words(&cpu, out, 4), [2, 3, 4, 1]);
+
+        // Two-field segments are de-interleaved into v8 and v9.
+        assert_eq!(run(&mut cpu, vmem(1, 0, 1, 0, 12, 8, 0x07)), 0); // vlseg2e32.v v8, (a2)
+        assert_eq!((elements(&cpu, 8), elements(&cpu, 9)), (vec![1, 3, 5, 7], vec![2, 4, 6, 8]));
+        assert_eq!(run(&mut cpu, vmem(1, 0, 1, 0, 14, 8, 0x27)), 0); // vsseg2e32.v v8, (a4)
+        assert_eq!(words(&cpu, out, 8), [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // Masked-off and tail elements: undisturbed, unless the policy is
+        // agnostic and the hart fills agnostic elements with ones.
+        cpu.vregs[0] = 0b101;
+        cpu.regs[10] = 3;
+        let masked_add = op_v(0x00, 0, 1, 0, 3, 10); // vadd.vi v10, v1, 0, v0.t
+        for (zimm, agnostic_ones, expected) in [
+            (e32_ta_ma, false, [1, 7, 3, 7]),
+            (e32_tu_mu, true, [1, 7, 3, 7]),
+            (e32_ta_ma, true, [1, u32::MAX, 3, u32::MAX]),
+        ]
+        {
+            cpu.agnostic_ones = agnostic_ones;
+            assert_eq!(run(&mut cpu, vsetvli(zimm)), 0);
+            cpu.vregs[10 * 16..11 * 16].copy_from_slice(&[7, 0, 0, 0].repeat(4));
+            assert_eq!(run(&mut cpu, masked_add), 0);
+            assert_eq!(elements(&cpu, 10), expected, "vtype {:#x}, ones {}", zimm, agnostic_ones);
+        }
+        // Masked-off elements of a load are not read or written.
+        cpu.agnostic_ones = false;
+        cpu.vregs[10 * 16..11 * 16].copy_from_slice(&[7, 0, 0, 0].repeat(4));
+        assert_eq!(run(&mut cpu, vmem(0, 0, 0, 0, 12, 10, 0x07)), 0); // vle32.v v10, (a2), v0.t
+        assert_eq!(elements(&cpu, 10), [1, 7, 3, 7]);
+    }
+
+    #[test]
+    fn test_bitmanip_and_word_instructions()
+    {
+        // Every case computes a2 from a0 and a1.
+        let r = |funct7: u32, funct3: u32, opcode: u8| (funct7 << 25) | (11 << 20) | (10 << 15) | (funct3 << 12) | (12 << 7) | opcode as u32;
+        let i = |imm: u32, funct3: u32, opcode: u8| (imm << 20) | (10 << 15) | (funct3 << 12) | (12 << 7) | opcode as u32;
+        let cases: &[(u32, u64, u64, u64)] = &[
+            (r(0x00, 0x0, OPCODE_R), u64::MAX, 1, 0), // add wraps
+            (r(0x00, 0x2, OPCODE_R), -1i64 as u64, 1, 1), // slt is signed
+            (i(32, 0x5, OPCODE_I), 1 << 40, 0, 1 << 8), // srli by 32
+            (r(0x00, 0x0, OPCODE_R_32), 0x7fff_ffff, 1, 0xffff_ffff_8000_0000), // addw
+            (i(0x401, 0x5, OPCODE_I_32), 0x8000_0000, 0, 0xffff_ffff_c000_0000), // sraiw
+            (r(0x10, 0x2, OPCODE_R), 3, 5, 11), // sh1add
+            (r(0x10, 0x6, OPCODE_R_32), 0xffff_ffff_0000_0001, 1, 9), // sh3add.uw
+            (r(0x04, 0x0, OPCODE_R_32), 0xffff_ffff_8000_0000, 1, 0x8000_0001), // add.uw
+            (i(0x084, 0x1, OPCODE_I_32), 0xffff_ffff_8000_0000, 0, 0x8_0000_0000), // slli.uw
+            (r(0x20, 0x7, OPCODE_R), 0b1111, 0b0101, 0b1010), // andn
+            (r(0x20, 0x4, OPCODE_R), 0, 0, u64::MAX), // xnor
+            (i(0x600, 0x1, OPCODE_I), 1, 0, 63), // clz
+            (i(0x601, 0x1, OPCODE_I_32), 0, 0, 32), // ctzw
+            (i(0x602, 0x1, OPCODE_I_32), 0xffff_ffff_0000_0003, 0, 2), // cpopw
+            (r(0x05, 0x4, OPCODE_R), -1i64 as u64, 1, -1i64 as u64), // min
+            (r(0x05, 0x7, OPCODE_R), -1i64 as u64, 1, u64::MAX), // maxu
+            (i(0x604, 0x1, OPCODE_I), 0x80, 0, 0xffff_ffff_ffff_ff80), // sext.b
+            (i(0x605, 0x1, OPCODE_I), 0x8000, 0, 0xffff_ffff_ffff_8000), // sext.h
+            (r(0x04, 0x4, OPCODE_R_32) & !(0x1f << 20), 0x1234_5678, 0, 0x5678), // zext.h
+            (r(0x30, 0x1, OPCODE_R), 1 << 63, 1, 1), // rol
+            (r(0x30, 0x5, OPCODE_R_32), 1, 1, 0xffff_ffff_8000_0000), // rorw
+            (i(0x604, 0x5, OPCODE_I), 0xf, 0, 0xf000_0000_0000_0000), // rori
+            (i(0x287, 0x5, OPCODE_I), 0x0100, 0, 0xff00), // orc.b
+            (i(0x6b8, 0x5, OPCODE_I), 0x0102_0304_0506_0708, 0, 0x0807_0605_0403_0201), // rev8
+            (r(0x05, 0x1, OPCODE_R), 0b101, 0b11, 0b1111), // clmul
+            (r(0x14, 0x1, OPCODE_R), 0, 65, 2), // bset
+            (i(0x483, 0x1, OPCODE_I), 0xf, 0, 0x7), // bclri
+            (i(0x483, 0x5, OPCODE_I), 0x8, 0, 1), // bexti
+            (i(0x680, 0x1, OPCODE_I), 1, 0, 0), // binvi
+        ];
+        let mut cpu = VirtualCPU::new();
+        for &(instruction, a, b, expected) in cases
+        {
+            cpu.regs[10] = a;
+            cpu.regs[11] = b;
+            cpu.execute(cpu.decode(instruction));
+            assert_eq!(cpu.regs[12], expected, "instruction {:#010x}", instruction);
+        }
+
+        cpu.set_isa(Isa::parse("rv64i_zicsr_zba").unwrap());
+        cpu.execute(cpu.decode(r(0x20, 0x7, OPCODE_R))); // andn
+        assert_eq!(cpu.read_csr(MCAUSE), Exception::IllegalInstruction.code());
+    }
+
+    #[test]
+    fn test_rv64gc_program()
+    {
+        // Assembled with the M, A and C extensions; the compressed parcels
+        // leave the 32-bit instructions on 16-bit boundaries.
+        let program: &[u8] = &[
+            0x99, 0x45, // c.li a1, 6
+            0x1d, 0x46, // c.li a2, 7
+            0xb3, 0x86, 0xc5, 0x02, // mul a3, a1, a2
+            0x6d, 0x57, // c.li a4, -5
+            0xb3, 0xc7, 0xe6, 0x02, // div a5, a3, a4
+            0x33, 0xe8, 0xe6, 0x02, // rem a6, a3, a4
+            0xb3, 0xd8, 0x06, 0x02, // divu a7, a3, zero
+            0x2f, 0x33, 0xd5, 0x00, // amoadd.d t1, a3, (a0)
+            0x2f, 0x34, 0x05, 0x10, // loop: lr.d s0, (a0)
+            0x05, 0x04, // c.addi s0, 1
+            0xaf, 0x34, 0x85, 0x18, // sc.d s1, s0, (a0)
+            0xfd, 0xf8, // c.bnez s1, loop
+            0xfd, 0x15, // c.addi a1, -1
+            0xed, 0xf9, // c.bnez a1, loop
+            0x50, 0x41, // c.lw a2, 4(a0)
+            0x11, 0xa0, // c.j end
+            0x01, 0x46, // c.li a2, 0
+            0x02, 0x90, // end: c.ebreak
+        ];
+        let mut cpu = VirtualCPU::with_ram(0x8000_0000, 0x10000);
+        cpu.ram.write(0x8000_0000, program).unwrap();
+        cpu.ram.write_u64(0x8000_1000, 0x1_0000_0064).unwrap();
+        cpu.write_csr(MTVEC, 0x8000_2000);
+        cpu.regs[10] = 0x8000_1000;
+        cpu.pc = 0x8000_0000;
+        while cpu.pc != 0x8000_2000
+        {
+            cpu.step();
+        }
+
+        assert_eq!(cpu.read_csr(MCAUSE), Exception::Breakpoint.code());
+        assert_eq!(cpu.read_csr(MEPC), 0x8000_0030);
+        assert_eq!(cpu.regs[13], 42); // a3
+        assert_eq!(cpu.regs[15], -8i64 as u64); // a5
+        assert_eq!(cpu.regs[16], 2); // a6
+        assert_eq!(cpu.regs[17], u64::MAX); // a7: division by zero
+        assert_eq!(cpu.regs[6], 0x1_0000_0064); // t1
+        assert_eq!(cpu.ram.read_u64(0x8000_1000), Some(0x1_0000_0094));
+        assert_eq!(cpu.regs[12], 1); // a2 from c.lw, past the skipped c.li
+        // Everything but the ebreak retired.
+        assert_eq!(cpu.read_csr(CYCLE), 47);
+        assert_eq!(cpu.read_csr(INSTRET), 46);
+
+        // sc fails once its reservation is gone.
+        assert_eq!(run(&mut cpu, 0x1885_34af), 0); // sc.d s1, s0, (a0)
+        assert_eq!(cpu.regs[9], 1);
+        assert_eq!(cpu.ram.read_u64(0x8000_1000), Some(0x1_0000_0094));
+    }
+
+    #[test]
+    fn test_crypto_instructions_and_seed()
+    {
+        let r = |funct7: u32, funct3: u32, opcode: u8| (funct7 << 25) | (11 << 20) | (10 << 15) | (funct3 << 12) | (12 << 7) | opcode as u32;
+        let i = |imm: u32, funct3: u32, opcode: u8| (imm << 20) | (10 << 15) | (funct3 << 12) | (12 << 7) | opcode as u32;
+        let cases: &[(u32, u64, u64, u64)] = &[
+            (i(0x100, 0x1, OPCODE_I), 0x6a09_e667, 0, 0xffff_ffff_ce20_b47e), // sha256sum0
+            (i(0x108, 0x1, OPCODE_I), 1, 0, 0x0002_0201), // sm3p0
+            (i(0x300, 0x1, OPCODE_I), 0x0000_0001, 0, 0x0b0d_090e), // aes64im
+            (i(0x31a, 0x1, OPCODE_I), 0, 0, 0x6363_6363_6363_6363), // aes64ks1i, round 0xa
+            (r(0x19, 0x0, OPCODE_R), 0, 0, 0x6363_6363_6363_6363), // aes64es
+            (r(0x3f, 0x0, OPCODE_R), 1 << 32, 0x4_0000_0003, 0x6_0000_0002), // aes64ks2
+            (r(0x04, 0x4, OPCODE_R), 0xaaaa_aaaa_1111_1111, 0xbbbb_bbbb_2222_2222, 0x2222_2222_1111_1111), // pack
+            (r(0x04, 0x7, OPCODE_R), 0x1234, 0x5678, 0x7834), // packh
+            (r(0x04, 0x4, OPCODE_R_32), 0x1111, 0x8000, 0xffff_ffff_8000_1111), // packw
+            (i(0x687, 0x5, OPCODE_I), 0x01, 0, 0x80), // brev8
+            (r(0x14, 0x4, OPCODE_R), 0x0807_0605_0403_0201, 0x0900_0102, 0x0101_0101_0001_0203), // xperm8
+        ];
+        let mut cpu = VirtualCPU::new();
+        for &(instruction, a, b, expected) in cases
+        {
+            cpu.regs[10] = a;
+            cpu.regs[11] = b;
+            cpu.execute(cpu.decode(instruction));
+            assert_eq!(cpu.regs[12], expected, "instruction {:#010x}", instruction);
+        }
+        cpu.execute(cpu.decode(i(0x31b, 0x1, OPCODE_I))); // aes64ks1i with a reserved round
+        assert_eq!(cpu.read_csr(MCAUSE), Exception::IllegalInstruction.code());
+
+        // seed must be accessed with a write, and only M-mode may by default.
+        let mut cpu = VirtualCPU::new();
+        cpu.execute(cpu.decode(0x0150_1573)); // csrrw a0, seed, zero
+        assert_eq!(cpu.regs[10] >> 30, 0b10);
+        assert_eq!(cpu.read_csr(MCAUSE), 0);
+        cpu.execute(cpu.decode(0x0150_2573)); // csrrs a0, seed, zero
+        assert_eq!(cpu.read_csr(MCAUSE), Exception::IllegalInstruction.code());
+        cpu.write_csr(MCAUSE, 0);
+        cpu.privilege = Privilege::Supervisor;
+        cpu.execute(cpu.decode(0x0150_1573));
+        assert_eq!(cpu.read_csr(MCAUSE), Exception::IllegalInstruction.code());
+        cpu.privilege = Privilege::Supervisor;
+        cpu.write_csr(MCAUSE, 0);
+        cpu.write_csr(MSECCFG, MSECCFG_SSEED);
+        cpu.execute(cpu.decode(0x0150_1573));
+        assert_eq!(cpu.read_csr(MCAUSE), 0);
+    }
+}
@@ -0,0 +1,1437 @@
+//! The V extension and its embedded subsets (Zve*).
+//!
+//! Register groups are read whole before the destination is written, so
+//! encodings that the spec reserves because sources and destination
+//! overlap still produce a result; only alignment and the v0 rule for
+//! masked destinations are checked.
+
+use super::float::{self, Format, DYNAMIC, ROD, RTZ};
+use super::{DecodedInstruction, VirtualCPU};
+use crate::csr::{MSTATUS, MSTATUS_SD, MSTATUS_VS, VL, VSTART, VTYPE, VXRM, VXSAT};
+use crate::isa::Extension;
+
+pub(super) const OPCODE_V: u8 = 0b1010111;
+
+// OP-V funct3: operand kinds
+const OPIVV: u8 = 0;
+const OPFVV: u8 = 1;
+const OPMVV: u8 = 2;
+const OPIVI: u8 = 3;
+const OPIVX: u8 = 4;
+const OPFVF: u8 = 5;
+const OPMVX: u8 = 6;
+const OPCFG: u8 = 7;
+
+/// Set in vtype when software asked for an unsupported setting.
+pub(super) const VTYPE_VILL: u64 = 1 << 63;
+
+// mop field of vector loads and stores
+const MOP_UNIT: u8 = 0;
+const MOP_STRIDED: u8 = 2;
+
+// lumop/sumop values of unit-stride accesses
+const UNIT_NORMAL: u8 = 0b00000;
+const UNIT_WHOLE: u8 = 0b01000;
+const UNIT_MASK: u8 = 0b01011;
+const UNIT_FAULT_FIRST: u8 = 0b10000;
+
+/// A valid vtype setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct VType
+{
+    /// SEW in bytes.
+    sew: usize,
+    /// log2 of LMUL, from -3 to 3.
+    lmul: i32,
+    tail_agnostic: bool,
+    mask_agnostic: bool,
+}
+
+impl VType
+{
+    /// Decodes a vtype value, or None for settings that set vill. `elen`
+    /// is the widest element in bytes.
+    fn decode(value: u64, elen: usize) -> Option<Self>
+    {
+        let vsew = (value >> 3) & 0x7;
+        let vlmul = (value & 0x7) as i32;
+        if value >> 8 != 0 || vsew > 3 || vlmul == 4
+        {
+            return None;
+        }
+        let sew = 1 << vsew;
+        let lmul = (vlmul << 29) >> 29;
+        // A fractional group must still hold an ELEN-wide element's worth.
+        if sew > elen || (lmul < 0 && sew << -lmul > elen)
+        {
+            return None;
+        }
+        Some(VType { sew, lmul, tail_agnostic: value & (1 << 6) != 0, mask_agnostic: value & (1 << 7) != 0 })
+    }
+
+    /// VLMAX: the number of elements in a register group.
+    fn vlmax(&self, vlenb: usize) -> usize
+    {
+        group_bytes(vlenb, self.lmul) / self.sew
+    }
+}
+
+fn group_bytes(vlenb: usize, emul: i32) -> usize
+{
+    if emul >= 0 { vlenb << emul } else { vlenb >> -emul }
+}
+
+/// Whether a group of 2^emul registers may start at `reg`.
+fn aligned(reg: usize, emul: i32) -> bool
+{
+    (-3..=3).contains(&emul) && reg.is_multiple_of(1 << emul.max(0))
+}
+
+fn mask(bits: u32) -> u64
+{
+    u64::MAX >> (64 - bits)
+}
+
+fn signed(value: u64, bits: u32) -> i64
+{
+    ((value << (64 - bits)) as i64) >> (64 - bits)
+}
+
+fn extend(value: u64, bits: u32, is_signed: bool) -> i128
+{
+    if is_signed { signed(value, bits) as i128 } else { (value & mask(bits)) as i128 }
+}
+
+/// Shifts right by `shift` bits, rounding as vxrm says.
+fn round_shift(value: i128, shift: u32, vxrm: u8) -> i128
+{
+    if shift == 0
+    {
+        return value;
+    }
+    let bit = |n: u32| (value >> n) & 1 != 0;
+    let below_half = value & ((1 << (shift - 1)) - 1) != 0;
+    let increment = match vxrm
+    {
+        0 => bit(shift - 1), // round to nearest, ties up
+        1 => bit(shift - 1) && (below_half || bit(shift)), // round to nearest, ties even
+        2 => false, // round down
+        _ => !bit(shift) && (bit(shift - 1) || below_half), // round to odd
+    };
+    (value >> shift) + increment as i128
+}
+
+/// The second operand of an arithmetic instruction.
+#[derive(Clone, Copy)]
+enum Operand
+{
+    Vector(usize),
+    /// An x or f register or an immediate.
+    Scalar(u64),
+}
+
+/// The fields of an OP-V arithmetic instruction.
+#[derive(Clone, Copy)]
+struct VectorOp
+{
+    funct6: u8,
+    vd: usize,
+    vs2: usize,
+    source: Operand,
+    /// The vs1/rs1 field, which selects the operation of unary instructions.
+    selector: usize,
+    unmasked: bool,
+    vtype: VType,
+    vl: usize,
+}
+
+/// What an integer element function needs besides its operands.
+struct Lane
+{
+    bits: u32,
+    vxrm: u8,
+    saturated: bool,
+}
+
+impl Lane
+{
+    fn signed(&self, value: u64) -> i128
+    {
+        signed(value, self.bits) as i128
+    }
+
+    fn shift(&self, amount: u64) -> u32
+    {
+        (amount & (self.bits as u64 - 1)) as u32
+    }
+
+    fn saturate_signed(&mut self, value: i128) -> u64
+    {
+        let max = (1i128 << (self.bits - 1)) - 1;
+        let clamped = value.clamp(-max - 1, max);
+        self.saturated |= clamped != value;
+        clamped as u64
+    }
+
+    fn saturate_unsigned(&mut self, value: i128) -> u64
+    {
+        let clamped = value.clamp(0, mask(self.bits) as i128);
+        self.saturated |= clamped != value;
+        clamped as u64
+    }
+}
+
+/// Computes vd[i] from a = vs2[i], b = vs1[i] or the scalar, and d = vd[i].
+/// Operands are zero-extended from SEW and results are truncated to it.
+type IntFunction = fn(u64, u64, u64, &mut Lane) -> u64;
+
+/// The same for floating point, with a rounding mode and accrued flags.
+type FloatFunction = fn(Format, u64, u64, u64, u8, &mut u8) -> u64;
+
+/// The element-wise instructions of OPIVV, OPIVX and OPIVI.
+fn opi_function(funct6: u8, funct3: u8) -> Option<IntFunction>
+{
+    let vv = funct3 == OPIVV;
+    let vi = funct3 == OPIVI;
+    let function: IntFunction = match funct6
+    {
+        0x00 => |a, b, _, _| a.wrapping_add(b), // vadd
+        0x02 if !vi => |a, b, _, _| a.wrapping_sub(b), // vsub
+        0x03 if !vv => |a, b, _, _| b.wrapping_sub(a), // vrsub
+        0x04 if !vi => |a, b, _, _| a.min(b), // vminu
+        0x05 if !vi => |a, b, _, lane| if lane.signed(a) < lane.signed(b) { a } else { b }, // vmin
+        0x06 if !vi => |a, b, _, _| a.max(b), // vmaxu
+        0x07 if !vi => |a, b, _, lane| if lane.signed(a) > lane.signed(b) { a } else { b }, // vmax
+        0x09 => |a, b, _, _| a & b, // vand
+        0x0a => |a, b, _, _| a | b, // vor
+        0x0b => |a, b, _, _| a ^ b, // vxor
+        0x20 => |a, b, _, lane| lane.saturate_unsigned(a as i128 + b as i128), // vsaddu
+        0x21 => |a, b, _, lane| { let sum = lane.signed(a) + lane.signed(b); lane.saturate_signed(sum) }, // vsadd
+        0x22 if !vi => |a, b, _, lane| lane.saturate_unsigned(a as i128 - b as i128), // vssubu
+        0x23 if !vi => |a, b, _, lane| { let difference = lane.signed(a) - lane.signed(b); lane.saturate_signed(difference) }, // vssub
+        0x25 => |a, b, _, lane| a << lane.shift(b), // vsll
+        0x27 if !vi => |a, b, _, lane|
+        {
+            let product = round_shift(lane.signed(a) * lane.signed(b), lane.bits - 1, lane.vxrm);
+            lane.saturate_signed(product)
+        }, // vsmul
+        0x28 => |a, b, _, lane| a >> lane.shift(b), // vsrl
+        0x29 => |a, b, _, lane| (lane.signed(a) >> lane.shift(b)) as u64, // vsra
+        0x2a => |a, b, _, lane| round_shift(a as i128, lane.shift(b), lane.vxrm) as u64, // vssrl
+        0x2b => |a, b, _, lane| round_shift(lane.signed(a), lane.shift(b), lane.vxrm) as u64, // vssra
+        _ => return None,
+    };
+    Some(function)
+}
+
+/// The element-wise instructions of OPMVV and OPMVX.
+fn opm_function(funct6: u8) -> Option<IntFunction>
+{
+    let function: IntFunction = match funct6
+    {
+        0x08 => |a, b, _, lane| round_shift(a as i128 + b as i128, 1, lane.vxrm) as u64, // vaaddu
+        0x09 => |a, b, _, lane| round_shift(lane.signed(a) + lane.signed(b), 1, lane.vxrm) as u64, // vaadd
+        0x0a => |a, b, _, lane| round_shift(a as i128 - b as i128, 1, lane.vxrm) as u64, // vasubu
+        0x0b => |a, b, _, lane| round_shift(lane.signed(a) - lane.signed(b), 1, lane.vxrm) as u64, // vasub
+        0x20 => |a, b, _, _| a.checked_div(b).unwrap_or(u64::MAX), // vdivu
+        0x21 => |a, b, _, lane| if b == 0 { u64::MAX } else { (lane.signed(a) / lane.signed(b)) as u64 }, // vdiv
+        0x22 => |a, b, _, _| a.checked_rem(b).unwrap_or(a), // vremu
+        0x23 => |a, b, _, lane| if b == 0 { a } else { (lane.signed(a) % lane.signed(b)) as u64 }, // vrem
+        0x24 => |a, b, _, lane| ((a as u128 * b as u128) >> lane.bits) as u64, // vmulhu
+        0x25 => |a, b, _, _| a.wrapping_mul(b), // vmul
+        0x26 => |a, b, _, lane| ((lane.signed(a) * b as i128) >> lane.bits) as u64, // vmulhsu
+        0x27 => |a, b, _, lane| ((lane.signed(a) * lane.signed(b)) >> lane.bits) as u64, // vmulh
+        0x29 => |a, b, d, _| b.wrapping_mul(d).wrapping_add(a), // vmadd
+        0x2b => |a, b, d, _| a.wrapping_sub(b.wrapping_mul(d)), // vnmsub
+        0x2d => |a, b, d, _| b.wrapping_mul(a).wrapping_add(d), // vmacc
+        0x2f => |a, b, d, _| d.wrapping_sub(b.wrapping_mul(a)), // vnmsac
+        _ => return None,
+    };
+    Some(function)
+}
+
+/// The element-wise instructions of OPFVV and OPFVF.
+fn opf_function(funct6: u8, funct3: u8) -> Option<FloatFunction>
+{
+    let vf = funct3 == OPFVF;
+    let function: FloatFunction = match funct6
+    {
+        0x00 => |format, a, b, _, rm, flags| float::add(format, a, b, rm, flags), // vfadd
+        0x02 => |format, a, b, _, rm, flags| float::sub(format, a, b, rm, flags), // vfsub
+        0x04 => |format, a, b, _, _, flags| float::min_max(format, a, b, false, flags), // vfmin
+        0x06 => |format, a, b, _, _, flags| float::min_max(format, a, b, true, flags), // vfmax
+        0x08 => |format, a, b, _, _, _| float::inject_sign(format, a, b, 0), // vfsgnj
+        0x09 => |format, a, b, _, _, _| float::inject_sign(format, a, b, 1), // vfsgnjn
+        0x0a => |format, a, b, _, _, _| float::inject_sign(format, a, b, 2), // vfsgnjx
+        0x20 => |format, a, b, _, rm, flags| float::div(format, a, b, rm, flags), // vfdiv
+        0x21 if vf => |format, a, b, _, rm, flags| float::div(format, b, a, rm, flags), // vfrdiv
+        0x24 => |format, a, b, _, rm, flags| float::mul(format, a, b, rm, flags), // vfmul
+        0x27 if vf => |format, a, b, _, rm, flags| float::sub(format, b, a, rm, flags), // vfrsub
+        0x28 => |format, a, b, d, rm, flags| float::fma(format, b, d, a, rm, flags), // vfmadd
+        0x29 => |format, a, b, d, rm, flags|
+        {
+            let sign = format.sign_bit();
+            float::fma(format, b ^ sign, d, a ^ sign, rm, flags)
+        }, // vfnmadd
+        0x2a => |format, a, b, d, rm, flags| float::fma(format, b, d, a ^ format.sign_bit(), rm, flags), // vfmsub
+        0x2b => |format, a, b, d, rm, flags| float::fma(format, b ^ format.sign_bit(), d, a, rm, flags), // vfnmsub
+        0x2c => |format, a, b, d, rm, flags| float::fma(format, b, a, d, rm, flags), // vfmacc
+        0x2d => |format, a, b, d, rm, flags|
+        {
+            let sign = format.sign_bit();
+            float::fma(format, b ^ sign, a, d ^ sign, rm, flags)
+        }, // vfnmacc
+        0x2e => |format, a, b, d, rm, flags| float::fma(format, b, a, d ^ format.sign_bit(), rm, flags), // vfmsac
+        0x2f => |format, a, b, d, rm, flags| float::fma(format, b ^ format.sign_bit(), a, d, rm, flags), // vfnmsac
+        _ => return None,
+    };
+    Some(function)
+}
+
+/// A vector load or store, moving elements vstart..count of each field.
+struct Transfer
+{
+    store: bool,
+    data: usize,
+    bytes: usize,
+    fields: usize,
+    /// Registers from one field of a segment to the next.
+    field_regs: usize,
+    count: usize,
+    unmasked: bool,
+    fault_only_first: bool,
+}
+
+impl VirtualCPU
+{
+    fn has_vector(&self) -> bool
+    {
+        self.isa.has(Extension::V) || self.isa.has(Extension::Zve32x)
+    }
+
+    /// Whether mstatus.VS lets vector instructions and CSRs be used.
+    pub(super) fn vector_enabled(&self) -> bool
+    {
+        self.has_vector() && self.csrs[MSTATUS as usize] & MSTATUS_VS != 0
+    }
+
+    pub(super) fn mark_vector_dirty(&mut self)
+    {
+        self.csrs[MSTATUS as usize] |= MSTATUS_VS | MSTATUS_SD;
+    }
+
+    /// ELEN in bytes.
+    fn elen(&self) -> usize
+    {
+        if self.isa.has(Extension::V) || self.isa.has(Extension::Zve64x) { 8 } else { 4 }
+    }
+
+    fn vtype(&self) -> Option<VType>
+    {
+        VType::decode(self.csrs[VTYPE as usize], self.elen())
+    }
+
+    /// The floating-point format of `bytes`-wide elements, if vector
+    /// instructions support it.
+    fn vector_format(&self, bytes: usize) -> Option<Format>
+    {
+        let supported = match bytes
+        {
+            4 => self.isa.has(Extension::V) || self.isa.has(Extension::Zve32f),
+            8 => self.isa.has(Extension::V) || self.isa.has(Extension::Zve64d),
+            _ => false,
+        };
+        if supported { Format::from_bytes(bytes) } else { None }
+    }
+
+    fn velement(&self, reg: usize, index: usize, bytes: usize) -> u64
+    {
+        let start = reg * self.vlenb + index * bytes;
+        self.vregs[start..start + bytes].iter().rev().fold(0, |value, &byte| value << 8 | byte as u64)
+    }
+
+    fn set_velement(&mut self, reg: usize, index: usize, bytes: usize, value: u64)
+    {
+        let start = reg * self.vlenb + index * bytes;
+        for (i, byte) in self.vregs[start..start + bytes].iter_mut().enumerate()
+        {
+            *byte = (value >> (8 * i)) as u8;
+        }
+    }
+
+    fn vmask(&self, reg: usize, index: usize) -> bool
+    {
+        self.vregs[reg * self.vlenb + index / 8] >> (index % 8) & 1 != 0
+    }
+
+    fn set_vmask(&mut self, reg: usize, index: usize, bit: bool)
+    {
+        let byte = &mut self.vregs[reg * self.vlenb + index / 8];
+        *byte = (*byte & !(1 << (index % 8))) | (bit as u8) << (index % 8);
+    }
+
+    fn operand(&self, operand: Operand, index: usize, bytes: usize) -> u64
+    {
+        match operand
+        {
+            Operand::Vector(reg) => self.velement(reg, index, bytes),
+            Operand::Scalar(value) => value & mask(bytes as u32 * 8),
+        }
+    }
+
+    /// Evaluates `element` for each body element from vstart to `vl` that
+    /// the mask leaves active; the rest are None.
+    fn body<T>(&self, vl: usize, unmasked: bool, mut element: impl FnMut(usize) -> T) -> Vec<Option<T>>
+    {
+        let vstart = self.csrs[VSTART as usize] as usize;
+        (0..vl).map(|i| (i >= vstart && (unmasked || self.vmask(0, i))).then(|| element(i))).collect()
+    }
+
+    /// Writes a result to the group of 2^emul registers at `vd`. Elements
+    /// before vstart are left alone, inactive (None) elements follow the
+    /// mask policy and those past the body the tail policy.
+    fn write_vector(&mut self, vd: usize, bytes: usize, emul: i32, body: &[Option<u64>], vtype: VType)
+    {
+        let vstart = self.csrs[VSTART as usize] as usize;
+        for (i, value) in body.iter().enumerate().skip(vstart)
+        {
+            match value
+            {
+                Some(value) => self.set_velement(vd, i, bytes, *value),
+                None if vtype.mask_agnostic && self.agnostic_ones => self.set_velement(vd, i, bytes, u64::MAX),
+                None => {}
+            }
+        }
+        if vtype.tail_agnostic && self.agnostic_ones
+        {
+            // The tail of a fractional group runs to the end of its register.
+            let end = group_bytes(self.vlenb, emul.max(0)) / bytes;
+            for i in body.len()..end
+            {
+                self.set_velement(vd, i, bytes, u64::MAX);
+            }
+        }
+    }
+
+    /// Writes a mask result. The tail of a mask register is always agnostic.
+    fn write_mask(&mut self, vd: usize, body: &[Option<bool>], vtype: VType)
+    {
+        let vstart = self.csrs[VSTART as usize] as usize;
+        for (i, bit) in body.iter().enumerate().skip(vstart)
+        {
+            match bit
+            {
+                Some(bit) => self.set_vmask(vd, i, *bit),
+                None if vtype.mask_agnostic && self.agnostic_ones => self.set_vmask(vd, i, true),
+                None => {}
+            }
+        }
+        if self.agnostic_ones
+        {
+            for i in body.len()..self.vlenb * 8
+            {
+                self.set_vmask(vd, i, true);
+            }
+        }
+    }
+
+    fn lane(&self, bytes: usize) -> Lane
+    {
+        Lane { bits: bytes as u32 * 8, vxrm: (self.csrs[VXRM as usize] & 0x3) as u8, saturated: false }
+    }
+
+    fn accrue_saturation(&mut self, lane: &Lane)
+    {
+        if lane.saturated
+        {
+            self.csrs[VXSAT as usize] = 1;
+        }
+    }
+
+    /// Whether the groups of an instruction are aligned, with vd, vs2 and a
+    /// vector vs1 spanning 2^emul registers, and a masked vd avoids v0.
+    fn groups_fit(&self, op: &VectorOp, vd: i32, vs2: i32, vs1: i32) -> bool
+    {
+        let source = match op.source
+        {
+            Operand::Vector(reg) => aligned(reg, vs1),
+            Operand::Scalar(_) => true,
+        };
+        aligned(op.vd, vd) && aligned(op.vs2, vs2) && source && (op.unmasked || op.vd != 0)
+    }
+
+    /// The same for instructions with a mask destination, which may be v0.
+    fn mask_groups_fit(&self, op: &VectorOp, vs2: i32, vs1: i32) -> bool
+    {
+        self.groups_fit(&VectorOp { unmasked: true, ..*op }, 0, vs2, vs1)
+    }
+
+    /// Whether SEW-to-2*SEW instructions can run with this vtype.
+    fn can_widen(&self, vtype: VType) -> bool
+    {
+        vtype.sew * 2 <= self.elen() && vtype.lmul < 3
+    }
+
+    /// vsetvli, vsetivli and vsetvl.
+    fn execute_vset(&mut self, instruction: &DecodedInstruction) -> Option<()>
+    {
+        let (rd, rs1) = (instruction.rd as usize, instruction.rs1 as usize);
+        let zimm = (instruction.funct7 as u64) << 5 | instruction.rs2 as u64;
+        let (value, immediate_avl) = if zimm >> 11 == 0
+        {
+            (zimm, false)
+        }
+        else if zimm >> 10 == 0b11
+        {
+            (zimm & 0x3ff, true)
+        }
+        else if instruction.funct7 == 0x40
+        {
+            (self.regs[instruction.rs2 as usize], false)
+        }
+        else
+        {
+            return None;
+        };
+        let vl = match VType::decode(value, self.elen())
+        {
+            Some(vtype) =>
+            {
+                let avl = if immediate_avl
+                {
+                    rs1 as u64
+                }
+                else if rs1 != 0
+                {
+                    self.regs[rs1]
+                }
+                else if rd != 0
+                {
+                    u64::MAX
+                }
+                else
+                {
+                    self.csrs[VL as usize]
+                };
+                self.csrs[VTYPE as usize] = value;
+                avl.min(vtype.vlmax(self.vlenb) as u64)
+            }
+            None =>
+            {
+                self.csrs[VTYPE as usize] = VTYPE_VILL;
+                0
+            }
+        };
+        self.csrs[VL as usize] = vl;
+        self.csrs[VSTART as usize] = 0;
+        self.regs[rd] = vl;
+        self.mark_vector_dirty();
+        Some(())
+    }
+
+    /// OP-V. Returns None if the instruction is illegal.
+    pub(super) fn execute_op_v(&mut self, instruction: &DecodedInstruction) -> Option<()>
+    {
+        if !self.vector_enabled()
+        {
+            return None;
+        }
+        let funct3 = instruction.funct3;
+        if funct3 == OPCFG
+        {
+            return self.execute_vset(instruction);
+        }
+        let funct6 = instruction.funct7 >> 1;
+        let unmasked = instruction.funct7 & 1 != 0;
+        let (vd, rs1, vs2) = (instruction.rd as usize, instruction.rs1 as usize, instruction.rs2 as usize);
+        if funct3 == OPIVI && funct6 == 0x27
+        {
+            self.move_whole_registers(vd, vs2, rs1 + 1, unmasked)?;
+        }
+        else
+        {
+            let vtype = self.vtype()?;
+            // Shifts, slides and gathers take an unsigned immediate.
+            let unsigned_immediate = matches!(funct6, 0x0c | 0x0e | 0x0f | 0x25 | 0x28..=0x2b | 0x2c..=0x2f);
+            let source = match funct3
+            {
+                OPIVV | OPMVV | OPFVV => Operand::Vector(rs1),
+                OPIVX | OPMVX => Operand::Scalar(self.regs[rs1]),
+                OPIVI if unsigned_immediate => Operand::Scalar(rs1 as u64),
+                OPIVI => Operand::Scalar(signed(rs1 as u64, 5) as u64),
+                _ => Operand::Scalar(self.fregs[rs1]),
+            };
+            let op = VectorOp { funct6, vd, vs2, source, selector: rs1, unmasked, vtype, vl: self.csrs[VL as usize] as usize };
+            match funct3
+            {
+                OPIVV | OPIVX | OPIVI => self.execute_opi(&op, funct3),
+                OPMVV | OPMVX => self.execute_opm(&op, funct3),
+                _ => self.execute_opf(&op, funct3),
+            }?;
+        }
+        self.csrs[VSTART as usize] = 0;
+        self.mark_vector_dirty();
+        Some(())
+    }
+
+    /// vmv1r.v, vmv2r.v, vmv4r.v and vmv8r.v, which ignore vtype and vl.
+    fn move_whole_registers(&mut self, vd: usize, vs2: usize, regs: usize, unmasked: bool) -> Option<()>
+    {
+        if !unmasked || !regs.is_power_of_two() || !vd.is_multiple_of(regs) || !vs2.is_multiple_of(regs)
+        {
+            return None;
+        }
+        let bytes = self.vtype().map_or(1, |vtype| vtype.sew);
+        let vstart = self.csrs[VSTART as usize] as usize;
+        for i in vstart..regs * self.vlenb / bytes
+        {
+            let value = self.velement(vs2, i, bytes);
+            self.set_velement(vd, i, bytes, value);
+        }
+        Some(())
+    }
+
+    fn integer_elementwise(&mut self, op: &VectorOp, function: IntFunction) -> Option<()>
+    {
+        let VType { sew, lmul, .. } = op.vtype;
+        if !self.groups_fit(op, lmul, lmul, lmul)
+        {
+            return None;
+        }
+        let mut lane = self.lane(sew);
+        let body = self.body(op.vl, op.unmasked, |i|
+        {
+            let (a, b, d) = (self.velement(op.vs2, i, sew), self.operand(op.source, i, sew), self.velement(op.vd, i, sew));
+            function(a, b, d, &mut lane)
+        });
+        self.accrue_saturation(&lane);
+        self.write_vector(op.vd, sew, lmul, &body, op.vtype);
+        Some(())
+    }
+
+    /// Folds the active elements of vs2 into vs1[0] and writes the result
+    /// to vd[0]. With vl = 0 the destination is left alone.
+    fn reduce(&mut self, op: &VectorOp, bytes: usize, mut fold: impl FnMut(&Self, u64, usize) -> u64) -> Option<()>
+    {
+        let Operand::Vector(vs1) = op.source else { return None };
+        if self.csrs[VSTART as usize] != 0 || !aligned(op.vs2, op.vtype.lmul)
+        {
+            return None;
+        }
+        if op.vl == 0
+        {
+            return Some(());
+        }
+        let mut accumulator = self.velement(vs1, 0, bytes);
+        for i in 0..op.vl
+        {
+            if op.unmasked || self.vmask(0, i)
+            {
+                accumulator = fold(self, accumulator, i);
+            }
+        }
+        self.write_vector(op.vd, bytes, 0, &[Some(accumulator)], op.vtype);
+        Some(())
+    }
+
+    /// vslideup, vslidedown, vslide1up and vslide1down and their
+    /// floating-point forms. `fill` is the scalar a slide1 inserts.
+    fn slide(&mut self, op: &VectorOp, offset: u64, up: bool, fill: Option<u64>) -> Option<()>
+    {
+        let VType { sew, lmul, .. } = op.vtype;
+        if !aligned(op.vd, lmul) || !aligned(op.vs2, lmul) || (!op.unmasked && op.vd == 0)
+        {
+            return None;
+        }
+        let vlmax = op.vtype.vlmax(self.vlenb) as u64;
+        let body = self.body(op.vl, op.unmasked, |i|
+        {
+            let i = i as u64;
+            match (up, fill)
+            {
+                (true, Some(value)) if i == 0 => value,
+                // Elements below the offset are never written.
+                (true, None) if i < offset => self.velement(op.vd, i as usize, sew),
+                (true, _) => self.velement(op.vs2, (i - offset) as usize, sew),
+                (false, Some(value)) if i + 1 == op.vl as u64 => value,
+                (false, _) => match i.checked_add(offset).filter(|&source| source < vlmax)
+                {
+                    Some(source) => self.velement(op.vs2, source as usize, sew),
+                    None => 0,
+                },
+            }
+        });
+        self.write_vector(op.vd, sew, lmul, &body, op.vtype);
+        Some(())
+    }
+
+    /// vrgather and vrgatherei16: vd[i] = vs2[index], or 0 past VLMAX.
+    fn gather(&mut self, op: &VectorOp, index_bytes: usize) -> Option<()>
+    {
+        let VType { sew, lmul, .. } = op.vtype;
+        let index_emul = lmul + index_bytes.trailing_zeros() as i32 - sew.trailing_zeros() as i32;
+        if !self.groups_fit(op, lmul, lmul, index_emul)
+        {
+            return None;
+        }
+        let vlmax = op.vtype.vlmax(self.vlenb) as u64;
+        let body = self.body(op.vl, op.unmasked, |i|
+        {
+            let index = match op.source
+            {
+                Operand::Vector(vs1) => self.velement(vs1, i, index_bytes),
+                Operand::Scalar(value) => value,
+            };
+            if index < vlmax { self.velement(op.vs2, index as usize, sew) } else { 0 }
+        });
+        self.write_vector(op.vd, sew, lmul, &body, op.vtype);
+        Some(())
+    }
+
+    fn execute_opi(&mut self, op: &VectorOp, funct3: u8) -> Option<()>
+    {
+        let VType { sew, lmul, .. } = op.vtype;
+        let bits = sew as u32 * 8;
+        let vv = funct3 == OPIVV;
+        let vi = funct3 == OPIVI;
+        match op.funct6
+        {
+            0x0c => self.gather(op, sew), // vrgather
+            0x0e if vv => self.gather(op, 2), // vrgatherei16
+            0x0e | 0x0f =>
+            {
+                let Operand::Scalar(offset) = op.source else { return None };
+                self.slide(op, offset, op.funct6 == 0x0e, None) // vslideup, vslidedown
+            }
+            0x10 | 0x12 if !op.unmasked && (op.funct6 == 0x10 || !vi) =>
+            {
+                // vadc and vsbc add or subtract the carry in v0.
+                if !self.groups_fit(op, lmul, lmul, lmul)
+                {
+                    return None;
+                }
+                let body = self.body(op.vl, true, |i|
+                {
+                    let (a, b) = (self.velement(op.vs2, i, sew), self.operand(op.source, i, sew));
+                    let carry = self.vmask(0, i) as u64;
+                    if op.funct6 == 0x10 { a.wrapping_add(b).wrapping_add(carry) } else { a.wrapping_sub(b).wrapping_sub(carry) }
+                });
+                self.write_vector(op.vd, sew, lmul, &body, op.vtype);
+                Some(())
+            }
+            0x11 | 0x13 if op.funct6 == 0x11 || !vi =>
+            {
+                // vmadc and vmsbc: the carry or borrow out, with v0 as the
+                // carry in when masked.
+                if !self.mask_groups_fit(op, lmul, lmul)
+                {
+                    return None;
+                }
+                let body = self.body(op.vl, true, |i|
+                {
+                    let (a, b) = (self.velement(op.vs2, i, sew) as i128, self.operand(op.source, i, sew) as i128);
+                    let carry = (!op.unmasked && self.vmask(0, i)) as i128;
+                    if op.funct6 == 0x11 { (a + b + carry) >> bits != 0 } else { a - b - carry < 0 }
+                });
+                self.write_mask(op.vd, &body, op.vtype);
+                Some(())
+            }
+            0x17 =>
+            {
+                // vmerge, or vmv.v with vm set and vs2 = 0.
+                if (op.unmasked && op.vs2 != 0) || !self.groups_fit(op, lmul, lmul, lmul)
+                {
+                    return None;
+                }
+                let body = self.body(op.vl, true, |i|
+                {
+                    if op.unmasked || self.vmask(0, i) { self.operand(op.source, i, sew) } else { self.velement(op.vs2, i, sew) }
+                });
+                self.write_vector(op.vd, sew, lmul, &body, op.vtype);
+                Some(())
+            }
+            0x18..=0x1f =>
+            {
+                let form_exists = match op.funct6
+                {
+                    0x1a | 0x1b => !vi,
+                    0x1e | 0x1f => !vv,
+                    _ => true,
+                };
+                if !form_exists || !self.mask_groups_fit(op, lmul, lmul)
+                {
+                    return None;
+                }
+                let body = self.body(op.vl, op.unmasked, |i|
+                {
+                    let (a, b) = (self.velement(op.vs2, i, sew), self.operand(op.source, i, sew));
+                    let (signed_a, signed_b) = (signed(a, bits), signed(b, bits));
+                    match op.funct6
+                    {
+                        0x18 => a == b, // vmseq
+                        0x19 => a != b, // vmsne
+                        0x1a => a < b, // vmsltu
+                        0x1b => signed_a < signed_b, // vmslt
+                        0x1c => a <= b, // vmsleu
+                        0x1d => signed_a <= signed_b, // vmsle
+                        0x1e => a > b, // vmsgtu
+                        _ => signed_a > signed_b, // vmsgt
+                    }
+                });
+                self.write_mask(op.vd, &body, op.vtype);
+                Some(())
+            }
+            0x2c..=0x2f =>
+            {
+                // vnsrl, vnsra, vnclipu and vnclip shift a 2*SEW vs2.
+                if !self.can_widen(op.vtype) || !self.groups_fit(op, lmul, lmul + 1, lmul)
+                {
+                    return None;
+                }
+                let wide_bits = bits * 2;
+                let mut lane = self.lane(sew);
+                let body = self.body(op.vl, op.unmasked, |i|
+                {
+                    let a = self.velement(op.vs2, i, sew * 2);
+                    let shift = (self.operand(op.source, i, sew) & (wide_bits as u64 - 1)) as u32;
+                    match op.funct6
+                    {
+                        0x2c => a >> shift,
+                        0x2d => (signed(a, wide_bits) >> shift) as u64,
+                        0x2e => lane.saturate_unsigned(round_shift(a as i128, shift, lane.vxrm)),
+                        _ => lane.saturate_signed(round_shift(signed(a, wide_bits) as i128, shift, lane.vxrm)),
+                    }
+                });
+                self.accrue_saturation(&lane);
+                self.write_vector(op.vd, sew, lmul, &body, op.vtype);
+                Some(())
+            }
+            0x30 | 0x31 if vv =>
+            {
+                // vwredsumu and vwredsum
+                if sew * 2 > self.elen()
+                {
+                    return None;
+                }
+                let is_signed = op.funct6 == 0x31;
+                self.reduce(op, sew * 2, |cpu, sum, i|
+                {
+                    sum.wrapping_add(extend(cpu.velement(op.vs2, i, sew), bits, is_signed) as u64)
+                })
+            }
+            _ =>
+            {
+                let function = opi_function(op.funct6, funct3)?;
+                self.integer_elementwise(op, function)
+            }
+        }
+    }
+
+    fn execute_opm(&mut self, op: &VectorOp, funct3: u8) -> Option<()>
+    {
+        let VType { sew, lmul, .. } = op.vtype;
+        let bits = sew as u32 * 8;
+        let vv = funct3 == OPMVV;
+        match op.funct6
+        {
+            0x00..=0x07 if vv =>
+            {
+                // vredsum, vredand, vredor, vredxor, vredminu, vredmin,
+                // vredmaxu and vredmax use the matching OPI function.
+                let function = opi_function([0x00, 0x09, 0x0a, 0x0b, 0x04, 0x05, 0x06, 0x07][op.funct6 as usize], OPIVV)?;
+                let mut lane = self.lane(sew);
+                self.reduce(op, sew, |cpu, accumulator, i| function(cpu.velement(op.vs2, i, sew), accumulator, 0, &mut lane))
+            }
+            0x0e | 0x0f if !vv =>
+            {
+                // vslide1up and vslide1down
+                let Operand::Scalar(value) = op.source else { return None };
+                self.slide(op, 1, op.funct6 == 0x0e, Some(value & mask(bits)))
+            }
+            0x10 if vv => match op.selector
+            {
+                0x00 if op.unmasked =>
+                {
+                    // vmv.x.s
+                    self.regs[op.vd] = signed(self.velement(op.vs2, 0, sew), bits) as u64;
+                    Some(())
+                }
+                0x10 | 0x11 =>
+                {
+                    // vcpop.m and vfirst.m
+                    let vstart = self.csrs[VSTART as usize] as usize;
+                    if vstart != 0
+                    {
+                        return None;
+                    }
+                    let mut set = (0..op.vl).filter(|&i| (op.unmasked || self.vmask(0, i)) && self.vmask(op.vs2, i));
+                    self.regs[op.vd] = if op.selector == 0x10 { set.count() as u64 } else { set.next().map_or(u64::MAX, |i| i as u64) };
+                    Some(())
+                }
+                _ => None,
+            },
+            0x10 if op.vs2 == 0 && op.unmasked =>
+            {
+                // vmv.s.x
+                let vstart = self.csrs[VSTART as usize] as usize;
+                if vstart < op.vl
+                {
+                    let value = self.operand(op.source, 0, sew);
+                    self.write_vector(op.vd, sew, 0, &[Some(value)], op.vtype);
+                }
+                Some(())
+            }
+            0x12 if vv && (2..=7).contains(&op.selector) =>
+            {
+                // vzext.vf8, vsext.vf8, vzext.vf4, vsext.vf4, vzext.vf2, vsext.vf2
+                let factor_log2 = 3 - (op.selector as i32 - 2) / 2;
+                let source_bytes = sew >> factor_log2;
+                if source_bytes == 0 || !self.groups_fit(op, lmul, lmul - factor_log2, 0)
+                {
+                    return None;
+                }
+                let is_signed = op.selector & 1 != 0;
+                let body = self.body(op.vl, op.unmasked, |i|
+                {
+                    extend(self.velement(op.vs2, i, source_bytes), source_bytes as u32 * 8, is_signed) as u64
+                });
+                self.write_vector(op.vd, sew, lmul, &body, op.vtype);
+                Some(())
+            }
+            0x14 if vv => self.mask_unary(op),
+            0x17 if vv && op.unmasked =>
+            {
+                // vcompress packs the elements selected by the vs1 mask.
+                let Operand::Vector(vs1) = op.source else { return None };
+                if self.csrs[VSTART as usize] != 0 || !aligned(op.vd, lmul) || !aligned(op.vs2, lmul)
+                {
+                    return None;
+                }
+                let body: Vec<Option<u64>> = (0..op.vl)
+                    .filter(|&i| self.vmask(vs1, i))
+                    .map(|i| Some(self.velement(op.vs2, i, sew)))
+                    .collect();
+                self.write_vector(op.vd, sew, lmul, &body, op.vtype);
+                Some(())
+            }
+            0x18..=0x1f if vv && op.unmasked =>
+            {
+                // Mask-register logical instructions.
+                let Operand::Vector(vs1) = op.source else { return None };
+                let body = self.body(op.vl, true, |i|
+                {
+                    let (a, b) = (self.vmask(op.vs2, i), self.vmask(vs1, i));
+                    match op.funct6
+                    {
+                        0x18 => a && !b, // vmandn
+                        0x19 => a && b, // vmand
+                        0x1a => a || b, // vmor
+                        0x1b => a != b, // vmxor
+                        0x1c => a || !b, // vmorn
+                        0x1d => !(a && b), // vmnand
+                        0x1e => !(a || b), // vmnor
+                        _ => a == b, // vmxnor
+                    }
+                });
+                self.write_mask(op.vd, &body, op.vtype);
+                Some(())
+            }
+            0x30..=0x3f => self.integer_widening(op, funct3),
+            _ =>
+            {
+                let function = opm_function(op.funct6)?;
+                self.integer_elementwise(op, function)
+            }
+        }
+    }
+
+    /// vmsbf, vmsif, vmsof, viota and vid.
+    fn mask_unary(&mut self, op: &VectorOp) -> Option<()>
+    {
+        let VType { sew, lmul, .. } = op.vtype;
+        match op.selector
+        {
+            0x01..=0x03 =>
+            {
+                let mut found = false;
+                let body = self.body(op.vl, op.unmasked, |i|
+                {
+                    let before = !found;
+                    found |= self.vmask(op.vs2, i);
+                    match op.selector
+                    {
+                        0x01 => !found, // vmsbf: set before the first
+                        0x02 => before && found, // vmsof: only the first
+                        _ => before, // vmsif: up to and including the first
+                    }
+                });
+                self.write_mask(op.vd, &body, op.vtype);
+                Some(())
+            }
+            0x10 | 0x11 =>
+            {
+                if !self.groups_fit(op, lmul, 0, 0) || (op.selector == 0x10 && self.csrs[VSTART as usize] != 0)
+                {
+                    return None;
+                }
+                let mut count = 0;
+                let body = self.body(op.vl, op.unmasked, |i|
+                {
+                    if op.selector == 0x11
+                    {
+                        return i as u64; // vid
+                    }
+                    let value = count; // viota
+                    count += self.vmask(op.vs2, i) as u64;
+                    value
+                });
+                self.write_vector(op.vd, sew, lmul, &body, op.vtype);
+                Some(())
+            }
+            _ => None,
+        }
+    }
+
+    /// The widening integer instructions, vwaddu to vwmaccsu.
+    fn integer_widening(&mut self, op: &VectorOp, funct3: u8) -> Option<()>
+    {
+        let VType { sew, lmul, .. } = op.vtype;
+        let bits = sew as u32 * 8;
+        // (vs2 signed, vs1/rs1 signed, vs2 already 2*SEW)
+        let (signed_a, signed_b, wide_a) = match op.funct6
+        {
+            0x30..=0x37 => { let is_signed = op.funct6 & 1 != 0; (is_signed, is_signed, op.funct6 >= 0x34) }
+            0x38 | 0x3c => (false, false, false), // vwmulu, vwmaccu
+            0x3a => (true, false, false), // vwmulsu
+            0x3b | 0x3d => (true, true, false), // vwmul, vwmacc
+            0x3e if funct3 == OPMVX => (true, false, false), // vwmaccus
+            0x3f => (false, true, false), // vwmaccsu
+            _ => return None,
+        };
+        if !self.can_widen(op.vtype) || !self.groups_fit(op, lmul + 1, if wide_a { lmul + 1 } else { lmul }, lmul)
+        {
+            return None;
+        }
+        let body = self.body(op.vl, op.unmasked, |i|
+        {
+            let a = if wide_a { self.velement(op.vs2, i, sew * 2) as i128 } else { extend(self.velement(op.vs2, i, sew), bits, signed_a) };
+            let b = extend(self.operand(op.source, i, sew), bits, signed_b);
+            let result = match op.funct6
+            {
+                0x30..=0x37 if op.funct6 & 2 == 0 => a + b,
+                0x30..=0x37 => a - b,
+                0x38..=0x3b => a * b,
+                _ => a * b + self.velement(op.vd, i, sew * 2) as i128,
+            };
+            result as u64
+        });
+        self.write_vector(op.vd, sew * 2, lmul + 1, &body, op.vtype);
+        Some(())
+    }
+
+    fn execute_opf(&mut self, op: &VectorOp, funct3: u8) -> Option<()>
+    {
+        if !self.fpu_enabled()
+        {
+            return None;
+        }
+        let rm = self.rounding_mode(DYNAMIC)?;
+        if op.funct6 == 0x12 && funct3 == OPFVV
+        {
+            return self.convert_vector(op, rm);
+        }
+        let VType { sew, lmul, .. } = op.vtype;
+        let format = self.vector_format(sew)?;
+        let mut op = *op;
+        if let Operand::Scalar(bits) = op.source
+        {
+            op.source = Operand::Scalar(format.unbox(bits));
+        }
+        let op = &op;
+        let vf = funct3 == OPFVF;
+        let mut flags = 0;
+        match op.funct6
+        {
+            0x01 | 0x03 | 0x05 | 0x07 if !vf =>
+            {
+                // vfredusum, vfredosum, vfredmin and vfredmax, all summed in order
+                let function = opf_function([0x00, 0x00, 0x04, 0x06][op.funct6 as usize / 2], OPFVV)?;
+                self.reduce(op, sew, |cpu, accumulator, i| function(format, accumulator, cpu.velement(op.vs2, i, sew), 0, rm, &mut flags))?;
+            }
+            0x0e | 0x0f if vf =>
+            {
+                let Operand::Scalar(value) = op.source else { return None };
+                self.slide(op, 1, op.funct6 == 0x0e, Some(value))?; // vfslide1up, vfslide1down
+            }
+            0x10 if !vf && op.selector == 0 && op.unmasked =>
+            {
+                // vfmv.f.s
+                let value = self.velement(op.vs2, 0, sew);
+                self.write_freg(format, op.vd, value);
+            }
+            0x10 if vf && op.vs2 == 0 && op.unmasked =>
+            {
+                // vfmv.s.f
+                if (self.csrs[VSTART as usize] as usize) < op.vl
+                {
+                    let value = self.operand(op.source, 0, sew);
+                    self.write_vector(op.vd, sew, 0, &[Some(value)], op.vtype);
+                }
+            }
+            0x13 if !vf =>
+            {
+                if !matches!(op.selector, 0x00 | 0x04 | 0x05 | 0x10) || !self.groups_fit(op, lmul, lmul, 0)
+                {
+                    return None;
+                }
+                let body = self.body(op.vl, op.unmasked, |i|
+                {
+                    let a = self.velement(op.vs2, i, sew);
+                    match op.selector
+                    {
+                        0x00 => float::sqrt(format, a, rm, &mut flags), // vfsqrt
+                        0x04 => float::estimate(format, a, true, &mut flags), // vfrsqrt7
+                        0x05 => float::estimate(format, a, false, &mut flags), // vfrec7
+                        _ => float::classify(format, a), // vfclass
+                    }
+                });
+                self.write_vector(op.vd, sew, lmul, &body, op.vtype);
+            }
+            0x17 if vf =>
+            {
+                // vfmerge, or vfmv.v.f with vm set and vs2 = 0.
+                if (op.unmasked && op.vs2 != 0) || !self.groups_fit(op, lmul, lmul, lmul)
+                {
+                    return None;
+                }
+                let body = self.body(op.vl, true, |i|
+                {
+                    if op.unmasked || self.vmask(0, i) { self.operand(op.source, i, sew) } else { self.velement(op.vs2, i, sew) }
+                });
+                self.write_vector(op.vd, sew, lmul, &body, op.vtype);
+            }
+            0x18 | 0x19 | 0x1b | 0x1c | 0x1d | 0x1f =>
+            {
+                if (!vf && op.funct6 >= 0x1d) || !self.mask_groups_fit(op, lmul, lmul)
+                {
+                    return None;
+                }
+                let body = self.body(op.vl, op.unmasked, |i|
+                {
+                    let (a, b) = (self.velement(op.vs2, i, sew), self.operand(op.source, i, sew));
+                    match op.funct6
+                    {
+                        0x18 => float::compare(format, a, b, false, true, &mut flags), // vmfeq
+                        0x19 => float::compare(format, a, b, true, true, &mut flags), // vmfle
+                        0x1b => float::compare(format, a, b, true, false, &mut flags), // vmflt
+                        0x1c => !float::compare(format, a, b, false, true, &mut flags), // vmfne
+                        0x1d => float::compare(format, b, a, true, false, &mut flags), // vmfgt
+                        _ => float::compare(format, b, a, true, true, &mut flags), // vmfge
+                    }
+                });
+                self.write_mask(op.vd, &body, op.vtype);
+            }
+            0x30..=0x3f => self.float_widening(op, format, rm, &mut flags)?,
+            _ =>
+            {
+                let function = opf_function(op.funct6, funct3)?;
+                if !self.groups_fit(op, lmul, lmul, lmul)
+                {
+                    return None;
+                }
+                let body = self.body(op.vl, op.unmasked, |i|
+                {
+                    let (a, b, d) = (self.velement(op.vs2, i, sew), self.operand(op.source, i, sew), self.velement(op.vd, i, sew));
+                    function(format, a, b, d, rm, &mut flags)
+                });
+                self.write_vector(op.vd, sew, lmul, &body, op.vtype);
+            }
+        }
+        self.accrue_fp_flags(flags);
+        Some(())
+    }
+
+    /// The widening floating-point instructions, which convert their SEW
+    /// operands exactly and then compute at 2*SEW.
+    fn float_widening(&mut self, op: &VectorOp, format: Format, rm: u8, flags: &mut u8) -> Option<()>
+    {
+        let VType { sew, lmul, .. } = op.vtype;
+        let wide = self.vector_format(sew * 2).filter(|_| self.can_widen(op.vtype))?;
+        let vf = matches!(op.source, Operand::Scalar(_));
+        if matches!(op.funct6, 0x31 | 0x33) && !vf
+        {
+            // vfwredusum and vfwredosum
+            return self.reduce(op, sew * 2, |cpu, sum, i|
+            {
+                let element = float::convert(format, wide, cpu.velement(op.vs2, i, sew), rm, flags);
+                float::add(wide, sum, element, rm, flags)
+            });
+        }
+        // Each maps to the single-width function it widens.
+        let (base, wide_a) = match op.funct6
+        {
+            0x30 => (0x00, false), // vfwadd
+            0x32 => (0x02, false), // vfwsub
+            0x34 => (0x00, true), // vfwadd.w
+            0x36 => (0x02, true), // vfwsub.w
+            0x38 => (0x24, false), // vfwmul
+            0x3c..=0x3f => (op.funct6 - 0x10, false), // vfwmacc, vfwnmacc, vfwmsac, vfwnmsac
+            _ => return None,
+        };
+        let function = opf_function(base, OPFVV)?;
+        if !self.groups_fit(op, lmul + 1, if wide_a { lmul + 1 } else { lmul }, lmul)
+        {
+            return None;
+        }
+        let body = self.body(op.vl, op.unmasked, |i|
+        {
+            let a = if wide_a
+            {
+                self.velement(op.vs2, i, sew * 2)
+            }
+            else
+            {
+                float::convert(format, wide, self.velement(op.vs2, i, sew), rm, flags)
+            };
+            let b = float::convert(format, wide, self.operand(op.source, i, sew), rm, flags);
+            function(wide, a, b, self.velement(op.vd, i, sew * 2), rm, flags)
+        });
+        self.write_vector(op.vd, sew * 2, lmul + 1, &body, op.vtype);
+        Some(())
+    }
+
+    /// VFUNARY0: single-width, widening and narrowing conversions.
+    fn convert_vector(&mut self, op: &VectorOp, rm: u8) -> Option<()>
+    {
+        let VType { sew, lmul, .. } = op.vtype;
+        let kind = op.selector & 0x7;
+        let (source_bytes, dest_bytes) = match op.selector >> 3
+        {
+            0 if kind != 4 && kind != 5 => (sew, sew),
+            1 if kind != 5 => (sew, sew * 2),
+            2 => (sew * 2, sew),
+            _ => return None,
+        };
+        if source_bytes != dest_bytes && !self.can_widen(op.vtype)
+        {
+            return None;
+        }
+        let rm = match kind
+        {
+            5 => ROD,
+            6 | 7 => RTZ,
+            _ => rm,
+        };
+        let source_emul = lmul + (source_bytes > sew) as i32;
+        let dest_emul = lmul + (dest_bytes > sew) as i32;
+        if !self.groups_fit(op, dest_emul, source_emul, 0)
+        {
+            return None;
+        }
+        let (source_bits, dest_bits) = (source_bytes as u32 * 8, dest_bytes as u32 * 8);
+        // Integer-to-float conversions need the destination format; the rest the source's.
+        let format = if kind == 2 || kind == 3 { self.vector_format(dest_bytes)? } else { self.vector_format(source_bytes)? };
+        let dest_format = if kind == 4 || kind == 5 { self.vector_format(dest_bytes)? } else { format };
+        let mut flags = 0;
+        let body = self.body(op.vl, op.unmasked, |i|
+        {
+            let a = self.velement(op.vs2, i, source_bytes);
+            match kind
+            {
+                0 | 1 | 6 | 7 => float::to_int(format, a, kind & 1 != 0, dest_bits, rm, &mut flags),
+                2 | 3 => float::from_int(format, a, kind == 3, source_bits, rm, &mut flags),
+                _ => float::convert(format, dest_format, a, rm, &mut flags),
+            }
+        });
+        self.write_vector(op.vd, dest_bytes, dest_emul, &body, op.vtype);
+        self.accrue_fp_flags(flags);
+        Some(())
+    }
+
+    /// Vector loads (LOAD-FP) and stores (STORE-FP) with a vector width.
+    /// Returns None if the instruction is illegal.
+    pub(super) fn execute_vector_memory(&mut self, instruction: &DecodedInstruction, store: bool) -> Option<()>
+    {
+        if !self.vector_enabled()
+        {
+            return None;
+        }
+        let eew: usize = match instruction.funct3
+        {
+            0x0 => 1,
+            0x5 => 2,
+            0x6 => 4,
+            0x7 => 8,
+            _ => return None,
+        };
+        let fields = (instruction.funct7 >> 4) as usize + 1;
+        let mew = instruction.funct7 & 0x8 != 0;
+        let mop = (instruction.funct7 >> 1) & 0x3;
+        let unmasked = instruction.funct7 & 1 != 0;
+        let (data, base, rs2) = (instruction.rd as usize, self.regs[instruction.rs1 as usize], instruction.rs2);
+        if mew || eew > self.elen()
+        {
+            return None;
+        }
+
+        let mut transfer = Transfer { store, data, bytes: eew, fields: 1, field_regs: 1, count: 0, unmasked: true, fault_only_first: false };
+        if mop == MOP_UNIT && rs2 == UNIT_WHOLE
+        {
+            // vl<n>re<eew>.v and vs<n>r.v move whole registers.
+            if !unmasked || !fields.is_power_of_two() || data % fields != 0
+            {
+                return None;
+            }
+            transfer.count = fields * self.vlenb / eew;
+            if self.transfer(&transfer, |_, i, _| base.wrapping_add((i * eew) as u64)).is_some()
+            {
+                self.csrs[VSTART as usize] = 0;
+            }
+            self.mark_vector_dirty();
+            return Some(());
+        }
+        if mop == MOP_UNIT && rs2 == UNIT_MASK
+        {
+            // vlm.v and vsm.v move ceil(vl / 8) bytes of a mask.
+            if !unmasked || fields != 1 || eew != 1
+            {
+                return None;
+            }
+            transfer.count = (self.csrs[VL as usize] as usize).div_ceil(8);
+            if self.transfer(&transfer, |_, i, _| base.wrapping_add(i as u64)).is_some()
+            {
+                if !store && self.agnostic_ones
+                {
+                    for i in transfer.count..self.vlenb
+                    {
+                        self.set_velement(data, i, 1, 0xff);
+                    }
+                }
+                self.csrs[VSTART as usize] = 0;
+            }
+            self.mark_vector_dirty();
+            return Some(());
+        }
+
+        let vtype = self.vtype()?;
+        let indexed = mop & 1 != 0;
+        let eew_emul = vtype.lmul + eew.trailing_zeros() as i32 - vtype.sew.trailing_zeros() as i32;
+        // Indexed accesses take SEW data and EEW indices.
+        let (bytes, emul) = if indexed { (vtype.sew, vtype.lmul) } else { (eew, eew_emul) };
+        let field_regs = 1 << emul.max(0);
+        if !aligned(data, emul) || field_regs * fields > 8 || data + field_regs * fields > 32
+            || (indexed && !aligned(rs2 as usize, eew_emul))
+            || (!store && !unmasked && data == 0)
+        {
+            return None;
+        }
+        let fault_only_first = mop == MOP_UNIT && rs2 == UNIT_FAULT_FIRST && !store;
+        if mop == MOP_UNIT && rs2 != UNIT_NORMAL && !fault_only_first
+        {
+            return None;
+        }
+        let vl = self.csrs[VL as usize] as usize;
+        let transfer = Transfer { store, data, bytes, fields, field_regs, count: vl, unmasked, fault_only_first };
+        let stride = self.regs[rs2 as usize];
+        let done = self.transfer(&transfer, |cpu, i, field|
+        {
+            let offset = match mop
+            {
+                MOP_UNIT => ((i * fields + field) * bytes) as u64,
+                MOP_STRIDED => (i as u64).wrapping_mul(stride).wrapping_add((field * bytes) as u64),
+                _ => cpu.velement(rs2 as usize, i, eew).wrapping_add((field * bytes) as u64),
+            };
+            base.wrapping_add(offset)
+        });
+        let Some(done) = done else { return Some(()) };
+        if done < vl
+        {
+            self.csrs[VL as usize] = done as u64;
+        }
+        if !store
+        {
+            for field in 0..fields
+            {
+                let reg = data + field * field_regs;
+                let body = self.body(done, unmasked, |i| self.velement(reg, i, bytes));
+                self.write_vector(reg, bytes, emul, &body, vtype);
+            }
+        }
+        self.csrs[VSTART as usize] = 0;
+        self.mark_vector_dirty();
+        Some(())
+    }
+
+    /// Moves the active elements of a transfer, stopping at the first
+    /// fault: it records the element in vstart and raises the exception,
+    /// returning None. A fault-only-first load instead stops quietly at a
+    /// faulting element other than the first, returning how many it loaded.
+    fn transfer(&mut self, transfer: &Transfer, address: impl Fn(&Self, usize, usize) -> u64) -> Option<usize>
+    {
+        let bytes = transfer.bytes;
+        let vstart = self.csrs[VSTART as usize] as usize;
+        for i in vstart..transfer.count
+        {
+            if !(transfer.unmasked || self.vmask(0, i))
+            {
+                continue;
+            }
+            for field in 0..transfer.fields
+            {
+                let addr = address(self, i, field);
+                let reg = transfer.data + field * transfer.field_regs;
+                let result = if transfer.store
+                {
+                    let value = self.velement(reg, i, bytes);
+                    self.store(addr, bytes, value)
+                }
+                else
+                {
+                    self.load(addr, bytes).map(|value| self.set_velement(reg, i, bytes, value))
+                };
+                if let Err(exception) = result
+                {
+                    if transfer.fault_only_first && i > 0
+                    {
+                        return Some(i);
+                    }
+                    self.csrs[VSTART as usize] = i as u64;
+                    self.raise_exception(exception, addr);
+                    return None;
+                }
+            }
+        }
+        Some(transfer.count)
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_vtype_decoding()
+    {
+        let m1_e32 = VType::decode(0b010_000, 8).unwrap();
+        assert_eq!((m1_e32.sew, m1_e32.lmul), (4, 0));
+        assert_eq!(m1_e32.vlmax(16), 4);
+        let mf8_e8_ta_ma = VType::decode(0b1100_0101, 8).unwrap();
+        assert_eq!((mf8_e8_ta_ma.lmul, mf8_e8_ta_ma.tail_agnostic, mf8_e8_ta_ma.mask_agnostic), (-3, true, true));
+        assert_eq!(mf8_e8_ta_ma.vlmax(16), 2);
+
+        assert_eq!(VType::decode(0b011_000, 4), None); // e64 without ELEN = 64
+        assert_eq!(VType::decode(0b001_111, 4), Some(VType { sew: 2, lmul: -1, tail_agnostic: false, mask_agnostic: false }));
+        assert_eq!(VType::decode(0b010_111, 4), None); // mf2 at e32 with ELEN = 32
+        assert_eq!(VType::decode(0b000_100, 8), None);
+        assert_eq!(VType::decode(1 << 8, 8), None);
+
+        assert_eq!(round_shift(0b1011, 2, 0), 0b11);
+        assert_eq!(round_shift(0b1010, 2, 1), 0b10);
+        assert_eq!(round_shift(0b1110, 2, 1), 0b100);
+        assert_eq!(round_shift(0b1011, 2, 2), 0b10);
+        assert_eq!(round_shift(0b1001, 2, 3), 0b11);
+        assert_eq!(round_shift(-3, 1, 0), -1);
+    }
+}